@@ -163,6 +163,13 @@ class DatabaseLoginsStorage(private val dbPath: String) : AutoCloseable, LoginsS
         }
     }
 
+    @Throws(LoginsStorageException::class)
+    override fun setConflictResolution(strategy: ConflictResolution) {
+        rustCallWithLock { raw, error ->
+            PasswordSyncAdapter.INSTANCE.sync15_passwords_set_conflict_resolution(raw, strategy.value, error)
+        }
+    }
+
     @Throws(LoginsStorageException::class)
     override fun delete(id: String): Boolean {
         return writeQueryCounters.measure {
@@ -13,6 +13,20 @@ class SyncUnlockInfo(
     val tokenserverURL: String
 )
 
+/**
+ * How a sync resolves a login that changed both locally and on the server.
+ */
+enum class ConflictResolution(internal val value: Int) {
+    /** Merge the two versions field by field. This is the default. */
+    FIELD_LEVEL(0),
+    /** Keep the server's version. */
+    SERVER_WINS(1),
+    /** Keep the local version. */
+    CLIENT_WINS(2),
+    /** Keep whichever version was changed most recently. */
+    NEWER_WINS(3)
+}
+
 interface LoginsStorage : AutoCloseable {
     /**
      * Lock (close) the database.
@@ -110,6 +124,15 @@ interface LoginsStorage : AutoCloseable {
     @Throws(LoginsStorageException::class)
     fun wipeLocal()
 
+    /**
+     * Choose how future syncs resolve conflicts. Defaults to [ConflictResolution.FIELD_LEVEL].
+     * Implementations which only support one strategy can leave this as a no-op.
+     *
+     * @throws [LoginsStorageException] On unexpected errors (IO failure, rust panics, etc)
+     */
+    @Throws(LoginsStorageException::class)
+    fun setConflictResolution(strategy: ConflictResolution) {}
+
     /**
      * Deletes the password with the given ID.
      *
@@ -60,6 +60,7 @@ internal interface PasswordSyncAdapter : Library {
     fun sync15_passwords_wipe(handle: LoginsDbHandle, error: RustError.ByReference)
     fun sync15_passwords_wipe_local(handle: LoginsDbHandle, error: RustError.ByReference)
     fun sync15_passwords_reset(handle: LoginsDbHandle, error: RustError.ByReference)
+    fun sync15_passwords_set_conflict_resolution(handle: LoginsDbHandle, strategy: Int, error: RustError.ByReference)
 
     fun sync15_passwords_touch(handle: LoginsDbHandle, id: String, error: RustError.ByReference)
 
@@ -0,0 +1,1407 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+#![allow(unknown_lints)]
+#![warn(rust_2018_idioms)]
+
+//! An async wrapper around `logins::LoginDb`, for use from `tokio`.
+//!
+//! All of `LoginDb`'s I/O is blocking, so calling it directly from async
+//! code stalls the executor. `AsyncLoginDb` instead runs each operation on
+//! tokio's blocking thread pool. Clones of it can be shared between tasks.
+//!
+//! Writes all go through one connection, behind a mutex, so they run one at
+//! a time, in the order they take the lock. A database opened from a file
+//! also has a pool of read-only connections, and uses write-ahead logging,
+//! so reads run alongside each other and alongside a write, and only see
+//! committed changes. In-memory databases can't be shared between
+//! connections, so their reads wait their turn with the writes.
+//!
+//! Every `LoginDb` method which does I/O has an async version here, except
+//! those which hand out borrows of the connection (transactions, savepoints,
+//! `stream_all` and interrupt scopes) or take borrowed SQL parameters
+//! (`explain_query`). Those, and anything else, can be reached through
+//! `run`. `AsyncLoginStore` provides the `sync15::Store` operations.
+
+use logins::{
+    AuditEntry, AutoVacuumMode, BackupOptions, BackupProgress, BitwardenUriMatchType, ChangeEvent,
+    CheckpointResult, CipherConfig, ConflictResolution, ConnectionOptions, CreationSource, CsvExportFormat,
+    EncryptionKey, ExportPermission, FieldLimits, ImportOptions, ImportReport, IntegrityReport,
+    JournalMode, LocalLogin, LockedLogin, Login, LoginChangeEvent, LoginDb, LoginGroup,
+    LoginSortOrder, LoginStatistics, LoginStore, LoginValidationError, MergeReport, MergeStrategy,
+    MigrationMetrics, MirrorLogin, OutgoingConfirmation, PasswordStrengthChecker, RepairReport,
+    Result, ScoredLogin, SecurityReport, StorageInfo, SyncPreview, SyncProgress, SyncStats,
+    SyncStatusCounts, TempStoreMode,
+};
+use rusqlite::{Connection, OpenFlags};
+use sql_support::SqlInterruptHandle;
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex, MutexGuard, TryLockError};
+use sync15::{
+    telemetry, CollectionRequest, IncomingChangeset, KeyBundle, MemoryCachedState,
+    OutgoingChangeset, ServerTimestamp, Store, StoreSyncAssociation, Sync15StorageClientInit,
+};
+use sync_guid::Guid;
+use tokio::sync::oneshot;
+
+/// How many read-only connections `open` gives a database.
+pub const DEFAULT_READERS: usize = 4;
+
+#[derive(Clone)]
+pub struct AsyncLoginDb {
+    writer: Arc<Mutex<LoginDb>>,
+    // Empty for in-memory databases.
+    readers: Arc<Vec<Mutex<LoginDb>>>,
+    next_reader: Arc<AtomicUsize>,
+    mem_cached_state: Arc<Mutex<MemoryCachedState>>,
+    interrupt_handle: Arc<SqlInterruptHandle>,
+}
+
+// Where a cancellable operation is up to, so that cancelling it only ever
+// interrupts that operation, and not whichever one holds the lock.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum OperationState {
+    Waiting,
+    Running,
+    Cancelled,
+    Done,
+}
+
+// Locks `mutex`, ignoring poisoning. A panic while the `LoginDb` is locked
+// rolls back any transaction it had open, so it's still usable.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+impl AsyncLoginDb {
+    /// Wraps `db`, which is used for reads as well as writes.
+    pub fn new(db: LoginDb) -> Self {
+        Self::with_readers(db, vec![])
+    }
+
+    fn with_readers(writer: LoginDb, readers: Vec<LoginDb>) -> Self {
+        let interrupt_handle = Arc::new(writer.new_interrupt_handle());
+        Self {
+            writer: Arc::new(Mutex::new(writer)),
+            readers: Arc::new(readers.into_iter().map(Mutex::new).collect()),
+            next_reader: Arc::default(),
+            mem_cached_state: Arc::default(),
+            interrupt_handle,
+        }
+    }
+
+    /// Opens the database at `path`, with `DEFAULT_READERS` read-only
+    /// connections.
+    pub async fn open(
+        path: impl AsRef<Path>,
+        encryption_key: Option<EncryptionKey>,
+    ) -> Result<Self> {
+        Self::open_with_readers(path, encryption_key, DEFAULT_READERS).await
+    }
+
+    /// Like `open`, but with `num_readers` read-only connections. With none,
+    /// reads wait for writes, as with `new`.
+    pub async fn open_with_readers(
+        path: impl AsRef<Path>,
+        encryption_key: Option<EncryptionKey>,
+        num_readers: usize,
+    ) -> Result<Self> {
+        let path: PathBuf = path.as_ref().into();
+        spawn_blocking(move || {
+            // The writer has to be opened first, since it creates the
+            // database, and upgrades its schema.
+            let writer = LoginDb::with_connection_options(
+                Connection::open(&path)?,
+                encryption_key.clone(),
+                None,
+                &ConnectionOptions {
+                    journal_mode: Some(JournalMode::Wal),
+                    ..ConnectionOptions::default()
+                },
+            )?;
+            let readers = (0..num_readers)
+                .map(|_| {
+                    let conn = Connection::open_with_flags(
+                        &path,
+                        OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+                    )?;
+                    LoginDb::with_connection(conn, encryption_key.clone(), None)
+                })
+                .collect::<Result<_>>()?;
+            Ok(Self::with_readers(writer, readers))
+        })
+        .await
+    }
+
+    pub async fn open_in_memory(encryption_key: Option<EncryptionKey>) -> Result<Self> {
+        let db = spawn_blocking(move || LoginDb::open_in_memory(encryption_key)).await?;
+        Ok(Self::new(db))
+    }
+
+    /// Runs `f` with the writing connection on the blocking thread pool. This
+    /// is how to call any `LoginDb` method which doesn't have an async
+    /// version here.
+    pub async fn run<F, T>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut LoginDb) -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let db = Arc::clone(&self.writer);
+        spawn_blocking(move || f(&mut lock(&db))).await
+    }
+
+    /// Like `run`, but with a read-only connection if there are any, so that
+    /// `f` doesn't wait for writes. `f` must only read.
+    pub async fn read<F, T>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&LoginDb) -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        if self.readers.is_empty() {
+            return self.run(move |db| f(db)).await;
+        }
+        let readers = Arc::clone(&self.readers);
+        let start = self.next_reader.fetch_add(1, Ordering::Relaxed);
+        spawn_blocking(move || {
+            // Use the first idle reader, starting at a different one each
+            // time. If they're all busy, wait for the one we started at.
+            for i in 0..readers.len() {
+                match readers[(start + i) % readers.len()].try_lock() {
+                    Ok(db) => return f(&db),
+                    Err(TryLockError::Poisoned(e)) => return f(&e.into_inner()),
+                    Err(TryLockError::WouldBlock) => {}
+                }
+            }
+            f(&lock(&readers[start % readers.len()]))
+        })
+        .await
+    }
+
+    /// Like `run`, but gives up if `cancel` receives a value first. If `f`
+    /// hasn't started yet, it never will; otherwise it's interrupted, which
+    /// operations that use an interrupt scope (like `sync`) notice promptly.
+    /// Either way, this fails with `ErrorKind::Interrupted` unless `f` had
+    /// already finished. Dropping the sender doesn't cancel anything.
+    pub async fn run_cancellable<F, T>(&self, f: F, cancel: oneshot::Receiver<()>) -> Result<T>
+    where
+        F: FnOnce(&mut LoginDb) -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let state = Arc::new(Mutex::new(OperationState::Waiting));
+        let task_state = Arc::clone(&state);
+        let db = Arc::clone(&self.writer);
+        let task = spawn_blocking(move || {
+            let mut db = lock(&db);
+            {
+                let mut state = lock(&task_state);
+                if *state == OperationState::Cancelled {
+                    return Err(interrupt_support::Interrupted.into());
+                }
+                *state = OperationState::Running;
+            }
+            let result = f(&mut db);
+            // Still holding the database lock, so a late `interrupt` can't
+            // hit the next operation.
+            *lock(&task_state) = OperationState::Done;
+            result
+        });
+        tokio::pin!(task);
+        tokio::select! {
+            result = &mut task => result,
+            Ok(()) = cancel => {
+                {
+                    let mut state = lock(&state);
+                    match *state {
+                        OperationState::Waiting => *state = OperationState::Cancelled,
+                        OperationState::Running => self.interrupt_handle.interrupt(),
+                        OperationState::Cancelled | OperationState::Done => {}
+                    }
+                }
+                task.await
+            }
+        }
+    }
+
+    // Opening, encrypting and backing up.
+
+    pub async fn open_and_get_salt(
+        path: impl AsRef<Path>,
+        encryption_key: impl Into<String>,
+    ) -> Result<String> {
+        let path: PathBuf = path.as_ref().into();
+        let key = encryption_key.into();
+        spawn_blocking(move || LoginDb::open_and_get_salt(path, &key)).await
+    }
+
+    pub async fn open_and_get_salt_with_cipher(
+        path: impl AsRef<Path>,
+        encryption_key: impl Into<String>,
+        cipher: CipherConfig,
+    ) -> Result<String> {
+        let path: PathBuf = path.as_ref().into();
+        let key = encryption_key.into();
+        spawn_blocking(move || LoginDb::open_and_get_salt_with_cipher(path, &key, &cipher)).await
+    }
+
+    pub async fn open_and_migrate_to_plaintext_header(
+        path: impl AsRef<Path>,
+        encryption_key: impl Into<String>,
+        salt: impl Into<String>,
+    ) -> Result<()> {
+        let path: PathBuf = path.as_ref().into();
+        let (key, salt) = (encryption_key.into(), salt.into());
+        spawn_blocking(move || LoginDb::open_and_migrate_to_plaintext_header(path, &key, &salt))
+            .await
+    }
+
+    pub async fn open_and_migrate_to_plaintext_header_with_cipher(
+        path: impl AsRef<Path>,
+        encryption_key: impl Into<String>,
+        salt: impl Into<String>,
+        cipher: CipherConfig,
+    ) -> Result<()> {
+        let path: PathBuf = path.as_ref().into();
+        let (key, salt) = (encryption_key.into(), salt.into());
+        spawn_blocking(move || {
+            LoginDb::open_and_migrate_to_plaintext_header_with_cipher(path, &key, &salt, &cipher)
+        })
+        .await
+    }
+
+    pub async fn encrypt(path: impl AsRef<Path>, new_key: impl Into<String>) -> Result<()> {
+        let path: PathBuf = path.as_ref().into();
+        let key = new_key.into();
+        spawn_blocking(move || LoginDb::encrypt(path, &key)).await
+    }
+
+    pub async fn encrypt_with_cipher(
+        path: impl AsRef<Path>,
+        new_key: impl Into<String>,
+        cipher: CipherConfig,
+    ) -> Result<()> {
+        let path: PathBuf = path.as_ref().into();
+        let key = new_key.into();
+        spawn_blocking(move || LoginDb::encrypt_with_cipher(path, &key, &cipher)).await
+    }
+
+    pub async fn decrypt(path: impl AsRef<Path>, current_key: impl Into<String>) -> Result<()> {
+        let path: PathBuf = path.as_ref().into();
+        let key = current_key.into();
+        spawn_blocking(move || LoginDb::decrypt(path, &key)).await
+    }
+
+    pub async fn decrypt_with_cipher(
+        path: impl AsRef<Path>,
+        current_key: impl Into<String>,
+        cipher: CipherConfig,
+    ) -> Result<()> {
+        let path: PathBuf = path.as_ref().into();
+        let key = current_key.into();
+        spawn_blocking(move || LoginDb::decrypt_with_cipher(path, &key, &cipher)).await
+    }
+
+    pub async fn backup(
+        &self,
+        dest_path: impl AsRef<Path>,
+        key: Option<EncryptionKey>,
+        options: BackupOptions,
+        mut progress: Option<Box<dyn FnMut(BackupProgress) + Send>>,
+    ) -> Result<()> {
+        let dest_path: PathBuf = dest_path.as_ref().into();
+        self.read(move |db| {
+            let progress = progress
+                .as_mut()
+                .map(|p| p.as_mut() as &mut dyn FnMut(BackupProgress));
+            db.backup(dest_path, key, &options, progress)
+        })
+        .await
+    }
+
+    pub async fn restore_from_backup(
+        src: impl AsRef<Path>,
+        dest: impl AsRef<Path>,
+        key: Option<EncryptionKey>,
+    ) -> Result<()> {
+        let (src, dest): (PathBuf, PathBuf) = (src.as_ref().into(), dest.as_ref().into());
+        spawn_blocking(move || LoginDb::restore_from_backup(src, dest, key)).await
+    }
+
+    pub async fn restore_from_backup_with_cipher(
+        src: impl AsRef<Path>,
+        dest: impl AsRef<Path>,
+        key: Option<EncryptionKey>,
+        cipher: CipherConfig,
+    ) -> Result<()> {
+        let (src, dest): (PathBuf, PathBuf) = (src.as_ref().into(), dest.as_ref().into());
+        spawn_blocking(move || LoginDb::restore_from_backup_with_cipher(src, dest, key, &cipher))
+            .await
+    }
+
+    // Settings and maintenance. These apply to the writing connection.
+
+    pub async fn set_temp_store_mode(&self, mode: TempStoreMode) -> Result<()> {
+        self.run(move |db| db.set_temp_store_mode(mode)).await
+    }
+
+    pub async fn get_db_size_bytes(&self) -> Result<u64> {
+        self.read(|db| db.get_db_size_bytes()).await
+    }
+
+    pub async fn get_wal_size_bytes(&self) -> Result<Option<u64>> {
+        self.read(|db| db.get_wal_size_bytes()).await
+    }
+
+    pub async fn get_storage_info(&self) -> Result<StorageInfo> {
+        self.run(|db| db.get_storage_info()).await
+    }
+
+    pub async fn set_busy_timeout(&self, ms: u32) -> Result<()> {
+        self.run(move |db| db.set_busy_timeout(ms)).await
+    }
+
+    pub async fn set_max_field_lengths(
+        &self,
+        max_password: usize,
+        max_username: usize,
+    ) -> Result<()> {
+        self.run(move |db| {
+            db.set_max_field_lengths(max_password, max_username);
+            Ok(())
+        })
+        .await
+    }
+
+    pub async fn field_limits(&self) -> Result<FieldLimits> {
+        self.run(|db| Ok(db.field_limits())).await
+    }
+
+    pub async fn set_conflict_resolution(&self, resolution: ConflictResolution) -> Result<()> {
+        self.run(move |db| {
+            db.set_conflict_resolution(resolution);
+            Ok(())
+        })
+        .await
+    }
+
+    pub async fn conflict_resolution(&self) -> Result<ConflictResolution> {
+        self.run(|db| Ok(db.conflict_resolution())).await
+    }
+
+    pub async fn set_auto_vacuum(&self, mode: AutoVacuumMode) -> Result<()> {
+        self.run(move |db| db.set_auto_vacuum(mode)).await
+    }
+
+    pub async fn vacuum(&self) -> Result<()> {
+        self.run(|db| db.vacuum()).await
+    }
+
+    pub async fn checkpoint(&self) -> Result<CheckpointResult> {
+        self.run(|db| db.checkpoint()).await
+    }
+
+    pub async fn set_cipher_kdf_iter(&self, iterations: u32) -> Result<()> {
+        self.run(move |db| db.set_cipher_kdf_iter(iterations)).await
+    }
+
+    pub async fn get_cipher_kdf_iter(&self) -> Result<u32> {
+        self.run(|db| db.get_cipher_kdf_iter()).await
+    }
+
+    pub async fn cipher_config(&self) -> Result<CipherConfig> {
+        self.run(|db| Ok(db.cipher_config())).await
+    }
+
+    pub async fn disable_mem_security(&self) -> Result<()> {
+        self.run(|db| db.disable_mem_security()).await
+    }
+
+    /// Note that the read-only connections keep using the old key, so the
+    /// database should be reopened afterwards.
+    pub async fn rekey_database(&self, new_encryption_key: impl Into<String>) -> Result<()> {
+        let key = new_encryption_key.into();
+        self.run(move |db| db.rekey_database(&key)).await
+    }
+
+    /// As with `rekey_database`, the database should be reopened afterwards.
+    pub async fn rekey_raw(&self, raw_key: [u8; 32]) -> Result<()> {
+        self.run(move |db| db.rekey_raw(&raw_key)).await
+    }
+
+    pub async fn register_change_hook<F>(&self, hook: F) -> Result<()>
+    where
+        F: Fn(ChangeEvent) + Send + 'static,
+    {
+        self.run(move |db| db.register_change_hook(hook)).await
+    }
+
+    pub async fn subscribe(&self) -> Result<mpsc::Receiver<LoginChangeEvent>> {
+        self.run(|db| db.subscribe()).await
+    }
+
+    pub async fn set_audit_actor(&self, actor: Option<String>) -> Result<()> {
+        self.run(move |db| {
+            db.set_audit_actor(actor);
+            Ok(())
+        })
+        .await
+    }
+
+    // Reading logins.
+
+    pub async fn find_all_dupes(&self) -> Result<Vec<(Login, Login)>> {
+        self.read(|db| db.find_all_dupes()).await
+    }
+
+    pub async fn get_all(&self) -> Result<Vec<Login>> {
+        self.read(|db| db.get_all()).await
+    }
+
+    pub async fn get_all_grouped_by_hostname(&self) -> Result<Vec<LoginGroup>> {
+        self.read(|db| db.get_all_grouped_by_hostname()).await
+    }
+
+    pub async fn get_all_with_custom_order(
+        &self,
+        order_by: impl Into<String>,
+    ) -> Result<Vec<Login>> {
+        let order_by = order_by.into();
+        self.read(move |db| db.get_all_with_custom_order(&order_by))
+            .await
+    }
+
+    pub async fn get_all_ordered(&self, order: LoginSortOrder) -> Result<Vec<Login>> {
+        self.read(move |db| db.get_all_ordered(order)).await
+    }
+
+    pub async fn get_all_locked(&self) -> Result<Vec<LockedLogin>> {
+        self.read(|db| db.get_all_locked()).await
+    }
+
+    pub async fn get_all_in_order(&self) -> Result<Vec<Login>> {
+        self.read(|db| db.get_all_in_order()).await
+    }
+
+    pub async fn get_security_report(
+        &self,
+        checker: Box<dyn PasswordStrengthChecker + Send>,
+        old_threshold_days: u64,
+    ) -> Result<SecurityReport> {
+        self.read(move |db| db.get_security_report(checker.as_ref(), old_threshold_days))
+            .await
+    }
+
+    pub async fn get_never_used(&self) -> Result<Vec<Login>> {
+        self.read(|db| db.get_never_used()).await
+    }
+
+    pub async fn get_weak_passwords(&self, min_length: usize) -> Result<Vec<Login>> {
+        self.read(move |db| db.get_weak_passwords(min_length)).await
+    }
+
+    pub async fn get_reused_passwords(&self) -> Result<Vec<Vec<Login>>> {
+        self.read(|db| db.get_reused_passwords()).await
+    }
+
+    pub async fn get_statistics(&self) -> Result<LoginStatistics> {
+        self.read(|db| db.get_statistics()).await
+    }
+
+    pub async fn get_sync_status_counts(&self) -> Result<SyncStatusCounts> {
+        self.read(|db| db.get_sync_status_counts()).await
+    }
+
+    pub async fn get_all_paginated(
+        &self,
+        after_guid: Option<String>,
+        limit: usize,
+    ) -> Result<Vec<Login>> {
+        self.read(move |db| db.get_all_paginated(after_guid.as_deref(), limit))
+            .await
+    }
+
+    pub async fn get_total_count(&self) -> Result<usize> {
+        self.read(|db| db.get_total_count()).await
+    }
+
+    pub async fn count(&self) -> Result<usize> {
+        self.read(|db| db.count()).await
+    }
+
+    pub async fn count_by_hostname(&self, hostname: impl Into<String>) -> Result<usize> {
+        let hostname = hostname.into();
+        self.read(move |db| db.count_by_hostname(&hostname)).await
+    }
+
+    pub async fn has_any_logins(&self) -> Result<bool> {
+        self.read(|db| db.has_any_logins()).await
+    }
+
+    pub async fn get_all_sorted_by_domain(&self) -> Result<Vec<Login>> {
+        self.read(|db| db.get_all_sorted_by_domain()).await
+    }
+
+    pub async fn get_all_for_hostname_with_subdomains(
+        &self,
+        hostname: impl Into<String>,
+    ) -> Result<Vec<Login>> {
+        let hostname = hostname.into();
+        self.read(move |db| db.get_all_for_hostname_with_subdomains(&hostname))
+            .await
+    }
+
+    pub async fn get_by_base_domain(&self, base_domain: impl Into<String>) -> Result<Vec<Login>> {
+        let base_domain = base_domain.into();
+        self.read(move |db| db.get_by_base_domain(&base_domain))
+            .await
+    }
+
+    pub async fn get_all_for_multiple_hostnames(
+        &self,
+        hostnames: Vec<String>,
+    ) -> Result<HashMap<String, Vec<Login>>> {
+        self.read(move |db| db.get_all_for_multiple_hostnames(&as_strs(&hostnames)))
+            .await
+    }
+
+    pub async fn get_by_guids(&self, ids: Vec<String>) -> Result<Vec<Login>> {
+        self.read(move |db| db.get_by_guids(&as_strs(&ids))).await
+    }
+
+    pub async fn get_by_guids_map(&self, ids: Vec<String>) -> Result<HashMap<String, Login>> {
+        self.read(move |db| db.get_by_guids_map(&as_strs(&ids)))
+            .await
+    }
+
+    pub async fn get_all_for_source(&self, source: CreationSource) -> Result<Vec<Login>> {
+        self.read(move |db| db.get_all_for_source(source)).await
+    }
+
+    pub async fn get_all_with_username_containing(
+        &self,
+        hostname: impl Into<String>,
+        username_prefix: impl Into<String>,
+    ) -> Result<Vec<Login>> {
+        let (hostname, username_prefix) = (hostname.into(), username_prefix.into());
+        self.read(move |db| db.get_all_with_username_containing(&hostname, &username_prefix))
+            .await
+    }
+
+    pub async fn query_logins(&self, query: impl Into<String>) -> Result<Vec<Login>> {
+        let query = query.into();
+        self.read(move |db| db.query_logins(&query)).await
+    }
+
+    pub async fn get_all_hostnames(&self) -> Result<Vec<String>> {
+        self.read(|db| db.get_all_hostnames()).await
+    }
+
+    pub async fn get_hostnames_matching(&self, prefix: impl Into<String>) -> Result<Vec<String>> {
+        let prefix = prefix.into();
+        self.read(move |db| db.get_hostnames_matching(&prefix))
+            .await
+    }
+
+    pub async fn get_by_hostname(&self, hostname: impl Into<String>) -> Result<Vec<Login>> {
+        let hostname = hostname.into();
+        self.read(move |db| db.get_by_hostname(&hostname)).await
+    }
+
+    pub async fn get_all_for_bitwarden_uri_match(
+        &self,
+        url: impl Into<String>,
+        match_type: BitwardenUriMatchType,
+    ) -> Result<Vec<Login>> {
+        let url = url.into();
+        self.read(move |db| db.get_all_for_bitwarden_uri_match(&url, match_type))
+            .await
+    }
+
+    pub async fn get_logins_for_url(&self, url: impl Into<String>) -> Result<Vec<Login>> {
+        let url = url.into();
+        self.read(move |db| db.get_logins_for_url(&url)).await
+    }
+
+    pub async fn get_logins_for_autocomplete(
+        &self,
+        url: impl Into<String>,
+    ) -> Result<Vec<ScoredLogin>> {
+        let url = url.into();
+        self.read(move |db| db.get_logins_for_autocomplete(&url))
+            .await
+    }
+
+    pub async fn get_all_for_app(&self, app_id: impl Into<String>) -> Result<Vec<Login>> {
+        let app_id = app_id.into();
+        self.read(move |db| db.get_all_for_app(&app_id)).await
+    }
+
+    pub async fn get_all_for_app_or_hostname(
+        &self,
+        app_id: impl Into<String>,
+        hostname: impl Into<String>,
+    ) -> Result<Vec<Login>> {
+        let (app_id, hostname) = (app_id.into(), hostname.into());
+        self.read(move |db| db.get_all_for_app_or_hostname(&app_id, &hostname))
+            .await
+    }
+
+    pub async fn get_all_for_autofill_any(
+        &self,
+        hostname: impl Into<String>,
+        realm: Option<String>,
+    ) -> Result<Vec<Login>> {
+        let hostname = hostname.into();
+        self.read(move |db| db.get_all_for_autofill_any(&hostname, realm.as_deref()))
+            .await
+    }
+
+    pub async fn get_all_for_domain_suffix(&self, suffix: impl Into<String>) -> Result<Vec<Login>> {
+        let suffix = suffix.into();
+        self.read(move |db| db.get_all_for_domain_suffix(&suffix))
+            .await
+    }
+
+    pub async fn get_all_by_guid_prefix(&self, prefix: impl Into<String>) -> Result<Vec<Login>> {
+        let prefix = prefix.into();
+        self.read(move |db| db.get_all_by_guid_prefix(&prefix))
+            .await
+    }
+
+    pub async fn get_all_mirror_without_local_overlay(&self) -> Result<Vec<MirrorLogin>> {
+        self.read(|db| db.get_all_mirror_without_local_overlay())
+            .await
+    }
+
+    pub async fn get_mirror_record(&self, guid: impl Into<String>) -> Result<Option<MirrorLogin>> {
+        let guid = guid.into();
+        self.read(move |db| db.get_mirror_record(&guid)).await
+    }
+
+    pub async fn get_local_record(&self, guid: impl Into<String>) -> Result<Option<LocalLogin>> {
+        let guid = guid.into();
+        self.read(move |db| db.get_local_record(&guid)).await
+    }
+
+    pub async fn get_by_id(&self, id: impl Into<String>) -> Result<Option<Login>> {
+        let id = id.into();
+        self.read(move |db| db.get_by_id(&id)).await
+    }
+
+    pub async fn unlock_login(&self, guid: impl Into<String>) -> Result<Option<String>> {
+        let guid = guid.into();
+        self.read(move |db| db.unlock_login(&guid)).await
+    }
+
+    /// Warms the page cache of each read-only connection (or of the writing
+    /// connection, if there are none).
+    pub async fn prefetch_for_autofill(&self, hostname: impl Into<String>) -> Result<()> {
+        let hostname = hostname.into();
+        if self.readers.is_empty() {
+            return self
+                .run(move |db| db.prefetch_for_autofill(&hostname))
+                .await;
+        }
+        let readers = Arc::clone(&self.readers);
+        spawn_blocking(move || {
+            for reader in readers.iter() {
+                lock(reader).prefetch_for_autofill(&hostname)?;
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    pub async fn get_audit_log(&self, guid: impl Into<String>) -> Result<Vec<AuditEntry>> {
+        let guid = guid.into();
+        self.read(move |db| db.get_audit_log(&guid)).await
+    }
+
+    pub async fn get_changed_since(&self, since_ms: i64) -> Result<Vec<Login>> {
+        self.read(move |db| db.get_changed_since(since_ms)).await
+    }
+
+    pub async fn get_deleted_since(&self, since_ms: i64) -> Result<Vec<String>> {
+        self.read(move |db| db.get_deleted_since(since_ms)).await
+    }
+
+    pub async fn get_all_with_creation_before(&self, before_ms: i64) -> Result<Vec<Login>> {
+        self.read(move |db| db.get_all_with_creation_before(before_ms))
+            .await
+    }
+
+    pub async fn exists(&self, id: impl Into<String>) -> Result<bool> {
+        let id = id.into();
+        self.read(move |db| db.exists(&id)).await
+    }
+
+    pub async fn dupe_exists(&self, login: Login) -> Result<bool> {
+        self.read(move |db| db.dupe_exists(&login)).await
+    }
+
+    pub async fn check_for_dupes(&self, login: Login) -> Result<()> {
+        self.read(move |db| db.check_for_dupes(&login)).await
+    }
+
+    pub async fn potential_dupes_ignoring_username(&self, login: Login) -> Result<Vec<Login>> {
+        self.read(move |db| db.potential_dupes_ignoring_username(&login))
+            .await
+    }
+
+    // Validation and repair. These use the writing connection's field limits.
+
+    pub async fn check_valid_with_no_dupes(&self, login: Login) -> Result<()> {
+        self.run(move |db| db.check_valid_with_no_dupes(&login))
+            .await
+    }
+
+    pub async fn fixup_and_check_for_dupes(&self, login: Login) -> Result<Login> {
+        self.run(move |db| db.fixup_and_check_for_dupes(login))
+            .await
+    }
+
+    pub async fn heal_orphaned_mirror_records(&self) -> Result<usize> {
+        self.run(|db| db.heal_orphaned_mirror_records()).await
+    }
+
+    pub async fn verify_integrity(&self) -> Result<IntegrityReport> {
+        self.read(|db| db.verify_integrity()).await
+    }
+
+    pub async fn validate_all_logins(&self) -> Result<Vec<(String, Vec<LoginValidationError>)>> {
+        self.run(|db| db.validate_all_logins()).await
+    }
+
+    pub async fn auto_fix_invalid_logins(&self) -> Result<usize> {
+        self.run(|db| db.auto_fix_invalid_logins()).await
+    }
+
+    pub async fn run_integrity_repair(&self) -> Result<RepairReport> {
+        self.run(|db| db.run_integrity_repair()).await
+    }
+
+    pub async fn get_quarantined_records(&self) -> Result<Vec<(i64, String, String)>> {
+        self.read(|db| db.get_quarantined_records()).await
+    }
+
+    pub async fn restore_from_quarantine(&self, id: i64) -> Result<()> {
+        self.run(move |db| db.restore_from_quarantine(id)).await
+    }
+
+    // Changing logins.
+
+    pub async fn touch(&self, id: impl Into<String>) -> Result<()> {
+        let id = id.into();
+        self.run(move |db| db.touch(&id)).await
+    }
+
+    pub async fn touch_many(&self, ids: Vec<String>) -> Result<()> {
+        self.run(move |db| db.touch_many(&as_strs(&ids))).await
+    }
+
+    pub async fn get_password_for_form_fill(
+        &self,
+        hostname: impl Into<String>,
+        username: impl Into<String>,
+    ) -> Result<Option<(String, String)>> {
+        let (hostname, username) = (hostname.into(), username.into());
+        self.run(move |db| db.get_password_for_form_fill(&hostname, &username))
+            .await
+    }
+
+    pub async fn add(&self, login: Login) -> Result<Login> {
+        self.run(move |db| db.add(login)).await
+    }
+
+    pub async fn add_many(&self, logins: Vec<Login>) -> Result<Vec<Login>> {
+        self.run(move |db| db.add_many(logins)).await
+    }
+
+    pub async fn import_multiple(&self, logins: Vec<Login>) -> Result<MigrationMetrics> {
+        self.run(move |db| db.import_multiple(&logins)).await
+    }
+
+    pub async fn import_from_csv<R>(
+        &self,
+        reader: R,
+        options: ImportOptions,
+    ) -> Result<ImportReport>
+    where
+        R: Read + Send + 'static,
+    {
+        self.run(move |db| db.import_from_csv(reader, options))
+            .await
+    }
+
+    /// Returns the number of logins written, and `writer`.
+    pub async fn export_to_json<W>(
+        &self,
+        mut writer: W,
+        permission: ExportPermission,
+    ) -> Result<(usize, W)>
+    where
+        W: Write + Send + 'static,
+    {
+        self.read(move |db| {
+            let count = db.export_to_json(&mut writer, permission)?;
+            Ok((count, writer))
+        })
+        .await
+    }
+
+    /// Returns the number of logins written, and `writer`.
+    pub async fn export_to_csv<W>(
+        &self,
+        mut writer: W,
+        format: CsvExportFormat,
+        permission: ExportPermission,
+    ) -> Result<(usize, W)>
+    where
+        W: Write + Send + 'static,
+    {
+        self.read(move |db| {
+            let count = db.export_to_csv(&mut writer, format, permission)?;
+            Ok((count, writer))
+        })
+        .await
+    }
+
+    pub async fn merge_db(
+        &self,
+        other_path: impl AsRef<Path>,
+        other_key: Option<String>,
+        strategy: MergeStrategy,
+    ) -> Result<MergeReport> {
+        let other_path: PathBuf = other_path.as_ref().into();
+        self.run(move |db| db.merge_db(other_path, other_key.as_deref(), strategy))
+            .await
+    }
+
+    pub async fn add_or_update(&self, login: Login) -> Result<Login> {
+        self.run(move |db| db.add_or_update(login)).await
+    }
+
+    pub async fn update(&self, login: Login) -> Result<()> {
+        self.run(move |db| db.update(login)).await
+    }
+
+    pub async fn delete(&self, id: impl Into<String>) -> Result<bool> {
+        let id = id.into();
+        self.run(move |db| db.delete(&id)).await
+    }
+
+    pub async fn reset_guid(
+        &self,
+        old_guid: impl Into<String>,
+        new_guid: Option<String>,
+    ) -> Result<String> {
+        let old_guid = old_guid.into();
+        self.run(move |db| db.reset_guid(&old_guid, new_guid.as_deref()))
+            .await
+    }
+
+    pub async fn delete_by_hostname(&self, hostname: impl Into<String>) -> Result<usize> {
+        let hostname = hostname.into();
+        self.run(move |db| db.delete_by_hostname(&hostname)).await
+    }
+
+    pub async fn merge_logins(
+        &self,
+        keep_id: impl Into<String>,
+        discard_id: impl Into<String>,
+    ) -> Result<()> {
+        let (keep_id, discard_id) = (keep_id.into(), discard_id.into());
+        self.run(move |db| db.merge_logins(&keep_id, &discard_id))
+            .await
+    }
+
+    pub async fn delete_all_created_before(&self, before_ms: i64) -> Result<usize> {
+        self.run(move |db| db.delete_all_created_before(before_ms))
+            .await
+    }
+
+    // Tombstones and wiping.
+
+    pub async fn count_deleted_ready_to_purge(&self, age_ms: u64) -> Result<usize> {
+        self.read(move |db| db.count_deleted_ready_to_purge(age_ms))
+            .await
+    }
+
+    pub async fn get_deleted_ready_to_purge(&self, age_ms: u64) -> Result<Vec<String>> {
+        self.read(move |db| db.get_deleted_ready_to_purge(age_ms))
+            .await
+    }
+
+    pub async fn purge_tombstones(&self, older_than_ms: i64) -> Result<usize> {
+        self.run(move |db| db.purge_tombstones(older_than_ms)).await
+    }
+
+    pub async fn purge_old_tombstones(&self) -> Result<usize> {
+        self.run(|db| db.purge_old_tombstones()).await
+    }
+
+    pub async fn get_tombstone_count(&self) -> Result<usize> {
+        self.read(|db| db.get_tombstone_count()).await
+    }
+
+    pub async fn wipe(&self) -> Result<()> {
+        self.run(|db| db.wipe(&db.begin_interrupt_scope())).await
+    }
+
+    pub async fn wipe_local(&self) -> Result<()> {
+        self.run(|db| db.wipe_local()).await
+    }
+
+    pub async fn wipe_local_changes(&self) -> Result<()> {
+        self.run(|db| db.wipe_local_changes()).await
+    }
+
+    // Syncing.
+
+    pub async fn reset(&self, assoc: StoreSyncAssociation) -> Result<()> {
+        self.run(move |db| db.reset(&assoc)).await
+    }
+
+    pub async fn apply_outgoing_confirmation(
+        &self,
+        confirmation: OutgoingConfirmation,
+    ) -> Result<()> {
+        self.run(move |db| db.apply_outgoing_confirmation(confirmation))
+            .await
+    }
+
+    pub async fn fetch_outgoing(&self, st: ServerTimestamp) -> Result<OutgoingChangeset> {
+        self.run(move |db| db.fetch_outgoing(st, &db.begin_interrupt_scope()))
+            .await
+    }
+
+    pub async fn get_outgoing_count(&self) -> Result<usize> {
+        self.read(|db| db.get_outgoing_count()).await
+    }
+
+    pub async fn has_pending_changes(&self) -> Result<bool> {
+        self.read(|db| db.has_pending_changes()).await
+    }
+
+    pub async fn apply_incoming_from_json(
+        &self,
+        json: impl Into<String>,
+        server_timestamp_seconds: f64,
+    ) -> Result<OutgoingChangeset> {
+        let json = json.into();
+        self.run(move |db| db.apply_incoming_from_json(&json, server_timestamp_seconds))
+            .await
+    }
+
+    pub async fn get_estimated_sync_payload_size(&self) -> Result<usize> {
+        self.read(|db| db.get_estimated_sync_payload_size()).await
+    }
+
+    /// Uses the writing connection, since the preview depends on its
+    /// conflict resolution setting.
+    pub async fn dry_run_apply_incoming(&self, inbound: IncomingChangeset) -> Result<SyncPreview> {
+        self.run(move |db| db.dry_run_apply_incoming(inbound)).await
+    }
+
+    pub async fn apply_incoming_with_progress<F>(
+        &self,
+        inbound: IncomingChangeset,
+        progress: F,
+    ) -> Result<OutgoingChangeset>
+    where
+        F: FnMut(SyncProgress) + Send + 'static,
+    {
+        self.run(move |db| db.apply_incoming_with_progress(inbound, progress))
+            .await
+    }
+
+    /// Applies `other`'s local changes to this database. `other` must be a
+    /// different database.
+    pub async fn apply_local_changes_from_other_db(
+        &self,
+        other: &AsyncLoginDb,
+    ) -> Result<SyncStats> {
+        if Arc::ptr_eq(&self.writer, &other.writer) {
+            return Err(logins::ErrorKind::InvalidOperation(
+                "Can't apply a database's changes to itself".into(),
+            )
+            .into());
+        }
+        let other = other.clone();
+        self.run(move |db| {
+            // Read `other` from its writing connection, so that its own
+            // uncommitted reads can't be stale, and hold it for the whole
+            // operation.
+            let other = lock(&other.writer);
+            db.apply_local_changes_from_other_db(&other)
+        })
+        .await
+    }
+
+    pub async fn apply_incoming_with_policy(
+        &self,
+        inbound: IncomingChangeset,
+        policy: ConflictResolution,
+    ) -> Result<OutgoingChangeset> {
+        self.run(move |db| db.apply_incoming_with_policy(inbound, policy))
+            .await
+    }
+
+    pub async fn apply_incoming_with_dedup(
+        &self,
+        inbound: IncomingChangeset,
+    ) -> Result<(OutgoingChangeset, usize)> {
+        self.run(move |db| db.apply_incoming_with_dedup(inbound))
+            .await
+    }
+
+    pub async fn get_last_sync_age_ms(&self) -> Result<Option<i64>> {
+        self.read(|db| db.get_last_sync_age_ms()).await
+    }
+
+    pub async fn needs_sync(&self, max_age_ms: i64) -> Result<bool> {
+        self.read(move |db| db.needs_sync(max_age_ms)).await
+    }
+
+    pub async fn set_global_state(&self, state: Option<String>) -> Result<()> {
+        self.run(move |db| db.set_global_state(&state)).await
+    }
+
+    pub async fn get_global_state(&self) -> Result<Option<String>> {
+        self.read(|db| db.get_global_state()).await
+    }
+
+    pub async fn migrate_global_state(&self) -> Result<()> {
+        self.run(|db| db.migrate_global_state()).await
+    }
+
+    /// Syncs the logins, as `logins::PasswordEngine::sync` does.
+    pub async fn sync(
+        &self,
+        storage_init: Sync15StorageClientInit,
+        root_sync_key: KeyBundle,
+    ) -> Result<telemetry::SyncTelemetryPing> {
+        let mem_cached_state = Arc::clone(&self.mem_cached_state);
+        self.run(move |db| {
+            logins::sync_login_db(
+                db,
+                &mut lock(&mem_cached_state),
+                &storage_init,
+                &root_sync_key,
+            )
+        })
+        .await
+    }
+
+    /// Like `sync`, but can be cancelled as `run_cancellable` describes.
+    pub async fn sync_cancellable(
+        &self,
+        storage_init: Sync15StorageClientInit,
+        root_sync_key: KeyBundle,
+        cancel: oneshot::Receiver<()>,
+    ) -> Result<telemetry::SyncTelemetryPing> {
+        let mem_cached_state = Arc::clone(&self.mem_cached_state);
+        self.run_cancellable(
+            move |db| {
+                logins::sync_login_db(
+                    db,
+                    &mut lock(&mem_cached_state),
+                    &storage_init,
+                    &root_sync_key,
+                )
+            },
+            cancel,
+        )
+        .await
+    }
+}
+
+/// The `sync15::Store` operations for the logins collection, for use by an
+/// async sync driver. Each runs `LoginStore`'s implementation with the
+/// writing connection, on the blocking thread pool, and can be interrupted
+/// by cancelling a `run_cancellable` operation, or with the database's
+/// interrupt handle. Errors are `failure::Error`s, as with `Store`.
+#[derive(Clone)]
+pub struct AsyncLoginStore {
+    db: AsyncLoginDb,
+}
+
+pub type StoreResult<T> = std::result::Result<T, failure::Error>;
+
+impl AsyncLoginStore {
+    pub fn new(db: AsyncLoginDb) -> Self {
+        Self { db }
+    }
+
+    async fn run<F, T>(&self, f: F) -> StoreResult<T>
+    where
+        F: FnOnce(&LoginStore<'_>) -> StoreResult<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let db = Arc::clone(&self.db.writer);
+        spawn_blocking(move || f(&LoginStore::new(&lock(&db)))).await
+    }
+
+    pub fn collection_name(&self) -> std::borrow::Cow<'static, str> {
+        "passwords".into()
+    }
+
+    pub async fn set_conflict_resolution(&self, strategy: ConflictResolution) -> StoreResult<()> {
+        Ok(self.db.set_conflict_resolution(strategy).await?)
+    }
+
+    /// Like `Store::apply_incoming`, but takes `telem` by value, and returns
+    /// it once it's been updated.
+    pub async fn apply_incoming(
+        &self,
+        inbound: Vec<IncomingChangeset>,
+        mut telem: telemetry::Engine,
+    ) -> StoreResult<(OutgoingChangeset, telemetry::Engine)> {
+        self.run(move |store| {
+            let outgoing = store.apply_incoming(inbound, &mut telem)?;
+            Ok((outgoing, telem))
+        })
+        .await
+    }
+
+    pub async fn sync_finished(
+        &self,
+        new_timestamp: ServerTimestamp,
+        records_synced: Vec<Guid>,
+    ) -> StoreResult<()> {
+        self.run(move |store| store.sync_finished(new_timestamp, records_synced))
+            .await
+    }
+
+    pub async fn get_collection_requests(
+        &self,
+        server_timestamp: ServerTimestamp,
+    ) -> StoreResult<Vec<CollectionRequest>> {
+        self.run(move |store| store.get_collection_requests(server_timestamp))
+            .await
+    }
+
+    pub async fn get_sync_assoc(&self) -> StoreResult<StoreSyncAssociation> {
+        self.run(|store| store.get_sync_assoc()).await
+    }
+
+    pub async fn reset(&self, assoc: StoreSyncAssociation) -> StoreResult<()> {
+        self.run(move |store| store.reset(&assoc)).await
+    }
+
+    pub async fn wipe(&self) -> StoreResult<()> {
+        self.run(|store| store.wipe()).await
+    }
+}
+
+fn as_strs(strings: &[String]) -> Vec<&str> {
+    strings.iter().map(String::as_str).collect()
+}
+
+// Runs `f` on the blocking thread pool. If it panics, so do we.
+async fn spawn_blocking<F, T, E>(f: F) -> std::result::Result<T, E>
+where
+    F: FnOnce() -> std::result::Result<T, E> + Send + 'static,
+    T: Send + 'static,
+    E: Send + 'static,
+{
+    match tokio::task::spawn_blocking(f).await {
+        Ok(result) => result,
+        Err(e) => std::panic::resume_unwind(e.into_panic()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use logins::{Error, ErrorKind};
+    use std::time::{Duration, Instant};
+
+    fn login(username: &str) -> Login {
+        Login {
+            hostname: "https://www.example.com".into(),
+            form_submit_url: Some("https://www.example.com".into()),
+            username: username.into(),
+            password: "password".into(),
+            ..Login::default()
+        }
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn test_concurrent_reads_with_one_writer() {
+        let dir = tempdir::TempDir::new("async_logins").unwrap();
+        let db = AsyncLoginDb::open(dir.path().join("logins.sqlite"), Some("testing".into()))
+            .await
+            .unwrap();
+        let writer = {
+            let db = db.clone();
+            tokio::spawn(async move {
+                for i in 0..50 {
+                    db.add(login(&format!("user{}", i))).await.unwrap();
+                }
+            })
+        };
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let db = db.clone();
+                tokio::spawn(async move {
+                    let mut last = 0;
+                    for _ in 0..20 {
+                        let count = db.count().await.unwrap();
+                        assert!(count >= last, "{} < {}", count, last);
+                        assert!(db.get_all().await.unwrap().len() >= count);
+                        last = count;
+                    }
+                })
+            })
+            .collect();
+        writer.await.unwrap();
+        for reader in readers {
+            reader.await.unwrap();
+        }
+        assert_eq!(db.count().await.unwrap(), 50);
+        let logins = db.get_by_hostname("https://www.example.com").await.unwrap();
+        assert_eq!(logins.len(), 50);
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn test_reads_dont_wait_for_writes() {
+        let dir = tempdir::TempDir::new("async_logins").unwrap();
+        let db = AsyncLoginDb::open(dir.path().join("logins.sqlite"), Some("testing".into()))
+            .await
+            .unwrap();
+        db.add(login("committed")).await.unwrap();
+
+        // Hold the writer, in the middle of an uncommitted write.
+        let (started_tx, started_rx) = oneshot::channel();
+        let writing = {
+            let db = db.clone();
+            tokio::spawn(async move {
+                db.run(move |db| {
+                    db.execute_in_transaction(|db| {
+                        db.add(login("uncommitted"))?;
+                        started_tx.send(()).unwrap();
+                        std::thread::sleep(Duration::from_millis(500));
+                        Ok(())
+                    })
+                })
+                .await
+            })
+        };
+        started_rx.await.unwrap();
+
+        let start = Instant::now();
+        let reads: Vec<_> = (0..DEFAULT_READERS)
+            .map(|_| {
+                let db = db.clone();
+                tokio::spawn(async move { db.count().await })
+            })
+            .collect();
+        for read in reads {
+            // Only the committed login is visible.
+            assert_eq!(read.await.unwrap().unwrap(), 1);
+        }
+        assert!(start.elapsed() < Duration::from_millis(500));
+
+        writing.await.unwrap().unwrap();
+        assert_eq!(db.count().await.unwrap(), 2);
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn test_cancel_before_start() {
+        let db = AsyncLoginDb::open_in_memory(Some("testing".into()))
+            .await
+            .unwrap();
+        // Hold the database, so the cancellable operation has to wait.
+        let (started_tx, started_rx) = oneshot::channel();
+        let busy = {
+            let db = db.clone();
+            tokio::spawn(async move {
+                db.run(move |_| {
+                    started_tx.send(()).unwrap();
+                    std::thread::sleep(Duration::from_millis(200));
+                    Ok(())
+                })
+                .await
+            })
+        };
+        started_rx.await.unwrap();
+
+        let (cancel_tx, cancel_rx) = oneshot::channel();
+        let cancelled = {
+            let db = db.clone();
+            tokio::spawn(async move {
+                db.run_cancellable(move |db| db.add(login("never")), cancel_rx)
+                    .await
+            })
+        };
+        cancel_tx.send(()).unwrap();
+        match cancelled.await.unwrap().unwrap_err().kind() {
+            ErrorKind::Interrupted(_) => {}
+            e => panic!("Unexpected error {:?}", e),
+        }
+        busy.await.unwrap().unwrap();
+        assert_eq!(db.count().await.unwrap(), 0);
+
+        // Dropping the sender doesn't cancel.
+        let (cancel_tx, cancel_rx) = oneshot::channel::<()>();
+        drop(cancel_tx);
+        db.run_cancellable(move |db| db.add(login("added")), cancel_rx)
+            .await
+            .unwrap();
+        assert_eq!(db.count().await.unwrap(), 1);
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn test_error_passes_through() {
+        let db = AsyncLoginDb::open_in_memory(Some("testing".into()))
+            .await
+            .unwrap();
+        let err: Error = db.touch("missing").await.unwrap_err();
+        match err.kind() {
+            ErrorKind::NoSuchRecord(id) => assert_eq!(id, "missing"),
+            e => panic!("Unexpected error {:?}", e),
+        }
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn test_store() {
+        let db = AsyncLoginDb::open_in_memory(Some("testing".into()))
+            .await
+            .unwrap();
+        let store = AsyncLoginStore::new(db.clone());
+        assert_eq!(store.collection_name(), "passwords");
+        assert_eq!(
+            store.get_sync_assoc().await.unwrap(),
+            StoreSyncAssociation::Disconnected
+        );
+        let requests = store
+            .get_collection_requests(ServerTimestamp(1000))
+            .await
+            .unwrap();
+        assert_eq!(requests.len(), 1);
+
+        let added = db.add(login("alice")).await.unwrap();
+        let (outgoing, _) = store
+            .apply_incoming(
+                vec![IncomingChangeset::new("passwords", ServerTimestamp(1000))],
+                telemetry::Engine::new("passwords"),
+            )
+            .await
+            .unwrap();
+        assert_eq!(outgoing.changes.len(), 1);
+        store
+            .sync_finished(ServerTimestamp(2000), vec![added.guid.clone()])
+            .await
+            .unwrap();
+        assert!(!db.has_pending_changes().await.unwrap());
+        assert!(store
+            .get_collection_requests(ServerTimestamp(2000))
+            .await
+            .unwrap()
+            .is_empty());
+    }
+}
@@ -0,0 +1,47 @@
+#![allow(unknown_lints)]
+#![warn(rust_2018_idioms)]
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use logins::{Login, LoginDb};
+
+const NUM_LOGINS: usize = 500;
+
+fn make_logins() -> Vec<Login> {
+    (0..NUM_LOGINS)
+        .map(|i| Login {
+            hostname: format!("https://www{}.example.com", i),
+            form_submit_url: Some(format!("https://www{}.example.com", i)),
+            username: "user".into(),
+            password: "password".into(),
+            ..Login::default()
+        })
+        .collect()
+}
+
+fn new_db() -> LoginDb {
+    LoginDb::open_in_memory(Some("benchmarking".into())).unwrap()
+}
+
+fn bench_add_many(c: &mut Criterion) {
+    c.bench_function("add 500 logins one at a time", |b| {
+        b.iter_batched(
+            || (new_db(), make_logins()),
+            |(db, logins)| {
+                for login in logins {
+                    db.add(login).unwrap();
+                }
+            },
+            BatchSize::PerIteration,
+        )
+    });
+    c.bench_function("add_many 500 logins", |b| {
+        b.iter_batched(
+            || (new_db(), make_logins()),
+            |(db, logins)| db.add_many(logins).unwrap(),
+            BatchSize::PerIteration,
+        )
+    });
+}
+
+criterion_group!(benches, bench_add_many);
+criterion_main!(benches);
@@ -0,0 +1,45 @@
+#![allow(unknown_lints)]
+#![warn(rust_2018_idioms)]
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use logins::{Login, LoginDb};
+
+const NUM_LOGINS: usize = 100;
+
+fn init_db() -> (LoginDb, Vec<String>) {
+    let db = LoginDb::open_in_memory(Some("benchmarking".into())).unwrap();
+    let logins = (0..NUM_LOGINS)
+        .map(|i| Login {
+            hostname: "https://www.example.com".into(),
+            form_submit_url: Some("https://www.example.com".into()),
+            username: format!("user{}", i),
+            password: "password".into(),
+            ..Login::default()
+        })
+        .collect();
+    let ids = db
+        .add_many(logins)
+        .unwrap()
+        .into_iter()
+        .map(|login| login.guid.into_string())
+        .collect();
+    (db, ids)
+}
+
+fn bench_touch(c: &mut Criterion) {
+    let (db, ids) = init_db();
+    let ids: Vec<&str> = ids.iter().map(String::as_str).collect();
+    c.bench_function("touch 100 logins one at a time", |b| {
+        b.iter(|| {
+            for id in &ids {
+                db.touch(id).unwrap();
+            }
+        })
+    });
+    c.bench_function("touch_many 100 logins", |b| {
+        b.iter(|| db.touch_many(&ids).unwrap())
+    });
+}
+
+criterion_group!(benches, bench_touch);
+criterion_main!(benches);
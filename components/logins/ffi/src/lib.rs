@@ -14,7 +14,7 @@ use ffi_support::{
     define_string_destructor, ByteBuffer, ExternError, FfiStr,
 };
 use logins::msg_types::{PasswordInfo, PasswordInfos};
-use logins::{Login, LoginDb, PasswordEngine, Result};
+use logins::{ConflictResolution, ErrorKind, Login, LoginDb, PasswordEngine, Result};
 use std::os::raw::c_char;
 use std::sync::{Arc, Mutex};
 
@@ -157,6 +157,26 @@ pub extern "C" fn sync15_passwords_disable_mem_security(handle: u64, error: &mut
     })
 }
 
+#[no_mangle]
+pub extern "C" fn sync15_passwords_set_conflict_resolution(
+    handle: u64,
+    strategy: i32,
+    error: &mut ExternError,
+) {
+    log::debug!("sync15_passwords_set_conflict_resolution");
+    ENGINES.call_with_result(error, handle, |state| -> Result<()> {
+        let strategy = match strategy {
+            0 => ConflictResolution::FieldLevel,
+            1 => ConflictResolution::ServerWins,
+            2 => ConflictResolution::ClientWins,
+            3 => ConflictResolution::NewerWins,
+            n => return Err(ErrorKind::InvalidConflictResolution(n).into()),
+        };
+        state.lock().unwrap().set_conflict_resolution(strategy);
+        Ok(())
+    })
+}
+
 #[no_mangle]
 pub extern "C" fn sync15_passwords_rekey_database(
     handle: u64,
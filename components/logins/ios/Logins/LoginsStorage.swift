@@ -5,6 +5,18 @@
 import Foundation
 import UIKit
 
+/// How a sync resolves a login that changed both locally and on the server.
+public enum ConflictResolution: Int32 {
+    /// Merge the two versions field by field.
+    case fieldLevel = 0
+    /// Keep the server's version.
+    case serverWins = 1
+    /// Keep the local version.
+    case clientWins = 2
+    /// Keep whichever version was changed most recently.
+    case newerWins = 3
+}
+
 // swiftlint:disable type_body_length
 open class LoginsStorage {
     private var raw: UInt64 = 0
@@ -235,6 +247,17 @@ open class LoginsStorage {
         }
     }
 
+    /// Choose how future syncs resolve a login that changed both locally and
+    /// on the server. Defaults to `.fieldLevel`.
+    open func setConflictResolution(_ strategy: ConflictResolution) throws {
+        try queue.sync {
+            let engine = try self.getUnlocked()
+            try LoginsStoreError.unwrap { err in
+                sync15_passwords_set_conflict_resolution(engine, strategy.rawValue, err)
+            }
+        }
+    }
+
     /// Disable memory security, which prevents keys from being swapped to disk.
     /// This allows some esoteric attacks, but can have a performance benefit.
     open func disableMemSecurity() throws {
@@ -97,6 +97,10 @@ void sync15_passwords_wipe(Sync15PasswordEngineHandle handle,
 void sync15_passwords_wipe_local(Sync15PasswordEngineHandle handle,
                                  Sync15PasswordsError *_Nonnull error);
 
+void sync15_passwords_set_conflict_resolution(Sync15PasswordEngineHandle handle,
+                                              int32_t strategy,
+                                              Sync15PasswordsError *_Nonnull error);
+
 void sync15_passwords_disable_mem_security(Sync15PasswordEngineHandle handle,
                                            Sync15PasswordsError *_Nonnull error);
 
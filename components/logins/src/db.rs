@@ -3,28 +3,38 @@
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
 use crate::error::*;
-use crate::login::{LocalLogin, Login, MirrorLogin, SyncLoginData, SyncStatus};
+use crate::login::{
+    self, CreationSource, FieldLimits, LocalLogin, LockedLogin, Login, MirrorLogin, SyncLoginData,
+    SyncStatus,
+};
 use crate::schema;
 use crate::update_plan::UpdatePlan;
 use crate::util;
 use lazy_static::lazy_static;
 use rusqlite::{
+    backup::{Backup, StepResult},
+    functions::FunctionFlags,
     named_params,
-    types::{FromSql, ToSql},
-    Connection, OpenFlags, NO_PARAMS,
+    types::{FromSql, FromSqlError, FromSqlResult, ToSql, ToSqlOutput, Type, ValueRef},
+    Connection, DatabaseName, DropBehavior, OpenFlags, Transaction, TransactionBehavior, NO_PARAMS,
 };
 use serde_derive::*;
 use sql_support::{self, ConnExt};
-use sql_support::{SqlInterruptHandle, SqlInterruptScope};
-use std::collections::HashSet;
-use std::ops::Deref;
-use std::path::Path;
+use sql_support::{SqlInterruptHandle, SqlInterruptScope, UncheckedTransaction};
+use std::cell::Cell;
+use std::collections::{HashMap, HashSet};
+use std::io::{Read, Write};
+use std::ops::{Deref, DerefMut};
+use std::path::{Path, PathBuf};
 use std::result;
-use std::sync::{atomic::AtomicUsize, Arc};
+use std::sync::{
+    atomic::{AtomicBool, AtomicUsize, Ordering},
+    mpsc, Arc, Mutex,
+};
 use std::time::{Duration, Instant, SystemTime};
 use sync15::{
-    extract_v1_state, telemetry, CollSyncIds, CollectionRequest, IncomingChangeset,
-    OutgoingChangeset, Payload, ServerTimestamp, Store, StoreSyncAssociation,
+    extract_v1_state, telemetry, CollSyncIds, CollectionRequest, ConflictResolution,
+    IncomingChangeset, OutgoingChangeset, Payload, ServerTimestamp, Store, StoreSyncAssociation,
 };
 use sync_guid::Guid;
 use url::{Host, Url};
@@ -49,495 +59,3681 @@ pub struct MigrationMetrics {
     errors: Vec<String>,
 }
 
-pub struct LoginDb {
-    pub db: Connection,
-    interrupt_counter: Arc<AtomicUsize>,
+/// What `LoginDb::import_from_csv` does with a login which duplicates an
+/// existing one, either as `find_dupe` sees it or by having the same GUID.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DuplicateStrategy {
+    /// Leave the existing login alone, and count the new one as skipped.
+    Skip,
+    /// Update the existing login with the new one's fields.
+    Replace,
+    /// Add the new login anyway, with a new GUID if necessary.
+    KeepBoth,
 }
 
-impl LoginDb {
-    pub fn with_connection(
-        db: Connection,
-        encryption_key: Option<&str>,
-        salt: Option<&str>,
-    ) -> Result<Self> {
-        #[cfg(test)]
-        {
-            util::init_test_logging();
-        }
+/// What `LoginDb::merge_db` does with a login from the other database which
+/// duplicates one here, either as `find_dupe` sees it or by having the same
+/// GUID. Duplicates with the same username, password and other fields are
+/// always skipped.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MergeStrategy {
+    /// Keep whichever of the two had its password changed most recently,
+    /// updating the login here if that's the other one.
+    KeepNewer,
+    /// Add the other login as well, with a new GUID if necessary.
+    KeepAll,
+}
 
-        if let Some(key) = encryption_key {
-            db.set_pragma("key", key)?
-                .set_pragma("secure_delete", true)?;
+/// The result of `LoginDb::merge_db`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub struct MergeReport {
+    /// The number of logins added.
+    pub inserted: usize,
+    /// The number of existing logins updated, for `MergeStrategy::KeepNewer`.
+    pub updated: usize,
+    /// The number of logins from the other database left out, including
+    /// invalid ones and ones which have been deleted here.
+    pub skipped: usize,
+}
 
-            sqlcipher_3_compat(&db)?;
+/// What `LoginDb::import_from_csv` does with a row which can't be read, or
+/// isn't a valid login.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InvalidStrategy {
+    /// Record the problem in `ImportReport::errors`, and carry on.
+    Skip,
+    /// Roll back the whole import, and return the error.
+    Abort,
+}
 
-            if let Some(s) = salt {
-                // If a salt is also provided, this means the consumer does not want the salt stored
-                // in the database header. Currently only iOS uses this.
-                db.set_pragma("cipher_plaintext_header_size", 32)?;
-                db.set_pragma("cipher_salt", format!("x'{}'", s))?;
-            }
-        }
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ImportOptions {
+    pub on_duplicate: DuplicateStrategy,
+    pub on_invalid: InvalidStrategy,
+}
 
-        // `temp_store = 2` is required on Android to force the DB to keep temp
-        // files in memory, since on Android there's no tmp partition. See
-        // https://github.com/mozilla/mentat/issues/505. Ideally we'd only
-        // do this on Android, or allow caller to configure it.
-        db.set_pragma("temp_store", 2)?;
+/// A row skipped by `LoginDb::import_from_csv`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ImportError {
+    /// The (1-based) number of the row, not counting the header.
+    pub row: usize,
+    pub message: String,
+}
 
-        let mut logins = Self {
-            db,
-            interrupt_counter: Arc::new(AtomicUsize::new(0)),
-        };
-        let tx = logins.db.transaction()?;
-        schema::init(&tx)?;
-        tx.commit()?;
-        Ok(logins)
-    }
+/// The result of `LoginDb::import_from_csv`.
+#[derive(Clone, Debug, PartialEq, Eq, Default)]
+pub struct ImportReport {
+    /// The number of logins added.
+    pub inserted: usize,
+    /// The number of existing logins updated, for `DuplicateStrategy::Replace`.
+    pub replaced: usize,
+    /// The number of rows which were duplicates or invalid.
+    pub skipped: usize,
+    /// Why each invalid row was skipped.
+    pub errors: Vec<ImportError>,
+}
 
-    pub fn open(path: impl AsRef<Path>, encryption_key: Option<&str>) -> Result<Self> {
-        Ok(Self::with_connection(
-            Connection::open(path)?,
-            encryption_key,
-            None,
-        )?)
-    }
+/// The column layouts `LoginDb::export_to_csv` can write. Both can be read
+/// by `LoginDb::import_from_csv`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CsvExportFormat {
+    /// `url,username,password,httpRealm,formActionOrigin,guid,timeCreated,
+    /// timeLastUsed,timePasswordChanged`.
+    Firefox,
+    /// `name,url,username,password`, where `name` is the login's host.
+    Chrome,
+}
 
-    pub fn open_with_salt(
-        path: impl AsRef<Path>,
-        encryption_key: &str,
-        salt: &str,
-    ) -> Result<Self> {
-        ensure_valid_salt(salt)?;
-        Ok(Self::with_connection(
-            Connection::open(path)?,
-            Some(encryption_key),
-            Some(salt),
-        )?)
-    }
+/// Required by `LoginDb::export_to_json` and `LoginDb::export_to_csv`, which
+/// write out passwords in plain text, so that code calling them stands out
+/// in review.
+#[derive(Debug)]
+pub struct ExportPermission(());
 
-    pub fn open_in_memory(encryption_key: Option<&str>) -> Result<Self> {
-        Ok(Self::with_connection(
-            Connection::open_in_memory()?,
-            encryption_key,
-            None,
-        )?)
+impl ExportPermission {
+    pub fn allow_plaintext_passwords() -> Self {
+        ExportPermission(())
     }
+}
 
-    /// Opens an existing database and fetches the salt.
-    /// This method is used by iOS consumers as part as the migration plan to store
-    /// the salt outside of the sqlite db headers.
-    ///
-    /// Will return an error if the database does not exist.
-    pub fn open_and_get_salt(path: impl AsRef<Path>, encryption_key: &str) -> Result<String> {
-        // Open the connection defensively without attempting to create a db if it doesn't exist.
-        let db = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
-        db.set_pragma("key", encryption_key)?;
-        sqlcipher_3_compat(&db)?;
-        let salt = db.query_one::<String>("PRAGMA cipher_salt")?;
-        Ok(salt)
-    }
+/// Counts of what happened to the records applied by
+/// `LoginDb::apply_local_changes_from_other_db`.
+#[derive(PartialEq, Debug, Clone, Copy, Default)]
+pub struct SyncStats {
+    /// The number of records (including tombstones) read from the other database.
+    pub incoming: usize,
+    /// The number of records which added, changed or deleted a local login.
+    pub applied: u32,
+    /// The number of records which matched a local login exactly, or were for
+    /// a login deleted here, so changed nothing.
+    pub reconciled: u32,
+    /// The number of records which were invalid, so were skipped.
+    pub failed: u32,
+}
 
-    pub fn open_and_migrate_to_plaintext_header(
-        path: impl AsRef<Path>,
-        encryption_key: &str,
-        salt: &str,
-    ) -> Result<()> {
-        ensure_valid_salt(salt)?;
-        // Open the connection defensively without attempting to create a db if it doesn't exist.
-        let db = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_WRITE)?;
-        db.set_pragma("key", encryption_key)?;
-        sqlcipher_3_compat(&db)?;
-        db.set_pragma("cipher_salt", format!("x'{}'", salt))?;
-        // This tricks the `cipher_plaintext_header_size` command to work properly.
-        let user_version = db.query_one::<i64>("PRAGMA user_version")?;
-        // Remove the salt from the database header.
-        db.set_pragma("cipher_plaintext_header_size", 32)?;
-        // Flush the header changes.
-        db.set_pragma("user_version", user_version)?;
-        db.close().map_err(|(_conn, err)| err)?;
-        Ok(())
-    }
+/// What applying an incoming changeset would do, as returned by
+/// `LoginDb::dry_run_apply_incoming`.
+#[derive(PartialEq, Debug, Clone, Default)]
+pub struct SyncPreview {
+    /// Logins which would be added.
+    pub to_insert: Vec<Login>,
+    /// Logins which would change, as `(old, new)` pairs.
+    pub to_update: Vec<(Login, Login)>,
+    /// The GUIDs of logins which would be deleted.
+    pub to_delete: Vec<String>,
+    /// The logins the sync would then upload. Local deletions, which are
+    /// uploaded as tombstones, aren't included.
+    pub to_upload: Vec<Login>,
+}
 
-    pub fn disable_mem_security(&self) -> Result<()> {
-        self.conn().set_pragma("cipher_memory_security", false)?;
-        Ok(())
+/// Which part of applying incoming records a `SyncProgress` is for.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum SyncProgressStage {
+    /// Looking up what we already have for each record.
+    Fetching,
+    /// Deciding what to do with each record.
+    Reconciling,
+}
+
+/// How far `LoginDb::apply_incoming_with_progress` has got through the
+/// incoming records, in the current stage.
+#[derive(PartialEq, Debug, Clone)]
+pub struct SyncProgress {
+    pub stage: SyncProgressStage,
+    pub processed: usize,
+    pub total: usize,
+    /// The GUID of the last record processed.
+    pub last_guid: String,
+}
+
+/// A login along with how likely it is to be the one the user wants, as
+/// returned by `LoginDb::get_logins_for_autocomplete`. Higher is better.
+#[derive(PartialEq, Debug, Clone)]
+pub struct ScoredLogin {
+    pub login: Login,
+    pub score: f32,
+}
+
+/// The logins saved for a single hostname, as returned by
+/// `LoginDb::get_all_grouped_by_hostname`.
+#[derive(PartialEq, Debug, Clone)]
+pub struct LoginGroup {
+    pub hostname: String,
+    /// Most recently used first.
+    pub logins: Vec<Login>,
+    /// The largest `time_last_used` of any login in the group.
+    pub most_recent_used: i64,
+}
+
+/// What `LoginDb::run_integrity_repair` fixed.
+#[derive(PartialEq, Debug, Clone, Copy, Default)]
+pub struct RepairReport {
+    /// As returned by `heal_orphaned_mirror_records`.
+    pub orphaned_mirror_records_healed: usize,
+    /// The number of extra `loginsL` rows removed because their GUID was
+    /// shared with another row.
+    pub duplicate_local_guids_removed: usize,
+    /// The number of local records moved to `loginsQuarantine`.
+    pub invalid_records_quarantined: usize,
+}
+
+// How a record is stored in `loginsQuarantine.raw_json`: the JSON `Login`,
+// plus the local-only fields which that skips, so that restoring the record
+// doesn't lose them. Records quarantined before these were added just get the
+// defaults.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct QuarantinedLogin {
+    #[serde(flatten)]
+    login: Login,
+    #[serde(default)]
+    creation_source: u8,
+    #[serde(default)]
+    creation_source_name: Option<String>,
+    #[serde(default)]
+    associated_app: Option<String>,
+}
+
+impl From<Login> for QuarantinedLogin {
+    fn from(login: Login) -> Self {
+        Self {
+            creation_source: login.creation_source.discriminant(),
+            creation_source_name: login.creation_source.source_name().map(Into::into),
+            associated_app: login.associated_app.clone(),
+            login,
+        }
     }
+}
 
-    /// Change the key on an existing encrypted database,
-    /// it must first be unlocked with the current encryption key.
-    /// Once the database is readable and writeable, PRAGMA rekey
-    /// can be used to re-encrypt every page in the database with a new key.
-    /// https://www.zetetic.net/sqlcipher/sqlcipher-api/#Changing_Key
-    pub fn rekey_database(&self, new_encryption_key: &str) -> Result<()> {
-        self.conn().set_pragma("rekey", new_encryption_key)?;
-        Ok(())
+impl From<QuarantinedLogin> for Login {
+    fn from(quarantined: QuarantinedLogin) -> Self {
+        Login {
+            creation_source: CreationSource::from_parts(
+                quarantined.creation_source,
+                quarantined.creation_source_name,
+            ),
+            associated_app: quarantined.associated_app,
+            ..quarantined.login
+        }
     }
+}
 
-    pub fn new_interrupt_handle(&self) -> SqlInterruptHandle {
-        SqlInterruptHandle::new(
-            self.db.get_interrupt_handle(),
-            self.interrupt_counter.clone(),
-        )
+/// The kinds of change recorded in the audit log.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AuditOperation {
+    Add,
+    Update,
+    Delete,
+    Touch,
+    Wipe,
+}
+
+impl AuditOperation {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            AuditOperation::Add => "add",
+            AuditOperation::Update => "update",
+            AuditOperation::Delete => "delete",
+            AuditOperation::Touch => "touch",
+            AuditOperation::Wipe => "wipe",
+        }
     }
+}
 
-    #[inline]
-    pub fn begin_interrupt_scope(&self) -> SqlInterruptScope {
-        SqlInterruptScope::new(self.interrupt_counter.clone())
+impl ToSql for AuditOperation {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(self.as_str()))
     }
 }
 
-// Checks if the provided string is a 32 len hex string.
-fn ensure_valid_salt(salt: &str) -> Result<()> {
-    if salt.len() == 32
-        && salt.as_bytes().iter().all(|c| match c {
-            b'A'..=b'F' => true,
-            b'a'..=b'f' => true,
-            b'0'..=b'9' => true,
-            _ => false,
+impl FromSql for AuditOperation {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        Ok(match value.as_str()? {
+            "add" => AuditOperation::Add,
+            "update" => AuditOperation::Update,
+            "delete" => AuditOperation::Delete,
+            "touch" => AuditOperation::Touch,
+            "wipe" => AuditOperation::Wipe,
+            _ => return Err(FromSqlError::InvalidType),
         })
-    {
-        return Ok(());
     }
-    Err(ErrorKind::InvalidSalt.into())
 }
 
-fn sqlcipher_3_compat(conn: &Connection) -> Result<()> {
-    // SQLcipher pre-4.0.0 compatibility. Using SHA1 still
-    // is less than ideal, but should be fine. Real uses of
-    // this (lockwise, etc) use a real random string for the
-    // encryption key, so the reduced KDF iteration count
-    // is fine.
-    conn.set_pragma("cipher_page_size", 1024)?
-        .set_pragma("kdf_iter", 64000)?
-        .set_pragma("cipher_hmac_algorithm", "HMAC_SHA1")?
-        .set_pragma("cipher_kdf_algorithm", "PBKDF2_HMAC_SHA1")?;
-    Ok(())
+/// A row of the audit log, as returned by `LoginDb::get_audit_log`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AuditEntry {
+    pub id: i64,
+    pub guid: String,
+    pub operation: AuditOperation,
+    /// A millisecond local timestamp.
+    pub timestamp_ms: i64,
+    /// The actor set by `LoginDb::set_audit_actor` when the change was made.
+    pub actor: String,
 }
 
-impl ConnExt for LoginDb {
-    #[inline]
-    fn conn(&self) -> &Connection {
-        &self.db
-    }
+/// The result of `LoginDb::verify_integrity`.
+#[derive(PartialEq, Debug, Clone, Default)]
+pub struct IntegrityReport {
+    /// True if neither check found a problem.
+    pub ok: bool,
+    /// Every problem reported by `PRAGMA integrity_check`, followed by any
+    /// from `PRAGMA foreign_key_check`.
+    pub messages: Vec<String>,
 }
 
-impl Deref for LoginDb {
-    type Target = Connection;
-    #[inline]
-    fn deref(&self) -> &Connection {
-        &self.db
+/// The ways Bitwarden can match a saved URI against the URL of a page, for
+/// `LoginDb::get_all_for_bitwarden_uri_match`.
+#[derive(Clone, Debug)]
+pub enum BitwardenUriMatchType {
+    /// The registrable domains (as approximated by
+    /// `util::approximate_base_domain`) are the same.
+    BaseDomain,
+    /// The hosts, including any port, are the same.
+    Host,
+    /// The URL starts with the login's hostname.
+    StartsWith,
+    /// The login's hostname matches the regex. Note that, unlike the other
+    /// match types, this doesn't use the URL at all.
+    Regex(regex::Regex),
+    /// Nothing matches.
+    Never,
+    /// What Bitwarden does by default, which is `BaseDomain`.
+    Default,
+}
+
+/// The orders `LoginDb::get_all_ordered` can return logins in. Logins which
+/// are otherwise equal are ordered by GUID, so the order is reproducible.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LoginSortOrder {
+    /// By hostname, ignoring case, as a management UI lists them.
+    Hostname,
+    /// Most recently used first, then by hostname, as for autofill.
+    LastUsed,
+    /// Oldest password first, for a security audit.
+    PasswordAge,
+    /// Most used first, then by hostname.
+    TimesUsed,
+}
+
+impl LoginSortOrder {
+    fn order_by(self) -> &'static str {
+        match self {
+            LoginSortOrder::Hostname => "hostname COLLATE NOCASE ASC, guid ASC",
+            LoginSortOrder::LastUsed => "timeLastUsed DESC, hostname ASC, guid ASC",
+            LoginSortOrder::PasswordAge => "timePasswordChanged ASC, guid ASC",
+            LoginSortOrder::TimesUsed => "timesUsed DESC, hostname ASC, guid ASC",
+        }
     }
 }
 
-// login specific stuff.
+/// The result of successfully uploading records to the server, as passed to
+/// `LoginDb::apply_outgoing_confirmation`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct OutgoingConfirmation {
+    /// The GUIDs of the records the server accepted.
+    pub guids: Vec<String>,
+    /// The server's timestamp after the upload.
+    pub new_server_timestamp: ServerTimestamp,
+}
 
-impl LoginDb {
-    fn mark_as_synchronized(
-        &self,
-        guids: &[&str],
-        ts: ServerTimestamp,
-        scope: &SqlInterruptScope,
-    ) -> Result<()> {
-        let tx = self.unchecked_transaction()?;
-        sql_support::each_chunk(guids, |chunk, _| -> Result<()> {
-            self.db.execute(
-                &format!(
-                    "DELETE FROM loginsM WHERE guid IN ({vars})",
-                    vars = sql_support::repeat_sql_vars(chunk.len())
-                ),
-                chunk,
-            )?;
-            scope.err_if_interrupted()?;
+/// Decides whether a password is weak, for `LoginDb::get_security_report`.
+pub trait PasswordStrengthChecker {
+    fn is_weak(&self, password: &str) -> bool;
+}
 
-            self.db.execute(
-                &format!(
-                    "INSERT OR IGNORE INTO loginsM (
-                         {common_cols}, is_overridden, server_modified
-                     )
-                     SELECT {common_cols}, 0, {modified_ms_i64}
-                     FROM loginsL
-                     WHERE is_deleted = 0 AND guid IN ({vars})",
-                    common_cols = schema::COMMON_COLS,
-                    modified_ms_i64 = ts.as_millis() as i64,
-                    vars = sql_support::repeat_sql_vars(chunk.len())
-                ),
-                chunk,
-            )?;
-            scope.err_if_interrupted()?;
+/// A summary of potential problems with the saved logins, as returned by
+/// `LoginDb::get_security_report`.
+#[derive(PartialEq, Debug, Clone, Copy, Default)]
+pub struct SecurityReport {
+    pub total: usize,
+    /// The number of logins whose password is also used by another login.
+    pub reused_count: usize,
+    pub weak_count: usize,
+    /// The number of logins whose password is older than the threshold.
+    pub old_password_count: usize,
+    /// The number of logins for `http://` (rather than `https://`) origins.
+    pub http_only_count: usize,
+    /// The number of logins which haven't been used since they were created.
+    pub never_used_count: usize,
+}
 
-            self.db.execute(
-                &format!(
-                    "DELETE FROM loginsL WHERE guid IN ({vars})",
-                    vars = sql_support::repeat_sql_vars(chunk.len())
-                ),
-                chunk,
-            )?;
-            scope.err_if_interrupted()?;
-            Ok(())
-        })?;
-        self.set_last_sync(ts)?;
-        tx.commit()?;
-        Ok(())
+/// Counts of the local records in each `SyncStatus`, as returned by
+/// `LoginDb::get_sync_status_counts`.
+#[derive(PartialEq, Debug, Clone, Copy, Default)]
+pub struct SyncStatusCounts {
+    pub new: usize,
+    pub changed: usize,
+    pub synced: usize,
+}
+
+/// Counts of logins for a dashboard-style UI, as returned by
+/// `LoginDb::get_statistics`.
+#[derive(PartialEq, Debug, Clone, Copy, Default)]
+pub struct LoginStatistics {
+    pub total_count: usize,
+    /// The number of logins which haven't changed since they were last synced.
+    pub synced_count: usize,
+    /// The number of logins which have never been synced.
+    pub local_only_count: usize,
+    /// The number of logins deleted locally, whose deletion hasn't been synced.
+    pub deleted_count: usize,
+    /// The number of logins which haven't been used since they were created.
+    pub never_used_count: usize,
+    pub used_last_30_days_count: usize,
+}
+
+/// The kind of change described by a `ChangeEvent`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChangeKind {
+    Insert,
+    Update,
+    /// Note that for `loginsL`, this includes records becoming tombstones.
+    Delete,
+}
+
+/// A change to a row in one of the logins tables, as passed to the hook
+/// registered by `LoginDb::register_change_hook`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChangeEvent {
+    pub kind: ChangeKind,
+    pub guid: Option<String>,
+    /// Either `"loginsL"` or `"loginsM"`.
+    pub table: &'static str,
+}
+
+/// A change to the logins as consumers see them, where a local record hides
+/// the mirror record with the same GUID. Sent to the receivers returned by
+/// `LoginDb::subscribe` once the change is committed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LoginChangeEvent {
+    Added(String),
+    Updated(String),
+    Deleted(String),
+    /// All logins were removed, by `LoginDb::wipe` or `wipe_local`. No
+    /// `Deleted` events are sent for them.
+    Wiped,
+    /// `LoginDb::reset` was called.
+    Reset,
+}
+
+type ChangeHook = Box<dyn Fn(ChangeEvent) + Send>;
+
+// Everything listening for changes, shared with the `logins_change_hook`
+// SQL function and the commit and rollback hooks.
+#[derive(Default)]
+struct ChangeListeners {
+    hook: Mutex<Option<ChangeHook>>,
+    subscribers: Mutex<Vec<mpsc::Sender<LoginChangeEvent>>>,
+    // Events for the subscribers, for each transaction and savepoint which
+    // `LoginDb` has started and not yet finished, outermost first. SQLite's
+    // rollback hook isn't called for `ROLLBACK TO`, so this is how we know
+    // which events a rolled back savepoint made.
+    pending: Mutex<Vec<Vec<LoginChangeEvent>>>,
+    // Events from a transaction which SQLite has started to commit, to be
+    // sent once the commit returns. Changes made outside of `LoginDb`'s
+    // transactions go straight here, and are sent from the commit hook.
+    committed: Mutex<Vec<LoginChangeEvent>>,
+    // Set while wiping, when subscribers get a single `Wiped` instead.
+    muted: AtomicBool,
+}
+
+impl ChangeListeners {
+    fn queue(&self, event: LoginChangeEvent) {
+        if self.muted.load(Ordering::SeqCst) {
+            return;
+        }
+        match self.pending.lock().unwrap().last_mut() {
+            Some(frame) => frame.push(event),
+            None => self.committed.lock().unwrap().push(event),
+        }
     }
 
-    // Fetch all the data for the provided IDs.
-    // TODO: Might be better taking a fn instead of returning all of it... But that func will likely
-    // want to insert stuff while we're doing this so ugh.
-    fn fetch_login_data(
-        &self,
-        records: &[(sync15::Payload, ServerTimestamp)],
-        telem: &mut telemetry::EngineIncoming,
-        scope: &SqlInterruptScope,
-    ) -> Result<Vec<SyncLoginData>> {
-        let mut sync_data = Vec::with_capacity(records.len());
+    // Called once a transaction or savepoint has started.
+    fn begin_frame(&self) {
+        self.pending.lock().unwrap().push(Vec::new());
+    }
+
+    // Called after rolling back to a savepoint, which is still active.
+    fn rollback_frame(&self) {
+        if let Some(frame) = self.pending.lock().unwrap().last_mut() {
+            frame.clear();
+        }
+    }
+
+    // Called once a transaction or savepoint has finished. If its changes
+    // were kept, its events move to the enclosing one, if any. Once the
+    // outermost has finished, and the connection is back in autocommit mode,
+    // the committed events are sent.
+    fn end_frame(&self, keep: bool, autocommit: bool) {
+        let mut pending = self.pending.lock().unwrap();
+        let frame = pending.pop().unwrap_or_default();
+        if keep {
+            match pending.last_mut() {
+                Some(parent) => parent.extend(frame),
+                None => self.committed.lock().unwrap().extend(frame),
+            }
+        }
+        let outermost = pending.is_empty();
+        drop(pending);
+        if outermost && autocommit {
+            self.send_committed();
+        }
+    }
+
+    fn on_commit(&self) {
+        let mut pending = self.pending.lock().unwrap();
         {
-            let mut seen_ids: HashSet<Guid> = HashSet::with_capacity(records.len());
-            for incoming in records.iter() {
-                if seen_ids.contains(&incoming.0.id) {
-                    throw!(ErrorKind::DuplicateGuid(incoming.0.id.to_string()))
-                }
-                seen_ids.insert(incoming.0.id.clone());
-                match SyncLoginData::from_payload(incoming.0.clone(), incoming.1) {
-                    Ok(v) => sync_data.push(v),
-                    Err(e) => {
-                        log::error!("Failed to deserialize record {:?}: {}", incoming.0.id, e);
-                        // Ideally we'd track new_failed, but it's unclear how
-                        // much value it has.
-                        telem.failed(1);
-                    }
-                }
+            let mut committed = self.committed.lock().unwrap();
+            for frame in pending.iter_mut() {
+                committed.append(frame);
             }
         }
-        scope.err_if_interrupted()?;
+        let untracked = pending.is_empty();
+        drop(pending);
+        // We can't tell when a transaction we didn't start finishes, so
+        // this is as close as we can get.
+        if untracked {
+            self.send_committed();
+        }
+    }
 
-        sql_support::each_chunk_mapped(
-            &records,
-            |r| r.0.id.as_str(),
-            |chunk, offset| -> Result<()> {
-                // pairs the bound parameter for the guid with an integer index.
-                let values_with_idx = sql_support::repeat_display(chunk.len(), ",", |i, f| {
-                    write!(f, "({},?)", i + offset)
-                });
-                let query = format!(
-                    "WITH to_fetch(guid_idx, fetch_guid) AS (VALUES {vals})
-                     SELECT
-                         {common_cols},
-                         is_overridden,
-                         server_modified,
-                         NULL as local_modified,
-                         NULL as is_deleted,
-                         NULL as sync_status,
-                         1 as is_mirror,
-                         to_fetch.guid_idx as guid_idx
-                     FROM loginsM
-                     JOIN to_fetch
-                         ON loginsM.guid = to_fetch.fetch_guid
+    fn on_rollback(&self) {
+        for frame in self.pending.lock().unwrap().iter_mut() {
+            frame.clear();
+        }
+        self.committed.lock().unwrap().clear();
+    }
 
-                     UNION ALL
+    fn send_committed(&self) {
+        let committed = std::mem::take(&mut *self.committed.lock().unwrap());
+        if committed.is_empty() {
+            return;
+        }
+        // Receivers which have gone away are forgotten.
+        self.subscribers
+            .lock()
+            .unwrap()
+            .retain(|sub| committed.iter().all(|e| sub.send(e.clone()).is_ok()));
+    }
+}
 
-                     SELECT
-                         {common_cols},
-                         NULL as is_overridden,
-                         NULL as server_modified,
-                         local_modified,
-                         is_deleted,
-                         sync_status,
-                         0 as is_mirror,
-                         to_fetch.guid_idx as guid_idx
-                     FROM loginsL
-                     JOIN to_fetch
-                         ON loginsL.guid = to_fetch.fetch_guid",
-                    // give each VALUES item 2 entries, an index and the parameter.
-                    vals = values_with_idx,
-                    common_cols = schema::COMMON_COLS,
-                );
+/// Where SQLite should keep its temporary tables and indices. Maps directly
+/// onto the values accepted by `PRAGMA temp_store`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum TempStoreMode {
+    /// Use SQLite's compile-time default.
+    Default = 0,
+    File = 1,
+    Memory = 2,
+}
 
-                let mut stmt = self.db.prepare(&query)?;
+impl Default for TempStoreMode {
+    fn default() -> Self {
+        // Android has no tmp partition, so we must force SQLite to keep temp
+        // files in memory there. See https://github.com/mozilla/mentat/issues/505.
+        if cfg!(target_os = "android") {
+            TempStoreMode::Memory
+        } else {
+            TempStoreMode::Default
+        }
+    }
+}
 
-                let rows = stmt.query_and_then(chunk, |row| {
-                    let guid_idx_i = row.get::<_, i64>("guid_idx")?;
-                    // Hitting this means our math is wrong...
-                    assert!(guid_idx_i >= 0);
+/// The key used to open an encrypted database.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum EncryptionKey {
+    /// A passphrase, which SQLCipher derives the key from using PBKDF2.
+    Passphrase(String),
+    /// Raw 256-bit key material, as 64 hex characters, which SQLCipher uses
+    /// as-is. Databases created by iOS use raw keys from the Keychain.
+    RawHex(String),
+}
 
-                    let guid_idx = guid_idx_i as usize;
-                    let is_mirror: bool = row.get("is_mirror")?;
-                    if is_mirror {
-                        sync_data[guid_idx].set_mirror(MirrorLogin::from_row(row)?)?;
-                    } else {
-                        sync_data[guid_idx].set_local(LocalLogin::from_row(row)?)?;
-                    }
-                    scope.err_if_interrupted()?;
-                    Ok(())
-                })?;
-                // `rows` is an Iterator<Item = Result<()>>, so we need to collect to handle the errors.
-                rows.collect::<Result<_>>()?;
-                Ok(())
-            },
-        )?;
-        Ok(sync_data)
+impl EncryptionKey {
+    // The value to pass to `PRAGMA key`.
+    fn pragma_value(&self) -> Result<String> {
+        match self {
+            EncryptionKey::Passphrase(passphrase) => Ok(passphrase.clone()),
+            EncryptionKey::RawHex(hex) => {
+                if hex.len() != 64 || !hex.bytes().all(|c| c.is_ascii_hexdigit()) {
+                    throw!(ErrorKind::InvalidEncryptionKey);
+                }
+                Ok(format!("x'{}'", hex))
+            }
+        }
     }
+}
 
-    // It would be nice if this were a batch-ish api (e.g. takes a slice of records and finds dupes
-    // for each one if they exist)... I can't think of how to write that query, though.
-    fn find_dupe(&self, l: &Login) -> Result<Option<Login>> {
-        let form_submit_host_port = l
-            .form_submit_url
-            .as_ref()
-            .and_then(|s| util::url_host_port(&s));
-        let args = named_params! {
-            ":hostname": l.hostname,
-            ":http_realm": l.http_realm,
-            ":username": l.username,
-            ":form_submit": form_submit_host_port,
-        };
-        let mut query = format!(
-            "SELECT {common}
-             FROM loginsL
-             WHERE hostname IS :hostname
-               AND httpRealm IS :http_realm
-               AND username IS :username",
-            common = schema::COMMON_COLS,
-        );
-        if form_submit_host_port.is_some() {
-            // Stolen from iOS
-            query += " AND (formSubmitURL = '' OR (instr(formSubmitURL, :form_submit) > 0))";
-        } else {
-            query += " AND formSubmitURL IS :form_submit"
+impl From<&str> for EncryptionKey {
+    fn from(passphrase: &str) -> Self {
+        EncryptionKey::Passphrase(passphrase.to_owned())
+    }
+}
+
+/// How SQLite reclaims the space left by deleted data. Maps directly onto
+/// the values accepted by `PRAGMA auto_vacuum`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum AutoVacuumMode {
+    /// Free pages are kept for reuse, and only `LoginDb::vacuum` shrinks
+    /// the file.
+    None = 0,
+    /// Free pages are removed from the file at the end of every transaction.
+    Full = 1,
+    /// Free pages are kept, but can be removed using
+    /// `PRAGMA incremental_vacuum`.
+    Incremental = 2,
+}
+
+/// The values of `PRAGMA journal_mode` that `LoginDb` supports.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JournalMode {
+    Delete,
+    /// Write-ahead logging, which lets readers and a writer use the database
+    /// at the same time. Has no effect on in-memory databases.
+    Wal,
+    Memory,
+}
+
+impl JournalMode {
+    fn as_str(self) -> &'static str {
+        match self {
+            JournalMode::Delete => "DELETE",
+            JournalMode::Wal => "WAL",
+            JournalMode::Memory => "MEMORY",
         }
-        Ok(self.try_query_row(&query, args, |row| Login::from_row(row), false)?)
     }
+}
 
-    pub fn get_all(&self) -> Result<Vec<Login>> {
-        let mut stmt = self.db.prepare_cached(&GET_ALL_SQL)?;
-        let rows = stmt.query_and_then(NO_PARAMS, Login::from_row)?;
-        rows.collect::<Result<_>>()
+/// The HMAC algorithms SQLCipher can use to authenticate pages.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HmacAlgorithm {
+    Sha1,
+    Sha256,
+    Sha512,
+}
+
+impl HmacAlgorithm {
+    fn as_str(self) -> &'static str {
+        match self {
+            HmacAlgorithm::Sha1 => "HMAC_SHA1",
+            HmacAlgorithm::Sha256 => "HMAC_SHA256",
+            HmacAlgorithm::Sha512 => "HMAC_SHA512",
+        }
     }
 
-    pub fn get_by_base_domain(&self, base_domain: &str) -> Result<Vec<Login>> {
-        // We first parse the input string as a host so it is normalized.
-        let base_host = match Host::parse(base_domain) {
-            Ok(d) => d,
-            Err(e) => {
-                // don't log the input string as it's PII.
-                log::warn!("get_by_base_domain was passed an invalid domain: {}", e);
-                return Ok(vec![]);
-            }
-        };
-        // We just do a linear scan. Another option is to have an indexed
-        // reverse-host column or similar, but current thinking is that it's
-        // extra complexity for (probably) zero actual benefit given the record
-        // counts are expected to be so low.
-        // A regex would probably make this simpler, but we don't want to drag
-        // in a regex lib just for this.
-        let mut stmt = self.db.prepare_cached(&GET_ALL_SQL)?;
-        let rows = stmt
-            .query_and_then(NO_PARAMS, Login::from_row)?
-            .filter(|r| {
-                let login = r
-                    .as_ref()
-                    .ok()
-                    .and_then(|login| Url::parse(&login.hostname).ok());
-                let this_host = login.as_ref().and_then(|url| url.host());
-                match (&base_host, this_host) {
-                    (Host::Domain(base), Some(Host::Domain(look))) => {
-                        // a fairly long-winded way of saying
-                        // `login.hostname == base_domain ||
-                        //  login.hostname.ends_with('.' + base_domain);`
-                        let mut rev_input = base.chars().rev();
-                        let mut rev_host = look.chars().rev();
-                        loop {
-                            match (rev_input.next(), rev_host.next()) {
-                                (Some(ref a), Some(ref b)) if a == b => continue,
-                                (None, None) => return true, // exactly equal
-                                (None, Some(ref h)) => return *h == '.',
-                                _ => return false,
-                            }
-                        }
-                    }
-                    // ip addresses must match exactly.
-                    (Host::Ipv4(base), Some(Host::Ipv4(look))) => *base == look,
-                    (Host::Ipv6(base), Some(Host::Ipv6(look))) => *base == look,
-                    // all "mismatches" in domain types are false.
-                    _ => false,
-                }
-            });
-        rows.collect::<Result<_>>()
+    // The matching value for `PRAGMA cipher_kdf_algorithm`.
+    fn kdf_algorithm(self) -> &'static str {
+        match self {
+            HmacAlgorithm::Sha1 => "PBKDF2_HMAC_SHA1",
+            HmacAlgorithm::Sha256 => "PBKDF2_HMAC_SHA256",
+            HmacAlgorithm::Sha512 => "PBKDF2_HMAC_SHA512",
+        }
     }
+}
 
-    pub fn get_by_id(&self, id: &str) -> Result<Option<Login>> {
-        self.try_query_row(
-            &GET_BY_GUID_SQL,
-            &[(":guid", &id as &dyn ToSql)],
-            Login::from_row,
-            true,
-        )
+/// The SQLCipher parameters used to open an encrypted database, which must
+/// match the ones it was created with. The default is SQLCipher 3's
+/// settings, which every database created by this crate uses; SQLCipher 4's
+/// own defaults are 256,000 iterations, 4096 byte pages and `Sha512`.
+/// PBKDF2 uses the same hash as `hmac_algorithm`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CipherConfig {
+    pub kdf_iter: u32,
+    pub page_size: u32,
+    pub hmac_algorithm: HmacAlgorithm,
+}
+
+impl Default for CipherConfig {
+    fn default() -> Self {
+        // SQLcipher pre-4.0.0 compatibility. Using SHA1 still
+        // is less than ideal, but should be fine. Real uses of
+        // this (lockwise, etc) use a real random string for the
+        // encryption key, so the reduced KDF iteration count
+        // is fine.
+        Self {
+            kdf_iter: 64000,
+            page_size: 1024,
+            hmac_algorithm: HmacAlgorithm::Sha1,
+        }
     }
+}
 
-    pub fn touch(&self, id: &str) -> Result<()> {
-        let tx = self.unchecked_transaction()?;
-        self.ensure_local_overlay_exists(id)?;
-        self.mark_mirror_overridden(id)?;
-        let now_ms = util::system_time_ms_i64(SystemTime::now());
-        // As on iOS, just using a record doesn't flip it's status to changed.
-        // TODO: this might be wrong for lockbox!
-        self.execute_named_cached(
-            "UPDATE loginsL
-             SET timeLastUsed = :now_millis,
-                 timesUsed = timesUsed + 1,
-                 local_modified = :now_millis
-             WHERE guid = :guid
-                 AND is_deleted = 0",
-            named_params! {
-                ":now_millis": now_ms,
-                ":guid": id,
-            },
+impl CipherConfig {
+    // Must be called after `PRAGMA key`, but before the database is read.
+    fn apply(&self, conn: &Connection, schema: Option<DatabaseName<'_>>) -> Result<()> {
+        conn.pragma_update(schema, "cipher_page_size", &self.page_size)?;
+        conn.pragma_update(schema, "kdf_iter", &self.kdf_iter)?;
+        conn.pragma_update(
+            schema,
+            "cipher_hmac_algorithm",
+            &self.hmac_algorithm.as_str(),
+        )?;
+        conn.pragma_update(
+            schema,
+            "cipher_kdf_algorithm",
+            &self.hmac_algorithm.kdf_algorithm(),
         )?;
-        tx.commit()?;
         Ok(())
     }
+}
 
-    pub fn add(&self, login: Login) -> Result<Login> {
-        let mut login = self.fixup_and_check_for_dupes(login)?;
+// The guts of `LoginDb::heal_orphaned_mirror_records`, which is also run while
+// opening the database, in the same transaction as `schema::init`.
+fn heal_orphaned_mirror_records_in(conn: &Connection) -> Result<usize> {
+    Ok(conn.execute(
+        "UPDATE loginsM SET is_overridden = 0
+         WHERE is_overridden = 1
+           AND guid NOT IN (SELECT guid FROM loginsL)",
+        NO_PARAMS,
+    )?)
+}
 
-        let tx = self.unchecked_transaction()?;
-        let now_ms = util::system_time_ms_i64(SystemTime::now());
+/// Options controlling how `LoginDb` configures its connection when opened.
+#[derive(Clone, Debug, Default)]
+pub struct ConnectionOptions {
+    pub temp_store: TempStoreMode,
+    /// Only used if an encryption key is given.
+    pub cipher: CipherConfig,
+    /// If set, `heal_orphaned_mirror_records` is run when the database is
+    /// opened.
+    pub heal_orphaned_mirror_records: bool,
+    /// If unset, SQLite's default (`Delete`, for databases on disk) is used.
+    pub journal_mode: Option<JournalMode>,
+    /// The value for `PRAGMA cache_size`. As with the pragma, a negative
+    /// value is a size in KiB rather than a number of pages. If unset,
+    /// SQLite's default is used.
+    pub cache_size_pages: Option<i32>,
+    /// How long to wait for another connection's lock to be released before
+    /// failing with `SQLITE_BUSY`. If unset, rusqlite's default of 5 seconds is
+    /// used. Can be changed later with `LoginDb::set_busy_timeout`.
+    pub busy_timeout_ms: Option<u32>,
+}
 
-        // Allow an empty GUID to be passed to indicate that we should generate
-        // one. (Note that the FFI, does not require that the `id` field be
-        // present in the JSON, and replaces it with an empty string if missing).
-        if login.guid.is_empty() {
-            login.guid = Guid::random()
-        }
+/// The result of `LoginDb::checkpoint`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CheckpointResult {
+    /// The number of frames left in the WAL file after the checkpoint.
+    pub log_frames: i32,
+    /// The number of those frames which have been written to the database.
+    pub checkpointed_frames: i32,
+}
 
-        // Fill in default metadata.
-        if login.time_created == 0 {
-            login.time_created = now_ms;
-        }
-        if login.time_password_changed == 0 {
-            login.time_password_changed = now_ms;
+/// The result of `LoginDb::get_storage_info`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StorageInfo {
+    /// As returned by `LoginDb::get_db_size_bytes`.
+    pub db_size_bytes: u64,
+    /// As returned by `LoginDb::get_wal_size_bytes`.
+    pub wal_size_bytes: Option<u64>,
+    /// The version of the schema, from `PRAGMA user_version`.
+    pub schema_version: i64,
+    /// As returned by `LoginDb::get_total_count`.
+    pub record_count: usize,
+}
+
+/// Options for `LoginDb::backup` and `LoginDb::restore_from_backup`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BackupOptions {
+    /// How many pages to copy at a time. Defaults to 100.
+    pub pages_per_step: Option<i32>,
+    /// How long to wait between steps, so that other connections get a
+    /// chance to write. Defaults to 10 milliseconds.
+    pub pause_between_steps: Option<Duration>,
+    /// How many steps in a row can find the source or destination locked
+    /// before the backup gives up with `BackupBusy`. Defaults to 100.
+    pub max_busy_retries: Option<u32>,
+}
+
+/// Passed to the progress callback after each step of a backup.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BackupProgress {
+    pub pages_remaining: i32,
+    pub pages_total: i32,
+}
+
+/// A transaction started by `LoginDb::begin_write_transaction`. Unless it's
+/// explicitly rolled back, it's committed when dropped.
+pub struct WriteTransaction<'conn> {
+    tx: Transaction<'conn>,
+    // Declared after `tx`, so it's dropped once the transaction has finished.
+    _frame: TransactionFrame,
+}
+
+// Ends the change events frame of a `WriteTransaction` when dropped. It's
+// always the outermost transaction, so its events are sent then.
+struct TransactionFrame(Arc<ChangeListeners>);
+
+impl Drop for TransactionFrame {
+    fn drop(&mut self) {
+        self.0.end_frame(true, true);
+    }
+}
+
+impl<'conn> WriteTransaction<'conn> {
+    pub fn commit(self) -> Result<()> {
+        Ok(self.tx.commit()?)
+    }
+
+    pub fn rollback(self) -> Result<()> {
+        Ok(self.tx.rollback()?)
+    }
+}
+
+impl<'conn> Deref for WriteTransaction<'conn> {
+    type Target = Connection;
+    #[inline]
+    fn deref(&self) -> &Connection {
+        &self.tx
+    }
+}
+
+/// A transaction started by `LoginDb::begin_read_transaction`, which gives a
+/// consistent view of the database. Attempts to write through the connection
+/// fail while it's active, and it's rolled back when dropped. It must be
+/// dropped, not leaked: a leaked one leaves its transaction open and the
+/// connection read-only.
+pub struct ReadTransaction<'conn> {
+    conn: &'conn Connection,
+    // Only `None` while it's being dropped.
+    tx: Option<UncheckedTransaction<'conn>>,
+}
+
+impl<'conn> Deref for ReadTransaction<'conn> {
+    type Target = Connection;
+    #[inline]
+    fn deref(&self) -> &Connection {
+        self.conn
+    }
+}
+
+impl<'conn> Drop for ReadTransaction<'conn> {
+    fn drop(&mut self) {
+        // `query_only` applies to the whole connection, not just the
+        // transaction, so it has to be turned off again before the transaction
+        // ends. If that fails, try once more after the rollback, rather than
+        // leaving the connection read-only.
+        let reset = self.conn.set_pragma("query_only", false);
+        drop(self.tx.take());
+        if reset.is_err() {
+            if let Err(e) = self.conn.set_pragma("query_only", false) {
+                log::warn!("Error ending a read transaction: {}", e);
+            }
         }
-        if login.time_last_used == 0 {
-            login.time_last_used = now_ms;
+    }
+}
+
+/// A savepoint started by `LoginDb::savepoint`. Unless it's released, changes
+/// made since it was started are rolled back when it's dropped.
+///
+/// Derefs to the `LoginDb`, so its methods can be used within the savepoint.
+pub struct LoginDbSavepoint<'a> {
+    db: &'a mut LoginDb,
+    // Already quoted, for use in SQL.
+    name: String,
+    finished: bool,
+}
+
+impl<'a> LoginDbSavepoint<'a> {
+    /// Keeps the changes made since the savepoint started. If it's the
+    /// outermost savepoint and there's no transaction, they're committed.
+    pub fn release(mut self) -> Result<()> {
+        self.finished = true;
+        let result = self
+            .db
+            .execute_batch(&format!("RELEASE SAVEPOINT {}", self.name));
+        self.db
+            .change_listeners
+            .end_frame(result.is_ok(), self.db.db.is_autocommit());
+        Ok(result?)
+    }
+
+    /// Undoes the changes made since the savepoint started, but leaves it
+    /// active, so it can be used again.
+    pub fn rollback_to(&mut self) -> Result<()> {
+        self.db
+            .execute_batch(&format!("ROLLBACK TO SAVEPOINT {}", self.name))?;
+        self.db.change_listeners.rollback_frame();
+        Ok(())
+    }
+}
+
+impl<'a> Deref for LoginDbSavepoint<'a> {
+    type Target = LoginDb;
+    #[inline]
+    fn deref(&self) -> &LoginDb {
+        self.db
+    }
+}
+
+impl<'a> DerefMut for LoginDbSavepoint<'a> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut LoginDb {
+        self.db
+    }
+}
+
+impl<'a> Drop for LoginDbSavepoint<'a> {
+    fn drop(&mut self) {
+        if self.finished {
+            return;
         }
-        if login.times_used == 0 {
-            login.times_used = 1;
+        // `ROLLBACK TO` leaves the savepoint (and, if it's the outermost one,
+        // the transaction) open, so we release it too.
+        if !self.db.db.is_autocommit() {
+            if let Err(e) = self.db.execute_batch(&format!(
+                "ROLLBACK TO SAVEPOINT {name}; RELEASE SAVEPOINT {name}",
+                name = self.name
+            )) {
+                log::warn!("Error dropping a savepoint: {}", e);
+            }
         }
+        self.db
+            .change_listeners
+            .end_frame(false, self.db.db.is_autocommit());
+    }
+}
 
-        let sql = format!(
-            "INSERT OR IGNORE INTO loginsL (
-                hostname,
-                httpRealm,
-                formSubmitURL,
-                usernameField,
-                passwordField,
-                timesUsed,
-                username,
-                password,
-                guid,
-                timeCreated,
+// A transaction started by `LoginDb::unchecked_transaction`. If a transaction
+// is already in progress (for example, in `execute_in_transaction`), this is a
+// savepoint within it instead, so `LoginDb` methods can be composed. Like
+// `UncheckedTransaction`, it's rolled back if dropped without being committed.
+struct NestableTransaction<'conn> {
+    conn: &'conn Connection,
+    listeners: &'conn ChangeListeners,
+    nested: bool,
+    finished: bool,
+}
+
+impl<'conn> NestableTransaction<'conn> {
+    fn new(
+        conn: &'conn Connection,
+        listeners: &'conn ChangeListeners,
+        behavior: TransactionBehavior,
+    ) -> Result<Self> {
+        let nested = begin_nestable(conn, listeners, behavior)?;
+        Ok(Self {
+            conn,
+            listeners,
+            nested,
+            finished: false,
+        })
+    }
+
+    fn commit(mut self) -> Result<()> {
+        self.finished = true;
+        end_nestable(self.conn, self.listeners, self.nested, true)
+    }
+}
+
+impl<'conn> Deref for NestableTransaction<'conn> {
+    type Target = Connection;
+    #[inline]
+    fn deref(&self) -> &Connection {
+        self.conn
+    }
+}
+
+impl<'conn> Drop for NestableTransaction<'conn> {
+    fn drop(&mut self) {
+        if self.finished {
+            return;
+        }
+        // If the connection is in autocommit mode, SQLite has already rolled
+        // back the whole transaction (for example, after `SQLITE_FULL`).
+        if self.conn.is_autocommit() {
+            self.listeners.end_frame(false, true);
+            return;
+        }
+        if let Err(e) = end_nestable(self.conn, self.listeners, self.nested, false) {
+            log::warn!("Error dropping a transaction: {}", e);
+        }
+    }
+}
+
+impl<'conn> ConnExt for NestableTransaction<'conn> {
+    #[inline]
+    fn conn(&self) -> &Connection {
+        self.conn
+    }
+}
+
+// Starts a transaction, or a savepoint if one is already in progress.
+// Returns true in the latter case.
+fn begin_nestable(
+    conn: &Connection,
+    listeners: &ChangeListeners,
+    behavior: TransactionBehavior,
+) -> Result<bool> {
+    let nested = !conn.is_autocommit();
+    conn.execute_batch(match (nested, behavior) {
+        (true, _) => "SAVEPOINT logins_nested",
+        (false, TransactionBehavior::Immediate) => "BEGIN IMMEDIATE",
+        (false, _) => "BEGIN DEFERRED",
+    })?;
+    listeners.begin_frame();
+    Ok(nested)
+}
+
+// Commits or rolls back something started by `begin_nestable`. Change events
+// are sent after a successful `COMMIT`.
+fn end_nestable(
+    conn: &Connection,
+    listeners: &ChangeListeners,
+    nested: bool,
+    commit: bool,
+) -> Result<()> {
+    let result = conn.execute_batch(match (nested, commit) {
+        (false, true) => "COMMIT",
+        (false, false) => "ROLLBACK",
+        (true, true) => "RELEASE logins_nested",
+        (true, false) => "ROLLBACK TO logins_nested; RELEASE logins_nested",
+    });
+    listeners.end_frame(commit && result.is_ok(), conn.is_autocommit());
+    Ok(result?)
+}
+
+pub struct LoginDb {
+    pub db: Connection,
+    interrupt_counter: Arc<AtomicUsize>,
+    field_limits: Cell<FieldLimits>,
+    conflict_resolution: Cell<ConflictResolution>,
+    change_listeners: Arc<ChangeListeners>,
+    audit_actor: Option<String>,
+    cipher: Cell<CipherConfig>,
+}
+
+impl LoginDb {
+    pub fn with_connection(
+        db: Connection,
+        encryption_key: Option<EncryptionKey>,
+        salt: Option<&str>,
+    ) -> Result<Self> {
+        Self::with_connection_options(db, encryption_key, salt, &ConnectionOptions::default())
+    }
+
+    pub fn with_connection_options(
+        db: Connection,
+        encryption_key: Option<EncryptionKey>,
+        salt: Option<&str>,
+        options: &ConnectionOptions,
+    ) -> Result<Self> {
+        #[cfg(test)]
+        {
+            util::init_test_logging();
+        }
+
+        if let Some(key) = encryption_key {
+            db.set_pragma("key", key.pragma_value()?)?
+                .set_pragma("secure_delete", true)?;
+
+            options.cipher.apply(&db, None)?;
+
+            if let Some(s) = salt {
+                // If a salt is also provided, this means the consumer does not want the salt stored
+                // in the database header. Currently only iOS uses this.
+                db.set_pragma("cipher_plaintext_header_size", 32)?;
+                db.set_pragma("cipher_salt", format!("x'{}'", s))?;
+            }
+        }
+
+        if let Some(mode) = options.journal_mode {
+            db.set_pragma("journal_mode", mode.as_str())?;
+        }
+        if let Some(pages) = options.cache_size_pages {
+            db.set_pragma("cache_size", pages)?;
+        }
+        if let Some(ms) = options.busy_timeout_ms {
+            db.busy_timeout(Duration::from_millis(ms.into()))?;
+        }
+
+        let mut logins = Self {
+            db,
+            interrupt_counter: Arc::new(AtomicUsize::new(0)),
+            field_limits: Cell::default(),
+            conflict_resolution: Cell::new(ConflictResolution::FieldLevel),
+            change_listeners: Arc::default(),
+            audit_actor: None,
+            cipher: Cell::new(options.cipher),
+        };
+        logins.set_temp_store_mode(options.temp_store)?;
+        let tx = logins.db.transaction()?;
+        schema::init(&tx)?;
+        if options.heal_orphaned_mirror_records {
+            let healed = heal_orphaned_mirror_records_in(&tx)?;
+            if healed > 0 {
+                log::warn!("Healed {} orphaned mirror records", healed);
+            }
+        }
+        tx.commit()?;
+        Ok(logins)
+    }
+
+    pub fn open(path: impl AsRef<Path>, encryption_key: Option<EncryptionKey>) -> Result<Self> {
+        Ok(Self::with_connection(
+            Connection::open(path)?,
+            encryption_key,
+            None,
+        )?)
+    }
+
+    pub fn open_with_salt(
+        path: impl AsRef<Path>,
+        encryption_key: &str,
+        salt: &str,
+    ) -> Result<Self> {
+        ensure_valid_salt(salt)?;
+        Ok(Self::with_connection(
+            Connection::open(path)?,
+            Some(encryption_key.into()),
+            Some(salt),
+        )?)
+    }
+
+    pub fn open_in_memory(encryption_key: Option<EncryptionKey>) -> Result<Self> {
+        Ok(Self::with_connection(
+            Connection::open_in_memory()?,
+            encryption_key,
+            None,
+        )?)
+    }
+
+    /// Opens an existing database and fetches the salt.
+    /// This method is used by iOS consumers as part as the migration plan to store
+    /// the salt outside of the sqlite db headers.
+    ///
+    /// Will return an error if the database does not exist.
+    pub fn open_and_get_salt(path: impl AsRef<Path>, encryption_key: &str) -> Result<String> {
+        Self::open_and_get_salt_with_cipher(path, encryption_key, &CipherConfig::default())
+    }
+
+    /// Like `open_and_get_salt`, for a database created with `cipher`.
+    pub fn open_and_get_salt_with_cipher(
+        path: impl AsRef<Path>,
+        encryption_key: &str,
+        cipher: &CipherConfig,
+    ) -> Result<String> {
+        // Open the connection defensively without attempting to create a db if it doesn't exist.
+        let db = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+        db.set_pragma("key", encryption_key)?;
+        cipher.apply(&db, None)?;
+        let salt = db.query_one::<String>("PRAGMA cipher_salt")?;
+        Ok(salt)
+    }
+
+    pub fn open_and_migrate_to_plaintext_header(
+        path: impl AsRef<Path>,
+        encryption_key: &str,
+        salt: &str,
+    ) -> Result<()> {
+        Self::open_and_migrate_to_plaintext_header_with_cipher(
+            path,
+            encryption_key,
+            salt,
+            &CipherConfig::default(),
+        )
+    }
+
+    /// Like `open_and_migrate_to_plaintext_header`, for a database created
+    /// with `cipher`.
+    pub fn open_and_migrate_to_plaintext_header_with_cipher(
+        path: impl AsRef<Path>,
+        encryption_key: &str,
+        salt: &str,
+        cipher: &CipherConfig,
+    ) -> Result<()> {
+        ensure_valid_salt(salt)?;
+        // Open the connection defensively without attempting to create a db if it doesn't exist.
+        let db = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_WRITE)?;
+        db.set_pragma("key", encryption_key)?;
+        cipher.apply(&db, None)?;
+        db.set_pragma("cipher_salt", format!("x'{}'", salt))?;
+        // This tricks the `cipher_plaintext_header_size` command to work properly.
+        let user_version = db.query_one::<i64>("PRAGMA user_version")?;
+        // Remove the salt from the database header.
+        db.set_pragma("cipher_plaintext_header_size", 32)?;
+        // Flush the header changes.
+        db.set_pragma("user_version", user_version)?;
+        db.close().map_err(|(_conn, err)| err)?;
+        Ok(())
+    }
+
+    /// Encrypts the existing, unencrypted database at `path` with `new_key`.
+    ///
+    /// The data is copied to a new encrypted database, which replaces the
+    /// original only once the copy is complete, so if this fails the original
+    /// is left as it was. There must be no other connections to the database.
+    pub fn encrypt(path: impl AsRef<Path>, new_key: &str) -> Result<()> {
+        Self::encrypt_with_cipher(path, new_key, &CipherConfig::default())
+    }
+
+    /// Like `encrypt`, but encrypts the database with `cipher`.
+    pub fn encrypt_with_cipher(
+        path: impl AsRef<Path>,
+        new_key: &str,
+        cipher: &CipherConfig,
+    ) -> Result<()> {
+        copy_database(path.as_ref(), None, Some(new_key), cipher)
+    }
+
+    /// Like `encrypt`, but replaces the existing database at `path`, which is
+    /// encrypted with `current_key`, with an unencrypted copy.
+    pub fn decrypt(path: impl AsRef<Path>, current_key: &str) -> Result<()> {
+        Self::decrypt_with_cipher(path, current_key, &CipherConfig::default())
+    }
+
+    /// Like `decrypt`, for a database encrypted with `cipher`.
+    pub fn decrypt_with_cipher(
+        path: impl AsRef<Path>,
+        current_key: &str,
+        cipher: &CipherConfig,
+    ) -> Result<()> {
+        copy_database(path.as_ref(), Some(current_key), None, cipher)
+    }
+
+    /// Writes a consistent snapshot of the database to `dest_path`,
+    /// replacing anything already there, using SQLite's online backup API.
+    /// Other connections can keep reading while the backup runs; if one
+    /// writes, the backup starts over.
+    ///
+    /// SQLCipher can only copy pages between databases with the same key, so
+    /// `key` must be the key this database was opened with. Databases opened
+    /// with a separate salt aren't supported.
+    pub fn backup(
+        &self,
+        dest_path: impl AsRef<Path>,
+        key: Option<EncryptionKey>,
+        options: &BackupOptions,
+        progress: Option<&mut dyn FnMut(BackupProgress)>,
+    ) -> Result<()> {
+        let mut dest = open_with_key(
+            dest_path.as_ref(),
+            OpenFlags::default(),
+            key.as_ref(),
+            &self.cipher_config(),
+        )?;
+        copy_pages(&self.db, &mut dest, options, progress)
+    }
+
+    /// Replaces the database at `dest`, which may be corrupt, with the backup
+    /// at `src`, which was made by `backup` using `key`. As with `encrypt`,
+    /// the backup is copied to a new file, which replaces `dest` only once the
+    /// copy is complete. There must be no other connections to `dest`.
+    pub fn restore_from_backup(
+        src: impl AsRef<Path>,
+        dest: impl AsRef<Path>,
+        key: Option<EncryptionKey>,
+    ) -> Result<()> {
+        Self::restore_from_backup_with_cipher(src, dest, key, &CipherConfig::default())
+    }
+
+    /// Like `restore_from_backup`, for a backup of a database opened with
+    /// `cipher`.
+    pub fn restore_from_backup_with_cipher(
+        src: impl AsRef<Path>,
+        dest: impl AsRef<Path>,
+        key: Option<EncryptionKey>,
+        cipher: &CipherConfig,
+    ) -> Result<()> {
+        let dest = dest.as_ref();
+        let src = open_with_key(
+            src.as_ref(),
+            OpenFlags::SQLITE_OPEN_READ_ONLY,
+            key.as_ref(),
+            cipher,
+        )?;
+        let mut copy_path = dest.as_os_str().to_owned();
+        copy_path.push(".restoring");
+        let copy_path = PathBuf::from(copy_path);
+        if copy_path.exists() {
+            std::fs::remove_file(&copy_path)?;
+        }
+        let mut copy = open_with_key(&copy_path, OpenFlags::default(), key.as_ref(), cipher)?;
+        if let Err(e) = copy_pages(&src, &mut copy, &BackupOptions::default(), None) {
+            drop(copy);
+            let _ = std::fs::remove_file(&copy_path);
+            return Err(e);
+        }
+        copy.close().map_err(|(_conn, err)| err)?;
+        remove_journal_files(dest)?;
+        std::fs::rename(&copy_path, dest)?;
+        Ok(())
+    }
+
+    pub fn set_temp_store_mode(&self, mode: TempStoreMode) -> Result<()> {
+        self.conn().set_pragma("temp_store", mode as u8)?;
+        Ok(())
+    }
+
+    /// Returns the size of the database, not counting the write-ahead log.
+    /// This includes unused pages, which `vacuum` removes.
+    pub fn get_db_size_bytes(&self) -> Result<u64> {
+        let page_count: i64 = self.query_one("PRAGMA page_count")?;
+        let page_size: i64 = self.query_one("PRAGMA page_size")?;
+        Ok((page_count * page_size) as u64)
+    }
+
+    /// Returns the size of the write-ahead log file, or `None` if the
+    /// database isn't in WAL mode.
+    pub fn get_wal_size_bytes(&self) -> Result<Option<u64>> {
+        let mode: String = self.query_one("PRAGMA journal_mode")?;
+        if !mode.eq_ignore_ascii_case("wal") {
+            return Ok(None);
+        }
+        let path: String = self.query_row(
+            "SELECT file FROM pragma_database_list WHERE name = 'main'",
+            NO_PARAMS,
+            |row| row.get(0),
+        )?;
+        match std::fs::metadata(format!("{}-wal", path)) {
+            Ok(metadata) => Ok(Some(metadata.len())),
+            // The log is deleted when the last connection closes, and is
+            // only created again on the next write.
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Some(0)),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Returns the sizes, schema version, and number of logins in one go,
+    /// for reporting storage usage.
+    pub fn get_storage_info(&self) -> Result<StorageInfo> {
+        Ok(StorageInfo {
+            db_size_bytes: self.get_db_size_bytes()?,
+            wal_size_bytes: self.get_wal_size_bytes()?,
+            schema_version: self.query_one("PRAGMA user_version")?,
+            record_count: self.get_total_count()?,
+        })
+    }
+
+    /// Changes how long to wait for another connection's lock to be released
+    /// before failing with `SQLITE_BUSY`. See
+    /// `ConnectionOptions::busy_timeout_ms`.
+    pub fn set_busy_timeout(&self, ms: u32) -> Result<()> {
+        self.db.busy_timeout(Duration::from_millis(ms.into()))?;
+        Ok(())
+    }
+
+    /// Changes the longest passwords and usernames, in bytes, which this
+    /// database accepts when adding, updating or importing logins, and which
+    /// `validate_all_logins` reports. Both default to 4096. Logins which are
+    /// already stored are left alone: `run_integrity_repair` doesn't
+    /// quarantine them, and `restore_from_quarantine` doesn't reject them.
+    pub fn set_max_field_lengths(&self, max_password: usize, max_username: usize) {
+        self.field_limits.set(FieldLimits {
+            max_password,
+            max_username,
+        });
+    }
+
+    /// The limits set by `set_max_field_lengths`.
+    pub fn field_limits(&self) -> FieldLimits {
+        self.field_limits.get()
+    }
+
+    /// Changes how syncs resolve conflicts between incoming records and local
+    /// changes, which by default is `ConflictResolution::FieldLevel`.
+    /// `apply_incoming_with_policy` and syncing directly with another database
+    /// aren't affected.
+    pub fn set_conflict_resolution(&self, resolution: ConflictResolution) {
+        self.conflict_resolution.set(resolution);
+    }
+
+    /// The strategy set by `set_conflict_resolution`.
+    pub fn conflict_resolution(&self) -> ConflictResolution {
+        self.conflict_resolution.get()
+    }
+
+    /// Sets the auto-vacuum mode. Switching between `None` and the other modes
+    /// on an existing database only takes effect after the next `vacuum`.
+    pub fn set_auto_vacuum(&self, mode: AutoVacuumMode) -> Result<()> {
+        self.conn().set_pragma("auto_vacuum", mode as u8)?;
+        Ok(())
+    }
+
+    /// Rebuilds the database file, leaving out unused pages. This can't be
+    /// done inside a transaction, so fails with `TransactionAlreadyOpen` if
+    /// one is open.
+    pub fn vacuum(&mut self) -> Result<()> {
+        if !self.db.is_autocommit() {
+            throw!(ErrorKind::TransactionAlreadyOpen);
+        }
+        self.db.execute_batch("VACUUM")?;
+        Ok(())
+    }
+
+    /// Copies everything in the write-ahead log into the database, and then
+    /// truncates the log, so it doesn't keep growing between SQLite's
+    /// automatic checkpoints. If another connection is using the database,
+    /// the checkpoint may not complete, in which case `checkpointed_frames`
+    /// is less than `log_frames`. When not in WAL mode, both are -1.
+    pub fn checkpoint(&self) -> Result<CheckpointResult> {
+        Ok(self
+            .db
+            .query_row("PRAGMA wal_checkpoint(TRUNCATE)", NO_PARAMS, |row| {
+                Ok(CheckpointResult {
+                    log_frames: row.get(1)?,
+                    checkpointed_frames: row.get(2)?,
+                })
+            })?)
+    }
+
+    /// Sets `PRAGMA kdf_iter`, the number of PBKDF2 iterations used to derive
+    /// the key from a passphrase. SQLCipher only uses this when it derives
+    /// the key, so to open a database created with a different count, pass
+    /// a `CipherConfig` in the `ConnectionOptions` instead. Backups and
+    /// databases attached later use the new count too.
+    pub fn set_cipher_kdf_iter(&self, iterations: u32) -> Result<()> {
+        self.conn().set_pragma("kdf_iter", iterations)?;
+        self.cipher.set(CipherConfig {
+            kdf_iter: iterations,
+            ..self.cipher.get()
+        });
+        Ok(())
+    }
+
+    /// The SQLCipher parameters this database uses, from the
+    /// `ConnectionOptions` it was opened with and `set_cipher_kdf_iter`.
+    /// They're also used for backups, and for the other database in
+    /// `merge_db`.
+    pub fn cipher_config(&self) -> CipherConfig {
+        self.cipher.get()
+    }
+
+    /// Returns the current value of `PRAGMA kdf_iter`.
+    pub fn get_cipher_kdf_iter(&self) -> Result<u32> {
+        // SQLCipher returns its settings as text.
+        Ok(self.db.query_row("PRAGMA kdf_iter", NO_PARAMS, |row| {
+            row.get::<_, String>(0)?
+                .parse()
+                .map_err(|e| rusqlite::Error::FromSqlConversionFailure(0, Type::Text, Box::new(e)))
+        })?)
+    }
+
+    pub fn disable_mem_security(&self) -> Result<()> {
+        self.conn().set_pragma("cipher_memory_security", false)?;
+        Ok(())
+    }
+
+    /// Change the key on an existing encrypted database,
+    /// it must first be unlocked with the current encryption key.
+    /// Once the database is readable and writeable, PRAGMA rekey
+    /// can be used to re-encrypt every page in the database with a new key.
+    /// https://www.zetetic.net/sqlcipher/sqlcipher-api/#Changing_Key
+    pub fn rekey_database(&self, new_encryption_key: &str) -> Result<()> {
+        self.conn().set_pragma("rekey", new_encryption_key)?;
+        Ok(())
+    }
+
+    /// Like `rekey_database`, but uses `raw_key` as the key material directly,
+    /// rather than deriving a key from a passphrase. This matches databases
+    /// created by SQLCipher consumers which use raw keys; to open the database
+    /// afterwards, use `EncryptionKey::RawHex`.
+    pub fn rekey_raw(&self, raw_key: &[u8; 32]) -> Result<()> {
+        let hex: String = raw_key.iter().map(|b| format!("{:02x}", b)).collect();
+        self.conn().set_pragma("rekey", format!("x'{}'", hex))?;
+        Ok(())
+    }
+
+    pub fn new_interrupt_handle(&self) -> SqlInterruptHandle {
+        SqlInterruptHandle::new(
+            self.db.get_interrupt_handle(),
+            self.interrupt_counter.clone(),
+        )
+    }
+
+    #[inline]
+    pub fn begin_interrupt_scope(&self) -> SqlInterruptScope {
+        SqlInterruptScope::new(self.interrupt_counter.clone())
+    }
+
+    /// Registers `hook` to be called whenever a row in `loginsL` or `loginsM`
+    /// is inserted, updated or deleted, replacing any previously registered
+    /// hook. The hook is called synchronously, while the change is being
+    /// made, and must not use this `LoginDb`.
+    pub fn register_change_hook<F>(&self, hook: F) -> Result<()>
+    where
+        F: Fn(ChangeEvent) + Send + 'static,
+    {
+        *self.change_listeners.hook.lock().unwrap() = Some(Box::new(hook));
+        self.install_change_listeners()
+    }
+
+    /// Returns a receiver for the changes made to the logins from now on.
+    /// Every subscriber receives every event, in the order the changes were
+    /// made, once the transaction making them has been committed. Changes
+    /// undone by rolling back a savepoint aren't sent. Dropping the receiver
+    /// unsubscribes.
+    pub fn subscribe(&self) -> Result<mpsc::Receiver<LoginChangeEvent>> {
+        let (sender, receiver) = mpsc::channel();
+        self.change_listeners
+            .subscribers
+            .lock()
+            .unwrap()
+            .push(sender);
+        self.install_change_listeners()?;
+        Ok(receiver)
+    }
+
+    fn install_change_listeners(&self) -> Result<()> {
+        // SQLite's `update_hook` only tells us the rowid of the changed row,
+        // and can't query the database to find its GUID (which, for deletes,
+        // would be gone anyway). Instead, we use temp triggers which pass the
+        // GUID to a function which calls the hook. The triggers also work
+        // out how the change looks to consumers, for the subscribers.
+        let listeners = Arc::clone(&self.change_listeners);
+        self.db.create_scalar_function(
+            "logins_change_hook",
+            4,
+            FunctionFlags::SQLITE_UTF8,
+            move |ctx| {
+                let guid: Option<String> = ctx.get(2)?;
+                if let Some(hook) = &*listeners.hook.lock().unwrap() {
+                    let kind = match ctx.get::<String>(0)?.as_str() {
+                        "insert" => ChangeKind::Insert,
+                        "update" => ChangeKind::Update,
+                        _ => ChangeKind::Delete,
+                    };
+                    let table = if ctx.get::<String>(1)? == "loginsM" {
+                        "loginsM"
+                    } else {
+                        "loginsL"
+                    };
+                    hook(ChangeEvent {
+                        kind,
+                        guid: guid.clone(),
+                        table,
+                    });
+                }
+                if let Some(guid) = guid {
+                    match ctx.get::<Option<String>>(3)?.as_deref() {
+                        Some("added") => listeners.queue(LoginChangeEvent::Added(guid)),
+                        Some("updated") => listeners.queue(LoginChangeEvent::Updated(guid)),
+                        Some("deleted") => listeners.queue(LoginChangeEvent::Deleted(guid)),
+                        _ => {}
+                    }
+                }
+                Ok(true)
+            },
+        )?;
+        let listeners = Arc::clone(&self.change_listeners);
+        self.db.commit_hook(Some(move || {
+            listeners.on_commit();
+            false
+        }));
+        let listeners = Arc::clone(&self.change_listeners);
+        self.db.rollback_hook(Some(move || listeners.on_rollback()));
+        self.db.execute_batch(&CREATE_CHANGE_HOOK_TRIGGERS_SQL)?;
+        Ok(())
+    }
+
+    // Runs `f` with the subscribers' row-level events muted, then queues
+    // `event` to be sent when the current transaction commits.
+    fn with_change_event<T>(
+        &self,
+        event: LoginChangeEvent,
+        f: impl FnOnce() -> Result<T>,
+    ) -> Result<T> {
+        self.change_listeners.muted.store(true, Ordering::SeqCst);
+        let result = f();
+        self.change_listeners.muted.store(false, Ordering::SeqCst);
+        if result.is_ok() {
+            self.change_listeners.queue(event);
+        }
+        result
+    }
+
+    /// Starts a transaction which will be committed when the returned guard
+    /// is dropped, unless it's explicitly rolled back. It's started with
+    /// `BEGIN IMMEDIATE`, so other connections can't write until it finishes.
+    pub fn begin_write_transaction(&mut self) -> Result<WriteTransaction<'_>> {
+        let mut tx = self
+            .db
+            .transaction_with_behavior(TransactionBehavior::Immediate)?;
+        tx.set_drop_behavior(DropBehavior::Commit);
+        self.change_listeners.begin_frame();
+        Ok(WriteTransaction {
+            tx,
+            _frame: TransactionFrame(Arc::clone(&self.change_listeners)),
+        })
+    }
+
+    /// Runs `f` in a transaction, which is committed if `f` returns `Ok`, and
+    /// rolled back if it returns an error (or panics). The transaction is
+    /// started with `BEGIN IMMEDIATE`.
+    ///
+    /// `f` can use any `LoginDb` methods, including `execute_in_transaction`
+    /// itself: the transactions they'd normally start become savepoints
+    /// within this one, so if one fails, only its own changes are undone.
+    pub fn execute_in_transaction<F, T>(&mut self, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut LoginDb) -> Result<T>,
+    {
+        let listeners = Arc::clone(&self.change_listeners);
+        let nested = begin_nestable(&self.db, &listeners, TransactionBehavior::Immediate)?;
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| f(self)));
+        let commit = matches!(result, Ok(Ok(_)));
+        let ended = end_nestable(&self.db, &listeners, nested, commit);
+        match result {
+            Ok(Ok(value)) => {
+                ended?;
+                Ok(value)
+            }
+            Ok(Err(e)) => {
+                if let Err(rollback_err) = ended {
+                    log::warn!("Error rolling back a transaction: {}", rollback_err);
+                }
+                Err(e)
+            }
+            Err(panic) => {
+                if let Err(rollback_err) = ended {
+                    log::warn!("Error rolling back a transaction: {}", rollback_err);
+                }
+                std::panic::resume_unwind(panic)
+            }
+        }
+    }
+
+    /// Starts a savepoint named `name`, for operations which want to be able
+    /// to undo part of their work. Savepoints can be nested, and can be used
+    /// inside `execute_in_transaction`.
+    pub fn savepoint<'a>(&'a mut self, name: &str) -> Result<LoginDbSavepoint<'a>> {
+        let name = format!("\"{}\"", name.replace('"', "\"\""));
+        self.db.execute_batch(&format!("SAVEPOINT {}", name))?;
+        self.change_listeners.begin_frame();
+        Ok(LoginDbSavepoint {
+            db: self,
+            name,
+            finished: false,
+        })
+    }
+
+    // These shadow the `ConnExt` methods of the same name, so every `LoginDb`
+    // method works inside `execute_in_transaction`.
+    fn unchecked_transaction(&self) -> Result<NestableTransaction<'_>> {
+        NestableTransaction::new(
+            &self.db,
+            &self.change_listeners,
+            TransactionBehavior::Deferred,
+        )
+    }
+
+    fn unchecked_transaction_imm(&self) -> Result<NestableTransaction<'_>> {
+        NestableTransaction::new(
+            &self.db,
+            &self.change_listeners,
+            TransactionBehavior::Immediate,
+        )
+    }
+
+    /// Starts a read-only transaction, which is rolled back when the returned
+    /// guard is dropped. Like `begin_write_transaction`, it's started with
+    /// `BEGIN IMMEDIATE`, so other connections can't write until it finishes.
+    pub fn begin_read_transaction(&self) -> Result<ReadTransaction<'_>> {
+        let tx = self.db.unchecked_transaction_imm()?;
+        // From here on, dropping the guard turns `query_only` off again, even
+        // if turning it on fails.
+        let tx = ReadTransaction {
+            conn: &self.db,
+            tx: Some(tx),
+        };
+        tx.set_pragma("query_only", true)?;
+        Ok(tx)
+    }
+
+    /// Returns the steps of SQLite's query plan for `sql`, which is useful
+    /// when checking that new queries use the indices we expect.
+    pub fn explain_query(&self, sql: &str, params: &[(&str, &dyn ToSql)]) -> Result<Vec<String>> {
+        let plan = sql_support::QueryPlan::new(&self.db, sql, params)?;
+        Ok(plan.plan.into_iter().map(|step| step.detail).collect())
+    }
+
+    /// Returns an in-memory snapshot of this database, so tests can compare
+    /// the state before and after an operation. SQLCipher doesn't support
+    /// SQLite's backup API for encrypted databases, so rather than using it,
+    /// we copy the rows of each table across.
+    #[cfg(test)]
+    pub fn clone_for_testing(&self) -> Result<LoginDb> {
+        let clone = LoginDb::open_in_memory(None)?;
+        let tx = clone.unchecked_transaction()?;
+        for table in &["loginsL", "loginsM", "loginsSyncMeta"] {
+            let mut select = self.db.prepare(&format!("SELECT * FROM {}", table))?;
+            let cols = select.column_names().join(", ");
+            let num_cols = select.column_count();
+            let mut insert = clone.db.prepare(&format!(
+                "INSERT INTO {} ({}) VALUES ({})",
+                table,
+                cols,
+                sql_support::repeat_sql_vars(num_cols)
+            ))?;
+            let mut rows = select.query(NO_PARAMS)?;
+            while let Some(row) = rows.next()? {
+                let values = (0..num_cols)
+                    .map(|i| row.get::<_, rusqlite::types::Value>(i))
+                    .collect::<rusqlite::Result<Vec<_>>>()?;
+                insert.execute(&values)?;
+            }
+        }
+        tx.commit()?;
+        Ok(clone)
+    }
+}
+
+// Checks if the provided string is a 32 len hex string.
+fn ensure_valid_salt(salt: &str) -> Result<()> {
+    if salt.len() == 32
+        && salt.as_bytes().iter().all(|c| match c {
+            b'A'..=b'F' => true,
+            b'a'..=b'f' => true,
+            b'0'..=b'9' => true,
+            _ => false,
+        })
+    {
+        return Ok(());
+    }
+    Err(ErrorKind::InvalidSalt.into())
+}
+
+// The local timestamp (in milliseconds) before which a tombstone must have
+// been deleted for it to be considered ready to purge.
+fn purge_cutoff_ms(age_ms: u64) -> i64 {
+    util::system_time_ms_i64(SystemTime::now()).saturating_sub(age_ms as i64)
+}
+
+// Where the columns `LoginDb::import_from_csv` uses are in a CSV file.
+struct CsvColumns {
+    url: usize,
+    username: usize,
+    password: usize,
+    http_realm: Option<usize>,
+    form_action_origin: Option<usize>,
+    guid: Option<usize>,
+    time_created: Option<usize>,
+    time_last_used: Option<usize>,
+    time_password_changed: Option<usize>,
+    // Which browser's format this is, for `CreationSource::Imported`.
+    source_name: &'static str,
+}
+
+impl CsvColumns {
+    fn from_headers(headers: &csv::StringRecord) -> Result<Self> {
+        let find = |name: &str| {
+            headers
+                .iter()
+                .position(|header| header.trim().eq_ignore_ascii_case(name))
+        };
+        let require = |name: &str| -> Result<usize> {
+            Ok(find(name).ok_or_else(|| ErrorKind::MissingCsvColumn(name.into()))?)
+        };
+        let http_realm = find("httpRealm");
+        Ok(CsvColumns {
+            url: require("url")?,
+            username: require("username")?,
+            password: require("password")?,
+            http_realm,
+            form_action_origin: find("formActionOrigin"),
+            guid: find("guid"),
+            time_created: find("timeCreated"),
+            time_last_used: find("timeLastUsed"),
+            time_password_changed: find("timePasswordChanged"),
+            source_name: if http_realm.is_none() && find("name").is_some() {
+                "Chrome"
+            } else {
+                "Firefox"
+            },
+        })
+    }
+
+    fn login_from_record(&self, record: &csv::StringRecord, limits: &FieldLimits) -> Result<Login> {
+        let field = |index: Option<usize>| index.and_then(|i| record.get(i)).unwrap_or("");
+        let timestamp = |index: Option<usize>| -> Result<i64> {
+            let value = field(index);
+            if value.is_empty() {
+                return Ok(0);
+            }
+            Ok(value
+                .parse()
+                .map_err(|_| LoginValidationError::IllegalFieldValue {
+                    field_info: format!("`{}` is not a valid timestamp", value),
+                })?)
+        };
+        let (http_realm, form_submit_url) = match (field(self.http_realm), self.form_action_origin)
+        {
+            ("", Some(_)) => (None, Some(field(self.form_action_origin).to_owned())),
+            // Chrome doesn't export where forms were submitted, so assume it
+            // was the login's own origin.
+            ("", None) => (None, Some(field(Some(self.url)).to_owned())),
+            (realm, _) => (Some(realm.to_owned()), None),
+        };
+        let guid = field(self.guid);
+        let login = Login {
+            guid: if login::is_valid_guid(guid) {
+                guid.into()
+            } else {
+                Guid::empty()
+            },
+            hostname: field(Some(self.url)).to_owned(),
+            http_realm,
+            form_submit_url,
+            username: field(Some(self.username)).to_owned(),
+            password: field(Some(self.password)).to_owned(),
+            time_created: timestamp(self.time_created)?,
+            time_last_used: timestamp(self.time_last_used)?,
+            time_password_changed: timestamp(self.time_password_changed)?,
+            creation_source: CreationSource::Imported {
+                source_name: self.source_name.into(),
+            },
+            ..Login::default()
+        };
+        login.fixup_with_limits(limits)
+    }
+}
+
+// Opens a connection for `LoginDb::backup` or `LoginDb::restore_from_backup`,
+// set up to read and write pages the same way `LoginDb` does.
+fn open_with_key(
+    path: &Path,
+    flags: OpenFlags,
+    key: Option<&EncryptionKey>,
+    cipher: &CipherConfig,
+) -> Result<Connection> {
+    let conn = Connection::open_with_flags(path, flags)?;
+    if let Some(key) = key {
+        conn.set_pragma("key", key.pragma_value()?)?;
+        cipher.apply(&conn, None)?;
+    }
+    Ok(conn)
+}
+
+fn copy_pages(
+    src: &Connection,
+    dest: &mut Connection,
+    options: &BackupOptions,
+    mut progress: Option<&mut dyn FnMut(BackupProgress)>,
+) -> Result<()> {
+    let pages_per_step = options.pages_per_step.unwrap_or(100);
+    let pause = options
+        .pause_between_steps
+        .unwrap_or_else(|| Duration::from_millis(10));
+    let max_busy_retries = options.max_busy_retries.unwrap_or(100);
+    let mut busy_retries = 0;
+    let backup = Backup::new(src, dest)?;
+    loop {
+        let result = backup.step(pages_per_step)?;
+        if let Some(callback) = progress.as_mut() {
+            let p = backup.progress();
+            callback(BackupProgress {
+                pages_remaining: p.remaining,
+                pages_total: p.pagecount,
+            });
+        }
+        match result {
+            StepResult::Done => return Ok(()),
+            StepResult::More => busy_retries = 0,
+            // The source or destination was locked, so we try again, unless
+            // it's been locked for too long.
+            _ => {
+                if busy_retries >= max_busy_retries {
+                    throw!(ErrorKind::BackupBusy(busy_retries));
+                }
+                busy_retries += 1;
+            }
+        }
+        std::thread::sleep(pause);
+    }
+}
+
+// The guts of `LoginDb::encrypt` and `LoginDb::decrypt`. A key of `None`
+// means unencrypted.
+fn copy_database(
+    path: &Path,
+    from_key: Option<&str>,
+    to_key: Option<&str>,
+    cipher: &CipherConfig,
+) -> Result<()> {
+    let options = ConnectionOptions {
+        cipher: *cipher,
+        ..ConnectionOptions::default()
+    };
+    // Opening the original checks that it exists and that the key is right,
+    // and upgrades it to the current schema, so it matches the copy's.
+    let db = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_WRITE)?;
+    drop(LoginDb::with_connection_options(
+        db,
+        from_key.map(Into::into),
+        None,
+        &options,
+    )?);
+
+    let mut copy_path = path.as_os_str().to_owned();
+    copy_path.push(".migrating");
+    let copy_path = PathBuf::from(copy_path);
+    if copy_path.exists() {
+        std::fs::remove_file(&copy_path)?;
+    }
+    let copy = LoginDb::with_connection_options(
+        Connection::open(&copy_path)?,
+        to_key.map(Into::into),
+        None,
+        &options,
+    )?;
+    if let Err(e) = copy_tables(&copy, path, from_key) {
+        drop(copy);
+        let _ = std::fs::remove_file(&copy_path);
+        return Err(e);
+    }
+    copy.db.close().map_err(|(_conn, err)| err)?;
+    remove_journal_files(path)?;
+    std::fs::rename(&copy_path, path)?;
+    Ok(())
+}
+
+// Removes any journal or write-ahead log left behind by the database at
+// `path`, before another database is renamed over it. They don't belong to
+// the new database, and SQLite would otherwise try to apply them to it.
+fn remove_journal_files(path: &Path) -> Result<()> {
+    for suffix in &["-journal", "-wal", "-shm"] {
+        let mut sidecar = path.as_os_str().to_owned();
+        sidecar.push(suffix);
+        let sidecar = PathBuf::from(sidecar);
+        if sidecar.exists() {
+            std::fs::remove_file(&sidecar)?;
+        }
+    }
+    Ok(())
+}
+
+fn copy_tables(copy: &LoginDb, path: &Path, from_key: Option<&str>) -> Result<()> {
+    copy.execute_named(
+        "ATTACH DATABASE :path AS source KEY :key",
+        named_params! {
+            ":path": path.to_string_lossy(),
+            ":key": from_key.unwrap_or(""),
+        },
+    )?;
+    if from_key.is_some() {
+        copy.cipher_config()
+            .apply(&copy.db, Some(DatabaseName::Attached("source")))?;
+    }
+    let tx = copy.unchecked_transaction()?;
+    for table in &[
+        "loginsL",
+        "loginsM",
+        "loginsSyncMeta",
+        "loginsQuarantine",
+        "loginsAuditLog",
+    ] {
+        // Columns added by migrations come last, so the column order in the
+        // original may not match the copy's.
+        let columns = {
+            let mut stmt = copy.prepare(&format!("PRAGMA main.table_info({})", table))?;
+            let names = stmt.query_map(NO_PARAMS, |row| row.get::<_, String>("name"))?;
+            names.collect::<result::Result<Vec<_>, _>>()?.join(", ")
+        };
+        copy.execute_batch(&format!(
+            "INSERT INTO main.{table} ({columns}) SELECT {columns} FROM source.{table}",
+            table = table,
+            columns = columns,
+        ))?;
+        let copied: i64 = copy.query_one(&format!("SELECT COUNT(*) FROM main.{}", table))?;
+        let expected: i64 = copy.query_one(&format!("SELECT COUNT(*) FROM source.{}", table))?;
+        if copied != expected {
+            throw!(ErrorKind::CopyIncomplete(table.to_string()));
+        }
+    }
+    tx.commit()?;
+    copy.execute_batch("DETACH DATABASE source")?;
+    Ok(())
+}
+
+impl ConnExt for LoginDb {
+    #[inline]
+    fn conn(&self) -> &Connection {
+        &self.db
+    }
+}
+
+impl Deref for LoginDb {
+    type Target = Connection;
+    #[inline]
+    fn deref(&self) -> &Connection {
+        &self.db
+    }
+}
+
+// login specific stuff.
+
+impl LoginDb {
+    fn mark_as_synchronized(
+        &self,
+        guids: &[&str],
+        ts: ServerTimestamp,
+        scope: &SqlInterruptScope,
+    ) -> Result<()> {
+        let tx = self.unchecked_transaction()?;
+        sql_support::each_chunk(guids, |chunk, _| -> Result<()> {
+            let (vars, params) = util::sql_placeholders_and_params(chunk);
+            self.db.execute(
+                &format!("DELETE FROM loginsM WHERE guid IN ({vars})", vars = vars),
+                &params,
+            )?;
+            scope.err_if_interrupted()?;
+
+            self.db.execute(
+                &format!(
+                    "INSERT OR IGNORE INTO loginsM (
+                         {common_cols}, is_overridden, server_modified
+                     )
+                     SELECT {common_cols}, 0, {modified_ms_i64}
+                     FROM loginsL
+                     WHERE is_deleted = 0 AND guid IN ({vars})",
+                    common_cols = schema::COMMON_COLS,
+                    modified_ms_i64 = ts.as_millis() as i64,
+                    vars = vars
+                ),
+                &params,
+            )?;
+            scope.err_if_interrupted()?;
+
+            self.db.execute(
+                &format!("DELETE FROM loginsL WHERE guid IN ({vars})", vars = vars),
+                &params,
+            )?;
+            scope.err_if_interrupted()?;
+            Ok(())
+        })?;
+        self.set_last_sync(ts)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Records that the server accepted the uploaded records listed in
+    /// `confirmation`: they're moved into the mirror (or, for tombstones,
+    /// removed entirely), and the last sync time is updated. This is what
+    /// happens at the end of a sync.
+    pub fn apply_outgoing_confirmation(&self, confirmation: OutgoingConfirmation) -> Result<()> {
+        let guids: Vec<&str> = confirmation.guids.iter().map(String::as_str).collect();
+        self.mark_as_synchronized(
+            &guids,
+            confirmation.new_server_timestamp,
+            &self.begin_interrupt_scope(),
+        )
+    }
+
+    // Fetch all the data for the provided IDs.
+    // TODO: Might be better taking a fn instead of returning all of it... But that func will likely
+    // want to insert stuff while we're doing this so ugh.
+    fn fetch_login_data(
+        &self,
+        records: &[(sync15::Payload, ServerTimestamp)],
+        telem: &mut telemetry::EngineIncoming,
+        scope: &SqlInterruptScope,
+        progress: &mut dyn FnMut(SyncProgress),
+    ) -> Result<Vec<SyncLoginData>> {
+        let mut sync_data = Vec::with_capacity(records.len());
+        {
+            let mut seen_ids: HashSet<Guid> = HashSet::with_capacity(records.len());
+            for incoming in records.iter() {
+                if seen_ids.contains(&incoming.0.id) {
+                    throw!(ErrorKind::DuplicateGuid(incoming.0.id.to_string()))
+                }
+                seen_ids.insert(incoming.0.id.clone());
+                match SyncLoginData::from_payload(incoming.0.clone(), incoming.1) {
+                    Ok(v) => sync_data.push(v),
+                    Err(e) => {
+                        log::error!("Failed to deserialize record {:?}: {}", incoming.0.id, e);
+                        // Ideally we'd track new_failed, but it's unclear how
+                        // much value it has.
+                        telem.failed(1);
+                    }
+                }
+            }
+        }
+        scope.err_if_interrupted()?;
+
+        // Records which failed to deserialize aren't in `sync_data`, so we
+        // can't index it by position in `records`.
+        let index_by_guid: HashMap<String, usize> = sync_data
+            .iter()
+            .enumerate()
+            .map(|(i, data)| (data.guid.to_string(), i))
+            .collect();
+        sql_support::each_chunk(records, |chunk, offset| -> Result<()> {
+            let processed = offset + chunk.len();
+            let guids: Vec<&str> = chunk.iter().map(|r| r.0.id.as_str()).collect();
+            let (vars, params) = util::sql_placeholders_and_params(&guids);
+            let query = format!(
+                "SELECT
+                         {common_cols},
+                         is_overridden,
+                         server_modified,
+                         NULL as local_modified,
+                         NULL as is_deleted,
+                         NULL as sync_status,
+                         1 as is_mirror
+                     FROM loginsM
+                     WHERE guid IN ({vars})
+
+                     UNION ALL
+
+                     SELECT
+                         {common_cols},
+                         NULL as is_overridden,
+                         NULL as server_modified,
+                         local_modified,
+                         is_deleted,
+                         sync_status,
+                         0 as is_mirror
+                     FROM loginsL
+                     WHERE guid IN ({vars})",
+                vars = vars,
+                common_cols = schema::COMMON_COLS,
+            );
+
+            let mut stmt = self.db.prepare(&query)?;
+
+            let rows = stmt.query_and_then(&params, |row| {
+                let guid: String = row.get("guid")?;
+                let guid_idx = match index_by_guid.get(&guid) {
+                    Some(&guid_idx) => guid_idx,
+                    None => return Ok(()),
+                };
+                let is_mirror: bool = row.get("is_mirror")?;
+                if is_mirror {
+                    sync_data[guid_idx].set_mirror(MirrorLogin::from_row(row)?)?;
+                } else {
+                    sync_data[guid_idx].set_local(LocalLogin::from_row(row)?)?;
+                }
+                scope.err_if_interrupted()?;
+                Ok(())
+            })?;
+            // `rows` is an Iterator<Item = Result<()>>, so we need to collect to handle the errors.
+            rows.collect::<Result<_>>()?;
+            progress(SyncProgress {
+                stage: SyncProgressStage::Fetching,
+                processed,
+                total: records.len(),
+                last_guid: records[processed - 1].0.id.to_string(),
+            });
+            Ok(())
+        })?;
+        Ok(sync_data)
+    }
+
+    // It would be nice if this were a batch-ish api (e.g. takes a slice of records and finds dupes
+    // for each one if they exist)... I can't think of how to write that query, though.
+    fn find_dupe(&self, l: &Login) -> Result<Option<Login>> {
+        let form_submit_host_port = l
+            .form_submit_url
+            .as_ref()
+            .and_then(|s| util::url_host_port(&s));
+        let args = named_params! {
+            ":hostname": l.hostname,
+            ":http_realm": l.http_realm,
+            ":username": l.username,
+            ":form_submit": form_submit_host_port,
+        };
+        let mut query = format!(
+            "SELECT {common}
+             FROM loginsL
+             WHERE hostname IS :hostname
+               AND httpRealm IS :http_realm
+               AND username IS :username",
+            common = schema::COMMON_COLS,
+        );
+        if form_submit_host_port.is_some() {
+            // Stolen from iOS
+            query += " AND (formSubmitURL = '' OR (instr(formSubmitURL, :form_submit) > 0))";
+        } else {
+            query += " AND formSubmitURL IS :form_submit"
+        }
+        Ok(self.try_query_row(&query, args, |row| Login::from_row(row), false)?)
+    }
+
+    /// Returns every pair of logins with the same hostname and username, for
+    /// a UI which helps the user clean up duplicates. Unlike `find_dupe`,
+    /// this ignores `http_realm` and `form_submit_url`, since logins which
+    /// differ only in those are common accidental duplicates. Each pair is
+    /// returned once, with the lower GUID first.
+    pub fn find_all_dupes(&self) -> Result<Vec<(Login, Login)>> {
+        let guid_pairs: Vec<(String, String)> = {
+            let mut stmt = self.db.prepare_cached(&FIND_ALL_DUPES_SQL)?;
+            let rows = stmt.query_map(NO_PARAMS, |row| Ok((row.get(0)?, row.get(1)?)))?;
+            rows.collect::<result::Result<_, _>>()?
+        };
+        let get = |guid: &str| -> Result<Login> {
+            match self.get_by_id(guid)? {
+                Some(login) => Ok(login),
+                None => throw!(ErrorKind::NoSuchRecord(guid.to_owned())),
+            }
+        };
+        guid_pairs
+            .iter()
+            .map(|(a, b)| Ok((get(a)?, get(b)?)))
+            .collect()
+    }
+
+    pub fn get_all(&self) -> Result<Vec<Login>> {
+        let mut stmt = self.db.prepare_cached(&GET_ALL_SQL)?;
+        let rows = stmt.query_and_then(NO_PARAMS, Login::from_row)?;
+        rows.collect::<Result<_>>()
+    }
+
+    /// Returns all logins grouped by hostname, with the most recently used
+    /// group first.
+    pub fn get_all_grouped_by_hostname(&self) -> Result<Vec<LoginGroup>> {
+        let mut groups: HashMap<String, LoginGroup> = HashMap::new();
+        for login in self.get_all()? {
+            let group = groups
+                .entry(login.hostname.clone())
+                .or_insert_with(|| LoginGroup {
+                    hostname: login.hostname.clone(),
+                    logins: vec![],
+                    most_recent_used: 0,
+                });
+            group.most_recent_used = group.most_recent_used.max(login.time_last_used);
+            group.logins.push(login);
+        }
+        let mut groups = groups.into_iter().map(|entry| entry.1).collect::<Vec<_>>();
+        for group in &mut groups {
+            group
+                .logins
+                .sort_by_key(|l| std::cmp::Reverse(l.time_last_used));
+        }
+        groups.sort_by(|a, b| {
+            b.most_recent_used
+                .cmp(&a.most_recent_used)
+                .then_with(|| a.hostname.cmp(&b.hostname))
+        });
+        Ok(groups)
+    }
+
+    /// Returns all logins, ordered by `order_by`, which is appended verbatim
+    /// as the `ORDER BY` clause of the query and may use any of the columns
+    /// in `schema::COMMON_COLS` - for example,
+    /// `CASE WHEN timesUsed > 0 THEN 0 ELSE 1 END, timeLastUsed DESC`.
+    ///
+    /// This is an escape hatch for advanced uses, and `order_by` must never
+    /// come from an untrusted source. We reject clauses containing `;` or
+    /// `--`, but that is only a very basic protection against SQL injection.
+    pub fn get_all_with_custom_order(&self, order_by: &str) -> Result<Vec<Login>> {
+        if order_by.contains(';') || order_by.contains("--") {
+            throw!(ErrorKind::InvalidOrderBy(order_by.into()));
+        }
+        let sql = format!(
+            "SELECT * FROM ({all}) ORDER BY {order_by}",
+            all = &*GET_ALL_SQL,
+            order_by = order_by
+        );
+        let mut stmt = self.db.prepare(&sql)?;
+        let rows = stmt.query_and_then(NO_PARAMS, Login::from_row)?;
+        rows.collect::<Result<_>>()
+    }
+
+    /// Returns all logins, in the given order.
+    pub fn get_all_ordered(&self, order: LoginSortOrder) -> Result<Vec<Login>> {
+        let sql = format!(
+            "SELECT * FROM ({all}) ORDER BY {order_by}",
+            all = &*GET_ALL_SQL,
+            order_by = order.order_by()
+        );
+        let mut stmt = self.db.prepare_cached(&sql)?;
+        let rows = stmt.query_and_then(NO_PARAMS, Login::from_row)?;
+        rows.collect::<Result<_>>()
+    }
+
+    /// Like `get_all`, but without reading any passwords from the database.
+    pub fn get_all_locked(&self) -> Result<Vec<LockedLogin>> {
+        let mut stmt = self.db.prepare_cached(&GET_ALL_LOCKED_SQL)?;
+        let rows = stmt.query_and_then(NO_PARAMS, LockedLogin::from_row)?;
+        rows.collect::<Result<_>>()
+    }
+
+    /// Like `get_all`, but ordered by GUID, so the results are reproducible.
+    pub fn get_all_in_order(&self) -> Result<Vec<Login>> {
+        let mut stmt = self.db.prepare_cached(&GET_ALL_IN_ORDER_SQL)?;
+        let rows = stmt.query_and_then(NO_PARAMS, Login::from_row)?;
+        rows.collect::<Result<_>>()
+    }
+
+    /// Summarizes the saved logins for a "password health" style UI. Passwords
+    /// are considered old if they haven't changed in `old_threshold_days`.
+    pub fn get_security_report(
+        &self,
+        checker: &dyn PasswordStrengthChecker,
+        old_threshold_days: u64,
+    ) -> Result<SecurityReport> {
+        let logins = self.get_all()?;
+        let now_ms = util::system_time_ms_i64(SystemTime::now());
+        let threshold_ms = old_threshold_days.saturating_mul(24 * 60 * 60 * 1000);
+        let old_cutoff_ms = now_ms.saturating_sub(threshold_ms.min(i64::MAX as u64) as i64);
+        let mut password_counts: HashMap<&str, usize> = HashMap::new();
+        for login in &logins {
+            *password_counts.entry(&login.password).or_default() += 1;
+        }
+        let mut report = SecurityReport {
+            total: logins.len(),
+            ..SecurityReport::default()
+        };
+        for login in &logins {
+            if password_counts[login.password.as_str()] > 1 {
+                report.reused_count += 1;
+            }
+            if checker.is_weak(&login.password) {
+                report.weak_count += 1;
+            }
+            if login.time_password_changed < old_cutoff_ms {
+                report.old_password_count += 1;
+            }
+            if login.hostname.starts_with("http://") {
+                report.http_only_count += 1;
+            }
+            if login.is_never_used() {
+                report.never_used_count += 1;
+            }
+        }
+        Ok(report)
+    }
+
+    /// Returns the logins which haven't been used since they were created
+    /// (see `Login::is_never_used`).
+    pub fn get_never_used(&self) -> Result<Vec<Login>> {
+        let mut stmt = self.db.prepare_cached(&GET_NEVER_USED_SQL)?;
+        let rows = stmt.query_and_then(NO_PARAMS, Login::from_row)?;
+        rows.collect::<Result<_>>()
+    }
+
+    /// Returns the logins whose password is shorter than `min_length`
+    /// characters.
+    pub fn get_weak_passwords(&self, min_length: usize) -> Result<Vec<Login>> {
+        let mut stmt = self.db.prepare_cached(&GET_SHORT_PASSWORDS_SQL)?;
+        let rows = stmt.query_and_then_named(
+            named_params! { ":min_length": min_length as i64 },
+            Login::from_row,
+        )?;
+        rows.collect::<Result<_>>()
+    }
+
+    /// Returns the logins which share a password with another login, grouped
+    /// by password. Within each group, logins are ordered by GUID.
+    pub fn get_reused_passwords(&self) -> Result<Vec<Vec<Login>>> {
+        let mut stmt = self.db.prepare_cached(&GET_REUSED_PASSWORDS_SQL)?;
+        let rows = stmt.query_and_then(NO_PARAMS, Login::from_row)?;
+        // The query orders by password, so each group is a run of rows.
+        let mut groups: Vec<Vec<Login>> = Vec::new();
+        for login in rows {
+            let login = login?;
+            match groups.last_mut() {
+                Some(group) if group[0].password == login.password => group.push(login),
+                _ => groups.push(vec![login]),
+            }
+        }
+        Ok(groups)
+    }
+
+    /// Returns counts of the logins in various states, all from a single query.
+    pub fn get_statistics(&self) -> Result<LoginStatistics> {
+        let cutoff_ms =
+            util::system_time_ms_i64(SystemTime::now() - Duration::from_secs(30 * 24 * 60 * 60));
+        self.query_row_and_then_named(
+            &GET_STATISTICS_SQL,
+            named_params! { ":cutoff_ms": cutoff_ms },
+            |row| -> Result<_> {
+                let count = |i| -> Result<usize> { Ok(row.get::<_, i64>(i)? as usize) };
+                Ok(LoginStatistics {
+                    total_count: count(0)?,
+                    synced_count: count(1)?,
+                    local_only_count: count(2)?,
+                    deleted_count: count(3)?,
+                    never_used_count: count(4)?,
+                    used_last_30_days_count: count(5)?,
+                })
+            },
+            true,
+        )
+    }
+
+    /// Counts the (non-deleted) local records in each `SyncStatus`, without
+    /// loading them. Logins which only exist in the mirror aren't counted,
+    /// since they have no local changes at all.
+    pub fn get_sync_status_counts(&self) -> Result<SyncStatusCounts> {
+        self.query_row_and_then_named(
+            &GET_SYNC_STATUS_COUNTS_SQL,
+            &[],
+            |row| -> Result<_> {
+                let count = |i| -> Result<usize> { Ok(row.get::<_, i64>(i)? as usize) };
+                Ok(SyncStatusCounts {
+                    new: count(0)?,
+                    changed: count(1)?,
+                    synced: count(2)?,
+                })
+            },
+            true,
+        )
+    }
+
+    /// Like `get_all_in_order`, but returns the logins lazily, so callers
+    /// which process one login at a time (e.g. for export) don't need to hold
+    /// every login in memory at once.
+    ///
+    /// Rather than holding a statement open for the lifetime of the iterator,
+    /// the logins are read `STREAM_PAGE_SIZE` at a time, ordered by GUID. The
+    /// pages are all read in one read transaction (as `begin_read_transaction`
+    /// starts), or in the current transaction if there is one, so they're a
+    /// consistent snapshot: until the iterator finishes or is dropped, writes
+    /// through this connection fail, and other connections can't write. Once
+    /// the iterator yields an error it yields nothing further.
+    pub fn stream_all(&self) -> Result<impl Iterator<Item = Result<Login>> + '_> {
+        self.stream_all_in_pages(STREAM_PAGE_SIZE)
+    }
+
+    fn stream_all_in_pages(
+        &self,
+        page_size: u32,
+    ) -> Result<impl Iterator<Item = Result<Login>> + '_> {
+        let fetch_page = move |after: &str| -> Result<Vec<Result<Login>>> {
+            let mut stmt = self.db.prepare_cached(&GET_PAGE_AFTER_GUID_SQL)?;
+            let rows = stmt.query_and_then_named(
+                &[(":after", &after), (":limit", &page_size)],
+                Login::from_row,
+            )?;
+            Ok(rows.collect())
+        };
+        let mut tx = if self.db.is_autocommit() {
+            Some(self.begin_read_transaction()?)
+        } else {
+            None
+        };
+        let mut page = fetch_page("")?.into_iter();
+        let mut more = page.len() == page_size as usize;
+        let mut last_guid = String::new();
+        let mut done = false;
+        Ok(std::iter::from_fn(move || {
+            if done {
+                return None;
+            }
+            let next = match page.next() {
+                Some(next) => Some(next),
+                None if more => match fetch_page(&last_guid) {
+                    Ok(next_page) => {
+                        more = next_page.len() == page_size as usize;
+                        page = next_page.into_iter();
+                        page.next()
+                    }
+                    Err(e) => Some(Err(e)),
+                },
+                None => None,
+            };
+            match &next {
+                Some(Ok(login)) => last_guid = login.guid.to_string(),
+                // There's nothing more to read, so end the transaction now,
+                // rather than when the iterator is dropped.
+                Some(Err(_)) | None => {
+                    done = true;
+                    drop(tx.take());
+                }
+            }
+            next
+        }))
+    }
+
+    /// Returns up to `limit` logins, ordered by hostname and then GUID,
+    /// starting after the login with GUID `after_guid` (or from the start, if
+    /// `None`). To fetch the next page, pass the GUID of the last login
+    /// returned. Since the cursor is a position in that order rather than an
+    /// offset, adding or deleting other logins between pages doesn't cause
+    /// logins to be skipped or repeated.
+    ///
+    /// Fails with `NoSuchRecord` if there's no login with GUID `after_guid`.
+    pub fn get_all_paginated(&self, after_guid: Option<&str>, limit: usize) -> Result<Vec<Login>> {
+        let limit = limit as i64;
+        let mut stmt;
+        let rows = match after_guid {
+            None => {
+                stmt = self.db.prepare_cached(&GET_FIRST_PAGE_BY_HOSTNAME_SQL)?;
+                stmt.query_and_then_named(named_params! { ":limit": limit }, Login::from_row)?
+            }
+            Some(after_guid) => {
+                let after = match self.get_by_id(after_guid)? {
+                    Some(after) => after,
+                    None => throw!(ErrorKind::NoSuchRecord(after_guid.to_owned())),
+                };
+                stmt = self.db.prepare_cached(&GET_PAGE_AFTER_HOSTNAME_SQL)?;
+                stmt.query_and_then_named(
+                    named_params! {
+                        ":hostname": after.hostname,
+                        ":guid": after.guid,
+                        ":limit": limit,
+                    },
+                    Login::from_row,
+                )?
+            }
+        };
+        rows.collect()
+    }
+
+    /// Returns the number of logins `get_all` would return.
+    pub fn get_total_count(&self) -> Result<usize> {
+        self.count()
+    }
+
+    /// Returns the number of logins `get_all` would return, without loading
+    /// them.
+    pub fn count(&self) -> Result<usize> {
+        let count: i64 =
+            self.query_row_and_then_named(&GET_TOTAL_COUNT_SQL, &[], |row| row.get(0), true)?;
+        Ok(count as usize)
+    }
+
+    /// Returns the number of logins `get_by_hostname` would return, without
+    /// loading them.
+    pub fn count_by_hostname(&self, hostname: &str) -> Result<usize> {
+        let count: i64 = self.query_row_and_then_named(
+            &COUNT_BY_HOSTNAME_SQL,
+            named_params! { ":hostname": hostname },
+            |row| row.get(0),
+            true,
+        )?;
+        Ok(count as usize)
+    }
+
+    /// Returns true if there are any logins at all, for a quick check of
+    /// whether the store is empty.
+    pub fn has_any_logins(&self) -> Result<bool> {
+        Ok(self.query_row_and_then_named(HAS_ANY_LOGINS_SQL, &[], |row| row.get(0), true)?)
+    }
+
+    /// Returns all logins ordered by registrable domain (as approximated by
+    /// `util::approximate_base_domain`), then hostname, then username, so the
+    /// logins for `mail.example.com` and `www.example.com` are listed together.
+    pub fn get_all_sorted_by_domain(&self) -> Result<Vec<Login>> {
+        let mut logins = self.get_all()?;
+        logins.sort_by_cached_key(|login| {
+            let host = Url::parse(&login.hostname)
+                .ok()
+                .and_then(|url| url.host_str().map(ToOwned::to_owned))
+                .unwrap_or_else(|| login.hostname.clone());
+            (
+                util::approximate_base_domain(&host),
+                login.hostname.clone(),
+                login.username.clone(),
+            )
+        });
+        Ok(logins)
+    }
+
+    /// Returns the logins for `hostname` (either a bare host or an origin)
+    /// along with those for any other host under the same registrable domain,
+    /// with exact matches first. So `www.example.com` will also return logins
+    /// for `auth.example.com`.
+    ///
+    /// The registrable domain is found by `util::approximate_base_domain`,
+    /// so for suffixes like `co.uk` this matches more than it should.
+    pub fn get_all_for_hostname_with_subdomains(&self, hostname: &str) -> Result<Vec<Login>> {
+        let host = match Url::parse(hostname) {
+            Ok(url) => url.host_str().map(ToOwned::to_owned),
+            Err(_) => Host::parse(hostname).ok().map(|h| h.to_string()),
+        };
+        let host = match host {
+            Some(host) => host,
+            None => {
+                // don't log the input string as it's PII.
+                log::warn!("get_all_for_hostname_with_subdomains was passed an invalid host");
+                return Ok(vec![]);
+            }
+        };
+        let mut logins = self.get_by_base_domain(&util::approximate_base_domain(&host))?;
+        logins.sort_by_key(|login| {
+            Url::parse(&login.hostname)
+                .ok()
+                .and_then(|url| url.host_str().map(|h| h != host))
+                .unwrap_or(true)
+        });
+        Ok(logins)
+    }
+
+    pub fn get_by_base_domain(&self, base_domain: &str) -> Result<Vec<Login>> {
+        // We first parse the input string as a host so it is normalized.
+        let base_host = match Host::parse(base_domain) {
+            Ok(d) => d,
+            Err(e) => {
+                // don't log the input string as it's PII.
+                log::warn!("get_by_base_domain was passed an invalid domain: {}", e);
+                return Ok(vec![]);
+            }
+        };
+        // We just do a linear scan. Another option is to have an indexed
+        // reverse-host column or similar, but current thinking is that it's
+        // extra complexity for (probably) zero actual benefit given the record
+        // counts are expected to be so low.
+        // A regex would probably make this simpler, but we don't want to drag
+        // in a regex lib just for this.
+        let mut stmt = self.db.prepare_cached(&GET_ALL_SQL)?;
+        let rows = stmt
+            .query_and_then(NO_PARAMS, Login::from_row)?
+            .filter(|r| {
+                let login = r
+                    .as_ref()
+                    .ok()
+                    .and_then(|login| Url::parse(&login.hostname).ok());
+                let this_host = login.as_ref().and_then(|url| url.host());
+                match (&base_host, this_host) {
+                    (Host::Domain(base), Some(Host::Domain(look))) => {
+                        // a fairly long-winded way of saying
+                        // `login.hostname == base_domain ||
+                        //  login.hostname.ends_with('.' + base_domain);`
+                        let mut rev_input = base.chars().rev();
+                        let mut rev_host = look.chars().rev();
+                        loop {
+                            match (rev_input.next(), rev_host.next()) {
+                                (Some(ref a), Some(ref b)) if a == b => continue,
+                                (None, None) => return true, // exactly equal
+                                (None, Some(ref h)) => return *h == '.',
+                                _ => return false,
+                            }
+                        }
+                    }
+                    // ip addresses must match exactly.
+                    (Host::Ipv4(base), Some(Host::Ipv4(look))) => *base == look,
+                    (Host::Ipv6(base), Some(Host::Ipv6(look))) => *base == look,
+                    // all "mismatches" in domain types are false.
+                    _ => false,
+                }
+            });
+        rows.collect::<Result<_>>()
+    }
+
+    /// Fetch the logins for all of `hostnames` at once, returning a map from
+    /// each hostname to the logins saved for it. Hostnames with no saved
+    /// logins are not present in the map.
+    pub fn get_all_for_multiple_hostnames(
+        &self,
+        hostnames: &[&str],
+    ) -> Result<HashMap<String, Vec<Login>>> {
+        let mut by_hostname: HashMap<String, Vec<Login>> = HashMap::new();
+        sql_support::each_chunk(hostnames, |chunk, _| -> Result<()> {
+            // The placeholders are numbered, so both halves of the union
+            // share the same bound values.
+            let (vars, params) = util::sql_placeholders_and_params(chunk);
+            let query = format!(
+                "SELECT {common_cols} FROM loginsL
+                 WHERE is_deleted = 0 AND hostname IN ({vars})
+                 UNION ALL
+                 SELECT {common_cols} FROM loginsM
+                 WHERE is_overridden = 0 AND hostname IN ({vars})",
+                common_cols = schema::COMMON_COLS,
+                vars = vars,
+            );
+            let mut stmt = self.db.prepare(&query)?;
+            let rows = stmt.query_and_then(&params, |row| -> Result<_> {
+                // Key on the stored hostname rather than the (possibly
+                // fixed-up) one in the `Login`, so it matches what was asked for.
+                Ok((row.get::<_, String>("hostname")?, Login::from_row(row)?))
+            })?;
+            for row in rows {
+                let (hostname, login) = row?;
+                by_hostname.entry(hostname).or_default().push(login);
+            }
+            Ok(())
+        })?;
+        Ok(by_hostname)
+    }
+
+    /// Fetches the logins with the given GUIDs, in the same order as `ids`.
+    /// GUIDs which don't exist are skipped, so the result may be shorter
+    /// than `ids`; use `get_by_guids_map` to tell which ones were found. A
+    /// GUID which appears more than once in `ids` appears as many times in
+    /// the result.
+    pub fn get_by_guids(&self, ids: &[&str]) -> Result<Vec<Login>> {
+        let by_guid = self.get_by_guids_map(ids)?;
+        Ok(ids
+            .iter()
+            .filter_map(|id| by_guid.get(*id).cloned())
+            .collect())
+    }
+
+    /// Like `get_by_guids`, but returns a map from GUID to login.
+    pub fn get_by_guids_map(&self, ids: &[&str]) -> Result<HashMap<String, Login>> {
+        self.get_by_guids_map_chunked(ids, sql_support::default_max_variable_number())
+    }
+
+    // Split out so that tests can use a small `chunk_size`.
+    fn get_by_guids_map_chunked(
+        &self,
+        ids: &[&str],
+        chunk_size: usize,
+    ) -> Result<HashMap<String, Login>> {
+        let mut by_guid = HashMap::with_capacity(ids.len());
+        sql_support::each_sized_chunk(ids, chunk_size, |chunk, _| -> Result<()> {
+            // As in `get_all_for_multiple_hostnames`, both halves of the union
+            // share the same numbered parameters.
+            let (vars, params) = util::sql_placeholders_and_params(chunk);
+            let query = format!(
+                "SELECT {common_cols} FROM loginsL
+                 WHERE is_deleted = 0 AND guid IN ({vars})
+                 UNION ALL
+                 SELECT {common_cols} FROM loginsM
+                 WHERE is_overridden IS NOT 1 AND guid IN ({vars})",
+                common_cols = schema::COMMON_COLS,
+                vars = vars,
+            );
+            let mut stmt = self.db.prepare(&query)?;
+            let rows = stmt.query_and_then(&params, Login::from_row)?;
+            for row in rows {
+                let login = row?;
+                by_guid.insert(login.guid.to_string(), login);
+            }
+            Ok(())
+        })?;
+        Ok(by_guid)
+    }
+
+    /// Returns all (non-deleted) logins which were created via `source`.
+    /// `Imported` logins only match if their `source_name` also matches.
+    pub fn get_all_for_source(&self, source: CreationSource) -> Result<Vec<Login>> {
+        let mut stmt = self.db.prepare_cached(&GET_ALL_FOR_SOURCE_SQL)?;
+        let rows = stmt.query_and_then_named(
+            named_params! {
+                ":creation_source": source.discriminant(),
+                ":creation_source_name": source.source_name(),
+            },
+            Login::from_row,
+        )?;
+        rows.collect::<Result<_>>()
+    }
+
+    /// Returns the (non-deleted) logins for `hostname` whose username starts
+    /// with `username_prefix`, most frequently used first.
+    pub fn get_all_with_username_containing(
+        &self,
+        hostname: &str,
+        username_prefix: &str,
+    ) -> Result<Vec<Login>> {
+        let mut stmt = self.db.prepare_cached(&GET_BY_USERNAME_PREFIX_SQL)?;
+        let rows = stmt.query_and_then_named(
+            named_params! {
+                ":hostname": hostname,
+                ":prefix": util::escape_like(username_prefix),
+            },
+            Login::from_row,
+        )?;
+        rows.collect::<Result<_>>()
+    }
+
+    /// Searches the logins for `query`, which may appear anywhere in the
+    /// hostname, username or username field, ignoring (ASCII) case. Passwords
+    /// are never searched. Logins whose hostname starts with `query` (with or
+    /// without the scheme) come first, then those whose username is `query`,
+    /// then any other matches.
+    ///
+    /// Queries shorter than two characters match nothing, since they'd match
+    /// almost everything anyway.
+    pub fn query_logins(&self, query: &str) -> Result<Vec<Login>> {
+        if query.chars().count() < MIN_QUERY_LEN {
+            return Ok(vec![]);
+        }
+        let mut stmt = self.db.prepare_cached(&QUERY_LOGINS_SQL)?;
+        let rows = stmt.query_and_then_named(
+            named_params! {
+                ":query": query,
+                ":pattern": util::escape_like(query),
+            },
+            Login::from_row,
+        )?;
+        rows.collect::<Result<_>>()
+    }
+
+    /// Returns the hostname of every login, sorted, without duplicates.
+    pub fn get_all_hostnames(&self) -> Result<Vec<String>> {
+        let mut stmt = self.db.prepare_cached(GET_ALL_HOSTNAMES_SQL)?;
+        let rows = stmt.query_map(NO_PARAMS, |row| row.get::<_, String>(0))?;
+        Ok(rows.collect::<result::Result<_, _>>()?)
+    }
+
+    /// Like `get_all_hostnames`, but only returns the hostnames which start
+    /// with `prefix` (which may contain `%` and `_`, which match literally).
+    pub fn get_hostnames_matching(&self, prefix: &str) -> Result<Vec<String>> {
+        let mut stmt = self.db.prepare_cached(GET_HOSTNAMES_MATCHING_SQL)?;
+        let rows = stmt.query_map_named(
+            named_params! { ":prefix": util::escape_like(prefix) },
+            |row| row.get::<_, String>(0),
+        )?;
+        Ok(rows.collect::<result::Result<_, _>>()?)
+    }
+
+    /// Returns the logins for `hostname`, most recently used first.
+    pub fn get_by_hostname(&self, hostname: &str) -> Result<Vec<Login>> {
+        let mut stmt = self.db.prepare_cached(&GET_BY_HOSTNAME_SQL)?;
+        let rows =
+            stmt.query_and_then_named(named_params! { ":hostname": hostname }, Login::from_row)?;
+        rows.collect::<Result<_>>()
+    }
+
+    /// Returns the logins which match `url` according to `match_type`.
+    pub fn get_all_for_bitwarden_uri_match(
+        &self,
+        url: &str,
+        match_type: BitwardenUriMatchType,
+    ) -> Result<Vec<Login>> {
+        fn base_domain(url: &str) -> Option<String> {
+            let url = Url::parse(url).ok()?;
+            Some(util::approximate_base_domain(url.host_str()?))
+        }
+        let matches: Box<dyn Fn(&Login) -> bool> = match match_type {
+            BitwardenUriMatchType::BaseDomain | BitwardenUriMatchType::Default => {
+                match base_domain(url) {
+                    Some(target) => Box::new(move |login| {
+                        base_domain(&login.hostname).as_ref() == Some(&target)
+                    }),
+                    None => return Ok(vec![]),
+                }
+            }
+            BitwardenUriMatchType::Host => match util::url_host_port(url) {
+                Some(target) => Box::new(move |login| {
+                    util::url_host_port(&login.hostname).as_ref() == Some(&target)
+                }),
+                None => return Ok(vec![]),
+            },
+            BitwardenUriMatchType::StartsWith => {
+                Box::new(move |login| url.starts_with(&login.hostname))
+            }
+            BitwardenUriMatchType::Regex(regex) => {
+                Box::new(move |login| regex.is_match(&login.hostname))
+            }
+            BitwardenUriMatchType::Never => return Ok(vec![]),
+        };
+        let mut logins = self.get_all()?;
+        logins.retain(|login| matches(login));
+        Ok(logins)
+    }
+
+    /// Returns the logins saved for the origin of `url` (which may be any
+    /// URL, not just an origin), much like Firefox's
+    /// `LoginManager.getLoginsSavingOrigin`. Form logins whose
+    /// `formSubmitURL` has the same host and port as `url` come first, then
+    /// other form logins, then HTTP auth logins, each most recently used
+    /// first. URLs which aren't `http` or `https` (`about:`, `data:`, etc.)
+    /// have no logins.
+    pub fn get_logins_for_url(&self, url: &str) -> Result<Vec<Login>> {
+        let parsed = match Url::parse(url) {
+            Ok(parsed) if parsed.scheme() == "http" || parsed.scheme() == "https" => parsed,
+            _ => return Ok(vec![]),
+        };
+        let host_port = match util::url_host_port(url) {
+            Some(host_port) => host_port,
+            None => return Ok(vec![]),
+        };
+        let mut logins = self.get_by_hostname(&parsed.origin().ascii_serialization())?;
+        logins.sort_by_key(|login| match &login.form_submit_url {
+            // As in `find_dupe`, an empty `formSubmitURL` matches any form.
+            Some(form_submit_url) => {
+                if form_submit_url.is_empty()
+                    || util::url_host_port(form_submit_url).as_ref() == Some(&host_port)
+                {
+                    0
+                } else {
+                    1
+                }
+            }
+            None => 2,
+        });
+        Ok(logins)
+    }
+
+    /// Returns the logins for the host of `url`, or any other host under
+    /// the same registrable domain (as `get_all_for_hostname_with_subdomains`
+    /// finds them), best match first. A login scores 1.0 if its host is the
+    /// same as `url`'s, or 0.8 if it's only under the same domain. It gets
+    /// 0.2 more if its `formSubmitURL` has the same host and port as `url`,
+    /// and small bonuses for how often it's been used, and for how recently,
+    /// which fades over a month or so.
+    pub fn get_logins_for_autocomplete(&self, url: &str) -> Result<Vec<ScoredLogin>> {
+        let host = match Url::parse(url)
+            .ok()
+            .and_then(|u| u.host_str().map(ToOwned::to_owned))
+        {
+            Some(host) => host,
+            None => return Ok(vec![]),
+        };
+        let host_port = util::url_host_port(url);
+        let base_domain = util::approximate_base_domain(&host);
+        // Narrow things down in SQL, before looking at the hosts properly.
+        let candidates = self.query_rows_and_then_named_cached(
+            &GET_FOR_AUTOCOMPLETE_SQL,
+            named_params! { ":base_domain": util::escape_like(&base_domain) },
+            Login::from_row,
+        )?;
+        let now_ms = util::system_time_ms_i64(SystemTime::now());
+        let mut scored: Vec<ScoredLogin> = candidates
+            .into_iter()
+            .filter_map(|login| {
+                let login_host = Url::parse(&login.hostname)
+                    .ok()
+                    .and_then(|u| u.host_str().map(ToOwned::to_owned))?;
+                let mut score = if login_host == host {
+                    AUTOCOMPLETE_EXACT_HOST_SCORE
+                } else if util::approximate_base_domain(&login_host) == base_domain {
+                    AUTOCOMPLETE_SAME_DOMAIN_SCORE
+                } else {
+                    return None;
+                };
+                let form_host_port = login
+                    .form_submit_url
+                    .as_ref()
+                    .and_then(|form_submit_url| util::url_host_port(form_submit_url));
+                if form_host_port.is_some() && form_host_port == host_port {
+                    score += AUTOCOMPLETE_FORM_SUBMIT_BONUS;
+                }
+                score += AUTOCOMPLETE_TIMES_USED_WEIGHT * (login.times_used.max(0) as f32).ln_1p();
+                if login.time_last_used > 0 {
+                    let age_ms = (now_ms - login.time_last_used).max(0) as f32;
+                    score += AUTOCOMPLETE_RECENCY_WEIGHT
+                        * (-age_ms / AUTOCOMPLETE_RECENCY_DECAY_MS).exp();
+                }
+                Some(ScoredLogin { login, score })
+            })
+            .collect();
+        scored.sort_by(|a, b| {
+            b.score
+                .partial_cmp(&a.score)
+                .unwrap_or(std::cmp::Ordering::Equal)
+        });
+        Ok(scored)
+    }
+
+    /// Returns the logins associated with the app `app_id`, most recently
+    /// used first.
+    pub fn get_all_for_app(&self, app_id: &str) -> Result<Vec<Login>> {
+        let mut stmt = self.db.prepare_cached(&GET_ALL_FOR_APP_SQL)?;
+        let rows =
+            stmt.query_and_then_named(named_params! { ":app_id": app_id }, Login::from_row)?;
+        rows.collect::<Result<_>>()
+    }
+
+    /// Returns the logins which are either associated with the app `app_id`
+    /// or for `hostname`, most recently used first. This is what an app
+    /// which is also linked to a website wants to autofill.
+    pub fn get_all_for_app_or_hostname(&self, app_id: &str, hostname: &str) -> Result<Vec<Login>> {
+        let mut stmt = self.db.prepare_cached(&GET_ALL_FOR_APP_OR_HOSTNAME_SQL)?;
+        let rows = stmt.query_and_then_named(
+            named_params! {
+                ":app_id": app_id,
+                ":hostname": hostname,
+            },
+            Login::from_row,
+        )?;
+        rows.collect::<Result<_>>()
+    }
+
+    /// The logins which could be used to fill in a login on `hostname`: when
+    /// `realm` is given, the logins for that HTTP auth realm, otherwise the
+    /// logins for forms on the page.
+    pub fn get_all_for_autofill_any(
+        &self,
+        hostname: &str,
+        realm: Option<&str>,
+    ) -> Result<Vec<Login>> {
+        let mut stmt = self.db.prepare_cached(&GET_FOR_AUTOFILL_SQL)?;
+        let rows = stmt.query_and_then_named(
+            named_params! {
+                ":hostname": hostname,
+                ":realm": realm,
+            },
+            Login::from_row,
+        )?;
+        rows.collect::<Result<_>>()
+    }
+
+    /// Returns the logins for `suffix` or any subdomain of it, ordered by
+    /// hostname. For example, `corp.example.com` (or `*.corp.example.com`)
+    /// matches `https://corp.example.com` and `https://dev.corp.example.com:8443`,
+    /// but not `https://notcorp.example.com`.
+    pub fn get_all_for_domain_suffix(&self, suffix: &str) -> Result<Vec<Login>> {
+        let suffix = suffix.trim_start_matches("*.");
+        let suffix = match Host::parse(suffix) {
+            Ok(host) => host.to_string(),
+            Err(e) => {
+                // don't log the input string as it's PII.
+                log::warn!(
+                    "get_all_for_domain_suffix was passed an invalid domain: {}",
+                    e
+                );
+                return Ok(vec![]);
+            }
+        };
+        let mut stmt = self.db.prepare_cached(&GET_BY_DOMAIN_SUFFIX_SQL)?;
+        let rows = stmt.query_and_then_named(
+            named_params! { ":suffix": util::escape_like(&suffix) },
+            Login::from_row,
+        )?;
+        rows.collect::<Result<_>>()
+    }
+
+    /// Returns the (non-deleted) logins whose GUID starts with `prefix`, for
+    /// use by debugging tools which only have part of a GUID (from a log, for
+    /// example). At most `MAX_GUID_PREFIX_MATCHES` logins are returned. Note
+    /// that, like all `LIKE` queries, the match ignores ASCII case.
+    pub fn get_all_by_guid_prefix(&self, prefix: &str) -> Result<Vec<Login>> {
+        let mut stmt = self.db.prepare_cached(&GET_BY_GUID_PREFIX_SQL)?;
+        let rows = stmt.query_and_then_named(
+            named_params! {
+                ":prefix": util::escape_like(prefix),
+                ":limit": MAX_GUID_PREFIX_MATCHES,
+            },
+            Login::from_row,
+        )?;
+        rows.collect::<Result<_>>()
+    }
+
+    /// Returns the mirror records which are marked as overridden, but have no
+    /// local record at all, not even a tombstone. Reads skip overridden
+    /// mirror records, so these logins have effectively disappeared. (A
+    /// mirror record which isn't overridden and has no local record is
+    /// normal, and is how most logins look after a sync.) This is a
+    /// diagnostic for investigating incomplete syncs, and callers should not
+    /// need it in normal operation.
+    pub fn get_all_mirror_without_local_overlay(&self) -> Result<Vec<MirrorLogin>> {
+        let mut stmt = self.db.prepare(
+            "SELECT * FROM loginsM
+             WHERE is_overridden = 1
+               AND guid NOT IN (SELECT guid FROM loginsL)",
+        )?;
+        let rows = stmt.query_and_then(NO_PARAMS, MirrorLogin::from_row)?;
+        rows.collect::<Result<_>>()
+    }
+
+    /// Returns the mirror record for `guid`, even if it's overridden by a
+    /// local record, for debugging sync problems.
+    pub fn get_mirror_record(&self, guid: &str) -> Result<Option<MirrorLogin>> {
+        self.try_query_row(
+            "SELECT * FROM loginsM WHERE guid = :guid",
+            named_params! { ":guid": guid },
+            MirrorLogin::from_row,
+            true,
+        )
+    }
+
+    /// Returns the local record for `guid`, even if it's a tombstone, for
+    /// debugging sync problems.
+    pub fn get_local_record(&self, guid: &str) -> Result<Option<LocalLogin>> {
+        self.try_query_row(
+            "SELECT * FROM loginsL WHERE guid = :guid",
+            named_params! { ":guid": guid },
+            LocalLogin::from_row,
+            true,
+        )
+    }
+
+    /// Returns every record in `loginsL`, including tombstones, along with
+    /// its sync state, for debugging sync problems. Logins which only exist
+    /// in the mirror aren't included.
+    #[cfg(any(test, feature = "debug"))]
+    pub fn get_all_with_sync_metadata(&self) -> Result<Vec<LocalLogin>> {
+        self.query_rows_and_then_named_cached(
+            "SELECT * FROM loginsL ORDER BY guid",
+            &[],
+            LocalLogin::from_row,
+        )
+    }
+
+    /// Clears `is_overridden` on each record returned by
+    /// `get_all_mirror_without_local_overlay`, so that the mirror version of
+    /// the login is visible again. Returns the number of records healed.
+    pub fn heal_orphaned_mirror_records(&self) -> Result<usize> {
+        heal_orphaned_mirror_records_in(&self.db)
+    }
+
+    /// Checks the database file for corruption, using SQLite's
+    /// `PRAGMA integrity_check` and `PRAGMA foreign_key_check`. Unlike
+    /// `run_integrity_repair`, this doesn't change anything; if the database
+    /// is corrupt, the only fix is to wipe it and sync again.
+    pub fn verify_integrity(&self) -> Result<IntegrityReport> {
+        let mut messages = self.query_rows_and_then_named(
+            "PRAGMA integrity_check",
+            &[],
+            |row| -> Result<String> { Ok(row.get(0)?) },
+        )?;
+        // A single "ok" row means there were no problems.
+        if messages.len() == 1 && messages[0] == "ok" {
+            messages.clear();
+        }
+        messages.extend(self.query_rows_and_then_named(
+            "PRAGMA foreign_key_check",
+            &[],
+            |row| -> Result<String> {
+                Ok(format!(
+                    "Foreign key violation: {} row {:?} references {}",
+                    row.get::<_, String>("table")?,
+                    row.get::<_, Option<i64>>("rowid")?,
+                    row.get::<_, String>("parent")?,
+                ))
+            },
+        )?);
+        Ok(IntegrityReport {
+            ok: messages.is_empty(),
+            messages,
+        })
+    }
+
+    /// Checks every login, local or only in the mirror, against
+    /// `Login::check_valid` and the limits from `set_max_field_lengths`,
+    /// returning the GUID and problems of each invalid one. Records are
+    /// checked as they're stored, before the fixups `get_all` would apply.
+    pub fn validate_all_logins(&self) -> Result<Vec<(String, Vec<LoginValidationError>)>> {
+        let limits = self.field_limits();
+        let mut invalid = vec![];
+        for sql in &[
+            "SELECT * FROM loginsL WHERE is_deleted = 0",
+            "SELECT * FROM loginsM WHERE is_overridden = 0",
+        ] {
+            let mut stmt = self.db.prepare(sql)?;
+            let rows = stmt.query_and_then(NO_PARAMS, |row| -> Result<_> {
+                let login = Login::from_row_unfixed(row)?;
+                match login.check_valid_with_limits(&limits) {
+                    Ok(()) => Ok(None),
+                    Err(e) => match e.kind() {
+                        ErrorKind::InvalidLogin(errors) => {
+                            Ok(Some((login.guid.into_string(), errors.0.clone())))
+                        }
+                        _ => Err(e),
+                    },
+                }
+            })?;
+            for row in rows {
+                if let Some(found) = row? {
+                    invalid.push(found);
+                }
+            }
+        }
+        Ok(invalid)
+    }
+
+    /// Fixes the logins `validate_all_logins` would report, where that's
+    /// possible, returning how many were fixed. As well as the usual
+    /// `Login::fixup` rules (which, for example, keep `formSubmitURL` and
+    /// drop `httpRealm` when both are set), whitespace around the hostname
+    /// is trimmed. Fixed mirror records get a local copy, so the fix is
+    /// uploaded on the next sync. Logins which can't be fixed are left alone.
+    pub fn auto_fix_invalid_logins(&mut self) -> Result<usize> {
+        let limits = self.field_limits();
+        let invalid = self.validate_all_logins()?;
+        let tx = self.unchecked_transaction()?;
+        let now_ms = util::system_time_ms_i64(SystemTime::now());
+        let mut num_fixed = 0;
+        for (guid, _) in &invalid {
+            let login = match self.get_by_id_unfixed(guid)? {
+                Some(login) => login,
+                None => continue,
+            };
+            let login = Login {
+                hostname: login.hostname.trim().into(),
+                ..login
+            };
+            let fixed = match login.fixup_with_limits(&limits) {
+                Ok(fixed) => fixed,
+                Err(e) => {
+                    log::warn!("Can't fix invalid login {}: {}", guid, e);
+                    continue;
+                }
+            };
+            self.ensure_local_overlay_exists(guid)?;
+            self.mark_mirror_overridden(guid)?;
+            self.execute_named_cached(
+                &format!(
+                    "UPDATE loginsL
+                     SET local_modified = :now_ms,
+                         hostname       = :hostname,
+                         httpRealm      = :http_realm,
+                         formSubmitURL  = :form_submit_url,
+                         usernameField  = :username_field,
+                         passwordField  = :password_field,
+                         sync_status    = max(sync_status, {changed})
+                     WHERE guid = :guid AND is_deleted = 0",
+                    changed = SyncStatus::Changed as u8
+                ),
+                named_params! {
+                    ":now_ms": now_ms,
+                    ":hostname": fixed.hostname,
+                    ":http_realm": fixed.http_realm,
+                    ":form_submit_url": fixed.form_submit_url,
+                    ":username_field": fixed.username_field,
+                    ":password_field": fixed.password_field,
+                    ":guid": guid,
+                },
+            )?;
+            self.append_audit_entry(guid, AuditOperation::Update, now_ms)?;
+            num_fixed += 1;
+        }
+        tx.commit()?;
+        Ok(num_fixed)
+    }
+
+    // The local version of `guid`, or the mirror one if there isn't one,
+    // without `Login::from_row`'s fixups.
+    fn get_by_id_unfixed(&self, guid: &str) -> Result<Option<Login>> {
+        self.try_query_row(
+            &GET_BY_GUID_SQL,
+            &[(":guid", &guid as &dyn ToSql)],
+            Login::from_row_unfixed,
+            true,
+        )
+    }
+
+    /// Attempts to fix known kinds of inconsistency in the database:
+    ///
+    /// - Rows in `loginsL` which share a GUID (which the schema should prevent,
+    ///   but databases from elsewhere might not) are removed, keeping the most
+    ///   recently modified.
+    /// - Local records which fail `Login::check_valid`, other than for being
+    ///   too long (see `set_max_field_lengths`), are moved to the
+    ///   `loginsQuarantine` table. If there's a mirror record for the same
+    ///   login, it becomes visible again.
+    /// - Finally, `heal_orphaned_mirror_records` is run.
+    pub fn run_integrity_repair(&mut self) -> Result<RepairReport> {
+        let mut report = RepairReport::default();
+        let tx = self.unchecked_transaction()?;
+        report.duplicate_local_guids_removed = tx.execute(
+            "DELETE FROM loginsL
+             WHERE EXISTS (
+                 SELECT 1 FROM loginsL AS other
+                 WHERE other.guid = loginsL.guid
+                   AND (ifnull(other.local_modified, 0) > ifnull(loginsL.local_modified, 0)
+                        OR (ifnull(other.local_modified, 0) = ifnull(loginsL.local_modified, 0)
+                            AND other.id > loginsL.id))
+             )",
+            NO_PARAMS,
+        )?;
+
+        let invalid: Vec<(i64, Login, String)> = {
+            let mut stmt = tx.prepare("SELECT * FROM loginsL WHERE is_deleted = 0")?;
+            let rows = stmt.query_and_then(NO_PARAMS, |row| -> Result<_> {
+                let login = Login::from_row_unfixed(row)?;
+                Ok(
+                    match login.check_valid_with_limits(&FieldLimits::unlimited()) {
+                        Ok(()) => None,
+                        Err(e) => Some((row.get("id")?, login, e.to_string())),
+                    },
+                )
+            })?;
+            rows.filter_map(|r| r.transpose()).collect::<Result<_>>()?
+        };
+        let now_ms = util::system_time_ms_i64(SystemTime::now());
+        for (id, login, reason) in &invalid {
+            log::warn!("Quarantining invalid record {}: {}", login.guid, reason);
+            tx.execute_named_cached(
+                "INSERT INTO loginsQuarantine (reason, raw_json, quarantined_at)
+                 VALUES (:reason, :raw_json, :now_ms)",
+                named_params! {
+                    ":reason": reason,
+                    ":raw_json": serde_json::to_string(&QuarantinedLogin::from(login.clone()))?,
+                    ":now_ms": now_ms,
+                },
+            )?;
+            tx.execute_named_cached(
+                "DELETE FROM loginsL WHERE id = :id",
+                named_params! { ":id": id },
+            )?;
+            tx.execute_named_cached(
+                "UPDATE loginsM SET is_overridden = 0 WHERE guid = :guid",
+                named_params! { ":guid": login.guid },
+            )?;
+            self.append_audit_entry(login.guid_str(), AuditOperation::Delete, now_ms)?;
+        }
+        report.invalid_records_quarantined = invalid.len();
+
+        report.orphaned_mirror_records_healed = heal_orphaned_mirror_records_in(&tx)?;
+        tx.commit()?;
+        Ok(report)
+    }
+
+    /// Returns the `(id, reason, raw_json)` of every record in the
+    /// `loginsQuarantine` table, oldest first. `raw_json` is the record as a
+    /// JSON `Login`, with its local-only fields (`creationSource`,
+    /// `creationSourceName` and `associatedApp`) included too.
+    pub fn get_quarantined_records(&self) -> Result<Vec<(i64, String, String)>> {
+        let mut stmt = self
+            .db
+            .prepare_cached("SELECT id, reason, raw_json FROM loginsQuarantine ORDER BY id")?;
+        let rows = stmt.query_and_then(NO_PARAMS, |row| -> Result<_> {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+        })?;
+        rows.collect()
+    }
+
+    /// Moves the quarantined record with the given `id` (as returned by
+    /// `get_quarantined_records`) back into `loginsL`, for use once the user
+    /// has confirmed they want it. The record is fixed up if possible, and is
+    /// left in quarantine if it's still invalid, or is a duplicate of another
+    /// login. If a login with the same GUID exists (for example, because
+    /// `run_integrity_repair` restored the server version), it's updated with
+    /// the quarantined version.
+    pub fn restore_from_quarantine(&self, id: i64) -> Result<()> {
+        let raw_json = self.try_query_row(
+            "SELECT raw_json FROM loginsQuarantine WHERE id = :id",
+            named_params! { ":id": id },
+            |row| -> Result<String> { Ok(row.get(0)?) },
+            true,
+        )?;
+        let raw_json = match raw_json {
+            Some(raw_json) => raw_json,
+            None => throw!(ErrorKind::NoSuchRecord(id.to_string())),
+        };
+        let login: QuarantinedLogin = serde_json::from_str(&raw_json)?;
+        let login = Login::from(login).fixup_with_limits(&FieldLimits::unlimited())?;
+        self.check_for_dupes(&login)?;
+
+        let tx = self.unchecked_transaction()?;
+        let now_ms = util::system_time_ms_i64(SystemTime::now());
+        if self.exists(login.guid_str())? {
+            self.update_in_tx(&login, now_ms)?;
+        } else {
+            // Clear out any tombstone, which would otherwise stop the insert.
+            self.execute_named_cached(
+                "DELETE FROM loginsL WHERE guid = :guid",
+                named_params! { ":guid": login.guid },
+            )?;
+            self.add_in_tx(login, now_ms)?;
+        }
+        self.execute_named_cached(
+            "DELETE FROM loginsQuarantine WHERE id = :id",
+            named_params! { ":id": id },
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Reads the records for `hostname` (without returning them) so that the
+    /// pages they live on are in SQLite's page cache, making the next autofill
+    /// query for it faster. This is purely a hint, and has no other effect.
+    pub fn prefetch_for_autofill(&self, hostname: &str) -> Result<()> {
+        // Note that we count `password` rather than `*`, since `count(*)` can
+        // be answered from the hostname indices without reading the rows.
+        for sql in &[
+            "SELECT count(password) FROM loginsL WHERE is_deleted = 0 AND hostname = :hostname",
+            "SELECT count(password) FROM loginsM WHERE is_overridden = 0 AND hostname = :hostname",
+        ] {
+            self.query_row_named(sql, named_params! { ":hostname": hostname }, |row| {
+                row.get::<_, i64>(0)
+            })?;
+        }
+        Ok(())
+    }
+
+    pub fn get_by_id(&self, id: &str) -> Result<Option<Login>> {
+        self.try_query_row(
+            &GET_BY_GUID_SQL,
+            &[(":guid", &id as &dyn ToSql)],
+            Login::from_row,
+            true,
+        )
+    }
+
+    /// Returns just the password for the login with the given GUID, for use
+    /// with the results of `get_all_locked`.
+    pub fn unlock_login(&self, guid: &str) -> Result<Option<String>> {
+        self.try_query_row(
+            UNLOCK_LOGIN_SQL,
+            &[(":guid", &guid as &dyn ToSql)],
+            |row| Ok(row.get::<_, String>(0)?),
+            true,
+        )
+    }
+
+    pub fn touch(&self, id: &str) -> Result<()> {
+        let tx = self.unchecked_transaction()?;
+        self.touch_in_tx(id)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    // The guts of `touch`, for callers which are already in a transaction.
+    fn touch_in_tx(&self, id: &str) -> Result<()> {
+        self.ensure_local_overlay_exists(id)?;
+        self.mark_mirror_overridden(id)?;
+        let now_ms = util::system_time_ms_i64(SystemTime::now());
+        // As on iOS, just using a record doesn't flip it's status to changed.
+        // TODO: this might be wrong for lockbox!
+        self.execute_named_cached(
+            "UPDATE loginsL
+             SET timeLastUsed = :now_millis,
+                 timesUsed = timesUsed + 1,
+                 local_modified = :now_millis
+             WHERE guid = :guid
+                 AND is_deleted = 0",
+            named_params! {
+                ":now_millis": now_ms,
+                ":guid": id,
+            },
+        )?;
+        self.append_audit_entry(id, AuditOperation::Touch, now_ms)?;
+        Ok(())
+    }
+
+    /// Sets who `add`, `update`, `delete`, `touch` and `wipe` (along with
+    /// the methods built on them, and the other methods which change logins,
+    /// like `import_multiple` and `merge_logins`) record as making their
+    /// changes in the audit log. Nothing is recorded while the actor is
+    /// `None`, which is the default.
+    pub fn set_audit_actor(&mut self, actor: Option<String>) {
+        self.audit_actor = actor;
+    }
+
+    /// Returns the audit log entries for `guid`, oldest first.
+    pub fn get_audit_log(&self, guid: &str) -> Result<Vec<AuditEntry>> {
+        self.query_rows_and_then_named_cached(
+            "SELECT id, guid, operation, timestamp_ms, actor FROM loginsAuditLog
+             WHERE guid = :guid
+             ORDER BY id ASC",
+            named_params! { ":guid": guid },
+            |row| -> Result<_> {
+                Ok(AuditEntry {
+                    id: row.get("id")?,
+                    guid: row.get("guid")?,
+                    operation: row.get("operation")?,
+                    timestamp_ms: row.get("timestamp_ms")?,
+                    actor: row.get("actor")?,
+                })
+            },
+        )
+    }
+
+    fn append_audit_entry(&self, guid: &str, operation: AuditOperation, now_ms: i64) -> Result<()> {
+        if let Some(actor) = &self.audit_actor {
+            self.execute_named_cached(
+                "INSERT INTO loginsAuditLog (guid, operation, timestamp_ms, actor)
+                 VALUES (:guid, :operation, :now_ms, :actor)",
+                named_params! {
+                    ":guid": guid,
+                    ":operation": operation,
+                    ":now_ms": now_ms,
+                    ":actor": actor,
+                },
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Like `touch`, but for many logins at once, in a single transaction and
+    /// with one statement per chunk of IDs rather than one per login. If any
+    /// of `ids` doesn't exist (or is deleted), fails with `NoSuchRecord` and
+    /// none of them are touched.
+    pub fn touch_many(&self, ids: &[&str]) -> Result<()> {
+        let ids: Vec<&str> = ids
+            .iter()
+            .cloned()
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect();
+        let tx = self.unchecked_transaction()?;
+        let now_ms = util::system_time_ms_i64(SystemTime::now());
+        sql_support::each_chunk(&ids, |chunk, _| -> Result<()> {
+            let (vars, params) = util::sql_placeholders_and_params(chunk);
+            self.db.execute(
+                &format!(
+                    "{clone_mirror} WHERE guid IN ({vars})",
+                    clone_mirror = &*CLONE_ENTIRE_MIRROR_SQL,
+                    vars = vars
+                ),
+                &params,
+            )?;
+            self.db.execute(
+                &format!(
+                    "UPDATE loginsM SET is_overridden = 1 WHERE guid IN ({})",
+                    vars
+                ),
+                &params,
+            )?;
+            // As with `touch`, this doesn't change the sync status.
+            let touched = self.db.execute(
+                &format!(
+                    "UPDATE loginsL
+                     SET timeLastUsed = {now_ms},
+                         timesUsed = timesUsed + 1,
+                         local_modified = {now_ms}
+                     WHERE is_deleted = 0 AND guid IN ({vars})",
+                    now_ms = now_ms,
+                    vars = vars
+                ),
+                &params,
+            )?;
+            if touched != chunk.len() {
+                for id in chunk {
+                    if self.get_by_id(id)?.is_none() {
+                        throw!(ErrorKind::NoSuchRecord((*id).to_owned()));
+                    }
+                }
+            }
+            for id in chunk {
+                self.append_audit_entry(id, AuditOperation::Touch, now_ms)?;
+            }
+            Ok(())
+        })?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Returns the `(username, password)` of the login for `hostname` with
+    /// the given username, and `touch`es it, for filling in a form. If there's
+    /// more than one such login (say, for different form targets), an
+    /// arbitrary one is used.
+    pub fn get_password_for_form_fill(
+        &self,
+        hostname: &str,
+        username: &str,
+    ) -> Result<Option<(String, String)>> {
+        let tx = self.unchecked_transaction()?;
+        let found = self.try_query_row(
+            GET_FOR_FORM_FILL_SQL,
+            named_params! {
+                ":hostname": hostname,
+                ":username": username,
+            },
+            |row| -> Result<_> { Ok((row.get::<_, String>(0)?, row.get(1)?, row.get(2)?)) },
+            true,
+        )?;
+        let result = match found {
+            Some((guid, username, password)) => {
+                self.touch_in_tx(&guid)?;
+                Some((username, password))
+            }
+            None => None,
+        };
+        tx.commit()?;
+        Ok(result)
+    }
+
+    pub fn add(&self, login: Login) -> Result<Login> {
+        let login = self.fixup_and_check_for_dupes(login)?;
+
+        let tx = self.unchecked_transaction()?;
+        let now_ms = util::system_time_ms_i64(SystemTime::now());
+        let login = self.add_in_tx(login, now_ms)?;
+        tx.commit()?;
+        Ok(login)
+    }
+
+    /// Adds all of `logins` in a single transaction, which is much faster
+    /// than calling `add` for each of them. Each login is fixed up and
+    /// validated just like `add` does. If any login is invalid, or two of
+    /// them have the same GUID, nothing is added. Returns the logins as they
+    /// were added (with GUIDs and timestamps filled in).
+    pub fn add_many(&self, logins: Vec<Login>) -> Result<Vec<Login>> {
+        let limits = self.field_limits();
+        let mut guids = HashSet::with_capacity(logins.len());
+        let mut fixed = Vec::with_capacity(logins.len());
+        for login in logins {
+            let login = login.fixup_with_limits(&limits)?;
+            if !login.guid.is_empty() && !guids.insert(login.guid.clone()) {
+                throw!(ErrorKind::DuplicateGuid(login.guid.to_string()));
+            }
+            fixed.push(login);
+        }
+        let logins = fixed;
+        let tx = self.unchecked_transaction()?;
+        let now_ms = util::system_time_ms_i64(SystemTime::now());
+        let mut added = Vec::with_capacity(logins.len());
+        for login in logins {
+            // Checked inside the transaction, so that logins earlier in the
+            // batch count too.
+            self.check_for_dupes(&login)?;
+            added.push(self.add_in_tx(login, now_ms)?);
+        }
+        tx.commit()?;
+        Ok(added)
+    }
+
+    // The guts of `add`, for callers which are already in a transaction and
+    // have checked `login` is valid.
+    fn add_in_tx(&self, mut login: Login, now_ms: i64) -> Result<Login> {
+        // Allow an empty GUID to be passed to indicate that we should generate
+        // one. (Note that the FFI, does not require that the `id` field be
+        // present in the JSON, and replaces it with an empty string if missing).
+        if login.guid.is_empty() {
+            login.guid = Guid::random()
+        }
+
+        // Fill in default metadata.
+        if login.time_created == 0 {
+            login.time_created = now_ms;
+        }
+        if login.time_password_changed == 0 {
+            login.time_password_changed = now_ms;
+        }
+        if login.time_last_used == 0 {
+            login.time_last_used = now_ms;
+        }
+        if login.times_used == 0 {
+            login.times_used = 1;
+        }
+        if login.creation_source == CreationSource::Unknown {
+            login.creation_source = CreationSource::UserSaved;
+        }
+
+        let sql = format!(
+            "INSERT OR IGNORE INTO loginsL (
+                hostname,
+                httpRealm,
+                formSubmitURL,
+                usernameField,
+                passwordField,
+                timesUsed,
+                username,
+                password,
+                guid,
+                timeCreated,
+                timeLastUsed,
+                timePasswordChanged,
+                creation_source,
+                creation_source_name,
+                associated_app,
+                local_modified,
+                is_deleted,
+                sync_status
+            ) VALUES (
+                :hostname,
+                :http_realm,
+                :form_submit_url,
+                :username_field,
+                :password_field,
+                :times_used,
+                :username,
+                :password,
+                :guid,
+                :time_created,
+                :time_last_used,
+                :time_password_changed,
+                :creation_source,
+                :creation_source_name,
+                :associated_app,
+                :local_modified,
+                0, -- is_deleted
+                {new} -- sync_status
+            )",
+            new = SyncStatus::New as u8
+        );
+
+        let rows_changed = self.execute_named(
+            &sql,
+            named_params! {
+                ":hostname": login.hostname,
+                ":http_realm": login.http_realm,
+                ":form_submit_url": login.form_submit_url,
+                ":username_field": login.username_field,
+                ":password_field": login.password_field,
+                ":username": login.username,
+                ":password": login.password,
+                ":guid": login.guid,
+                ":time_created": login.time_created,
+                ":times_used": login.times_used,
+                ":time_last_used": login.time_last_used,
+                ":time_password_changed": login.time_password_changed,
+                ":creation_source": login.creation_source.discriminant(),
+                ":creation_source_name": login.creation_source.source_name(),
+                ":associated_app": login.associated_app,
+                ":local_modified": now_ms,
+            },
+        )?;
+        if rows_changed == 0 {
+            log::error!(
+                "Record {:?} already exists (use `update` to update records, not add)",
+                login.guid
+            );
+            throw!(ErrorKind::DuplicateGuid(login.guid.into_string()));
+        }
+        self.append_audit_entry(login.guid_str(), AuditOperation::Add, now_ms)?;
+        Ok(login)
+    }
+
+    pub fn import_multiple(&self, logins: &[Login]) -> Result<MigrationMetrics> {
+        // Check if the logins table is empty first.
+        let mut num_existing_logins =
+            self.query_row::<i64, _, _>("SELECT COUNT(*) FROM loginsL", NO_PARAMS, |r| r.get(0))?;
+        num_existing_logins +=
+            self.query_row::<i64, _, _>("SELECT COUNT(*) FROM loginsM", NO_PARAMS, |r| r.get(0))?;
+        if num_existing_logins > 0 {
+            return Err(ErrorKind::NonEmptyTable.into());
+        }
+        let limits = self.field_limits();
+        let tx = self.unchecked_transaction()?;
+        let now_ms = util::system_time_ms_i64(SystemTime::now());
+        let import_start = Instant::now();
+        let sql = format!(
+            "INSERT OR IGNORE INTO loginsL (
+                hostname,
+                httpRealm,
+                formSubmitURL,
+                usernameField,
+                passwordField,
+                timesUsed,
+                username,
+                password,
+                guid,
+                timeCreated,
                 timeLastUsed,
                 timePasswordChanged,
+                creation_source,
+                creation_source_name,
+                associated_app,
                 local_modified,
                 is_deleted,
                 sync_status
@@ -554,1089 +3750,6996 @@ impl LoginDb {
                 :time_created,
                 :time_last_used,
                 :time_password_changed,
+                :creation_source,
+                :creation_source_name,
+                :associated_app,
                 :local_modified,
                 0, -- is_deleted
                 {new} -- sync_status
             )",
             new = SyncStatus::New as u8
         );
+        // Everything we import is `Imported`, but we keep the source name if
+        // the caller gave us one.
+        let imported = CreationSource::Imported {
+            source_name: String::new(),
+        };
+        let import_start_total_logins: u64 = logins.len() as u64;
+        let mut num_failed_fixup: u64 = 0;
+        let mut num_failed_insert: u64 = 0;
+        let mut fixup_phase_duration = Duration::new(0, 0);
+        let mut fixup_errors: Vec<String> = Vec::new();
+        let mut insert_errors: Vec<String> = Vec::new();
+
+        for login in logins {
+            let old_guid = &login.guid; // Keep the old GUID around so we can debug errors easily.
+                                        // GUIDs from other stores (Fennec, for example) may not be valid
+                                        // for us, so give those records a new one before validating them.
+            let with_new_guid;
+            let login = if login::is_valid_guid(old_guid) {
+                login
+            } else {
+                with_new_guid = Login {
+                    guid: Guid::random(),
+                    ..login.clone()
+                };
+                &with_new_guid
+            };
+            // This is a little bit of hoop-jumping to avoid cloning each borrowed item
+            // in order to *possibly* created a fixed-up version.
+            let mut login = login;
+            let maybe_fixed_login = login.maybe_fixup_with_limits(&limits).and_then(|fixed| {
+                match &fixed {
+                    None => self.check_for_dupes(login)?,
+                    Some(l) => self.check_for_dupes(&l)?,
+                };
+                Ok(fixed)
+            });
+            match &maybe_fixed_login {
+                Ok(None) => {} // The provided login was fine all along
+                Ok(Some(l)) => {
+                    // We made a new, fixed-up Login.
+                    login = l;
+                }
+                Err(e) => {
+                    log::warn!("Skipping login {} as it is invalid ({}).", login.guid, e);
+                    fixup_errors.push(e.label().into());
+                    num_failed_fixup += 1;
+                    continue;
+                }
+            };
+            // Now we can safely insert it, knowing that it's valid data.
+            let guid = &login.guid;
+            fixup_phase_duration = import_start.elapsed();
+            match self.execute_named_cached(
+                &sql,
+                named_params! {
+                    ":hostname": login.hostname,
+                    ":http_realm": login.http_realm,
+                    ":form_submit_url": login.form_submit_url,
+                    ":username_field": login.username_field,
+                    ":password_field": login.password_field,
+                    ":username": login.username,
+                    ":password": login.password,
+                    ":guid": guid,
+                    ":time_created": login.time_created,
+                    ":times_used": login.times_used,
+                    ":time_last_used": login.time_last_used,
+                    ":time_password_changed": login.time_password_changed,
+                    ":creation_source": imported.discriminant(),
+                    ":creation_source_name": login.creation_source.source_name(),
+                    ":associated_app": login.associated_app,
+                    ":local_modified": now_ms,
+                },
+            ) {
+                Ok(inserted) => {
+                    if inserted > 0 {
+                        self.append_audit_entry(login.guid_str(), AuditOperation::Add, now_ms)?;
+                    }
+                    log::info!("Imported {} (new GUID {}) successfully.", old_guid, guid)
+                }
+                Err(e) => {
+                    log::warn!("Could not import {} ({}).", old_guid, e);
+                    insert_errors.push(Error::from(e).label().into());
+                    num_failed_insert += 1;
+                }
+            };
+        }
+        tx.commit()?;
+
+        let num_post_fixup = import_start_total_logins - num_failed_fixup;
+        let num_failed = num_failed_fixup + num_failed_insert;
+        let insert_phase_duration = import_start
+            .elapsed()
+            .checked_sub(fixup_phase_duration)
+            .unwrap_or_else(|| Duration::new(0, 0));
+        let mut all_errors = Vec::new();
+        all_errors.extend(fixup_errors.clone());
+        all_errors.extend(insert_errors.clone());
+
+        let metrics = MigrationMetrics {
+            fixup_phase: MigrationPhaseMetrics {
+                num_processed: import_start_total_logins,
+                num_succeeded: num_post_fixup,
+                num_failed: num_failed_fixup,
+                total_duration: fixup_phase_duration.as_millis(),
+                errors: fixup_errors,
+            },
+            insert_phase: MigrationPhaseMetrics {
+                num_processed: num_post_fixup,
+                num_succeeded: num_post_fixup - num_failed_insert,
+                num_failed: num_failed_insert,
+                total_duration: insert_phase_duration.as_millis(),
+                errors: insert_errors,
+            },
+            num_processed: import_start_total_logins,
+            num_succeeded: import_start_total_logins - num_failed,
+            num_failed,
+            total_duration: fixup_phase_duration
+                .checked_add(insert_phase_duration)
+                .unwrap_or_else(|| Duration::new(0, 0))
+                .as_millis(),
+            errors: all_errors,
+        };
+        log::info!(
+            "Finished importing logins with the following metrics: {:#?}",
+            metrics
+        );
+        Ok(metrics)
+    }
+
+    /// Imports the logins from a CSV file exported by Chrome (with the columns
+    /// `name,url,username,password`) or Firefox (`url,username,password,
+    /// httpRealm,formActionOrigin,guid,timeCreated,timeLastUsed,
+    /// timePasswordChanged`). Columns are found by name, so their order
+    /// doesn't matter, and extra columns are ignored.
+    ///
+    /// The import runs in a single transaction. Chrome doesn't export form
+    /// action origins, so its logins are given their own origin as one. Invalid
+    /// GUIDs are replaced with new ones.
+    pub fn import_from_csv<R: Read>(
+        &self,
+        reader: R,
+        options: ImportOptions,
+    ) -> Result<ImportReport> {
+        let mut reader = csv::Reader::from_reader(reader);
+        let columns = CsvColumns::from_headers(reader.headers()?)?;
+        let limits = self.field_limits();
+        let mut report = ImportReport::default();
+        let tx = self.unchecked_transaction()?;
+        let now_ms = util::system_time_ms_i64(SystemTime::now());
+        for (i, record) in reader.records().enumerate() {
+            let login = match record
+                .map_err(Error::from)
+                .and_then(|record| columns.login_from_record(&record, &limits))
+            {
+                Ok(login) => login,
+                Err(e) => {
+                    if options.on_invalid == InvalidStrategy::Abort {
+                        return Err(e);
+                    }
+                    report.skipped += 1;
+                    report.errors.push(ImportError {
+                        row: i + 1,
+                        message: e.to_string(),
+                    });
+                    continue;
+                }
+            };
+            self.import_login_in_tx(login, options.on_duplicate, now_ms, &mut report)?;
+        }
+        tx.commit()?;
+        Ok(report)
+    }
+
+    /// Writes every login (including its password) to `writer` as a JSON
+    /// array, in the format `Login` serializes to, and returns how many
+    /// there were.
+    pub fn export_to_json<W: Write>(&self, writer: W, _: ExportPermission) -> Result<usize> {
+        let logins = self.get_all()?;
+        serde_json::to_writer(writer, &logins)?;
+        Ok(logins.len())
+    }
+
+    /// Writes every login (including its password) to `writer` as CSV, in
+    /// the layout used by `format`'s browser, and returns how many there
+    /// were.
+    pub fn export_to_csv<W: Write>(
+        &self,
+        writer: W,
+        format: CsvExportFormat,
+        _: ExportPermission,
+    ) -> Result<usize> {
+        let logins = self.get_all()?;
+        let mut writer = csv::Writer::from_writer(writer);
+        match format {
+            CsvExportFormat::Firefox => {
+                writer.write_record([
+                    "url",
+                    "username",
+                    "password",
+                    "httpRealm",
+                    "formActionOrigin",
+                    "guid",
+                    "timeCreated",
+                    "timeLastUsed",
+                    "timePasswordChanged",
+                ])?;
+                for login in &logins {
+                    writer.write_record([
+                        login.hostname.as_str(),
+                        &login.username,
+                        &login.password,
+                        login.http_realm.as_deref().unwrap_or_default(),
+                        login.form_submit_url.as_deref().unwrap_or_default(),
+                        login.guid_str(),
+                        &login.time_created.to_string(),
+                        &login.time_last_used.to_string(),
+                        &login.time_password_changed.to_string(),
+                    ])?;
+                }
+            }
+            CsvExportFormat::Chrome => {
+                writer.write_record(["name", "url", "username", "password"])?;
+                for login in &logins {
+                    let url = Url::parse(&login.hostname).ok();
+                    let name = url
+                        .as_ref()
+                        .and_then(|url| url.host_str())
+                        .unwrap_or(&login.hostname);
+                    writer.write_record([
+                        name,
+                        &login.hostname,
+                        &login.username,
+                        &login.password,
+                    ])?;
+                }
+            }
+        }
+        writer.flush()?;
+        Ok(logins.len())
+    }
+
+    fn import_login_in_tx(
+        &self,
+        mut login: Login,
+        on_duplicate: DuplicateStrategy,
+        now_ms: i64,
+        report: &mut ImportReport,
+    ) -> Result<()> {
+        let same_guid = if login.guid.is_empty() {
+            None
+        } else {
+            self.get_by_id(&login.guid)?
+        };
+        let guid_taken = same_guid.is_some();
+        let existing = match self.find_dupe(&login)? {
+            Some(dupe) => Some(dupe),
+            None => same_guid,
+        };
+        match (existing, on_duplicate) {
+            (Some(_), DuplicateStrategy::Skip) => report.skipped += 1,
+            (Some(existing), DuplicateStrategy::Replace) => {
+                login.guid = existing.guid;
+                self.update_in_tx(&login, now_ms)?;
+                report.replaced += 1;
+            }
+            (None, _) | (Some(_), DuplicateStrategy::KeepBoth) => {
+                if guid_taken {
+                    login.guid = Guid::random();
+                }
+                self.add_in_tx(login, now_ms)?;
+                report.inserted += 1;
+            }
+        }
+        Ok(())
+    }
+
+    /// Merges the logins from the database at `other_path`, which is
+    /// encrypted with `other_key` (if it's encrypted at all), into this one,
+    /// in a single transaction. The other database isn't changed, and must
+    /// already have been opened (and so upgraded) by this version, with the
+    /// same `cipher_config` as this one.
+    ///
+    /// Logins from the other database are fixed up like any other new login,
+    /// and skipped if they can't be. Logins which have been deleted here
+    /// aren't brought back, and updated logins keep the other database's
+    /// usage metadata.
+    pub fn merge_db(
+        &self,
+        other_path: impl AsRef<Path>,
+        other_key: Option<&str>,
+        strategy: MergeStrategy,
+    ) -> Result<MergeReport> {
+        self.execute_named(
+            "ATTACH DATABASE :path AS other KEY :key",
+            named_params! {
+                ":path": other_path.as_ref().to_string_lossy(),
+                ":key": other_key.unwrap_or(""),
+            },
+        )?;
+        let result = self.merge_attached_db(other_key.is_some(), strategy);
+        self.execute_batch("DETACH DATABASE other")?;
+        result
+    }
+
+    // The guts of `merge_db`, once the other database is attached as `other`.
+    fn merge_attached_db(&self, encrypted: bool, strategy: MergeStrategy) -> Result<MergeReport> {
+        if encrypted {
+            self.cipher_config()
+                .apply(&self.db, Some(DatabaseName::Attached("other")))?;
+        }
+        let others = {
+            let mut stmt = self.db.prepare(&format!(
+                "SELECT {common} FROM other.loginsL WHERE is_deleted = 0
+                 UNION ALL
+                 SELECT {common} FROM other.loginsM WHERE is_overridden IS NOT 1",
+                common = schema::COMMON_COLS,
+            ))?;
+            let rows = stmt.query_and_then(NO_PARAMS, Login::from_row_unfixed)?;
+            rows.collect::<Result<Vec<_>>>()?
+        };
+        let mut report = MergeReport::default();
+        let limits = self.field_limits();
+        let tx = self.unchecked_transaction()?;
+        let now_ms = util::system_time_ms_i64(SystemTime::now());
+        for login in others {
+            let mut login = match login.fixup_with_limits(&limits) {
+                Ok(login) => login,
+                Err(e) => {
+                    log::warn!("Skipping invalid login from the other database: {}", e);
+                    report.skipped += 1;
+                    continue;
+                }
+            };
+            // Logins deleted here stay deleted.
+            if self.is_deleted_locally(&login.guid)? {
+                report.skipped += 1;
+                continue;
+            }
+            let same_guid = self.get_by_id(&login.guid)?;
+            let guid_taken = same_guid.is_some();
+            let existing = match self.find_dupe(&login)? {
+                Some(dupe) => Some(dupe),
+                None => same_guid,
+            };
+            match (existing, strategy) {
+                (Some(existing), _) if Login::diff(&existing, &login).is_empty() => {
+                    report.skipped += 1
+                }
+                (Some(existing), MergeStrategy::KeepNewer) => {
+                    if login.time_password_changed > existing.time_password_changed {
+                        login.guid = existing.guid;
+                        self.replace_in_tx(&login, now_ms)?;
+                        report.updated += 1;
+                    } else {
+                        report.skipped += 1;
+                    }
+                }
+                (None, _) | (Some(_), MergeStrategy::KeepAll) => {
+                    if guid_taken {
+                        login.guid = Guid::random();
+                    }
+                    self.add_in_tx(login, now_ms)?;
+                    report.inserted += 1;
+                }
+            }
+        }
+        tx.commit()?;
+        Ok(report)
+    }
+
+    /// Adds `login`, or updates it if it already exists, in a single
+    /// transaction, returning the login as it was persisted. If `login` has
+    /// no GUID, but is a duplicate of an existing login (as `find_dupe` sees
+    /// it), that login is updated instead of a new one being added.
+    ///
+    /// When updating, `time_created` and `times_used` come from the existing
+    /// record, and (as with `update`) the update counts as a use.
+    pub fn add_or_update(&self, login: Login) -> Result<Login> {
+        let mut login = login.fixup_with_limits(&self.field_limits())?;
+
+        let tx = self.unchecked_transaction()?;
+        if login.guid.is_empty() {
+            if let Some(dupe) = self.find_dupe(&login)? {
+                if self.exists(dupe.guid_str())? {
+                    login.guid = dupe.guid;
+                }
+            }
+        }
+        self.check_for_dupes(&login)?;
+        let now_ms = util::system_time_ms_i64(SystemTime::now());
+        let guid = if !login.guid.is_empty() && self.exists(login.guid_str())? {
+            self.update_in_tx(&login, now_ms)?;
+            login.guid
+        } else {
+            self.add_in_tx(login, now_ms)?.guid
+        };
+        let login = match self.get_by_id(guid.as_str())? {
+            Some(login) => login,
+            None => throw!(ErrorKind::NoSuchRecord(guid.into_string())),
+        };
+        tx.commit()?;
+        Ok(login)
+    }
+
+    pub fn update(&self, login: Login) -> Result<()> {
+        let login = self.fixup_and_check_for_dupes(login)?;
+
+        let tx = self.unchecked_transaction()?;
+        let now_ms = util::system_time_ms_i64(SystemTime::now());
+        self.update_in_tx(&login, now_ms)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    // The guts of `update`, for callers which are already in a transaction
+    // and have checked `login` is valid.
+    fn update_in_tx(&self, login: &Login, now_ms: i64) -> Result<()> {
+        // Note: These fail with DuplicateGuid if the record doesn't exist.
+        self.ensure_local_overlay_exists(login.guid_str())?;
+        self.mark_mirror_overridden(login.guid_str())?;
+
+        let sql = format!(
+            "UPDATE loginsL
+             SET local_modified      = :now_millis,
+                 timeLastUsed        = :now_millis,
+                 -- Only update timePasswordChanged if, well, the password changed.
+                 timePasswordChanged = (CASE
+                     WHEN password = :password
+                     THEN timePasswordChanged
+                     ELSE :now_millis
+                 END),
+                 httpRealm           = :http_realm,
+                 formSubmitURL       = :form_submit_url,
+                 usernameField       = :username_field,
+                 passwordField       = :password_field,
+                 timesUsed           = timesUsed + 1,
+                 username            = :username,
+                 password            = :password,
+                 hostname            = :hostname,
+                 associated_app      = :associated_app,
+                 -- leave New records as they are, otherwise update them to `changed`
+                 sync_status         = max(sync_status, {changed})
+             WHERE guid = :guid",
+            changed = SyncStatus::Changed as u8
+        );
+
+        self.db.execute_named(
+            &sql,
+            named_params! {
+                ":hostname": login.hostname,
+                ":username": login.username,
+                ":password": login.password,
+                ":http_realm": login.http_realm,
+                ":form_submit_url": login.form_submit_url,
+                ":username_field": login.username_field,
+                ":password_field": login.password_field,
+                ":associated_app": login.associated_app,
+                ":guid": login.guid,
+                ":now_millis": now_ms,
+            },
+        )?;
+        self.append_audit_entry(login.guid_str(), AuditOperation::Update, now_ms)?;
+        Ok(())
+    }
+
+    // Like `update_in_tx`, but for changes which come from another copy of
+    // the login (for example, in another database) rather than from the user,
+    // so its usage metadata is kept instead of the change counting as a use.
+    // If the local record is a tombstone, this brings it back.
+    fn replace_in_tx(&self, login: &Login, now_ms: i64) -> Result<()> {
+        // Note: These fail with DuplicateGuid if the record doesn't exist.
+        self.ensure_local_overlay_exists(login.guid_str())?;
+        self.mark_mirror_overridden(login.guid_str())?;
+
+        let sql = format!(
+            "UPDATE loginsL
+             SET local_modified      = :now_millis,
+                 timeLastUsed        = :time_last_used,
+                 timePasswordChanged = :time_password_changed,
+                 timesUsed           = :times_used,
+                 httpRealm           = :http_realm,
+                 formSubmitURL       = :form_submit_url,
+                 usernameField       = :username_field,
+                 passwordField       = :password_field,
+                 username            = :username,
+                 password            = :password,
+                 hostname            = :hostname,
+                 associated_app      = :associated_app,
+                 is_deleted          = 0,
+                 -- leave New records as they are, otherwise update them to `changed`
+                 sync_status         = max(sync_status, {changed})
+             WHERE guid = :guid",
+            changed = SyncStatus::Changed as u8
+        );
+
+        self.db.execute_named(
+            &sql,
+            named_params! {
+                ":hostname": login.hostname,
+                ":username": login.username,
+                ":password": login.password,
+                ":http_realm": login.http_realm,
+                ":form_submit_url": login.form_submit_url,
+                ":username_field": login.username_field,
+                ":password_field": login.password_field,
+                ":associated_app": login.associated_app,
+                ":time_last_used": login.time_last_used,
+                ":time_password_changed": login.time_password_changed,
+                ":times_used": login.times_used,
+                ":guid": login.guid,
+                ":now_millis": now_ms,
+            },
+        )?;
+        self.append_audit_entry(login.guid_str(), AuditOperation::Update, now_ms)?;
+        Ok(())
+    }
+
+    // Whether `guid` has a local tombstone.
+    fn is_deleted_locally(&self, guid: &str) -> Result<bool> {
+        Ok(self.db.query_row_named(
+            "SELECT EXISTS(SELECT 1 FROM loginsL WHERE guid = :guid AND is_deleted = 1)",
+            named_params! { ":guid": guid },
+            |row| row.get(0),
+        )?)
+    }
+
+    pub fn check_valid_with_no_dupes(&self, login: &Login) -> Result<()> {
+        login.check_valid_with_limits(&self.field_limits())?;
+        self.check_for_dupes(login)
+    }
+
+    pub fn fixup_and_check_for_dupes(&self, login: Login) -> Result<Login> {
+        let login = login.fixup_with_limits(&self.field_limits())?;
+        self.check_for_dupes(&login)?;
+        Ok(login)
+    }
+
+    pub fn check_for_dupes(&self, login: &Login) -> Result<()> {
+        if self.dupe_exists(&login)? {
+            throw!(LoginValidationError::DuplicateLogin);
+        }
+        Ok(())
+    }
+
+    pub fn dupe_exists(&self, login: &Login) -> Result<bool> {
+        // Note: the query below compares the guids of the given login with existing logins
+        //  to prevent a login from being considered a duplicate of itself (e.g. during updates).
+        Ok(self.db.query_row_named(
+            "SELECT EXISTS(
+                SELECT 1 FROM loginsL
+                WHERE is_deleted = 0
+                    AND guid <> :guid
+                    AND hostname = :hostname
+                    AND NULLIF(username, '') = :username
+                    AND (
+                        formSubmitURL = :form_submit
+                        OR
+                        httpRealm = :http_realm
+                    )
+
+                UNION ALL
+
+                SELECT 1 FROM loginsM
+                WHERE is_overridden = 0
+                    AND guid <> :guid
+                    AND hostname = :hostname
+                    AND NULLIF(username, '') = :username
+                    AND (
+                        formSubmitURL = :form_submit
+                        OR
+                        httpRealm = :http_realm
+                    )
+             )",
+            named_params! {
+                ":guid": &login.guid,
+                ":hostname": &login.hostname,
+                ":username": &login.username,
+                ":http_realm": login.http_realm.as_ref(),
+                ":form_submit": login.form_submit_url.as_ref(),
+            },
+            |row| row.get(0),
+        )?)
+    }
+
+    pub fn potential_dupes_ignoring_username(&self, login: &Login) -> Result<Vec<Login>> {
+        // Could be lazy_static-ed...
+        lazy_static::lazy_static! {
+            static ref DUPES_IGNORING_USERNAME_SQL: String = format!(
+                "SELECT {common_cols} FROM loginsL
+                WHERE is_deleted = 0
+                    AND hostname = :hostname
+                    AND (
+                        formSubmitURL = :form_submit
+                        OR
+                        httpRealm = :http_realm
+                    )
+
+                UNION ALL
+
+                SELECT {common_cols} FROM loginsM
+                WHERE is_overridden = 0
+                    AND hostname = :hostname
+                    AND (
+                        formSubmitURL = :form_submit
+                        OR
+                        httpRealm = :http_realm
+                    )
+                ",
+                common_cols = schema::COMMON_COLS
+            );
+        }
+        let mut stmt = self.db.prepare_cached(&DUPES_IGNORING_USERNAME_SQL)?;
+        let params = named_params! {
+            ":hostname": &login.hostname,
+            ":http_realm": login.http_realm.as_ref(),
+            ":form_submit": login.form_submit_url.as_ref(),
+        };
+        // Needs to be two lines for borrow checker
+        let rows = stmt.query_and_then_named(params, Login::from_row)?;
+        rows.collect()
+    }
+
+    pub fn exists(&self, id: &str) -> Result<bool> {
+        Ok(self.db.query_row_named(
+            "SELECT EXISTS(
+                 SELECT 1 FROM loginsL
+                 WHERE guid = :guid AND is_deleted = 0
+                 UNION ALL
+                 SELECT 1 FROM loginsM
+                 WHERE guid = :guid AND is_overridden IS NOT 1
+             )",
+            named_params! { ":guid": id },
+            |row| row.get(0),
+        )?)
+    }
+
+    /// Delete the record with the provided id. Returns true if the record
+    /// existed already.
+    pub fn delete(&self, id: &str) -> Result<bool> {
+        let tx = self.unchecked_transaction_imm()?;
+        let exists = self.exists(id)?;
+        let now_ms = util::system_time_ms_i64(SystemTime::now());
+        self.delete_in_tx(id, now_ms)?;
+        tx.commit()?;
+        Ok(exists)
+    }
+
+    // The guts of `delete`, for callers which are already in a transaction.
+    fn delete_in_tx(&self, id: &str, now_ms: i64) -> Result<()> {
+        // For IDs that have, mark is_deleted and clear sensitive fields
+        self.execute_named(
+            &format!(
+                "UPDATE loginsL
+                 SET local_modified = :now_ms,
+                     sync_status = {status_changed},
+                     is_deleted = 1,
+                     password = '',
+                     hostname = '',
+                     username = ''
+                 WHERE guid = :guid",
+                status_changed = SyncStatus::Changed as u8
+            ),
+            named_params! { ":now_ms": now_ms, ":guid": id },
+        )?;
+
+        // Mark the mirror as overridden
+        self.execute_named(
+            "UPDATE loginsM SET is_overridden = 1 WHERE guid = :guid",
+            named_params! { ":guid": id },
+        )?;
+
+        // If we don't have a local record for this ID, but do have it in the mirror
+        // insert a tombstone.
+        self.execute_named(&format!("
+            INSERT OR IGNORE INTO loginsL
+                    (guid, local_modified, is_deleted, sync_status, hostname, timeCreated, timePasswordChanged, password, username)
+            SELECT   guid, :now_ms,        1,          {changed},   '',       timeCreated, :now_ms,                   '',       ''
+            FROM loginsM
+            WHERE guid = :guid",
+            changed = SyncStatus::Changed as u8),
+            named_params! { ":now_ms": now_ms, ":guid": id })?;
+        self.append_audit_entry(id, AuditOperation::Delete, now_ms)?;
+        Ok(())
+    }
+
+    /// Changes the GUID of the login `old_guid` to `new_guid`, or to a random
+    /// one if that's `None`, and returns it. Only logins which have never
+    /// been synced can be changed, since the server (and other clients) know
+    /// synced logins by their GUID; for those, this fails with
+    /// `InvalidOperation`.
+    pub fn reset_guid(&self, old_guid: &str, new_guid: Option<&str>) -> Result<String> {
+        let new_guid = match new_guid {
+            Some(guid) if !login::is_valid_guid(guid) => {
+                throw!(LoginValidationError::InvalidFormat {
+                    field: "guid".into(),
+                })
+            }
+            Some(guid) => guid.to_owned(),
+            None => Guid::random().into_string(),
+        };
+        let tx = self.unchecked_transaction_imm()?;
+        // Once synced, a login with no local changes only lives in the mirror.
+        let status: Option<u8> = self.try_query_row(
+            &format!(
+                "SELECT sync_status FROM loginsL WHERE guid = :guid AND is_deleted = 0
+                 UNION ALL
+                 SELECT {synced} FROM loginsM WHERE guid = :guid AND is_overridden = 0",
+                synced = SyncStatus::Synced as u8
+            ),
+            named_params! { ":guid": old_guid },
+            |row| Ok::<_, Error>(row.get(0)?),
+            true,
+        )?;
+        match status {
+            Some(status) if status == SyncStatus::New as u8 => {}
+            Some(_) => throw!(ErrorKind::InvalidOperation(format!(
+                "Can't change the GUID of {:?}, since it's been synced",
+                old_guid
+            ))),
+            None => throw!(ErrorKind::NoSuchRecord(old_guid.to_owned())),
+        }
+        let in_use: bool = self.query_row_named(
+            "SELECT EXISTS(
+                 SELECT 1 FROM loginsL WHERE guid = :guid
+                 UNION ALL
+                 SELECT 1 FROM loginsM WHERE guid = :guid
+             )",
+            named_params! { ":guid": new_guid },
+            |row| row.get(0),
+        )?;
+        if in_use {
+            throw!(ErrorKind::DuplicateGuid(new_guid));
+        }
+        self.execute_named_cached(
+            "UPDATE loginsL SET guid = :new_guid WHERE guid = :old_guid",
+            named_params! { ":new_guid": new_guid, ":old_guid": old_guid },
+        )?;
+        // The log is looked up by GUID, so the change is recorded under both.
+        let now_ms = util::system_time_ms_i64(SystemTime::now());
+        self.append_audit_entry(old_guid, AuditOperation::Update, now_ms)?;
+        self.append_audit_entry(&new_guid, AuditOperation::Update, now_ms)?;
+        tx.commit()?;
+        Ok(new_guid)
+    }
+
+    /// Deletes every login for `hostname`, all in one transaction. Logins
+    /// which have never been synced are removed outright, since the server
+    /// doesn't know about them; the rest become tombstones, as `delete` would
+    /// make. `hostname` must match exactly, so logins for the same host on a
+    /// different port (or scheme) aren't deleted. Returns the number of logins
+    /// deleted.
+    pub fn delete_by_hostname(&self, hostname: &str) -> Result<usize> {
+        let tx = self.unchecked_transaction_imm()?;
+        let now_ms = util::system_time_ms_i64(SystemTime::now());
+        let logins = self.get_by_hostname(hostname)?;
+        for login in &logins {
+            let removed = self.execute_named_cached(
+                &format!(
+                    "DELETE FROM loginsL
+                     WHERE guid = :guid
+                       AND sync_status = {new}
+                       AND guid NOT IN (SELECT guid FROM loginsM)",
+                    new = SyncStatus::New as u8
+                ),
+                named_params! { ":guid": login.guid_str() },
+            )?;
+            if removed == 0 {
+                self.delete_in_tx(login.guid_str(), now_ms)?;
+            } else {
+                self.append_audit_entry(login.guid_str(), AuditOperation::Delete, now_ms)?;
+            }
+        }
+        tx.commit()?;
+        Ok(logins.len())
+    }
+
+    /// Merges the login with GUID `discard_id` into the one with GUID
+    /// `keep_id`, for cleaning up duplicates found by `find_all_dupes`. The
+    /// kept login's `times_used` becomes the sum of both, and its
+    /// `time_last_used` the later of the two. The discarded login is then
+    /// deleted, as `delete` would.
+    pub fn merge_logins(&self, keep_id: &str, discard_id: &str) -> Result<()> {
+        if keep_id == discard_id {
+            throw!(ErrorKind::DuplicateGuid(keep_id.to_owned()));
+        }
+        let tx = self.unchecked_transaction_imm()?;
+        let discard = match self.get_by_id(discard_id)? {
+            Some(discard) => discard,
+            None => throw!(ErrorKind::NoSuchRecord(discard_id.to_owned())),
+        };
+        // Fails with NoSuchRecord if `keep_id` doesn't exist.
+        self.ensure_local_overlay_exists(keep_id)?;
+        self.mark_mirror_overridden(keep_id)?;
+        let now_ms = util::system_time_ms_i64(SystemTime::now());
+        self.execute_named_cached(
+            &format!(
+                "UPDATE loginsL
+                 SET timesUsed = timesUsed + :times_used,
+                     timeLastUsed = max(ifnull(timeLastUsed, 0), :time_last_used),
+                     local_modified = :now_ms,
+                     sync_status = max(sync_status, {changed})
+                 WHERE guid = :guid AND is_deleted = 0",
+                changed = SyncStatus::Changed as u8
+            ),
+            named_params! {
+                ":times_used": discard.times_used,
+                ":time_last_used": discard.time_last_used,
+                ":now_ms": now_ms,
+                ":guid": keep_id,
+            },
+        )?;
+        self.append_audit_entry(keep_id, AuditOperation::Update, now_ms)?;
+        self.delete_in_tx(discard_id, now_ms)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Returns the logins which have been changed locally, and not yet
+    /// synced, since `since_ms` (a local timestamp in milliseconds). Unlike
+    /// `fetch_outgoing`, this only looks at local records, and doesn't need a
+    /// sync timestamp.
+    pub fn get_changed_since(&self, since_ms: i64) -> Result<Vec<Login>> {
+        let mut stmt = self.db.prepare_cached(&GET_CHANGED_SINCE_SQL)?;
+        let rows =
+            stmt.query_and_then_named(named_params! { ":since_ms": since_ms }, Login::from_row)?;
+        rows.collect::<Result<_>>()
+    }
+
+    /// Returns the GUIDs of the logins which have been deleted locally since
+    /// `since_ms` (a local timestamp in milliseconds), and whose tombstones
+    /// haven't yet been synced.
+    pub fn get_deleted_since(&self, since_ms: i64) -> Result<Vec<String>> {
+        let mut stmt = self.db.prepare_cached(
+            "SELECT guid FROM loginsL
+             WHERE is_deleted = 1
+               AND local_modified >= :since_ms",
+        )?;
+        let rows = stmt.query_and_then_named(named_params! { ":since_ms": since_ms }, |row| {
+            row.get::<_, String>(0)
+        })?;
+        Ok(rows.collect::<result::Result<_, _>>()?)
+    }
+
+    /// Returns the logins created before `before_ms` (in milliseconds since
+    /// the epoch).
+    pub fn get_all_with_creation_before(&self, before_ms: i64) -> Result<Vec<Login>> {
+        let mut stmt = self.db.prepare_cached(&GET_ALL_CREATED_BEFORE_SQL)?;
+        let rows =
+            stmt.query_and_then_named(named_params! { ":before_ms": before_ms }, Login::from_row)?;
+        rows.collect::<Result<_>>()
+    }
+
+    /// Deletes, as `delete` would, every login returned by
+    /// `get_all_with_creation_before`, all in one transaction. Returns the
+    /// number of logins deleted.
+    pub fn delete_all_created_before(&self, before_ms: i64) -> Result<usize> {
+        let tx = self.unchecked_transaction_imm()?;
+        let now_ms = util::system_time_ms_i64(SystemTime::now());
+        let logins = self.get_all_with_creation_before(before_ms)?;
+        for login in &logins {
+            self.delete_in_tx(login.guid_str(), now_ms)?;
+        }
+        tx.commit()?;
+        Ok(logins.len())
+    }
+
+    /// Returns the number of tombstones which were deleted more than `age_ms`
+    /// milliseconds ago, and have finished syncing, so are ready to be purged.
+    /// Deletions which haven't been uploaded yet are never ready.
+    pub fn count_deleted_ready_to_purge(&self, age_ms: u64) -> Result<usize> {
+        let count = self.query_row_and_then_named(
+            &format!(
+                "SELECT COUNT(*) FROM loginsL WHERE {filter}",
+                filter = &*DELETED_READY_TO_PURGE_FILTER
+            ),
+            named_params! { ":cutoff_ms": purge_cutoff_ms(age_ms) },
+            |row| row.get::<_, i64>(0),
+            true,
+        )?;
+        Ok(count as usize)
+    }
+
+    /// Like `count_deleted_ready_to_purge`, but returns the GUIDs of those
+    /// tombstones.
+    pub fn get_deleted_ready_to_purge(&self, age_ms: u64) -> Result<Vec<String>> {
+        Ok(self.query_rows_and_then_named_cached(
+            &format!(
+                "SELECT guid FROM loginsL WHERE {filter}",
+                filter = &*DELETED_READY_TO_PURGE_FILTER
+            ),
+            named_params! { ":cutoff_ms": purge_cutoff_ms(age_ms) },
+            |row| row.get::<_, String>(0),
+        )?)
+    }
+
+    /// Deletes the synced tombstones which were deleted before
+    /// `older_than_ms` (a millisecond local timestamp) - the ones
+    /// `count_deleted_ready_to_purge` counts - and returns how many were
+    /// purged. Deletions which haven't been uploaded yet, and tombstones which
+    /// hide a mirror record, are left alone, so they're still synced.
+    pub fn purge_tombstones(&self, older_than_ms: i64) -> Result<usize> {
+        Ok(self.execute_named(
+            &format!(
+                "DELETE FROM loginsL WHERE {filter}",
+                filter = &*DELETED_READY_TO_PURGE_FILTER
+            ),
+            named_params! { ":cutoff_ms": older_than_ms },
+        )?)
+    }
+
+    /// Like `purge_tombstones`, for tombstones deleted more than
+    /// `DEFAULT_TOMBSTONE_MAX_AGE_MS` (30 days) ago.
+    pub fn purge_old_tombstones(&self) -> Result<usize> {
+        self.purge_tombstones(purge_cutoff_ms(DEFAULT_TOMBSTONE_MAX_AGE_MS))
+    }
+
+    /// Returns the number of tombstones in `loginsL`, whether or not they've
+    /// been synced.
+    pub fn get_tombstone_count(&self) -> Result<usize> {
+        let count: i64 = self.query_one("SELECT COUNT(*) FROM loginsL WHERE is_deleted = 1")?;
+        Ok(count as usize)
+    }
+
+    fn mark_mirror_overridden(&self, guid: &str) -> Result<()> {
+        self.execute_named_cached(
+            "UPDATE loginsM SET is_overridden = 1 WHERE guid = :guid",
+            named_params! { ":guid": guid },
+        )?;
+        Ok(())
+    }
+
+    fn ensure_local_overlay_exists(&self, guid: &str) -> Result<()> {
+        let already_have_local: bool = self.db.query_row_named(
+            "SELECT EXISTS(SELECT 1 FROM loginsL WHERE guid = :guid)",
+            named_params! { ":guid": guid },
+            |row| row.get(0),
+        )?;
+
+        if already_have_local {
+            return Ok(());
+        }
+
+        log::debug!("No overlay; cloning one for {:?}.", guid);
+        let changed = self.clone_mirror_to_overlay(guid)?;
+        if changed == 0 {
+            log::error!("Failed to create local overlay for GUID {:?}.", guid);
+            throw!(ErrorKind::NoSuchRecord(guid.to_owned()));
+        }
+        Ok(())
+    }
+
+    fn clone_mirror_to_overlay(&self, guid: &str) -> Result<usize> {
+        Ok(self
+            .execute_named_cached(&*CLONE_SINGLE_MIRROR_SQL, &[(":guid", &guid as &dyn ToSql)])?)
+    }
+
+    pub fn reset(&self, assoc: &StoreSyncAssociation) -> Result<()> {
+        log::info!("Executing reset on password store!");
+        let tx = self.unchecked_transaction()?;
+        self.with_change_event(LoginChangeEvent::Reset, || self.reset_in_tx(assoc))?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn reset_in_tx(&self, assoc: &StoreSyncAssociation) -> Result<()> {
+        self.execute_all(&[
+            &*CLONE_ENTIRE_MIRROR_SQL,
+            "DELETE FROM loginsM",
+            &format!("UPDATE loginsL SET sync_status = {}", SyncStatus::New as u8),
+        ])?;
+        self.set_last_sync(ServerTimestamp(0))?;
+        match assoc {
+            StoreSyncAssociation::Disconnected => {
+                self.delete_meta(schema::GLOBAL_SYNCID_META_KEY)?;
+                self.delete_meta(schema::COLLECTION_SYNCID_META_KEY)?;
+            }
+            StoreSyncAssociation::Connected(ids) => {
+                self.put_meta(schema::GLOBAL_SYNCID_META_KEY, &ids.global)?;
+                self.put_meta(schema::COLLECTION_SYNCID_META_KEY, &ids.coll)?;
+            }
+        };
+        self.delete_meta(schema::GLOBAL_STATE_META_KEY)?;
+        Ok(())
+    }
+
+    pub fn wipe(&self, scope: &SqlInterruptScope) -> Result<()> {
+        let tx = self.unchecked_transaction()?;
+        log::info!("Executing wipe on password store!");
+        self.with_change_event(LoginChangeEvent::Wiped, || self.wipe_in_tx(scope))?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn wipe_in_tx(&self, scope: &SqlInterruptScope) -> Result<()> {
+        let now_ms = util::system_time_ms_i64(SystemTime::now());
+        scope.err_if_interrupted()?;
+        if let Some(actor) = &self.audit_actor {
+            self.execute_named(
+                &format!(
+                    "INSERT INTO loginsAuditLog (guid, operation, timestamp_ms, actor)
+                     SELECT guid, :operation, :now_ms, :actor FROM ({all})",
+                    all = &*GET_ALL_SQL
+                ),
+                named_params! {
+                    ":operation": AuditOperation::Wipe,
+                    ":now_ms": now_ms,
+                    ":actor": actor,
+                },
+            )?;
+        }
+        self.execute_named(
+            &format!(
+                "
+                UPDATE loginsL
+                SET local_modified = :now_ms,
+                    sync_status = {changed},
+                    is_deleted = 1,
+                    password = '',
+                    hostname = '',
+                    username = ''
+                WHERE is_deleted = 0",
+                changed = SyncStatus::Changed as u8
+            ),
+            named_params! { ":now_ms": now_ms },
+        )?;
+        scope.err_if_interrupted()?;
+
+        self.execute("UPDATE loginsM SET is_overridden = 1", NO_PARAMS)?;
+        scope.err_if_interrupted()?;
+
+        self.execute_named(
+            &format!("
+                INSERT OR IGNORE INTO loginsL
+                      (guid, local_modified, is_deleted, sync_status, hostname, timeCreated, timePasswordChanged, password, username)
+                SELECT guid, :now_ms,        1,          {changed},   '',       timeCreated, :now_ms,             '',       ''
+                FROM loginsM",
+                changed = SyncStatus::Changed as u8),
+            named_params! { ":now_ms": now_ms })?;
+        scope.err_if_interrupted()?;
+        Ok(())
+    }
+
+    pub fn wipe_local(&self) -> Result<()> {
+        log::info!("Executing wipe_local on password store!");
+        let tx = self.unchecked_transaction()?;
+        self.with_change_event(LoginChangeEvent::Wiped, || {
+            Ok(self.execute_all(&[
+                "DELETE FROM loginsL",
+                "DELETE FROM loginsM",
+                "DELETE FROM loginsSyncMeta",
+                "DELETE FROM loginsQuarantine",
+            ])?)
+        })?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Throws away local changes which haven't been uploaded yet: logins
+    /// added since the last sync are deleted, and changed (or deleted) ones
+    /// go back to how they are in the mirror. Local records which are
+    /// `Synced` (for example, ones which have only been touched) are kept.
+    pub fn wipe_local_changes(&self) -> Result<()> {
+        let tx = self.unchecked_transaction()?;
+        self.execute_named(
+            "UPDATE loginsM SET is_overridden = 0
+             WHERE guid IN (SELECT guid FROM loginsL WHERE sync_status IS NOT :synced)",
+            named_params! { ":synced": SyncStatus::Synced as u8 },
+        )?;
+        self.execute_named(
+            "DELETE FROM loginsL WHERE sync_status IS NOT :synced",
+            named_params! { ":synced": SyncStatus::Synced as u8 },
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Deletes everything in the mirror, without touching local records, so
+    /// tests which set up the mirror by hand can start from a clean slate.
+    /// Never do this outside of tests - it would break syncing.
+    #[cfg(test)]
+    pub fn wipe_mirror(&self) -> Result<()> {
+        self.execute_all(&["DELETE FROM loginsM"])?;
+        Ok(())
+    }
+
+    /// Inserts `login` directly into the mirror, so tests can set up sync
+    /// states without going through a whole sync.
+    #[cfg(test)]
+    pub fn insert_mirror_record(&self, login: MirrorLogin) -> Result<()> {
+        let mut plan = UpdatePlan::default();
+        plan.plan_mirror_insert(login.login, login.server_modified, login.is_overridden);
+        self.execute_plan(plan, &self.begin_interrupt_scope())
+    }
+
+    /// Like `wipe_mirror`, but for the local records. Unlike `wipe_local`,
+    /// this leaves the mirror and sync metadata alone.
+    #[cfg(test)]
+    pub fn wipe_local_table(&self) -> Result<()> {
+        self.execute_all(&["DELETE FROM loginsL"])?;
+        Ok(())
+    }
+
+    fn reconcile(
+        &self,
+        records: Vec<SyncLoginData>,
+        server_now: ServerTimestamp,
+        policy: ConflictResolution,
+        telem: &mut telemetry::EngineIncoming,
+        scope: &SqlInterruptScope,
+        progress: &mut dyn FnMut(SyncProgress),
+    ) -> Result<UpdatePlan> {
+        let mut plan = UpdatePlan::default();
+        let total = records.len();
+        let mut last_guid = String::new();
+
+        for (processed, mut record) in records.into_iter().enumerate() {
+            if processed > 0 && processed % SYNC_PROGRESS_BATCH_SIZE == 0 {
+                progress(SyncProgress {
+                    stage: SyncProgressStage::Reconciling,
+                    processed,
+                    total,
+                    last_guid: last_guid.clone(),
+                });
+            }
+            scope.err_if_interrupted()?;
+            last_guid = record.guid().to_string();
+            log::debug!("Processing remote change {}", record.guid());
+            let upstream = if let Some(inbound) = record.inbound.0.take() {
+                inbound
+            } else {
+                log::debug!("Processing inbound deletion (always prefer)");
+                plan.plan_delete(record.guid.clone());
+                continue;
+            };
+            let upstream_time = record.inbound.1;
+            match (record.mirror.take(), record.local.take()) {
+                (mirror, Some(local)) if policy != ConflictResolution::FieldLevel => {
+                    let local_wins = match policy {
+                        ConflictResolution::ClientWins => true,
+                        ConflictResolution::NewerWins => {
+                            util::system_time_ms_i64(local.local_modified)
+                                > upstream_time.as_millis()
+                        }
+                        _ => false,
+                    };
+                    log::debug!(
+                        "  Conflict between remote and local, {} wins by {:?}",
+                        if local_wins { "local" } else { "remote" },
+                        policy
+                    );
+                    plan.plan_pick_winner(
+                        &local.login,
+                        mirror.is_some(),
+                        (upstream, upstream_time),
+                        local_wins,
+                    );
+                    telem.reconciled(1);
+                }
+                (Some(mirror), Some(local)) => {
+                    log::debug!("  Conflict between remote and local, Resolving with 3WM");
+                    plan.plan_field_level_merge(local, mirror, upstream, upstream_time, server_now);
+                    telem.reconciled(1);
+                }
+                (Some(_mirror), None) => {
+                    log::debug!("  Forwarding mirror to remote");
+                    plan.plan_mirror_update(upstream, upstream_time);
+                    telem.applied(1);
+                }
+                (None, Some(local)) => {
+                    log::debug!("  Conflicting record without shared parent, using newer");
+                    plan.plan_two_way_merge(&local.login, (upstream, upstream_time));
+                    telem.reconciled(1);
+                }
+                (None, None) => {
+                    if let Some(dupe) = self.find_dupe(&upstream)? {
+                        log::debug!(
+                            "  Incoming record {} was is a dupe of local record {}",
+                            upstream.guid,
+                            dupe.guid
+                        );
+                        plan.plan_two_way_merge(&dupe, (upstream, upstream_time));
+                    } else {
+                        log::debug!("  No dupe found, inserting into mirror");
+                        plan.plan_mirror_insert(upstream, upstream_time, false);
+                    }
+                    telem.applied(1);
+                }
+            }
+        }
+        if total > 0 {
+            progress(SyncProgress {
+                stage: SyncProgressStage::Reconciling,
+                processed: total,
+                total,
+                last_guid,
+            });
+        }
+        Ok(plan)
+    }
+
+    fn execute_plan(&self, plan: UpdatePlan, scope: &SqlInterruptScope) -> Result<()> {
+        // Because rusqlite want a mutable reference to create a transaction
+        // (as a way to save us from ourselves), we side-step that by creating
+        // it manually. This also means it becomes a savepoint when called
+        // from `execute_in_transaction`.
+        let tx = self.unchecked_transaction()?;
+        plan.execute(&tx, scope)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    pub fn fetch_outgoing(
+        &self,
+        st: ServerTimestamp,
+        scope: &SqlInterruptScope,
+    ) -> Result<OutgoingChangeset> {
+        // Taken from iOS. Arbitrarily large, so that clients that want to
+        // process deletions first can; for us it doesn't matter.
+        const TOMBSTONE_SORTINDEX: i32 = 5_000_000;
+        const DEFAULT_SORTINDEX: i32 = 1;
+        let mut outgoing = OutgoingChangeset::new("passwords", st);
+        let mut stmt = self.db.prepare_cached(&format!(
+            "SELECT * FROM loginsL WHERE sync_status IS NOT {synced}",
+            synced = SyncStatus::Synced as u8
+        ))?;
+        let rows = stmt.query_and_then(NO_PARAMS, |row| {
+            scope.err_if_interrupted()?;
+            Ok(if row.get::<_, bool>("is_deleted")? {
+                Payload::new_tombstone(row.get::<_, String>("guid")?)
+                    .with_sortindex(TOMBSTONE_SORTINDEX)
+            } else {
+                let login = Login::from_row(row)?;
+                Payload::from_record(login)?.with_sortindex(DEFAULT_SORTINDEX)
+            })
+        })?;
+        outgoing.changes = rows.collect::<Result<_>>()?;
+
+        Ok(outgoing)
+    }
+
+    /// Returns the number of records (including tombstones) `fetch_outgoing`
+    /// would return, without loading them.
+    pub fn get_outgoing_count(&self) -> Result<usize> {
+        let count: i64 = self.query_row_and_then_named(
+            "SELECT COUNT(*) FROM loginsL WHERE sync_status IS NOT :synced",
+            named_params! { ":synced": SyncStatus::Synced as u8 },
+            |row| row.get(0),
+            true,
+        )?;
+        Ok(count as usize)
+    }
+
+    /// Returns true if there are local changes to upload.
+    pub fn has_pending_changes(&self) -> Result<bool> {
+        Ok(self.get_outgoing_count()? > 0)
+    }
+
+    /// Applies a JSON array of login records as if they had just been
+    /// downloaded from the server at `server_timestamp_seconds`. Tombstones
+    /// are records with `"deleted": true`. This makes it easy to exercise the
+    /// reconciliation logic (for example, with JSON fixtures) without needing
+    /// a full sync stack.
+    pub fn apply_incoming_from_json(
+        &self,
+        json: &str,
+        server_timestamp_seconds: f64,
+    ) -> Result<OutgoingChangeset> {
+        let payloads: Vec<Payload> = serde_json::from_str(json)?;
+        let timestamp = ServerTimestamp::from_float_seconds(server_timestamp_seconds);
+        let mut inbound = IncomingChangeset::new("passwords", timestamp);
+        inbound.changes = payloads.into_iter().map(|p| (p, timestamp)).collect();
+        let scope = self.begin_interrupt_scope();
+        let mut telem = telemetry::Engine::new("passwords");
+        self.do_apply_incoming(inbound, &mut telem, &scope)
+    }
+
+    /// Estimates the number of bytes we'd upload if we synced now, by summing
+    /// the size of the JSON for each outgoing record. This is only an estimate,
+    /// since the BSOs we actually upload add some overhead to each record.
+    pub fn get_estimated_sync_payload_size(&self) -> Result<usize> {
+        let scope = self.begin_interrupt_scope();
+        let outgoing = self.fetch_outgoing(ServerTimestamp(0), &scope)?;
+        Ok(outgoing
+            .changes
+            .into_iter()
+            .map(|payload| payload.into_json_string().len())
+            .sum())
+    }
+
+    fn do_apply_incoming(
+        &self,
+        inbound: IncomingChangeset,
+        telem: &mut telemetry::Engine,
+        scope: &SqlInterruptScope,
+    ) -> Result<OutgoingChangeset> {
+        let policy = self.conflict_resolution();
+        self.do_apply_incoming_with_policy(inbound, policy, telem, scope, &mut |_| {})
+    }
+
+    fn do_apply_incoming_with_policy(
+        &self,
+        inbound: IncomingChangeset,
+        policy: ConflictResolution,
+        telem: &mut telemetry::Engine,
+        scope: &SqlInterruptScope,
+        progress: &mut dyn FnMut(SyncProgress),
+    ) -> Result<OutgoingChangeset> {
+        let mut incoming_telemetry = telemetry::EngineIncoming::new();
+        let result =
+            self.apply_incoming_records(&inbound, policy, &mut incoming_telemetry, scope, progress);
+        telem.incoming(incoming_telemetry);
+        result?;
+        Ok(self.fetch_outgoing(inbound.timestamp, scope)?)
+    }
+
+    // Reconciles the records in `inbound` with what we have locally, and
+    // applies the result.
+    fn apply_incoming_records(
+        &self,
+        inbound: &IncomingChangeset,
+        policy: ConflictResolution,
+        telem: &mut telemetry::EngineIncoming,
+        scope: &SqlInterruptScope,
+        progress: &mut dyn FnMut(SyncProgress),
+    ) -> Result<()> {
+        let data = self.fetch_login_data(&inbound.changes, telem, scope, progress)?;
+        let plan = self.reconcile(data, inbound.timestamp, policy, telem, scope, progress)?;
+        self.execute_plan(plan, scope)
+    }
+
+    /// Works out what applying `inbound` during a sync would change, without
+    /// changing anything. Records are reconciled exactly as a sync would
+    /// reconcile them, but the resulting plan is only inspected, never
+    /// executed.
+    pub fn dry_run_apply_incoming(&self, inbound: IncomingChangeset) -> Result<SyncPreview> {
+        let scope = self.begin_interrupt_scope();
+        let mut telem = telemetry::EngineIncoming::new();
+        let data = self.fetch_login_data(&inbound.changes, &mut telem, &scope, &mut |_| {})?;
+        let policy = self.conflict_resolution();
+        let plan = self.reconcile(
+            data,
+            inbound.timestamp,
+            policy,
+            &mut telem,
+            &scope,
+            &mut |_| {},
+        )?;
+
+        let mut preview = SyncPreview::default();
+        let merged: HashSet<&Guid> = plan.local_updates.iter().map(|l| &l.login.guid).collect();
+        let inserted: HashSet<&Guid> = plan
+            .mirror_inserts
+            .iter()
+            .filter(|(_, _, is_overridden)| !is_overridden)
+            .map(|(login, _, _)| &login.guid)
+            .collect();
+        for local in &plan.local_updates {
+            if let Some(old) = self.get_by_id(local.guid_str())? {
+                preview.to_update.push((old, local.login.clone()));
+            }
+        }
+        // Mirror updates are only visible for logins without local changes.
+        for (login, _) in &plan.mirror_updates {
+            if merged.contains(&login.guid) || self.get_local_record(login.guid_str())?.is_some() {
+                continue;
+            }
+            if let Some(old) = self.get_by_id(login.guid_str())? {
+                preview.to_update.push((old, login.clone()));
+            }
+        }
+        // Overridden mirror inserts are hidden by the local record that won.
+        for (login, _, is_overridden) in &plan.mirror_inserts {
+            if *is_overridden {
+                continue;
+            }
+            match self.get_by_id(login.guid_str())? {
+                Some(old) => preview.to_update.push((old, login.clone())),
+                None => preview.to_insert.push(login.clone()),
+            }
+        }
+        let deleted: HashSet<&Guid> = plan
+            .delete_local
+            .iter()
+            .chain(plan.delete_mirror.iter())
+            .filter(|guid| !inserted.contains(guid))
+            .collect();
+        for guid in deleted {
+            if self.exists(guid.as_str())? {
+                preview.to_delete.push(guid.to_string());
+            }
+        }
+        preview.to_delete.sort();
+
+        preview.to_upload = plan
+            .local_updates
+            .iter()
+            .map(|local| local.login.clone())
+            .collect();
+        for payload in self.fetch_outgoing(inbound.timestamp, &scope)?.changes {
+            if payload.is_tombstone() {
+                continue;
+            }
+            let login: Login = payload.into_record()?;
+            if !merged.contains(&login.guid) && !plan.delete_local.contains(&login.guid) {
+                preview.to_upload.push(login);
+            }
+        }
+        Ok(preview)
+    }
+
+    /// Applies an incoming changeset as a sync would, calling `progress` as
+    /// each batch of records is fetched, and then as each batch is
+    /// reconciled, so that large syncs can report how far they've got.
+    pub fn apply_incoming_with_progress<F>(
+        &self,
+        inbound: IncomingChangeset,
+        mut progress: F,
+    ) -> Result<OutgoingChangeset>
+    where
+        F: FnMut(SyncProgress),
+    {
+        let scope = self.begin_interrupt_scope();
+        let mut telem = telemetry::Engine::new("passwords");
+        let policy = self.conflict_resolution();
+        self.do_apply_incoming_with_policy(inbound, policy, &mut telem, &scope, &mut progress)
+    }
+
+    /// Applies the local changes from `other` (everything it would upload if
+    /// it synced now) to this database, for syncing directly between two
+    /// devices without a server. The changes are applied as local changes
+    /// here too, so they're uploaded by the next sync, and the mirror still
+    /// matches the server.
+    ///
+    /// An incoming login updates the local login with the same GUID, or
+    /// failing that, a duplicate of it (as `find_dupe` sees it), keeping the
+    /// incoming usage metadata. Otherwise it's added, unless it duplicates a
+    /// login which is only in the mirror. Logins deleted here stay deleted,
+    /// and incoming tombstones delete the local login.
+    /// Incoming logins are fixed up and validated as `add` would, and ones
+    /// which are still invalid are counted as failed and skipped.
+    pub fn apply_local_changes_from_other_db(&mut self, other: &LoginDb) -> Result<SyncStats> {
+        let changes: Vec<LocalLogin> = other.query_rows_and_then_named_cached(
+            "SELECT * FROM loginsL WHERE sync_status IS NOT :synced",
+            named_params! { ":synced": SyncStatus::Synced as u8 },
+            LocalLogin::from_row,
+        )?;
+        let mut stats = SyncStats {
+            incoming: changes.len(),
+            ..SyncStats::default()
+        };
+        let limits = self.field_limits();
+        let tx = self.unchecked_transaction()?;
+        let now_ms = util::system_time_ms_i64(SystemTime::now());
+        for change in changes {
+            if change.is_deleted {
+                if self.exists(change.guid_str())? {
+                    self.delete_in_tx(change.guid_str(), now_ms)?;
+                    stats.applied += 1;
+                }
+                continue;
+            }
+            let login = match change.login.fixup_with_limits(&limits) {
+                Ok(login) => login,
+                Err(e) => {
+                    log::warn!("Skipping invalid record from the other database: {}", e);
+                    stats.failed += 1;
+                    continue;
+                }
+            };
+            if self.is_deleted_locally(&login.guid)? {
+                stats.reconciled += 1;
+                continue;
+            }
+            let existing = match self.get_by_id(&login.guid)? {
+                Some(existing) => Some(existing),
+                None => self.find_dupe(&login)?,
+            };
+            match existing {
+                Some(existing) if Login::diff(&existing, &login).is_empty() => {
+                    stats.reconciled += 1;
+                }
+                Some(existing) => {
+                    self.replace_in_tx(
+                        &Login {
+                            guid: existing.guid,
+                            ..login
+                        },
+                        now_ms,
+                    )?;
+                    stats.applied += 1;
+                }
+                // `find_dupe` only looks at local records, so this catches
+                // duplicates of logins which are only in the mirror.
+                None if self.dupe_exists(&login)? => {
+                    stats.reconciled += 1;
+                }
+                None => {
+                    self.add_in_tx(login, now_ms)?;
+                    stats.applied += 1;
+                }
+            }
+        }
+        tx.commit()?;
+        Ok(stats)
+    }
+
+    /// Like applying an incoming changeset during a sync, but resolving
+    /// conflicts with local changes according to `policy` instead of always
+    /// merging. Incoming tombstones and records which only match a local
+    /// record as a dupe are handled as usual.
+    pub fn apply_incoming_with_policy(
+        &self,
+        inbound: IncomingChangeset,
+        policy: ConflictResolution,
+    ) -> Result<OutgoingChangeset> {
+        let scope = self.begin_interrupt_scope();
+        let mut telem = telemetry::Engine::new("passwords");
+        self.do_apply_incoming_with_policy(inbound, policy, &mut telem, &scope, &mut |_| {})
+    }
+
+    /// Applies an incoming changeset which may contain more than one record
+    /// for the same GUID, keeping only the newest record for each. Returns the
+    /// outgoing changeset along with the number of duplicates which were
+    /// dropped.
+    pub fn apply_incoming_with_dedup(
+        &self,
+        mut inbound: IncomingChangeset,
+    ) -> Result<(OutgoingChangeset, usize)> {
+        let num_dupes = inbound.dedup_by_guid();
+        if num_dupes > 0 {
+            log::warn!(
+                "Dropped {} incoming records with duplicate GUIDs",
+                num_dupes
+            );
+        }
+        let scope = self.begin_interrupt_scope();
+        let mut telem = telemetry::Engine::new("passwords");
+        let outgoing = self.do_apply_incoming(inbound, &mut telem, &scope)?;
+        Ok((outgoing, num_dupes))
+    }
+
+    fn put_meta(&self, key: &str, value: &dyn ToSql) -> Result<()> {
+        self.execute_named_cached(
+            "REPLACE INTO loginsSyncMeta (key, value) VALUES (:key, :value)",
+            named_params! { ":key": key, ":value": value },
+        )?;
+        Ok(())
+    }
+
+    fn get_meta<T: FromSql>(&self, key: &str) -> Result<Option<T>> {
+        Ok(self.try_query_row(
+            "SELECT value FROM loginsSyncMeta WHERE key = :key",
+            named_params! { ":key": key },
+            |row| Ok::<_, Error>(row.get(0)?),
+            true,
+        )?)
+    }
+
+    fn delete_meta(&self, key: &str) -> Result<()> {
+        self.execute_named_cached(
+            "DELETE FROM loginsSyncMeta WHERE key = :key",
+            named_params! { ":key": key },
+        )?;
+        Ok(())
+    }
+
+    fn set_last_sync(&self, last_sync: ServerTimestamp) -> Result<()> {
+        log::debug!("Updating last sync to {}", last_sync);
+        let last_sync_millis = last_sync.as_millis() as i64;
+        self.put_meta(schema::LAST_SYNC_META_KEY, &last_sync_millis)
+    }
+
+    fn get_last_sync(&self) -> Result<Option<ServerTimestamp>> {
+        Ok(self
+            .get_meta::<i64>(schema::LAST_SYNC_META_KEY)?
+            .map(ServerTimestamp))
+    }
+
+    /// How long ago, in milliseconds, we last synced, or `None` if we never
+    /// have.
+    pub fn get_last_sync_age_ms(&self) -> Result<Option<i64>> {
+        let now_ms = util::system_time_ms_i64(SystemTime::now());
+        Ok(self
+            .get_last_sync()?
+            .map(|last_sync| now_ms - last_sync.as_millis()))
+    }
+
+    /// Returns true if we've never synced, or last synced more than
+    /// `max_age_ms` ago.
+    pub fn needs_sync(&self, max_age_ms: i64) -> Result<bool> {
+        Ok(match self.get_last_sync_age_ms()? {
+            Some(age_ms) => age_ms > max_age_ms,
+            None => true,
+        })
+    }
+
+    pub fn set_global_state(&self, state: &Option<String>) -> Result<()> {
+        let to_write = match state {
+            Some(ref s) => s,
+            None => "",
+        };
+        self.put_meta(schema::GLOBAL_STATE_META_KEY, &to_write)
+    }
+
+    pub fn get_global_state(&self) -> Result<Option<String>> {
+        self.get_meta::<String>(schema::GLOBAL_STATE_META_KEY)
+    }
+
+    /// A utility we can kill by the end of 2019 ;)
+    pub fn migrate_global_state(&self) -> Result<()> {
+        let tx = self.unchecked_transaction_imm()?;
+        if let Some(old_state) = self.get_meta("global_state")? {
+            log::info!("there's old global state - migrating");
+            let (new_sync_ids, new_global_state) = extract_v1_state(old_state, "passwords");
+            if let Some(sync_ids) = new_sync_ids {
+                self.put_meta(schema::GLOBAL_SYNCID_META_KEY, &sync_ids.global)?;
+                self.put_meta(schema::COLLECTION_SYNCID_META_KEY, &sync_ids.coll)?;
+                log::info!("migrated the sync IDs");
+            }
+            if let Some(new_global_state) = new_global_state {
+                self.set_global_state(&Some(new_global_state))?;
+                log::info!("migrated the global state");
+            }
+            self.delete_meta("global_state")?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+}
+
+pub struct LoginStore<'a> {
+    pub db: &'a LoginDb,
+    pub scope: sql_support::SqlInterruptScope,
+}
+
+impl<'a> LoginStore<'a> {
+    pub fn new(db: &'a LoginDb) -> Self {
+        Self {
+            db,
+            scope: db.begin_interrupt_scope(),
+        }
+    }
+}
+
+impl<'a> Store for LoginStore<'a> {
+    fn collection_name(&self) -> std::borrow::Cow<'static, str> {
+        "passwords".into()
+    }
+
+    fn set_conflict_resolution(&mut self, strategy: ConflictResolution) {
+        self.db.set_conflict_resolution(strategy);
+    }
+
+    fn apply_incoming(
+        &self,
+        inbound: Vec<IncomingChangeset>,
+        telem: &mut telemetry::Engine,
+    ) -> result::Result<OutgoingChangeset, failure::Error> {
+        assert_eq!(inbound.len(), 1, "logins only requests one item");
+        let inbound = inbound.into_iter().next().unwrap();
+        Ok(self.db.do_apply_incoming(inbound, telem, &self.scope)?)
+    }
+
+    fn sync_finished(
+        &self,
+        new_timestamp: ServerTimestamp,
+        records_synced: Vec<Guid>,
+    ) -> result::Result<(), failure::Error> {
+        self.db.mark_as_synchronized(
+            &records_synced.iter().map(Guid::as_str).collect::<Vec<_>>(),
+            new_timestamp,
+            &self.scope,
+        )?;
+        Ok(())
+    }
+
+    fn get_collection_requests(
+        &self,
+        server_timestamp: ServerTimestamp,
+    ) -> result::Result<Vec<CollectionRequest>, failure::Error> {
+        let since = self.db.get_last_sync()?.unwrap_or_default();
+        Ok(if since == server_timestamp {
+            vec![]
+        } else {
+            vec![CollectionRequest::new("passwords").full().newer_than(since)]
+        })
+    }
+
+    fn get_sync_assoc(&self) -> result::Result<StoreSyncAssociation, failure::Error> {
+        let global = self.db.get_meta(schema::GLOBAL_SYNCID_META_KEY)?;
+        let coll = self.db.get_meta(schema::COLLECTION_SYNCID_META_KEY)?;
+        Ok(if let (Some(global), Some(coll)) = (global, coll) {
+            StoreSyncAssociation::Connected(CollSyncIds { global, coll })
+        } else {
+            StoreSyncAssociation::Disconnected
+        })
+    }
+
+    fn reset(&self, assoc: &StoreSyncAssociation) -> result::Result<(), failure::Error> {
+        self.db.reset(assoc)?;
+        Ok(())
+    }
+
+    fn wipe(&self) -> result::Result<(), failure::Error> {
+        self.db.wipe(&self.scope)?;
+        Ok(())
+    }
+}
+
+/// How old tombstones must be for `LoginDb::purge_old_tombstones` to purge
+/// them.
+pub const DEFAULT_TOMBSTONE_MAX_AGE_MS: u64 = 30 * 24 * 60 * 60 * 1000;
+
+lazy_static! {
+    // Tombstones for deletions which have finished syncing, and which don't
+    // hide a mirror record. Tombstones which haven't been uploaded yet are
+    // never ready, however old, since purging them would lose the deletion.
+    static ref DELETED_READY_TO_PURGE_FILTER: String = format!(
+        "is_deleted = 1
+         AND sync_status = {synced}
+         AND local_modified < :cutoff_ms
+         AND guid NOT IN (SELECT guid FROM loginsM)",
+        synced = SyncStatus::Synced as u8
+    );
+}
+
+// Logins which haven't been used since they were created, which `add` records
+// by setting both timestamps to the same time. (`timesUsed` can't be used for
+// this, since `add` treats 0 as unset, and starts it at 1.) Must match
+// `Login::is_never_used`.
+const NEVER_USED_FILTER: &str = "ifnull(timeLastUsed, 0) <= timeCreated";
+
+// The columns whose values consumers see, for working out whether a write
+// changed a login or just its sync bookkeeping.
+const LOGIN_CONTENT_COLS: &[&str] = &[
+    "hostname",
+    "httpRealm",
+    "formSubmitURL",
+    "usernameField",
+    "passwordField",
+    "username",
+    "password",
+    "timesUsed",
+    "timeLastUsed",
+    "timePasswordChanged",
+];
+
+// An SQL expression which is true if the logins `a` and `b` (table names or
+// aliases, or `OLD` and `NEW` in a trigger) differ in any of their content.
+fn login_content_differs(a: &str, b: &str) -> String {
+    LOGIN_CONTENT_COLS
+        .iter()
+        .map(|col| format!("{a}.{col} IS NOT {b}.{col}", a = a, b = b, col = col))
+        .collect::<Vec<_>>()
+        .join(" OR ")
+}
+
+lazy_static! {
+    // The last argument to `logins_change_hook` is how the change looks to
+    // consumers, who see the local record for a GUID if there is one, and
+    // the mirror record otherwise, or NULL if they can't see a difference.
+    static ref CREATE_CHANGE_HOOK_TRIGGERS_SQL: String = format!(
+        "
+        CREATE TEMP TRIGGER IF NOT EXISTS loginsL_change_hook_insert
+        AFTER INSERT ON loginsL
+        BEGIN
+            SELECT logins_change_hook(
+                CASE WHEN NEW.is_deleted THEN 'delete' ELSE 'insert' END,
+                'loginsL',
+                NEW.guid,
+                CASE
+                    WHEN NOT EXISTS(SELECT 1 FROM loginsM WHERE guid = NEW.guid)
+                        THEN CASE WHEN NEW.is_deleted THEN NULL ELSE 'added' END
+                    WHEN NEW.is_deleted THEN 'deleted'
+                    WHEN EXISTS(
+                        SELECT 1 FROM loginsM m
+                        WHERE m.guid = NEW.guid AND ({new_differs_from_mirror})
+                    ) THEN 'updated'
+                END
+            );
+        END;
+
+        CREATE TEMP TRIGGER IF NOT EXISTS loginsL_change_hook_update
+        AFTER UPDATE ON loginsL
+        BEGIN
+            SELECT logins_change_hook(
+                CASE WHEN NEW.is_deleted AND NOT OLD.is_deleted THEN 'delete' ELSE 'update' END,
+                'loginsL',
+                NEW.guid,
+                CASE
+                    WHEN NEW.is_deleted AND NOT OLD.is_deleted THEN 'deleted'
+                    WHEN OLD.is_deleted AND NOT NEW.is_deleted THEN 'added'
+                    WHEN NOT NEW.is_deleted AND ({new_differs_from_old}) THEN 'updated'
+                END
+            );
+        END;
+
+        CREATE TEMP TRIGGER IF NOT EXISTS loginsL_change_hook_delete
+        AFTER DELETE ON loginsL
+        BEGIN
+            SELECT logins_change_hook(
+                'delete',
+                'loginsL',
+                OLD.guid,
+                CASE
+                    WHEN OLD.is_deleted THEN NULL
+                    WHEN NOT EXISTS(SELECT 1 FROM loginsM WHERE guid = OLD.guid) THEN 'deleted'
+                    WHEN EXISTS(
+                        SELECT 1 FROM loginsM m
+                        WHERE m.guid = OLD.guid AND ({old_differs_from_mirror})
+                    ) THEN 'updated'
+                END
+            );
+        END;
+
+        CREATE TEMP TRIGGER IF NOT EXISTS loginsM_change_hook_insert
+        AFTER INSERT ON loginsM
+        BEGIN
+            SELECT logins_change_hook(
+                'insert',
+                'loginsM',
+                NEW.guid,
+                CASE WHEN NOT EXISTS(SELECT 1 FROM loginsL WHERE guid = NEW.guid) THEN 'added' END
+            );
+        END;
+
+        CREATE TEMP TRIGGER IF NOT EXISTS loginsM_change_hook_update
+        AFTER UPDATE ON loginsM
+        BEGIN
+            SELECT logins_change_hook(
+                'update',
+                'loginsM',
+                NEW.guid,
+                CASE
+                    WHEN NOT EXISTS(SELECT 1 FROM loginsL WHERE guid = NEW.guid)
+                         AND ({new_differs_from_old})
+                        THEN 'updated'
+                END
+            );
+        END;
+
+        CREATE TEMP TRIGGER IF NOT EXISTS loginsM_change_hook_delete
+        AFTER DELETE ON loginsM
+        BEGIN
+            SELECT logins_change_hook(
+                'delete',
+                'loginsM',
+                OLD.guid,
+                CASE WHEN NOT EXISTS(SELECT 1 FROM loginsL WHERE guid = OLD.guid) THEN 'deleted' END
+            );
+        END;
+        ",
+        new_differs_from_mirror = login_content_differs("m", "NEW"),
+        new_differs_from_old = login_content_differs("OLD", "NEW"),
+        old_differs_from_mirror = login_content_differs("m", "OLD"),
+    );
+}
+
+/// The shortest query `LoginDb::query_logins` will search for.
+const MIN_QUERY_LEN: usize = 2;
+
+/// The most logins `get_all_by_guid_prefix` will return.
+const MAX_GUID_PREFIX_MATCHES: u32 = 10;
+
+const STREAM_PAGE_SIZE: u32 = 100;
+
+// How `get_logins_for_autocomplete` scores logins.
+const AUTOCOMPLETE_EXACT_HOST_SCORE: f32 = 1.0;
+const AUTOCOMPLETE_SAME_DOMAIN_SCORE: f32 = 0.8;
+const AUTOCOMPLETE_FORM_SUBMIT_BONUS: f32 = 0.2;
+const AUTOCOMPLETE_TIMES_USED_WEIGHT: f32 = 0.02;
+const AUTOCOMPLETE_RECENCY_WEIGHT: f32 = 0.1;
+/// The recency bonus falls by a factor of `e` every 30 days.
+const AUTOCOMPLETE_RECENCY_DECAY_MS: f32 = 30.0 * 24.0 * 60.0 * 60.0 * 1000.0;
+
+/// How many records `reconcile` gets through between progress reports.
+const SYNC_PROGRESS_BATCH_SIZE: usize = 100;
+
+const GET_FOR_FORM_FILL_SQL: &str = "
+    SELECT guid, username, password FROM loginsL
+    WHERE is_deleted = 0
+      AND hostname = :hostname
+      AND username = :username
+
+    UNION ALL
+
+    SELECT guid, username, password FROM loginsM
+    WHERE is_overridden = 0
+      AND hostname = :hostname
+      AND username = :username
+
+    LIMIT 1";
+
+const UNLOCK_LOGIN_SQL: &str = "
+    SELECT password FROM loginsL
+    WHERE is_deleted = 0
+      AND guid = :guid
+
+    UNION ALL
+
+    SELECT password FROM loginsM
+    WHERE is_overridden IS NOT 1
+      AND guid = :guid";
+
+const GET_ALL_HOSTNAMES_SQL: &str = "
+    SELECT hostname FROM loginsL WHERE is_deleted = 0
+    UNION
+    SELECT hostname FROM loginsM WHERE is_overridden = 0
+    ORDER BY hostname ASC";
+
+const HAS_ANY_LOGINS_SQL: &str = "
+    SELECT EXISTS(SELECT 1 FROM loginsL WHERE is_deleted = 0)
+        OR EXISTS(SELECT 1 FROM loginsM WHERE is_overridden = 0)";
+
+const GET_HOSTNAMES_MATCHING_SQL: &str = "
+    SELECT hostname FROM loginsL
+    WHERE is_deleted = 0
+      AND hostname LIKE :prefix || '%' ESCAPE '\\'
+    UNION
+    SELECT hostname FROM loginsM
+    WHERE is_overridden = 0
+      AND hostname LIKE :prefix || '%' ESCAPE '\\'
+    ORDER BY hostname ASC";
+
+lazy_static! {
+    static ref GET_ALL_SQL: String = format!(
+        "SELECT {common_cols} FROM loginsL WHERE is_deleted = 0
+         UNION ALL
+         SELECT {common_cols} FROM loginsM WHERE is_overridden = 0",
+        common_cols = schema::COMMON_COLS,
+    );
+    static ref GET_ALL_FOR_SOURCE_SQL: String = format!(
+        "SELECT {common_cols} FROM loginsL
+         WHERE is_deleted = 0
+           AND creation_source = :creation_source
+           AND creation_source_name IS :creation_source_name
+         UNION ALL
+         SELECT {common_cols} FROM loginsM
+         WHERE is_overridden = 0
+           AND creation_source = :creation_source
+           AND creation_source_name IS :creation_source_name",
+        common_cols = schema::COMMON_COLS,
+    );
+    static ref GET_BY_GUID_PREFIX_SQL: String = format!(
+        "SELECT {common_cols} FROM loginsL
+         WHERE is_deleted = 0
+           AND guid LIKE :prefix || '%' ESCAPE '\\'
+         UNION ALL
+         SELECT {common_cols} FROM loginsM
+         WHERE is_overridden = 0
+           AND guid LIKE :prefix || '%' ESCAPE '\\'
+         LIMIT :limit",
+        common_cols = schema::COMMON_COLS,
+    );
+    static ref QUERY_LOGINS_SQL: String = format!(
+        "SELECT * FROM ({all})
+         WHERE hostname LIKE '%' || :pattern || '%' ESCAPE '\\'
+            OR username LIKE '%' || :pattern || '%' ESCAPE '\\'
+            OR usernameField LIKE '%' || :pattern || '%' ESCAPE '\\'
+         ORDER BY
+            CASE
+                WHEN hostname LIKE :pattern || '%' ESCAPE '\\'
+                  OR hostname LIKE '%://' || :pattern || '%' ESCAPE '\\' THEN 0
+                WHEN username = :query COLLATE NOCASE THEN 1
+                ELSE 2
+            END,
+            hostname ASC,
+            guid ASC",
+        all = &*GET_ALL_SQL,
+    );
+    static ref GET_FOR_AUTOCOMPLETE_SQL: String = format!(
+        "SELECT * FROM ({all})
+         WHERE hostname LIKE '%' || :base_domain || '%' ESCAPE '\\'",
+        all = &*GET_ALL_SQL,
+    );
+    static ref GET_BY_USERNAME_PREFIX_SQL: String = format!(
+        "SELECT {common_cols} FROM loginsL
+         WHERE is_deleted = 0
+           AND hostname = :hostname
+           AND username LIKE :prefix || '%' ESCAPE '\\'
+         UNION ALL
+         SELECT {common_cols} FROM loginsM
+         WHERE is_overridden = 0
+           AND hostname = :hostname
+           AND username LIKE :prefix || '%' ESCAPE '\\'
+         ORDER BY timesUsed DESC",
+        common_cols = schema::COMMON_COLS,
+    );
+    static ref GET_BY_HOSTNAME_SQL: String = format!(
+        "SELECT {common_cols} FROM loginsL
+         WHERE is_deleted = 0
+           AND hostname = :hostname
+         UNION ALL
+         SELECT {common_cols} FROM loginsM
+         WHERE is_overridden = 0
+           AND hostname = :hostname
+         ORDER BY timeLastUsed DESC",
+        common_cols = schema::COMMON_COLS,
+    );
+    static ref GET_ALL_FOR_APP_SQL: String = format!(
+        "SELECT {common_cols} FROM loginsL
+         WHERE is_deleted = 0
+           AND associated_app = :app_id
+         UNION ALL
+         SELECT {common_cols} FROM loginsM
+         WHERE is_overridden = 0
+           AND associated_app = :app_id
+         ORDER BY timeLastUsed DESC",
+        common_cols = schema::COMMON_COLS,
+    );
+    static ref GET_ALL_FOR_APP_OR_HOSTNAME_SQL: String = format!(
+        "SELECT {common_cols} FROM loginsL
+         WHERE is_deleted = 0
+           AND (associated_app = :app_id OR hostname = :hostname)
+         UNION ALL
+         SELECT {common_cols} FROM loginsM
+         WHERE is_overridden = 0
+           AND (associated_app = :app_id OR hostname = :hostname)
+         ORDER BY timeLastUsed DESC",
+        common_cols = schema::COMMON_COLS,
+    );
+    static ref GET_FOR_AUTOFILL_SQL: String = format!(
+        "SELECT {common_cols} FROM loginsL
+         WHERE is_deleted = 0
+           AND hostname = :hostname
+           AND ((:realm IS NULL AND formSubmitURL IS NOT NULL) OR httpRealm = :realm)
+         UNION ALL
+         SELECT {common_cols} FROM loginsM
+         WHERE is_overridden = 0
+           AND hostname = :hostname
+           AND ((:realm IS NULL AND formSubmitURL IS NOT NULL) OR httpRealm = :realm)",
+        common_cols = schema::COMMON_COLS,
+    );
+    // Hostnames are origins, so the suffix must be preceded by either a `.` or
+    // the `/` of the scheme, and may be followed by a port.
+    static ref GET_BY_DOMAIN_SUFFIX_SQL: String = format!(
+        "SELECT * FROM ({all})
+         WHERE hostname LIKE '%.' || :suffix ESCAPE '\\'
+            OR hostname LIKE '%.' || :suffix || ':%' ESCAPE '\\'
+            OR hostname LIKE '%/' || :suffix ESCAPE '\\'
+            OR hostname LIKE '%/' || :suffix || ':%' ESCAPE '\\'
+         ORDER BY hostname ASC",
+        all = &*GET_ALL_SQL
+    );
+    static ref GET_CHANGED_SINCE_SQL: String = format!(
+        "SELECT {common_cols} FROM loginsL
+         WHERE local_modified >= :since_ms
+           AND is_deleted = 0
+           AND sync_status != {synced}",
+        common_cols = schema::COMMON_COLS,
+        synced = SyncStatus::Synced as u8,
+    );
+    static ref GET_NEVER_USED_SQL: String = format!(
+        "SELECT * FROM ({all}) WHERE {never_used}",
+        all = &*GET_ALL_SQL,
+        never_used = NEVER_USED_FILTER,
+    );
+    static ref GET_SHORT_PASSWORDS_SQL: String = format!(
+        "SELECT * FROM ({all}) WHERE length(password) < :min_length",
+        all = &*GET_ALL_SQL
+    );
+    static ref GET_REUSED_PASSWORDS_SQL: String = format!(
+        "WITH logins AS ({all})
+         SELECT * FROM logins
+         WHERE password IN (
+             SELECT password FROM logins GROUP BY password HAVING COUNT(*) > 1
+         )
+         ORDER BY password, guid",
+        all = &*GET_ALL_SQL
+    );
+    static ref FIND_ALL_DUPES_SQL: String = format!(
+        "WITH logins AS ({all})
+         SELECT a.guid, b.guid
+         FROM logins a
+         JOIN logins b
+           ON a.hostname = b.hostname
+          AND ifnull(a.username, '') = ifnull(b.username, '')
+          AND a.guid < b.guid
+         ORDER BY a.hostname, a.guid, b.guid",
+        all = &*GET_ALL_SQL,
+    );
+    static ref GET_SYNC_STATUS_COUNTS_SQL: String = format!(
+        "SELECT
+             COUNT(CASE WHEN sync_status = {new} THEN 1 END),
+             COUNT(CASE WHEN sync_status = {changed} THEN 1 END),
+             COUNT(CASE WHEN sync_status = {synced} THEN 1 END)
+         FROM loginsL
+         WHERE is_deleted = 0",
+        new = SyncStatus::New as u8,
+        changed = SyncStatus::Changed as u8,
+        synced = SyncStatus::Synced as u8,
+    );
+    static ref GET_STATISTICS_SQL: String = format!(
+        "SELECT
+             COUNT(CASE WHEN NOT is_deleted THEN 1 END),
+             COUNT(CASE WHEN NOT is_deleted AND sync_status = {synced} THEN 1 END),
+             COUNT(CASE WHEN NOT is_deleted AND sync_status = {new} THEN 1 END),
+             COUNT(CASE WHEN is_deleted THEN 1 END),
+             COUNT(CASE WHEN NOT is_deleted AND {never_used} THEN 1 END),
+             COUNT(CASE WHEN NOT is_deleted AND timeLastUsed >= :cutoff_ms THEN 1 END)
+         FROM (
+             SELECT is_deleted, sync_status, timeCreated, timeLastUsed FROM loginsL
+             UNION ALL
+             SELECT 0, {synced}, timeCreated, timeLastUsed FROM loginsM WHERE is_overridden = 0
+         )",
+        synced = SyncStatus::Synced as u8,
+        new = SyncStatus::New as u8,
+        never_used = NEVER_USED_FILTER,
+    );
+    static ref GET_ALL_CREATED_BEFORE_SQL: String = format!(
+        "SELECT * FROM ({all}) WHERE timeCreated < :before_ms",
+        all = &*GET_ALL_SQL
+    );
+    static ref GET_ALL_LOCKED_SQL: String = format!(
+        "SELECT {cols} FROM loginsL WHERE is_deleted = 0
+         UNION ALL
+         SELECT {cols} FROM loginsM WHERE is_overridden = 0",
+        cols = LockedLogin::COLUMNS,
+    );
+    static ref GET_ALL_IN_ORDER_SQL: String =
+        format!("SELECT * FROM ({}) ORDER BY guid ASC", &*GET_ALL_SQL);
+    static ref GET_PAGE_AFTER_GUID_SQL: String = format!(
+        "SELECT * FROM ({}) WHERE guid > :after ORDER BY guid ASC LIMIT :limit",
+        &*GET_ALL_SQL
+    );
+    static ref GET_FIRST_PAGE_BY_HOSTNAME_SQL: String = format!(
+        "SELECT * FROM ({}) ORDER BY hostname ASC, guid ASC LIMIT :limit",
+        &*GET_ALL_SQL
+    );
+    static ref GET_PAGE_AFTER_HOSTNAME_SQL: String = format!(
+        "SELECT * FROM ({})
+         WHERE hostname > :hostname OR (hostname = :hostname AND guid > :guid)
+         ORDER BY hostname ASC, guid ASC
+         LIMIT :limit",
+        &*GET_ALL_SQL
+    );
+    static ref GET_TOTAL_COUNT_SQL: String = format!("SELECT COUNT(*) FROM ({})", &*GET_ALL_SQL);
+    static ref COUNT_BY_HOSTNAME_SQL: String = format!(
+        "SELECT COUNT(*) FROM ({}) WHERE hostname = :hostname",
+        &*GET_ALL_SQL
+    );
+    static ref GET_BY_GUID_SQL: String = format!(
+        "SELECT {common_cols}
+         FROM loginsL
+         WHERE is_deleted = 0
+           AND guid = :guid
+
+         UNION ALL
+
+         SELECT {common_cols}
+         FROM loginsM
+         WHERE is_overridden IS NOT 1
+           AND guid = :guid
+         ORDER BY hostname ASC
+
+         LIMIT 1",
+        common_cols = schema::COMMON_COLS,
+    );
+    static ref CLONE_ENTIRE_MIRROR_SQL: String = format!(
+        "INSERT OR IGNORE INTO loginsL ({common_cols}, local_modified, is_deleted, sync_status)
+         SELECT {common_cols}, NULL AS local_modified, 0 AS is_deleted, 0 AS sync_status
+         FROM loginsM",
+        common_cols = schema::COMMON_COLS,
+    );
+    static ref CLONE_SINGLE_MIRROR_SQL: String =
+        format!("{} WHERE guid = :guid", &*CLONE_ENTIRE_MIRROR_SQL,);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Panics if SQLite would need to scan a table to run `sql`, rather than
+    // using an index.
+    fn assert_query_uses_index(db: &LoginDb, sql: &str, params: &[(&str, &dyn ToSql)]) {
+        let plan = db.explain_query(sql, params).unwrap();
+        // Older SQLite versions say "SCAN TABLE foo", newer ones "SCAN foo".
+        let scans = plan
+            .iter()
+            .filter(|step| step.starts_with("SCAN") && !step.contains("USING"))
+            .collect::<Vec<_>>();
+        assert!(
+            scans.is_empty(),
+            "Query doesn't use an index: {}\nPlan: {:#?}",
+            sql,
+            plan
+        );
+    }
+
+    #[test]
+    fn test_hostname_queries_use_index() {
+        let db = LoginDb::open_in_memory(Some("testing".into())).unwrap();
+        let params = named_params! {
+            ":hostname": "https://www.example.com",
+            ":prefix": "alice",
+        };
+        assert_query_uses_index(&db, &GET_BY_USERNAME_PREFIX_SQL, params);
+        assert_query_uses_index(
+            &db,
+            &GET_BY_HOSTNAME_SQL,
+            named_params! { ":hostname": "https://www.example.com" },
+        );
+        assert_query_uses_index(
+            &db,
+            &GET_FOR_AUTOFILL_SQL,
+            named_params! {
+                ":hostname": "https://www.example.com",
+                ":realm": Some("realm"),
+            },
+        );
+        assert_query_uses_index(
+            &db,
+            &format!(
+                "SELECT {common_cols} FROM loginsL
+                 WHERE is_deleted = 0 AND hostname = :hostname
+                 UNION ALL
+                 SELECT {common_cols} FROM loginsM
+                 WHERE is_overridden = 0 AND hostname = :hostname",
+                common_cols = schema::COMMON_COLS
+            ),
+            named_params! { ":hostname": "https://www.example.com" },
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "doesn't use an index")]
+    fn test_assert_query_uses_index_catches_scans() {
+        let db = LoginDb::open_in_memory(Some("testing".into())).unwrap();
+        assert_query_uses_index(
+            &db,
+            "SELECT guid FROM loginsL WHERE password = :password",
+            named_params! { ":password": "hunter2" },
+        );
+    }
+    #[test]
+    fn test_bad_record() {
+        let db = LoginDb::open_in_memory(Some("testing".into())).unwrap();
+        let scope = db.begin_interrupt_scope();
+        let mut telem = sync15::telemetry::EngineIncoming::new();
+        let res = db
+            .fetch_login_data(
+                &[
+                    // tombstone
+                    (
+                        sync15::Payload::new_tombstone("dummy_000001"),
+                        sync15::ServerTimestamp(10000),
+                    ),
+                    // invalid
+                    (
+                        sync15::Payload::from_json(serde_json::json!({
+                            "id": "dummy_000002",
+                            "garbage": "data",
+                            "etc": "not a login"
+                        }))
+                        .unwrap(),
+                        sync15::ServerTimestamp(10000),
+                    ),
+                    // valid
+                    (
+                        sync15::Payload::from_json(serde_json::json!({
+                            "id": "dummy_000003",
+                            "formSubmitURL": "https://www.example.com/submit",
+                            "hostname": "https://www.example.com",
+                            "username": "test",
+                            "password": "test",
+                        }))
+                        .unwrap(),
+                        sync15::ServerTimestamp(10000),
+                    ),
+                ],
+                &mut telem,
+                &scope,
+                &mut |_| {},
+            )
+            .unwrap();
+        assert_eq!(telem.get_failed(), 1);
+        assert_eq!(res.len(), 2);
+        assert_eq!(res[0].guid, "dummy_000001");
+        assert_eq!(res[1].guid, "dummy_000003");
+    }
+
+    #[test]
+    fn test_bad_record_before_existing_login() {
+        let db = LoginDb::open_in_memory(Some("testing".into())).unwrap();
+        db.add(Login {
+            guid: "dummy_000002".into(),
+            ..Login::with_defaults_for_testing("https://www.example.com", "test", "test")
+        })
+        .unwrap();
+        let scope = db.begin_interrupt_scope();
+        let mut telem = sync15::telemetry::EngineIncoming::new();
+        let res = db
+            .fetch_login_data(
+                &[
+                    // invalid, so it isn't in the result.
+                    (
+                        sync15::Payload::from_json(serde_json::json!({
+                            "id": "dummy_000001",
+                            "garbage": "data",
+                        }))
+                        .unwrap(),
+                        sync15::ServerTimestamp(10000),
+                    ),
+                    // valid, with a local record which must end up on its own
+                    // entry rather than on the one after it.
+                    (
+                        sync15::Payload::from_json(serde_json::json!({
+                            "id": "dummy_000002",
+                            "formSubmitURL": "https://www.example.com",
+                            "hostname": "https://www.example.com",
+                            "username": "test",
+                            "password": "test",
+                        }))
+                        .unwrap(),
+                        sync15::ServerTimestamp(10000),
+                    ),
+                ],
+                &mut telem,
+                &scope,
+                &mut |_| {},
+            )
+            .unwrap();
+        assert_eq!(telem.get_failed(), 1);
+        assert_eq!(res.len(), 1);
+        assert_eq!(res[0].guid, "dummy_000002");
+        assert_eq!(res[0].local.as_ref().unwrap().login.username, "test");
+    }
+
+    #[test]
+    fn test_apply_incoming_with_dedup() {
+        let db = LoginDb::open_in_memory(Some("testing".into())).unwrap();
+        let record = |password: &str| {
+            sync15::Payload::from_json(serde_json::json!({
+                "id": "dummy_000001",
+                "formSubmitURL": "https://www.example.com/submit",
+                "hostname": "https://www.example.com",
+                "username": "test",
+                "password": password,
+            }))
+            .unwrap()
+        };
+        let mut inbound = IncomingChangeset::new("passwords", ServerTimestamp(30000));
+        inbound.changes = vec![
+            (record("old"), ServerTimestamp(10000)),
+            (record("new"), ServerTimestamp(20000)),
+        ];
+        let (outgoing, num_dupes) = db.apply_incoming_with_dedup(inbound).unwrap();
+        assert_eq!(num_dupes, 1);
+        assert!(outgoing.changes.is_empty());
+        let login = db.get_by_id("dummy_000001").unwrap().unwrap();
+        assert_eq!(login.password, "new");
+    }
+
+    #[test]
+    fn test_find_dupe_form_submit_without_host() {
+        let db = LoginDb::open_in_memory(Some("testing".into())).unwrap();
+        let existing = db
+            .add(Login {
+                form_submit_url: Some("https://b.example.com/javascript".into()),
+                ..Login::with_defaults_for_testing("https://www.example.com", "test", "test")
+            })
+            .unwrap();
+        let with_form_submit = |form_submit_url: &str| Login {
+            form_submit_url: Some(form_submit_url.into()),
+            ..Login::with_defaults_for_testing("https://www.example.com", "test", "other")
+        };
+        // These have no host, so shouldn't match on part of one.
+        for form_submit_url in &["javascript:", "mailto:a@b"] {
+            assert!(
+                db.find_dupe(&with_form_submit(form_submit_url))
+                    .unwrap()
+                    .is_none(),
+                "{:?}",
+                form_submit_url
+            );
+        }
+        let dupe = db
+            .find_dupe(&with_form_submit("https://b.example.com/other"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(dupe.guid, existing.guid);
+    }
+
+    #[test]
+    fn test_check_valid_with_no_dupes() {
+        let db = LoginDb::open_in_memory(Some("testing".into())).unwrap();
+        db.add(Login {
+            guid: "dummy_000001".into(),
+            form_submit_url: Some("https://www.example.com".into()),
+            hostname: "https://www.example.com".into(),
+            http_realm: None,
+            username: "test".into(),
+            password: "test".into(),
+            ..Login::default()
+        })
+        .unwrap();
+
+        let unique_login_guid = Guid::empty();
+        let unique_login = Login {
+            guid: unique_login_guid.clone(),
+            form_submit_url: None,
+            hostname: "https://www.example.com".into(),
+            http_realm: Some("https://www.example.com".into()),
+            username: "test".into(),
+            password: "test".into(),
+            ..Login::default()
+        };
+
+        let duplicate_login = Login {
+            guid: Guid::empty(),
+            form_submit_url: Some("https://www.example.com".into()),
+            hostname: "https://www.example.com".into(),
+            http_realm: None,
+            username: "test".into(),
+            password: "test2".into(),
+            ..Login::default()
+        };
+
+        let updated_login = Login {
+            guid: unique_login_guid,
+            form_submit_url: None,
+            hostname: "https://www.example.com".into(),
+            http_realm: Some("https://www.example.com".into()),
+            username: "test".into(),
+            password: "test4".into(),
+            ..Login::default()
+        };
+
+        struct TestCase {
+            login: Login,
+            should_err: bool,
+            expected_err: &'static str,
+        }
+
+        let test_cases = [
+            TestCase {
+                // unique_login should not error because it does not share the same hostname,
+                // username, and formSubmitURL or httpRealm with the pre-existing login
+                // (login with guid "dummy_000001").
+                login: unique_login,
+                should_err: false,
+                expected_err: "",
+            },
+            TestCase {
+                // duplicate_login has the same hostname, username, and formSubmitURL as a pre-existing
+                // login (guid "dummy_000001") and duplicate_login has no guid value, i.e. its guid
+                // doesn't match with that of a pre-existing record so it can't be considered update,
+                // so it should error.
+                login: duplicate_login,
+                should_err: true,
+                expected_err: "Invalid login: [DuplicateLogin]",
+            },
+            TestCase {
+                // updated_login is an update to unique_login (has the same guid) so it is not a dupe
+                // and should not error.
+                login: updated_login,
+                should_err: false,
+                expected_err: "",
+            },
+        ];
+
+        for tc in &test_cases {
+            let login_check = db.check_valid_with_no_dupes(&tc.login);
+            if tc.should_err {
+                assert!(&login_check.is_err());
+                assert_eq!(&login_check.unwrap_err().to_string(), tc.expected_err)
+            } else {
+                assert!(&login_check.is_ok())
+            }
+        }
+    }
+
+    #[test]
+    fn test_add_validates_guid() {
+        let db = LoginDb::open_in_memory(Some("testing".into())).unwrap();
+        let login_with_guid = |guid: &str| Login {
+            guid: guid.into(),
+            hostname: "https://www.example.com".into(),
+            http_realm: Some("https://www.example.com".into()),
+            username: guid.into(),
+            password: "test".into(),
+            ..Login::default()
+        };
+        for bad in &["has space", "has\nnewline", &"a".repeat(33), "{brackets}"] {
+            match db.add(login_with_guid(bad)).unwrap_err().kind() {
+                ErrorKind::InvalidLogin(errs) => assert_eq!(
+                    errs.0,
+                    [LoginValidationError::InvalidFormat {
+                        field: "guid".into(),
+                    }]
+                ),
+                e => panic!("Unexpected error {:?}", e),
+            }
+        }
+        for good in &[
+            "dummy_000001",
+            "Abc-_123XYZ",
+            &"a".repeat(32),
+            "{5d7f3a02-0b5e-4a8f-9c1d-2e4f6a8b0c1d}",
+        ] {
+            let added = db.add(login_with_guid(good)).unwrap();
+            assert_eq!(added.guid, *good);
+            db.update(Login {
+                password: "changed".into(),
+                ..added
+            })
+            .unwrap();
+        }
+    }
+
+    #[test]
+    fn test_add_login_new() {
+        let db = LoginDb::open_in_memory(Some("testing".into())).unwrap();
+        let added = db
+            .add(Login::new("https://www.example.com", "user", "pass"))
+            .unwrap();
+        assert!(!added.guid.is_empty());
+        assert!(login::is_valid_guid(added.guid_str()));
+        let fetched = db.get_by_id(added.guid_str()).unwrap().unwrap();
+        assert_eq!(fetched.username, "user");
+        assert_eq!(fetched.form_submit_url, Some(String::new()));
+
+        let added = db
+            .add(Login::with_guid(
+                "dummy_000001",
+                "https://www.example.com",
+                "other",
+                "pass",
+            ))
+            .unwrap();
+        assert_eq!(added.guid, "dummy_000001");
+    }
+
+    #[test]
+    fn test_unicode_submit() {
+        let db = LoginDb::open_in_memory(Some("testing".into())).unwrap();
+        db.add(Login {
+            guid: "dummy_000001".into(),
+            form_submit_url: Some("http://😍.com".into()),
+            hostname: "http://😍.com".into(),
+            http_realm: None,
+            username: "😍".into(),
+            username_field: "😍".into(),
+            password: "😍".into(),
+            password_field: "😍".into(),
+            ..Login::default()
+        })
+        .unwrap();
+        let fetched = db
+            .get_by_id("dummy_000001")
+            .expect("should work")
+            .expect("should get a record");
+        assert_eq!(fetched.hostname, "http://xn--r28h.com");
+        assert_eq!(fetched.form_submit_url.unwrap(), "http://xn--r28h.com");
+        assert_eq!(fetched.username, "😍");
+        assert_eq!(fetched.username_field, "😍");
+        assert_eq!(fetched.password, "😍");
+        assert_eq!(fetched.password_field, "😍");
+    }
+
+    #[test]
+    fn test_unicode_realm() {
+        let db = LoginDb::open_in_memory(Some("testing".into())).unwrap();
+        db.add(Login {
+            guid: "dummy_000001".into(),
+            form_submit_url: None,
+            hostname: "http://😍.com".into(),
+            http_realm: Some("😍😍".into()),
+            username: "😍".into(),
+            password: "😍".into(),
+            ..Login::default()
+        })
+        .unwrap();
+        let fetched = db
+            .get_by_id("dummy_000001")
+            .expect("should work")
+            .expect("should get a record");
+        assert_eq!(fetched.hostname, "http://xn--r28h.com");
+        assert_eq!(fetched.http_realm.unwrap(), "😍😍");
+    }
+
+    fn check_matches(db: &LoginDb, query: &str, expected: &[&str]) {
+        let mut results = db
+            .get_by_base_domain(query)
+            .unwrap()
+            .into_iter()
+            .map(|l| l.hostname)
+            .collect::<Vec<String>>();
+        results.sort();
+        let mut sorted = expected.to_owned();
+        sorted.sort();
+        assert_eq!(sorted, results);
+    }
+
+    fn check_good_bad(
+        good: Vec<&str>,
+        bad: Vec<&str>,
+        good_queries: Vec<&str>,
+        zero_queries: Vec<&str>,
+    ) {
+        let db = LoginDb::open_in_memory(Some("testing".into())).unwrap();
+        for h in good.iter().chain(bad.iter()) {
+            db.add(Login {
+                hostname: (*h).into(),
+                http_realm: Some((*h).into()),
+                password: "test".into(),
+                ..Login::default()
+            })
+            .unwrap();
+        }
+        for query in good_queries {
+            check_matches(&db, query, &good);
+        }
+        for query in zero_queries {
+            check_matches(&db, query, &[]);
+        }
+    }
+
+    #[test]
+    fn test_get_by_base_domain_invalid() {
+        check_good_bad(
+            vec!["https://example.com"],
+            vec![],
+            vec![],
+            vec!["invalid query"],
+        );
+    }
+
+    #[test]
+    fn test_get_by_base_domain() {
+        check_good_bad(
+            vec![
+                "https://example.com",
+                "https://www.example.com",
+                "http://www.example.com",
+                "http://www.example.com:8080",
+                "http://sub.example.com:8080",
+                "https://sub.example.com:8080",
+                "https://sub.sub.example.com",
+                "ftp://sub.example.com",
+            ],
+            vec![
+                "https://badexample.com",
+                "https://example.co",
+                "https://example.com.au",
+            ],
+            vec!["example.com"],
+            vec!["foo.com"],
+        );
+        // punycode! This is likely to need adjusting once we normalize
+        // on insert.
+        check_good_bad(
+            vec![
+                "http://xn--r28h.com", // punycoded version of "http://😍.com"
+            ],
+            vec!["http://💖.com"],
+            vec!["😍.com", "xn--r28h.com"],
+            vec![],
+        );
+    }
+
+    #[test]
+    fn test_get_by_base_domain_ipv4() {
+        check_good_bad(
+            vec!["http://127.0.0.1", "https://127.0.0.1:8000"],
+            vec!["https://127.0.0.0", "https://example.com"],
+            vec!["127.0.0.1"],
+            vec!["127.0.0.2"],
+        );
+    }
+
+    #[test]
+    fn test_get_by_base_domain_ipv6() {
+        check_good_bad(
+            vec!["http://[::1]", "https://[::1]:8000"],
+            vec!["https://[0:0:0:0:0:0:1:1]", "https://example.com"],
+            vec!["[::1]", "[0:0:0:0:0:0:0:1]"],
+            vec!["[0:0:0:0:0:0:1:2]"],
+        );
+    }
+
+    #[test]
+    fn test_get_all_for_multiple_hostnames() {
+        let db = LoginDb::open_in_memory(Some("testing".into())).unwrap();
+        for (hostname, username) in &[
+            ("https://www.example.com", "user1"),
+            ("https://www.example.com", "user2"),
+            ("https://www.example2.com", "user1"),
+            ("https://www.example3.com", "user1"),
+        ] {
+            db.add(Login {
+                hostname: (*hostname).into(),
+                http_realm: Some("realm".into()),
+                username: (*username).into(),
+                password: "password".into(),
+                ..Login::default()
+            })
+            .unwrap();
+        }
+
+        let found = db
+            .get_all_for_multiple_hostnames(&[
+                "https://www.example.com",
+                "https://www.example2.com",
+                "https://www.unknown.com",
+            ])
+            .unwrap();
+        assert_eq!(found.len(), 2);
+        let mut usernames = found["https://www.example.com"]
+            .iter()
+            .map(|l| l.username.as_str())
+            .collect::<Vec<_>>();
+        usernames.sort();
+        assert_eq!(usernames, vec!["user1", "user2"]);
+        assert_eq!(found["https://www.example2.com"].len(), 1);
+        assert!(!found.contains_key("https://www.unknown.com"));
+
+        // Make sure we still find everything when the hostnames span multiple chunks.
+        let mut many = (0..sql_support::default_max_variable_number())
+            .map(|i| format!("https://www.unknown{}.com", i))
+            .collect::<Vec<_>>();
+        many.insert(0, "https://www.example.com".into());
+        many.push("https://www.example3.com".into());
+        let many_refs = many.iter().map(String::as_str).collect::<Vec<_>>();
+        let found = db.get_all_for_multiple_hostnames(&many_refs).unwrap();
+        assert_eq!(found.len(), 2);
+        assert_eq!(found["https://www.example.com"].len(), 2);
+        assert_eq!(found["https://www.example3.com"].len(), 1);
+    }
+
+    #[test]
+    fn test_get_by_guids() {
+        let db = LoginDb::open_in_memory(Some("testing".into())).unwrap();
+        let mut ids: Vec<String> = (0..9)
+            .map(|i| {
+                db.add(Login::with_defaults_for_testing(
+                    "https://www.example.com",
+                    &format!("user{}", i),
+                    "password",
+                ))
+                .unwrap()
+                .guid
+                .to_string()
+            })
+            .collect();
+        // Mirror-only records are found too.
+        db.insert_mirror_record(MirrorLogin {
+            login: Login {
+                guid: "dummy_000001".into(),
+                ..Login::with_defaults_for_testing("https://www.example.org", "mirror", "pw")
+            },
+            is_overridden: false,
+            server_modified: ServerTimestamp(1_000_000),
+        })
+        .unwrap();
+        ids.push("dummy_000001".into());
+        ids.reverse();
+
+        let mut wanted: Vec<&str> = ids.iter().map(String::as_str).collect();
+        wanted.insert(4, "missing");
+        let by_guid = db.get_by_guids_map_chunked(&wanted, 3).unwrap();
+        assert_eq!(by_guid.len(), 10);
+        assert!(!by_guid.contains_key("missing"));
+        assert_eq!(by_guid["dummy_000001"].username, "mirror");
+
+        let found: Vec<String> = db
+            .get_by_guids(&wanted)
+            .unwrap()
+            .into_iter()
+            .map(|login| login.guid.to_string())
+            .collect();
+        assert_eq!(found, ids);
+        assert_eq!(db.get_by_guids(&[]).unwrap().len(), 0);
+
+        // Repeated GUIDs are returned each time they're asked for.
+        let repeated = [ids[0].as_str(), "missing", ids[1].as_str(), ids[0].as_str()];
+        let found: Vec<String> = db
+            .get_by_guids(&repeated)
+            .unwrap()
+            .into_iter()
+            .map(|login| login.guid.to_string())
+            .collect();
+        assert_eq!(found, vec![ids[0].clone(), ids[1].clone(), ids[0].clone()]);
+    }
+
+    #[test]
+    fn test_counts() {
+        let db = LoginDb::open_in_memory(Some("testing".into())).unwrap();
+        assert_eq!(db.count().unwrap(), 0);
+        assert!(!db.has_any_logins().unwrap());
+
+        // Mirror-only.
+        db.insert_mirror_record(MirrorLogin {
+            login: Login {
+                guid: "dummy_000001".into(),
+                ..Login::with_defaults_for_testing("https://www.example.com", "mirror", "pw")
+            },
+            is_overridden: false,
+            server_modified: ServerTimestamp(1_000_000),
+        })
+        .unwrap();
+        assert_eq!(db.count().unwrap(), 1);
+        assert!(db.has_any_logins().unwrap());
+
+        // In the mirror, but overridden by a local change.
+        db.insert_mirror_record(MirrorLogin {
+            login: Login {
+                guid: "dummy_000002".into(),
+                ..Login::with_defaults_for_testing("https://www.example.com", "both", "pw")
+            },
+            is_overridden: false,
+            server_modified: ServerTimestamp(1_000_000),
+        })
+        .unwrap();
+        db.touch("dummy_000002").unwrap();
+
+        // Local-only, including one which is deleted.
+        for (hostname, username) in &[
+            ("https://www.example.com", "local"),
+            ("https://www.example.org", "local"),
+        ] {
+            db.add(Login::with_defaults_for_testing(hostname, username, "pw"))
+                .unwrap();
+        }
+        let deleted = db
+            .add(Login::with_defaults_for_testing(
+                "https://www.example.com",
+                "deleted",
+                "pw",
+            ))
+            .unwrap();
+        db.delete(deleted.guid_str()).unwrap();
+
+        assert_eq!(db.count().unwrap(), db.get_all().unwrap().len());
+        assert_eq!(db.count().unwrap(), 4);
+        assert_eq!(
+            db.count_by_hostname("https://www.example.com").unwrap(),
+            db.get_by_hostname("https://www.example.com").unwrap().len()
+        );
+        assert_eq!(db.count_by_hostname("https://www.example.com").unwrap(), 3);
+        assert_eq!(db.count_by_hostname("https://www.example.org").unwrap(), 1);
+        assert_eq!(db.count_by_hostname("https://www.example.net").unwrap(), 0);
+
+        db.wipe_local().unwrap();
+        assert_eq!(db.count().unwrap(), 0);
+        assert!(!db.has_any_logins().unwrap());
+    }
+
+    #[test]
+    fn test_get_all_for_source() {
+        let db = LoginDb::open_in_memory(Some("testing".into())).unwrap();
+        let login = |username: &str, creation_source: CreationSource| Login {
+            hostname: "https://www.example.com".into(),
+            http_realm: Some("realm".into()),
+            username: username.into(),
+            password: "password".into(),
+            creation_source,
+            ..Login::default()
+        };
+        // Importing requires an empty database, so do it first.
+        db.import_multiple(&[
+            login("chrome", CreationSource::Unknown),
+            login(
+                "safari",
+                CreationSource::Imported {
+                    source_name: "Safari".into(),
+                },
+            ),
+        ])
+        .unwrap();
+        let saved = db.add(login("saved", CreationSource::UserSaved)).unwrap();
+        let defaulted = db.add(login("defaulted", CreationSource::Unknown)).unwrap();
+        assert_eq!(saved.creation_source, CreationSource::UserSaved);
+        assert_eq!(defaulted.creation_source, CreationSource::UserSaved);
+
+        let mut inbound = IncomingChangeset::new("passwords", ServerTimestamp(10000));
+        inbound.changes = vec![(
+            sync15::Payload::from_json(serde_json::json!({
+                "id": "dummy_000001",
+                "formSubmitURL": "https://www.example.com/submit",
+                "hostname": "https://www.example.com",
+                "username": "synced",
+                "password": "password",
+            }))
+            .unwrap(),
+            ServerTimestamp(10000),
+        )];
+        db.apply_incoming_with_dedup(inbound).unwrap();
+
+        let usernames_for = |source: CreationSource| {
+            let mut logins = db.get_all_for_source(source.clone()).unwrap();
+            assert!(logins.iter().all(|l| l.creation_source == source));
+            logins.sort_by(|a, b| a.username.cmp(&b.username));
+            logins.into_iter().map(|l| l.username).collect::<Vec<_>>()
+        };
+        assert_eq!(
+            usernames_for(CreationSource::UserSaved),
+            vec!["defaulted", "saved"]
+        );
+        assert_eq!(
+            usernames_for(CreationSource::Imported {
+                source_name: "".into()
+            }),
+            vec!["chrome"]
+        );
+        assert_eq!(
+            usernames_for(CreationSource::Imported {
+                source_name: "Safari".into()
+            }),
+            vec!["safari"]
+        );
+        assert_eq!(usernames_for(CreationSource::Synced), vec!["synced"]);
+        assert!(usernames_for(CreationSource::Unknown).is_empty());
+
+        // Records which predate the `creation_source` column read back as `Unknown`.
+        db.execute_all(&[
+            "UPDATE loginsL SET creation_source = 0 WHERE username = 'saved'",
+            "UPDATE loginsL SET creation_source = 99 WHERE username = 'defaulted'",
+        ])
+        .unwrap();
+        assert_eq!(
+            db.get_by_id(&saved.guid).unwrap().unwrap().creation_source,
+            CreationSource::Unknown
+        );
+        assert_eq!(usernames_for(CreationSource::Unknown), vec!["saved"]);
+    }
+
+    #[test]
+    fn test_get_all_with_username_containing() {
+        let db = LoginDb::open_in_memory(Some("testing".into())).unwrap();
+        for (username, times_used) in &[
+            ("alice@example.com", 1),
+            ("alice_admin", 5),
+            ("bob@example.com", 10),
+            ("aliceXadmin", 1),
+        ] {
+            db.add(Login {
+                hostname: "https://www.example.com".into(),
+                http_realm: Some("realm".into()),
+                username: (*username).into(),
+                password: "password".into(),
+                times_used: *times_used,
+                ..Login::default()
+            })
+            .unwrap();
+        }
+        let usernames = |hostname: &str, prefix: &str| {
+            db.get_all_with_username_containing(hostname, prefix)
+                .unwrap()
+                .into_iter()
+                .map(|l| l.username)
+                .collect::<Vec<_>>()
+        };
+        let alices = usernames("https://www.example.com", "alice");
+        assert_eq!(alices.len(), 3);
+        assert_eq!(alices[0], "alice_admin");
+        assert!(alices.contains(&"alice@example.com".to_string()));
+        assert!(!alices.contains(&"bob@example.com".to_string()));
+        // `_` and `%` in the prefix must match literally.
+        assert_eq!(
+            usernames("https://www.example.com", "alice_"),
+            vec!["alice_admin"]
+        );
+        assert!(usernames("https://www.example.com", "%").is_empty());
+        assert!(usernames("https://www.example2.com", "alice").is_empty());
+    }
+
+    #[test]
+    fn test_apply_incoming_from_json() {
+        let db = LoginDb::open_in_memory(Some("testing".into())).unwrap();
+        let outgoing = db
+            .apply_incoming_from_json(include_str!("../fixtures/incoming_new.json"), 1000.0)
+            .unwrap();
+        assert!(outgoing.changes.is_empty());
+        assert_eq!(db.get_all().unwrap().len(), 2);
+        let bob = db.get_by_id("dummy_000002").unwrap().unwrap();
+        assert_eq!(bob.http_realm, Some("My Realm".into()));
+        assert_eq!(bob.times_used, 3);
+
+        let local = db
+            .add(Login {
+                hostname: "https://www.example.net".into(),
+                http_realm: Some("realm".into()),
+                username: "carol".into(),
+                password: "password".into(),
+                ..Login::default()
+            })
+            .unwrap();
+        let outgoing = db
+            .apply_incoming_from_json(include_str!("../fixtures/incoming_changes.json"), 2000.0)
+            .unwrap();
+        assert_eq!(outgoing.timestamp, ServerTimestamp(2_000_000));
+        assert_eq!(outgoing.changes.len(), 1);
+        assert_eq!(outgoing.changes[0].id, local.guid);
+
+        let alice = db.get_by_id("dummy_000001").unwrap().unwrap();
+        assert_eq!(alice.password, "hunter3");
+        assert_eq!(alice.time_password_changed, 1_600_000_000_000);
+        assert!(db.get_by_id("dummy_000002").unwrap().is_none());
+        assert_eq!(db.get_all().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_get_estimated_sync_payload_size() {
+        let db = LoginDb::open_in_memory(Some("testing".into())).unwrap();
+        assert_eq!(db.get_estimated_sync_payload_size().unwrap(), 0);
+        for i in 0..10 {
+            db.add(Login {
+                hostname: format!("https://www.example{}.com", i),
+                http_realm: Some("realm".into()),
+                username: "user".into(),
+                password: "password".into(),
+                ..Login::default()
+            })
+            .unwrap();
+        }
+        let estimate = db.get_estimated_sync_payload_size().unwrap();
+        let scope = db.begin_interrupt_scope();
+        let outgoing = db.fetch_outgoing(ServerTimestamp(0), &scope).unwrap();
+        assert_eq!(outgoing.changes.len(), 10);
+        let actual: usize = outgoing
+            .changes
+            .iter()
+            .map(|p| serde_json::to_string(p).unwrap().len())
+            .sum();
+        // Each record is at least its hostname, username and password.
+        assert!(estimate > 10 * "https://www.example0.com".len());
+        assert!(estimate <= actual && estimate * 2 >= actual);
+    }
+
+    #[test]
+    fn test_get_all_mirror_without_local_overlay() {
+        let db = LoginDb::open_in_memory(Some("testing".into())).unwrap();
+        db.apply_incoming_from_json(include_str!("../fixtures/incoming_new.json"), 1000.0)
+            .unwrap();
+        // Both records are only in the mirror after the sync, which is fine.
+        assert!(db
+            .get_all_mirror_without_local_overlay()
+            .unwrap()
+            .is_empty());
+
+        // Changing a record gives it a local overlay, which is fine too.
+        db.touch("dummy_000001").unwrap();
+        assert!(db
+            .get_all_mirror_without_local_overlay()
+            .unwrap()
+            .is_empty());
+
+        // Losing the overlay without clearing `is_overridden` hides the record
+        // entirely.
+        db.execute_all(&["DELETE FROM loginsL WHERE guid = 'dummy_000001'"])
+            .unwrap();
+        assert!(db.get_by_id("dummy_000001").unwrap().is_none());
+        let found = db.get_all_mirror_without_local_overlay().unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].guid_str(), "dummy_000001");
+        assert_eq!(found[0].server_modified, ServerTimestamp(1_000_000));
+    }
+
+    #[test]
+    fn test_heal_orphaned_mirror_records() {
+        let dir = tempdir::TempDir::new("heal_orphaned_mirror").unwrap();
+        let dbpath = dir.path().join("logins.sqlite");
+        {
+            let db = LoginDb::open(&dbpath, Some("testing".into())).unwrap();
+            db.apply_incoming_from_json(include_str!("../fixtures/incoming_new.json"), 1000.0)
+                .unwrap();
+            db.touch("dummy_000001").unwrap();
+            db.execute_all(&["DELETE FROM loginsL WHERE guid = 'dummy_000001'"])
+                .unwrap();
+            assert_eq!(db.get_all_mirror_without_local_overlay().unwrap().len(), 1);
+        }
+        // Healing is opt-in.
+        let db = LoginDb::open(&dbpath, Some("testing".into())).unwrap();
+        assert_eq!(db.get_all_mirror_without_local_overlay().unwrap().len(), 1);
+        drop(db);
+
+        let db = LoginDb::with_connection_options(
+            Connection::open(&dbpath).unwrap(),
+            Some("testing".into()),
+            None,
+            &ConnectionOptions {
+                heal_orphaned_mirror_records: true,
+                ..ConnectionOptions::default()
+            },
+        )
+        .unwrap();
+        assert!(db
+            .get_all_mirror_without_local_overlay()
+            .unwrap()
+            .is_empty());
+        // The record is visible again, as a synced login which only exists in
+        // the mirror.
+        let alice = db.get_by_id("dummy_000001").unwrap().unwrap();
+        assert_eq!(alice.password, "hunter2");
+        assert!(db.get_local_record("dummy_000001").unwrap().is_none());
+        // Nothing is duplicated, or scheduled for upload.
+        assert_eq!(db.get_all().unwrap().len(), 2);
+        let scope = db.begin_interrupt_scope();
+        assert!(db
+            .fetch_outgoing(ServerTimestamp(0), &scope)
+            .unwrap()
+            .changes
+            .is_empty());
+        assert_eq!(db.heal_orphaned_mirror_records().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_get_all_by_guid_prefix() {
+        let db = LoginDb::open_in_memory(Some("testing".into())).unwrap();
+        for guid in &["abc_000001", "abc_000002", "abd_000001", "ab_0000001"] {
+            db.add(Login {
+                guid: (*guid).into(),
+                hostname: format!("https://{}.example.com", guid),
+                http_realm: Some("realm".into()),
+                username: "user".into(),
+                password: "password".into(),
+                ..Login::default()
+            })
+            .unwrap();
+        }
+        let guids = |prefix: &str| {
+            let mut guids = db
+                .get_all_by_guid_prefix(prefix)
+                .unwrap()
+                .into_iter()
+                .map(|l| l.guid.into_string())
+                .collect::<Vec<_>>();
+            guids.sort();
+            guids
+        };
+        assert_eq!(guids("abc"), vec!["abc_000001", "abc_000002"]);
+        assert_eq!(guids("abd"), vec!["abd_000001"]);
+        // `_` is matched literally, not as a wildcard.
+        assert_eq!(guids("ab_"), vec!["ab_0000001"]);
+        assert!(guids("xyz").is_empty());
+
+        for i in 0..MAX_GUID_PREFIX_MATCHES + 5 {
+            db.add(Login {
+                guid: format!("many_{:06}", i).into(),
+                hostname: format!("https://many{}.example.com", i),
+                http_realm: Some("realm".into()),
+                username: "user".into(),
+                password: "password".into(),
+                ..Login::default()
+            })
+            .unwrap();
+        }
+        assert_eq!(guids("man").len(), MAX_GUID_PREFIX_MATCHES as usize);
+    }
+
+    #[test]
+    fn test_apply_local_changes_from_other_db() {
+        let login = |hostname: &str, username: &str| Login {
+            hostname: hostname.into(),
+            http_realm: Some("realm".into()),
+            username: username.into(),
+            password: "password".into(),
+            ..Login::default()
+        };
+        let hostnames = |db: &LoginDb| {
+            let mut hostnames = db
+                .get_all()
+                .unwrap()
+                .into_iter()
+                .map(|l| l.hostname)
+                .collect::<Vec<_>>();
+            hostnames.sort();
+            hostnames
+        };
+        let mut db_a = LoginDb::open_in_memory(Some("testing".into())).unwrap();
+        let mut db_b = LoginDb::open_in_memory(Some("testing".into())).unwrap();
+        db_a.add(login("https://a.example.com", "alice")).unwrap();
+        db_a.add(login("https://shared.example.com", "sam"))
+            .unwrap();
+        let bob = db_b.add(login("https://b.example.com", "bob")).unwrap();
+        // A duplicate of a login in `db_a`, but with a different GUID.
+        db_b.add(login("https://shared.example.com", "sam"))
+            .unwrap();
+        let deleted = db_b
+            .add(login("https://deleted.example.com", "dave"))
+            .unwrap();
+        db_b.delete(&deleted.guid).unwrap();
+
+        let stats = db_a.apply_local_changes_from_other_db(&db_b).unwrap();
+        assert_eq!(
+            stats,
+            SyncStats {
+                incoming: 3,
+                applied: 1,
+                reconciled: 1,
+                failed: 0,
+            }
+        );
+        let expected = vec![
+            "https://a.example.com",
+            "https://b.example.com",
+            "https://shared.example.com",
+        ];
+        assert_eq!(hostnames(&db_a), expected);
+        // The new login is a local change, which the next sync uploads.
+        let local = db_a.get_local_record(&bob.guid).unwrap().unwrap();
+        assert_eq!(local.sync_status, SyncStatus::New);
+        assert!(db_a.get_mirror_record(&bob.guid).unwrap().is_none());
+        assert_eq!(db_a.get_outgoing_count().unwrap(), 3);
+
+        // And back the other way.
+        let stats = db_b.apply_local_changes_from_other_db(&db_a).unwrap();
+        assert_eq!(stats.applied, 1);
+        assert_eq!(stats.failed, 0);
+        assert_eq!(hostnames(&db_b), expected);
+
+        // Changes keep their metadata, and deletions are applied.
+        let mut changed = db_b.get_by_id(&bob.guid).unwrap().unwrap();
+        changed.password = "new password".into();
+        db_b.update(changed).unwrap();
+        let changed = db_b.get_by_id(&bob.guid).unwrap().unwrap();
+        let alice = db_b
+            .get_all()
+            .unwrap()
+            .into_iter()
+            .find(|l| l.username == "alice")
+            .unwrap();
+        db_b.delete(&alice.guid).unwrap();
+        db_a.apply_local_changes_from_other_db(&db_b).unwrap();
+        let bob = db_a.get_by_id(&bob.guid).unwrap().unwrap();
+        assert_eq!(bob.password, "new password");
+        assert_eq!(bob.time_password_changed, changed.time_password_changed);
+        assert_eq!(bob.times_used, changed.times_used);
+        assert!(db_a.get_by_id(&alice.guid).unwrap().is_none());
+
+        // A login deleted here stays deleted.
+        let mut db_c = LoginDb::open_in_memory(Some("testing".into())).unwrap();
+        db_c.apply_local_changes_from_other_db(&db_a).unwrap();
+        db_c.delete(&bob.guid).unwrap();
+        let stats = db_c.apply_local_changes_from_other_db(&db_a).unwrap();
+        assert_eq!(stats.applied, 0);
+        assert!(db_c.get_by_id(&bob.guid).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_get_all_in_order() {
+        let db = LoginDb::open_in_memory(Some("testing".into())).unwrap();
+        db.apply_incoming_from_json(include_str!("../fixtures/incoming_new.json"), 1000.0)
+            .unwrap();
+        for guid in &["dummy_000003", "dummy_000000"] {
+            db.add(Login {
+                guid: (*guid).into(),
+                hostname: "https://www.example.net".into(),
+                http_realm: Some((*guid).into()),
+                username: "user".into(),
+                password: "password".into(),
+                ..Login::default()
+            })
+            .unwrap();
+        }
+        let guids = db
+            .get_all_in_order()
+            .unwrap()
+            .into_iter()
+            .map(|l| l.guid.into_string())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            guids,
+            vec![
+                "dummy_000000",
+                "dummy_000001",
+                "dummy_000002",
+                "dummy_000003"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_prefetch_for_autofill() {
+        let db = LoginDb::open_in_memory(Some("testing".into())).unwrap();
+        db.prefetch_for_autofill("https://www.example.com").unwrap();
+        db.apply_incoming_from_json(include_str!("../fixtures/incoming_new.json"), 1000.0)
+            .unwrap();
+        db.add(Login {
+            hostname: "https://www.example.com".into(),
+            http_realm: Some("realm".into()),
+            username: "local".into(),
+            password: "password".into(),
+            ..Login::default()
+        })
+        .unwrap();
+        db.prefetch_for_autofill("https://www.example.com").unwrap();
+        db.prefetch_for_autofill("https://www.unknown.com").unwrap();
+        let found = db
+            .get_all_for_multiple_hostnames(&["https://www.example.com"])
+            .unwrap();
+        let mut usernames = found["https://www.example.com"]
+            .iter()
+            .map(|l| l.username.as_str())
+            .collect::<Vec<_>>();
+        usernames.sort();
+        assert_eq!(usernames, vec!["alice", "local"]);
+    }
+
+    #[test]
+    fn test_get_all_grouped_by_hostname() {
+        let db = LoginDb::open_in_memory(Some("testing".into())).unwrap();
+        for (hostname, username, time_last_used) in &[
+            ("https://www.example.com", "a", 1000),
+            ("https://www.example.com", "b", 5000),
+            ("https://www.example2.com", "a", 3000),
+            ("https://www.example3.com", "a", 4000),
+            ("https://www.example3.com", "b", 2000),
+            ("https://www.example3.com", "c", 6000),
+        ] {
+            db.add(Login {
+                hostname: (*hostname).into(),
+                http_realm: Some("realm".into()),
+                username: (*username).into(),
+                password: "password".into(),
+                time_last_used: *time_last_used,
+                ..Login::default()
+            })
+            .unwrap();
+        }
+        let deleted = db
+            .add(Login {
+                hostname: "https://www.deleted.com".into(),
+                http_realm: Some("realm".into()),
+                username: "a".into(),
+                password: "password".into(),
+                time_last_used: 9000,
+                ..Login::default()
+            })
+            .unwrap();
+        db.delete(&deleted.guid).unwrap();
+
+        let groups = db.get_all_grouped_by_hostname().unwrap();
+        let summary = groups
+            .iter()
+            .map(|g| (g.hostname.as_str(), g.logins.len(), g.most_recent_used))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            vec![
+                ("https://www.example3.com", 3, 6000),
+                ("https://www.example.com", 2, 5000),
+                ("https://www.example2.com", 1, 3000),
+            ]
+        );
+        let usernames = groups[0]
+            .logins
+            .iter()
+            .map(|l| l.username.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(usernames, vec!["c", "a", "b"]);
+    }
+
+    #[test]
+    fn test_execute_in_transaction() {
+        let mut db = LoginDb::open_in_memory(Some("testing".into())).unwrap();
+        let login = |username: &str| {
+            Login::with_defaults_for_testing("https://www.example.com", username, "password")
+        };
+        let usernames = |db: &LoginDb| {
+            let mut usernames: Vec<_> = db
+                .get_all()
+                .unwrap()
+                .into_iter()
+                .map(|l| l.username)
+                .collect();
+            usernames.sort();
+            usernames
+        };
+
+        // Errors roll back everything.
+        let result: Result<()> = db.execute_in_transaction(|db| {
+            db.add(login("a"))?;
+            db.add(login("b"))?;
+            throw!(ErrorKind::NoSuchRecord("test".into()))
+        });
+        assert!(result.is_err());
+        assert_eq!(usernames(&db), Vec::<String>::new());
+        assert!(db.is_autocommit());
+
+        // Nested calls only roll back their own changes.
+        let added = db
+            .execute_in_transaction(|db| {
+                let a = db.add(login("a"))?;
+                let inner: Result<()> = db.execute_in_transaction(|db| {
+                    db.add(login("b"))?;
+                    throw!(ErrorKind::NoSuchRecord("test".into()))
+                });
+                assert!(inner.is_err());
+                db.execute_in_transaction(|db| db.add(login("c")))?;
+                // A failing `LoginDb` method only undoes its own changes.
+                assert!(db.add(login("a")).is_err());
+                Ok(a)
+            })
+            .unwrap();
+        assert_eq!(usernames(&db), vec!["a", "c"]);
+        assert_eq!(db.get_by_id(&added.guid).unwrap().unwrap().username, "a");
+        assert!(db.is_autocommit());
+
+        // Committed inner calls are still rolled back with the outer one.
+        let result: Result<()> = db.execute_in_transaction(|db| {
+            db.execute_in_transaction(|db| db.add(login("d")))?;
+            db.delete(&added.guid)?;
+            throw!(ErrorKind::NoSuchRecord("test".into()))
+        });
+        assert!(result.is_err());
+        assert_eq!(usernames(&db), vec!["a", "c"]);
+        assert!(db.is_autocommit());
+    }
+
+    #[test]
+    fn test_savepoint() {
+        let mut db = LoginDb::open_in_memory(Some("testing".into())).unwrap();
+        let login = |username: &str| {
+            Login::with_defaults_for_testing("https://www.example.com", username, "password")
+        };
+        let usernames = |db: &LoginDb| {
+            let mut usernames: Vec<_> = db
+                .get_all()
+                .unwrap()
+                .into_iter()
+                .map(|l| l.username)
+                .collect();
+            usernames.sort();
+            usernames
+        };
+
+        // Dropping a savepoint reverts its changes.
+        {
+            let sp = db.savepoint("test").unwrap();
+            sp.add(login("a")).unwrap();
+            assert_eq!(usernames(&sp), vec!["a"]);
+        }
+        assert_eq!(usernames(&db), Vec::<String>::new());
+        assert!(db.is_autocommit());
+
+        // Releasing it keeps them.
+        let sp = db.savepoint("test").unwrap();
+        sp.add(login("a")).unwrap();
+        sp.release().unwrap();
+        assert_eq!(usernames(&db), vec!["a"]);
+        assert!(db.is_autocommit());
+
+        // `rollback_to` undoes changes, but the savepoint can still be used.
+        let mut sp = db.savepoint("it's \"quoted\"").unwrap();
+        sp.add(login("b")).unwrap();
+        sp.rollback_to().unwrap();
+        sp.add(login("c")).unwrap();
+        sp.release().unwrap();
+        assert_eq!(usernames(&db), vec!["a", "c"]);
+
+        // Savepoints nest, including inside `execute_in_transaction`.
+        db.execute_in_transaction(|db| {
+            let mut outer = db.savepoint("outer").unwrap();
+            outer.add(login("d")).unwrap();
+            {
+                let inner = outer.savepoint("inner").unwrap();
+                inner.add(login("e")).unwrap();
+            }
+            outer.add(login("f")).unwrap();
+            outer.release()
+        })
+        .unwrap();
+        assert_eq!(usernames(&db), vec!["a", "c", "d", "f"]);
+        assert!(db.is_autocommit());
+    }
+
+    #[test]
+    fn test_write_transaction() {
+        let mut db = LoginDb::open_in_memory(Some("testing".into())).unwrap();
+        let insert = "INSERT INTO loginsSyncMeta (key, value) VALUES ('test', 'value')";
+        {
+            let tx = db.begin_write_transaction().unwrap();
+            tx.execute(insert, NO_PARAMS).unwrap();
+            tx.rollback().unwrap();
+        }
+        assert_eq!(db.get_meta::<String>("test").unwrap(), None);
+        {
+            // Dropping the transaction commits it.
+            let tx = db.begin_write_transaction().unwrap();
+            tx.execute(insert, NO_PARAMS).unwrap();
+        }
+        assert_eq!(db.get_meta::<String>("test").unwrap(), Some("value".into()));
+        db.delete_meta("test").unwrap();
+        {
+            let tx = db.begin_write_transaction().unwrap();
+            tx.execute(insert, NO_PARAMS).unwrap();
+            tx.commit().unwrap();
+        }
+        assert_eq!(db.get_meta::<String>("test").unwrap(), Some("value".into()));
+    }
+
+    #[test]
+    fn test_read_transaction() {
+        let dir = tempdir::TempDir::new("read_transaction").unwrap();
+        let dbpath = dir.path().join("logins.sqlite");
+        let db = LoginDb::open(&dbpath, Some("testing".into())).unwrap();
+        let other = LoginDb::open(&dbpath, Some("testing".into())).unwrap();
+        other.busy_timeout(Duration::from_millis(0)).unwrap();
+        let login = Login {
+            hostname: "https://www.example.com".into(),
+            http_realm: Some("realm".into()),
+            username: "user".into(),
+            password: "password".into(),
+            ..Login::default()
+        };
+        {
+            let tx = db.begin_read_transaction().unwrap();
+            assert_eq!(
+                tx.query_one::<i64>("SELECT COUNT(*) FROM loginsL").unwrap(),
+                0
+            );
+            // Neither this connection nor any other can write.
+            assert!(tx.execute("DELETE FROM loginsSyncMeta", NO_PARAMS).is_err());
+            assert!(other.add(login.clone()).is_err());
+        }
+        // Once it's dropped, both connections can write again.
+        db.add(login.clone()).unwrap();
+        other
+            .add(Login {
+                username: "other".into(),
+                ..login
+            })
+            .unwrap();
+        assert_eq!(db.get_all().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_writes_after_read_transaction() {
+        let db = LoginDb::open_in_memory(Some("testing".into())).unwrap();
+        let add = |username: &str| {
+            db.add(Login::with_defaults_for_testing(
+                "https://www.example.com",
+                username,
+                "password",
+            ))
+        };
+
+        drop(db.begin_read_transaction().unwrap());
+        add("a").unwrap();
+
+        // Even if SQLite has already ended the transaction.
+        let tx = db.begin_read_transaction().unwrap();
+        tx.execute_batch("ROLLBACK").unwrap();
+        assert!(add("b").is_err());
+        drop(tx);
+        add("b").unwrap();
+
+        // Or if a nested one failed to start.
+        let tx = db.begin_read_transaction().unwrap();
+        assert!(db.begin_read_transaction().is_err());
+        assert!(add("c").is_err());
+        drop(tx);
+        add("c").unwrap();
+
+        // Or if a stream is dropped part of the way through.
+        let mut stream = db.stream_all_in_pages(1).unwrap();
+        stream.next().unwrap().unwrap();
+        drop(stream);
+        add("d").unwrap();
+
+        assert_eq!(db.get_all().unwrap().len(), 4);
+    }
+
+    #[test]
+    fn test_get_all_with_custom_order() {
+        let db = LoginDb::open_in_memory(Some("testing".into())).unwrap();
+        for (username, times_used, time_last_used) in &[
+            ("unused_old", 0, 1000),
+            ("used_old", 3, 2000),
+            ("unused_new", 0, 5000),
+            ("used_new", 1, 4000),
+        ] {
+            let login = db
+                .add(Login {
+                    hostname: "https://www.example.com".into(),
+                    http_realm: Some("realm".into()),
+                    username: (*username).into(),
+                    password: "password".into(),
+                    time_last_used: *time_last_used,
+                    ..Login::default()
+                })
+                .unwrap();
+            // `add` counts a new login as used once, so set it afterwards.
+            db.execute_named(
+                "UPDATE loginsL SET timesUsed = :times_used WHERE guid = :guid",
+                named_params! { ":times_used": times_used, ":guid": login.guid },
+            )
+            .unwrap();
+        }
+        let usernames = db
+            .get_all_with_custom_order(
+                "CASE WHEN timesUsed > 0 THEN 0 ELSE 1 END, timeLastUsed DESC",
+            )
+            .unwrap()
+            .into_iter()
+            .map(|l| l.username)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            usernames,
+            vec!["used_new", "used_old", "unused_new", "unused_old"]
+        );
 
-        let rows_changed = self.execute_named(
-            &sql,
-            named_params! {
-                ":hostname": login.hostname,
-                ":http_realm": login.http_realm,
-                ":form_submit_url": login.form_submit_url,
-                ":username_field": login.username_field,
-                ":password_field": login.password_field,
-                ":username": login.username,
-                ":password": login.password,
-                ":guid": login.guid,
-                ":time_created": login.time_created,
-                ":times_used": login.times_used,
-                ":time_last_used": login.time_last_used,
-                ":time_password_changed": login.time_password_changed,
-                ":local_modified": now_ms,
-            },
-        )?;
-        if rows_changed == 0 {
-            log::error!(
-                "Record {:?} already exists (use `update` to update records, not add)",
-                login.guid
+        for bad in &["guid; DROP TABLE loginsL", "guid -- comment"] {
+            match db.get_all_with_custom_order(bad).unwrap_err().kind() {
+                ErrorKind::InvalidOrderBy(s) => assert_eq!(s, bad),
+                e => panic!("Unexpected error {:?}", e),
+            }
+        }
+        // Something that isn't valid SQL is just an SQL error.
+        assert!(db.get_all_with_custom_order("no_such_column").is_err());
+    }
+
+    #[test]
+    fn test_get_all_ordered() {
+        let db = LoginDb::open_in_memory(Some("testing".into())).unwrap();
+        for (username, hostname, times_used, time_last_used, time_password_changed) in &[
+            ("b", "https://b.example.com", 5, 3000, 2000),
+            ("A", "https://A.example.com", 1, 3000, 4000),
+            ("c", "https://c.example.com", 9, 1000, 1000),
+            ("a", "https://a.example.com", 5, 2000, 3000),
+        ] {
+            let login = db
+                .add(Login {
+                    hostname: hostname.to_lowercase(),
+                    http_realm: Some("realm".into()),
+                    username: (*username).into(),
+                    password: "password".into(),
+                    ..Login::default()
+                })
+                .unwrap();
+            // `add` sets the times itself, and normalizes the hostname, so set
+            // them afterwards.
+            db.execute_named(
+                "UPDATE loginsL
+                 SET hostname = :hostname,
+                     timesUsed = :times_used,
+                     timeLastUsed = :time_last_used,
+                     timePasswordChanged = :time_password_changed
+                 WHERE guid = :guid",
+                named_params! {
+                    ":hostname": hostname,
+                    ":times_used": times_used,
+                    ":time_last_used": time_last_used,
+                    ":time_password_changed": time_password_changed,
+                    ":guid": login.guid,
+                },
+            )
+            .unwrap();
+        }
+        let usernames = |order| {
+            db.get_all_ordered(order)
+                .unwrap()
+                .into_iter()
+                .map(|l| l.username)
+                .collect::<Vec<_>>()
+        };
+
+        let by_hostname = usernames(LoginSortOrder::Hostname);
+        let mut first_two = by_hostname[..2].to_vec();
+        first_two.sort();
+        assert_eq!(first_two, vec!["A", "a"]);
+        assert_eq!(by_hostname[2..].to_vec(), vec!["b", "c"]);
+        assert_eq!(
+            usernames(LoginSortOrder::LastUsed),
+            vec!["A", "b", "a", "c"]
+        );
+        assert_eq!(
+            usernames(LoginSortOrder::PasswordAge),
+            vec!["c", "b", "a", "A"]
+        );
+        assert_eq!(
+            usernames(LoginSortOrder::TimesUsed),
+            vec!["c", "a", "b", "A"]
+        );
+    }
+
+    #[test]
+    fn test_query_logins() {
+        let db = LoginDb::open_in_memory(Some("testing".into())).unwrap();
+        let add = |hostname: &str, username: &str, username_field: &str, password: &str| {
+            db.add(Login {
+                hostname: hostname.into(),
+                form_submit_url: Some(hostname.into()),
+                username: username.into(),
+                username_field: username_field.into(),
+                password: password.into(),
+                ..Login::default()
+            })
+            .unwrap()
+            .guid
+        };
+        let substring = add("https://www.mail.example.com", "someone", "", "pw");
+        let exact_username = add("https://www.example.org", "Mail", "", "pw");
+        let hostname_prefix = add("https://mail.example.com", "someone", "", "pw");
+        let username_field = add("https://www.example.net", "someone", "email", "pw");
+        add("https://www.example.edu", "someone", "", "mailbox");
+
+        let guids = |query| {
+            db.query_logins(query)
+                .unwrap()
+                .into_iter()
+                .map(|l| l.guid)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            guids("MAIL"),
+            vec![
+                hostname_prefix,
+                exact_username,
+                username_field,
+                substring.clone()
+            ]
+        );
+        assert_eq!(guids("www.mail"), vec![substring]);
+        // Passwords are never searched.
+        assert!(guids("mailbox").is_empty());
+        // Wildcards are matched literally.
+        assert!(guids("%%").is_empty());
+        assert!(guids("m").is_empty());
+    }
+
+    #[test]
+    fn test_audit_log() {
+        let mut db = LoginDb::open_in_memory(Some("testing".into())).unwrap();
+        let unaudited = db
+            .add(Login::with_defaults_for_testing(
+                "https://www.example.org",
+                "user",
+                "password",
+            ))
+            .unwrap();
+        assert_eq!(db.get_audit_log(&unaudited.guid).unwrap(), vec![]);
+
+        db.set_audit_actor(Some("tester".into()));
+        let login = db
+            .add(Login::with_defaults_for_testing(
+                "https://www.example.com",
+                "user",
+                "password",
+            ))
+            .unwrap();
+        db.update(Login {
+            password: "password2".into(),
+            ..login.clone()
+        })
+        .unwrap();
+        db.touch(&login.guid).unwrap();
+        db.delete(&login.guid).unwrap();
+        db.set_audit_actor(None);
+        db.touch(&unaudited.guid).unwrap();
+
+        let entries = db.get_audit_log(&login.guid).unwrap();
+        assert_eq!(
+            entries.iter().map(|e| e.operation).collect::<Vec<_>>(),
+            vec![
+                AuditOperation::Add,
+                AuditOperation::Update,
+                AuditOperation::Touch,
+                AuditOperation::Delete,
+            ]
+        );
+        assert!(entries
+            .iter()
+            .all(|e| e.guid == login.guid.as_str() && e.actor == "tester"));
+        assert!(entries
+            .windows(2)
+            .all(|w| w[0].id < w[1].id && w[0].timestamp_ms <= w[1].timestamp_ms));
+        assert_eq!(db.get_audit_log(&unaudited.guid).unwrap(), vec![]);
+
+        db.set_audit_actor(Some("wiper".into()));
+        db.wipe(&db.begin_interrupt_scope()).unwrap();
+        let entries = db.get_audit_log(&unaudited.guid).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].operation, AuditOperation::Wipe);
+        assert_eq!(entries[0].actor, "wiper");
+        // The deleted login isn't wiped again.
+        assert_eq!(db.get_audit_log(&login.guid).unwrap().len(), 4);
+
+        // The audit log survives a local wipe.
+        db.wipe_local().unwrap();
+        assert_eq!(db.get_audit_log(&login.guid).unwrap().len(), 4);
+    }
+
+    #[test]
+    fn test_audit_log_batch_operations() {
+        let mut db = LoginDb::open_in_memory(Some("testing".into())).unwrap();
+        db.set_audit_actor(Some("batch".into()));
+        let ops = |db: &LoginDb, guid: &str| {
+            db.get_audit_log(guid)
+                .unwrap()
+                .into_iter()
+                .map(|e| {
+                    assert_eq!(e.actor, "batch");
+                    e.operation
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let imported = vec![
+            Login::with_defaults_for_testing("https://www.example.com", "alice", "password"),
+            Login::with_defaults_for_testing("https://www.example.com", "bob", "password"),
+        ];
+        db.import_multiple(&imported).unwrap();
+        let (a, b) = (imported[0].guid_str(), imported[1].guid_str());
+        assert_eq!(ops(&db, a), vec![AuditOperation::Add]);
+        assert_eq!(ops(&db, b), vec![AuditOperation::Add]);
+
+        db.touch_many(&[a, b]).unwrap();
+        db.merge_logins(a, b).unwrap();
+        assert_eq!(
+            ops(&db, a),
+            vec![
+                AuditOperation::Add,
+                AuditOperation::Touch,
+                AuditOperation::Update
+            ]
+        );
+        assert_eq!(
+            ops(&db, b),
+            vec![
+                AuditOperation::Add,
+                AuditOperation::Touch,
+                AuditOperation::Delete
+            ]
+        );
+
+        let new_guid = db.reset_guid(a, Some("reset-guid")).unwrap();
+        assert_eq!(ops(&db, a).last(), Some(&AuditOperation::Update));
+        assert_eq!(ops(&db, &new_guid), vec![AuditOperation::Update]);
+
+        let padded = db
+            .add(Login::with_defaults_for_testing(
+                "https://www.example.org",
+                "carol",
+                "password",
+            ))
+            .unwrap();
+        db.execute_all(&[&format!(
+            "UPDATE loginsL SET hostname = ' https://www.example.org ' WHERE guid = '{}'",
+            padded.guid
+        )])
+        .unwrap();
+        assert_eq!(db.auto_fix_invalid_logins().unwrap(), 1);
+        assert_eq!(
+            ops(&db, &padded.guid),
+            vec![AuditOperation::Add, AuditOperation::Update]
+        );
+
+        db.execute_all(&[&format!(
+            "UPDATE loginsL SET hostname = 'https://www.example.org/' WHERE guid = '{}'",
+            padded.guid
+        )])
+        .unwrap();
+        assert_eq!(
+            db.run_integrity_repair()
+                .unwrap()
+                .invalid_records_quarantined,
+            1
+        );
+        let (id, _, _) = db.get_quarantined_records().unwrap()[0].clone();
+        db.restore_from_quarantine(id).unwrap();
+        assert_eq!(
+            ops(&db, &padded.guid),
+            vec![
+                AuditOperation::Add,
+                AuditOperation::Update,
+                AuditOperation::Delete,
+                AuditOperation::Add
+            ]
+        );
+    }
+
+    #[test]
+    fn test_change_hook() {
+        use std::sync::Mutex;
+        let db = LoginDb::open_in_memory(Some("testing".into())).unwrap();
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let hook_events = Arc::clone(&events);
+        db.register_change_hook(move |e| hook_events.lock().unwrap().push(e))
+            .unwrap();
+        let take_events = || std::mem::take(&mut *events.lock().unwrap());
+        let event = |kind, guid: &Guid, table| ChangeEvent {
+            kind,
+            guid: Some(guid.to_string()),
+            table,
+        };
+
+        let login = db
+            .add(Login {
+                hostname: "https://www.example.com".into(),
+                http_realm: Some("realm".into()),
+                username: "user".into(),
+                password: "password".into(),
+                ..Login::default()
+            })
+            .unwrap();
+        assert_eq!(
+            take_events(),
+            vec![event(ChangeKind::Insert, &login.guid, "loginsL")]
+        );
+
+        db.update(Login {
+            password: "password2".into(),
+            ..login.clone()
+        })
+        .unwrap();
+        assert_eq!(
+            take_events(),
+            vec![event(ChangeKind::Update, &login.guid, "loginsL")]
+        );
+
+        db.delete(&login.guid).unwrap();
+        assert_eq!(
+            take_events(),
+            vec![event(ChangeKind::Delete, &login.guid, "loginsL")]
+        );
+
+        db.apply_incoming_from_json(include_str!("../fixtures/incoming_new.json"), 1000.0)
+            .unwrap();
+        let synced = take_events();
+        assert_eq!(synced.len(), 2);
+        assert!(synced
+            .iter()
+            .all(|e| e.kind == ChangeKind::Insert && e.table == "loginsM"));
+    }
+
+    #[test]
+    fn test_subscribe() {
+        let db = LoginDb::open_in_memory(Some("testing".into())).unwrap();
+        let first = db.subscribe().unwrap();
+        let second = db.subscribe().unwrap();
+        let dropped = db.subscribe().unwrap();
+        drop(dropped);
+
+        let login = db
+            .add(Login {
+                hostname: "https://www.example.com".into(),
+                http_realm: Some("realm".into()),
+                username: "user".into(),
+                password: "password".into(),
+                ..Login::default()
+            })
+            .unwrap();
+        db.update(Login {
+            password: "password2".into(),
+            ..login.clone()
+        })
+        .unwrap();
+        db.delete(&login.guid).unwrap();
+
+        let guid = login.guid.to_string();
+        let expected = vec![
+            LoginChangeEvent::Added(guid.clone()),
+            LoginChangeEvent::Updated(guid.clone()),
+            LoginChangeEvent::Deleted(guid),
+        ];
+        assert_eq!(first.try_iter().collect::<Vec<_>>(), expected);
+        assert_eq!(second.try_iter().collect::<Vec<_>>(), expected);
+
+        // Nothing is sent for changes which are rolled back.
+        {
+            let tx = db.unchecked_transaction().unwrap();
+            db.add(Login {
+                hostname: "https://www.example.org".into(),
+                http_realm: Some("realm".into()),
+                username: "user".into(),
+                password: "password".into(),
+                ..Login::default()
+            })
+            .unwrap();
+            drop(tx);
+        }
+        assert_eq!(first.try_recv().ok(), None);
+
+        // ...or undone by rolling back a savepoint, and nothing is sent
+        // until the transaction has been committed.
+        let mut db = db;
+        let new_login = |username: &str| Login {
+            hostname: "https://www.example.org".into(),
+            http_realm: Some("realm".into()),
+            username: username.into(),
+            password: "password".into(),
+            ..Login::default()
+        };
+        let kept = db
+            .execute_in_transaction(|db| {
+                let kept = db.add(new_login("kept"))?;
+                {
+                    let mut sp = db.savepoint("undone")?;
+                    sp.add(new_login("rolled back to"))?;
+                    sp.rollback_to()?;
+                    sp.add(new_login("dropped"))?;
+                }
+                // A nested transaction which fails is rolled back too.
+                assert!(db
+                    .execute_in_transaction(|db| {
+                        db.add(new_login("failed"))?;
+                        db.add(new_login("failed"))
+                    })
+                    .is_err());
+                assert_eq!(first.try_recv().ok(), None);
+                Ok(kept)
+            })
+            .unwrap();
+        assert_eq!(
+            first.try_iter().collect::<Vec<_>>(),
+            vec![LoginChangeEvent::Added(kept.guid.to_string())]
+        );
+        {
+            let tx = db.begin_write_transaction().unwrap();
+            tx.execute("DELETE FROM loginsL WHERE guid = ?", &[kept.guid.as_str()])
+                .unwrap();
+            tx.rollback().unwrap();
+        }
+        assert_eq!(first.try_recv().ok(), None);
+        let sp = db.savepoint("outermost").unwrap();
+        sp.delete(&kept.guid).unwrap();
+        assert_eq!(first.try_recv().ok(), None);
+        sp.release().unwrap();
+        assert_eq!(
+            first.try_iter().collect::<Vec<_>>(),
+            vec![LoginChangeEvent::Deleted(kept.guid.to_string())]
+        );
+        assert_eq!(second.try_iter().count(), 5);
+
+        // Synced records are seen too, but wiping only sends `Wiped`.
+        db.apply_incoming_from_json(include_str!("../fixtures/incoming_new.json"), 1000.0)
+            .unwrap();
+        let synced = first.try_iter().collect::<Vec<_>>();
+        assert_eq!(synced.len(), 2);
+        assert!(synced
+            .iter()
+            .all(|e| matches!(e, LoginChangeEvent::Added(_))));
+        db.wipe_local().unwrap();
+        assert_eq!(
+            first.try_iter().collect::<Vec<_>>(),
+            vec![LoginChangeEvent::Wiped]
+        );
+
+        db.reset(&StoreSyncAssociation::Disconnected).unwrap();
+        assert_eq!(
+            second.try_iter().skip(2).collect::<Vec<_>>(),
+            vec![LoginChangeEvent::Wiped, LoginChangeEvent::Reset]
+        );
+    }
+
+    #[test]
+    fn test_get_all_locked() {
+        let db = LoginDb::open_in_memory(Some("testing".into())).unwrap();
+        db.apply_incoming_from_json(include_str!("../fixtures/incoming_new.json"), 1000.0)
+            .unwrap();
+        let local = db
+            .add(Login {
+                hostname: "https://www.example.net".into(),
+                http_realm: Some("realm".into()),
+                username: "carol".into(),
+                password: "password".into(),
+                time_last_used: 1234,
+                times_used: 5,
+                ..Login::default()
+            })
+            .unwrap();
+        let deleted = db
+            .add(Login {
+                hostname: "https://www.example.org".into(),
+                http_realm: Some("realm".into()),
+                username: "dave".into(),
+                password: "password".into(),
+                ..Login::default()
+            })
+            .unwrap();
+        db.delete(&deleted.guid).unwrap();
+
+        let mut locked = db.get_all_locked().unwrap();
+        locked.sort_by(|a, b| a.id.cmp(&b.id));
+        let mut expected = db.get_all().unwrap();
+        expected.sort_by(|a, b| a.guid.cmp(&b.guid));
+        assert_eq!(locked.len(), 3);
+        for (locked, login) in locked.iter().zip(expected) {
+            assert_eq!(
+                *locked,
+                LockedLogin {
+                    id: login.guid.into_string(),
+                    hostname: login.hostname,
+                    username: login.username,
+                    username_field: login.username_field,
+                    password_field: login.password_field,
+                    http_realm: login.http_realm,
+                    form_submit_url: login.form_submit_url,
+                    time_last_used: login.time_last_used,
+                    times_used: login.times_used as u32,
+                }
             );
-            throw!(ErrorKind::DuplicateGuid(login.guid.into_string()));
         }
-        tx.commit()?;
-        Ok(login)
+        let carol = locked.iter().find(|l| l.id == local.guid).unwrap();
+        assert_eq!(carol.times_used, 5);
+        assert_eq!(carol.time_last_used, 1234);
+    }
+
+    #[test]
+    fn test_unlock_login() {
+        let db = LoginDb::open_in_memory(Some("testing".into())).unwrap();
+        db.apply_incoming_from_json(include_str!("../fixtures/incoming_new.json"), 1000.0)
+            .unwrap();
+        let login = db
+            .add(Login {
+                hostname: "https://www.example.net".into(),
+                http_realm: Some("realm".into()),
+                username: "carol".into(),
+                password: "secret".into(),
+                ..Login::default()
+            })
+            .unwrap();
+
+        assert_eq!(
+            db.unlock_login(&login.guid).unwrap(),
+            Some("secret".to_string())
+        );
+        assert_eq!(
+            db.unlock_login("dummy_000001").unwrap(),
+            Some("hunter2".to_string())
+        );
+        assert_eq!(db.unlock_login("nonexistent").unwrap(), None);
+
+        db.delete(&login.guid).unwrap();
+        db.delete("dummy_000001").unwrap();
+        assert_eq!(db.unlock_login(&login.guid).unwrap(), None);
+        assert_eq!(db.unlock_login("dummy_000001").unwrap(), None);
+    }
+
+    #[test]
+    fn test_stream_all() {
+        let db = LoginDb::open_in_memory(Some("testing".into())).unwrap();
+        db.apply_incoming_from_json(include_str!("../fixtures/incoming_new.json"), 1000.0)
+            .unwrap();
+        for i in 0..5 {
+            db.add(Login {
+                hostname: "https://www.example.net".into(),
+                http_realm: Some("realm".into()),
+                username: format!("user{}", i),
+                password: "password".into(),
+                ..Login::default()
+            })
+            .unwrap();
+        }
+        let expected = db.get_all_in_order().unwrap();
+        assert_eq!(expected.len(), 7);
+
+        let streamed = db
+            .stream_all()
+            .unwrap()
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(streamed, expected);
+
+        // Page boundaries, including one that falls exactly on the end.
+        for page_size in &[1, 2, 7] {
+            let streamed = db
+                .stream_all_in_pages(*page_size)
+                .unwrap()
+                .collect::<Result<Vec<_>>>()
+                .unwrap();
+            assert_eq!(streamed, expected);
+        }
+    }
+
+    #[test]
+    fn test_stream_all_snapshot() {
+        let dir = tempdir::TempDir::new("stream_all_snapshot").unwrap();
+        let dbpath = dir.path().join("logins.sqlite");
+        let db = LoginDb::open(&dbpath, Some("testing".into())).unwrap();
+        let add = |db: &LoginDb, guid: &str| {
+            db.add(Login {
+                guid: guid.into(),
+                ..Login::with_defaults_for_testing(
+                    &format!("https://{}.example.com", guid),
+                    "user",
+                    "password",
+                )
+            })
+        };
+        for guid in &["dummy_000001", "dummy_000003", "dummy_000005"] {
+            add(&db, guid).unwrap();
+        }
+        let expected = db.get_all_in_order().unwrap();
+        let other = LoginDb::open(&dbpath, Some("testing".into())).unwrap();
+        other.set_busy_timeout(10).unwrap();
+
+        let mut stream = db.stream_all_in_pages(1).unwrap();
+        let mut streamed = vec![stream.next().unwrap().unwrap()];
+        // Neither connection can write between pages, so the rest of the
+        // logins are as they were when the stream started.
+        assert!(add(&db, "dummy_000002").is_err());
+        assert!(add(&other, "dummy_000004").is_err());
+        assert!(other.delete("dummy_000003").is_err());
+        streamed.extend(stream.map(Result::unwrap));
+        assert_eq!(streamed, expected);
+
+        // Once it's finished, both can write again.
+        add(&db, "dummy_000002").unwrap();
+        add(&other, "dummy_000004").unwrap();
+        assert_eq!(db.get_all().unwrap().len(), 5);
+    }
+
+    #[test]
+    fn test_stream_all_error() {
+        let db = LoginDb::open_in_memory(Some("testing".into())).unwrap();
+        db.apply_incoming_from_json(include_str!("../fixtures/incoming_new.json"), 1000.0)
+            .unwrap();
+        // Something `Login::from_row` can't read.
+        db.execute_all(&["UPDATE loginsM SET timesUsed = 'bogus' WHERE guid = 'dummy_000002'"])
+            .unwrap();
+        for page_size in &[1, STREAM_PAGE_SIZE] {
+            let results: Vec<_> = db.stream_all_in_pages(*page_size).unwrap().collect();
+            assert_eq!(results.len(), 2);
+            assert_eq!(results[0].as_ref().unwrap().guid, "dummy_000001");
+            assert!(results[1].is_err());
+        }
+    }
+
+    struct LengthChecker;
+
+    impl PasswordStrengthChecker for LengthChecker {
+        fn is_weak(&self, password: &str) -> bool {
+            password.len() < 8
+        }
+    }
+
+    #[test]
+    fn test_get_security_report() {
+        let db = LoginDb::open_in_memory(Some("testing".into())).unwrap();
+        let now_ms = util::system_time_ms_i64(SystemTime::now());
+        let day_ms = 24 * 60 * 60 * 1000;
+        // (hostname, password, time_password_changed, time_last_used)
+        for (hostname, password, changed, last_used) in &[
+            ("https://a.example.com", "hunter2", now_ms, now_ms + 1),
+            (
+                "http://b.example.com",
+                "shared-secret",
+                now_ms - 100 * day_ms,
+                now_ms + 1,
+            ),
+            ("https://c.example.com", "shared-secret", now_ms, now_ms),
+            (
+                "https://d.example.com",
+                "Str0ng&Unique!",
+                now_ms,
+                now_ms + 1,
+            ),
+        ] {
+            db.add(Login {
+                hostname: (*hostname).into(),
+                http_realm: Some("realm".into()),
+                username: "user".into(),
+                password: (*password).into(),
+                time_created: now_ms,
+                time_password_changed: *changed,
+                time_last_used: *last_used,
+                ..Login::default()
+            })
+            .unwrap();
+        }
+        let deleted = db
+            .add(Login {
+                hostname: "http://e.example.com".into(),
+                http_realm: Some("realm".into()),
+                username: "user".into(),
+                password: "hunter2".into(),
+                ..Login::default()
+            })
+            .unwrap();
+        db.delete(&deleted.guid).unwrap();
+
+        assert_eq!(
+            db.get_security_report(&LengthChecker, 30).unwrap(),
+            SecurityReport {
+                total: 4,
+                reused_count: 2,
+                weak_count: 1,
+                old_password_count: 1,
+                http_only_count: 1,
+                never_used_count: 1,
+            }
+        );
+        assert_eq!(
+            db.get_security_report(&LengthChecker, 365)
+                .unwrap()
+                .old_password_count,
+            0
+        );
+    }
+
+    fn make_conflict(server_time: ServerTimestamp) -> (LoginDb, IncomingChangeset) {
+        let db = LoginDb::open_in_memory(Some("testing".into())).unwrap();
+        db.apply_incoming_from_json(include_str!("../fixtures/incoming_new.json"), 1000.0)
+            .unwrap();
+        let mut login = db.get_by_id("dummy_000001").unwrap().unwrap();
+        login.password = "local-pw".into();
+        db.update(login).unwrap();
+
+        let payload: Payload = serde_json::from_value(serde_json::json!({
+            "id": "dummy_000001",
+            "hostname": "https://www.example.com",
+            "formSubmitURL": "https://www.example.com/login",
+            "username": "alice",
+            "password": "hunter2",
+            "usernameField": "remote-user",
+            "passwordField": "pass",
+            "timeCreated": 1_500_000_000_000i64,
+            "timePasswordChanged": 1_500_000_000_000i64,
+            "timesUsed": 1,
+        }))
+        .unwrap();
+        let mut inbound = IncomingChangeset::new("passwords", server_time);
+        inbound.changes.push((payload, server_time));
+        (db, inbound)
+    }
+
+    #[test]
+    fn test_apply_incoming_with_policy() {
+        let now_ms = util::system_time_ms_i64(SystemTime::now());
+        let past = ServerTimestamp::from_millis(now_ms - 60 * 60 * 1000);
+        let future = ServerTimestamp::from_millis(now_ms + 60 * 60 * 1000);
+        // (policy, server time, expected password, expected username field, uploads)
+        for (policy, server_time, password, username_field, uploads) in &[
+            (
+                ConflictResolution::FieldLevel,
+                past,
+                "local-pw",
+                "remote-user",
+                true,
+            ),
+            (
+                ConflictResolution::ClientWins,
+                future,
+                "local-pw",
+                "user",
+                true,
+            ),
+            (
+                ConflictResolution::ServerWins,
+                past,
+                "hunter2",
+                "remote-user",
+                false,
+            ),
+            (
+                ConflictResolution::NewerWins,
+                past,
+                "local-pw",
+                "user",
+                true,
+            ),
+            (
+                ConflictResolution::NewerWins,
+                future,
+                "hunter2",
+                "remote-user",
+                false,
+            ),
+        ] {
+            let (db, inbound) = make_conflict(*server_time);
+            let outgoing = db.apply_incoming_with_policy(inbound, *policy).unwrap();
+            let login = db.get_by_id("dummy_000001").unwrap().unwrap();
+            assert_eq!(login.password, *password, "{:?}", policy);
+            assert_eq!(login.username_field, *username_field, "{:?}", policy);
+            assert_eq!(
+                outgoing.changes.iter().any(|p| p.id() == "dummy_000001"),
+                *uploads,
+                "{:?}",
+                policy
+            );
+            // Either way, the mirror should now match the server.
+            let mirror_username_field: String = db
+                .query_one("SELECT usernameField FROM loginsM WHERE guid = 'dummy_000001'")
+                .unwrap();
+            assert_eq!(mirror_username_field, "remote-user", "{:?}", policy);
+        }
     }
 
-    pub fn import_multiple(&self, logins: &[Login]) -> Result<MigrationMetrics> {
-        // Check if the logins table is empty first.
-        let mut num_existing_logins =
-            self.query_row::<i64, _, _>("SELECT COUNT(*) FROM loginsL", NO_PARAMS, |r| r.get(0))?;
-        num_existing_logins +=
-            self.query_row::<i64, _, _>("SELECT COUNT(*) FROM loginsM", NO_PARAMS, |r| r.get(0))?;
-        if num_existing_logins > 0 {
-            return Err(ErrorKind::NonEmptyTable.into());
-        }
-        let tx = self.unchecked_transaction()?;
+    #[test]
+    fn test_local_only_fields_survive_losing_conflicts() {
         let now_ms = util::system_time_ms_i64(SystemTime::now());
-        let import_start = Instant::now();
-        let sql = format!(
-            "INSERT OR IGNORE INTO loginsL (
-                hostname,
-                httpRealm,
-                formSubmitURL,
-                usernameField,
-                passwordField,
-                timesUsed,
-                username,
-                password,
-                guid,
-                timeCreated,
-                timeLastUsed,
-                timePasswordChanged,
-                local_modified,
-                is_deleted,
-                sync_status
-            ) VALUES (
-                :hostname,
-                :http_realm,
-                :form_submit_url,
-                :username_field,
-                :password_field,
-                :times_used,
-                :username,
-                :password,
-                :guid,
-                :time_created,
-                :time_last_used,
-                :time_password_changed,
-                :local_modified,
-                0, -- is_deleted
-                {new} -- sync_status
-            )",
-            new = SyncStatus::New as u8
-        );
-        let import_start_total_logins: u64 = logins.len() as u64;
-        let mut num_failed_fixup: u64 = 0;
-        let mut num_failed_insert: u64 = 0;
-        let mut fixup_phase_duration = Duration::new(0, 0);
-        let mut fixup_errors: Vec<String> = Vec::new();
-        let mut insert_errors: Vec<String> = Vec::new();
+        let future = ServerTimestamp::from_millis(now_ms + 60 * 60 * 1000);
+        let creation_source = CreationSource::Imported {
+            source_name: "Chrome".into(),
+        };
+        let check = |db: &LoginDb, guid: &str, what: &str| {
+            let login = db.get_by_id(guid).unwrap().unwrap();
+            assert_eq!(login.password, "hunter2", "{}", what);
+            assert_eq!(login.creation_source, creation_source, "{}", what);
+            assert_eq!(
+                login.associated_app.as_deref(),
+                Some("com.example.app"),
+                "{}",
+                what
+            );
+        };
 
-        for login in logins {
-            // This is a little bit of hoop-jumping to avoid cloning each borrowed item
-            // in order to *possibly* created a fixed-up version.
-            let mut login = login;
-            let maybe_fixed_login = login.maybe_fixup().and_then(|fixed| {
-                match &fixed {
-                    None => self.check_for_dupes(login)?,
-                    Some(l) => self.check_for_dupes(&l)?,
-                };
-                Ok(fixed)
-            });
-            match &maybe_fixed_login {
-                Ok(None) => {} // The provided login was fine all along
-                Ok(Some(l)) => {
-                    // We made a new, fixed-up Login.
-                    login = l;
-                }
-                Err(e) => {
-                    log::warn!("Skipping login {} as it is invalid ({}).", login.guid, e);
-                    fixup_errors.push(e.label().into());
-                    num_failed_fixup += 1;
-                    continue;
-                }
-            };
-            // Now we can safely insert it, knowing that it's valid data.
-            let old_guid = &login.guid; // Keep the old GUID around so we can debug errors easily.
-            let guid = if old_guid.is_valid_for_sync_server() {
-                old_guid.clone()
-            } else {
-                Guid::random()
-            };
-            fixup_phase_duration = import_start.elapsed();
-            match self.execute_named_cached(
-                &sql,
-                named_params! {
-                    ":hostname": login.hostname,
-                    ":http_realm": login.http_realm,
-                    ":form_submit_url": login.form_submit_url,
-                    ":username_field": login.username_field,
-                    ":password_field": login.password_field,
-                    ":username": login.username,
-                    ":password": login.password,
-                    ":guid": guid,
-                    ":time_created": login.time_created,
-                    ":times_used": login.times_used,
-                    ":time_last_used": login.time_last_used,
-                    ":time_password_changed": login.time_password_changed,
-                    ":local_modified": now_ms,
-                },
-            ) {
-                Ok(_) => log::info!("Imported {} (new GUID {}) successfully.", old_guid, guid),
-                Err(e) => {
-                    log::warn!("Could not import {} ({}).", old_guid, e);
-                    insert_errors.push(Error::from(e).label().into());
-                    num_failed_insert += 1;
-                }
-            };
+        // Picking the server's record, with a mirror record.
+        for policy in &[
+            ConflictResolution::ServerWins,
+            ConflictResolution::NewerWins,
+        ] {
+            let (db, inbound) = make_conflict(future);
+            db.execute_batch(
+                "UPDATE loginsL
+                 SET creation_source = 2,
+                     creation_source_name = 'Chrome',
+                     associated_app = 'com.example.app'
+                 WHERE guid = 'dummy_000001'",
+            )
+            .unwrap();
+            db.apply_incoming_with_policy(inbound, *policy).unwrap();
+            check(&db, "dummy_000001", &format!("{:?}", policy));
         }
-        tx.commit()?;
 
-        let num_post_fixup = import_start_total_logins - num_failed_fixup;
-        let num_failed = num_failed_fixup + num_failed_insert;
-        let insert_phase_duration = import_start
-            .elapsed()
-            .checked_sub(fixup_phase_duration)
-            .unwrap_or_else(|| Duration::new(0, 0));
-        let mut all_errors = Vec::new();
-        all_errors.extend(fixup_errors.clone());
-        all_errors.extend(insert_errors.clone());
+        // A two-way merge, for a local record with the same GUID or a dupe.
+        for (incoming_guid, what) in &[("dummy_000001", "two-way merge"), ("dummy_000002", "dupe")]
+        {
+            let db = LoginDb::open_in_memory(Some("testing".into())).unwrap();
+            db.add(Login {
+                guid: "dummy_000001".into(),
+                creation_source: creation_source.clone(),
+                associated_app: Some("com.example.app".into()),
+                ..Login::with_defaults_for_testing("https://www.example.com", "alice", "local-pw")
+            })
+            .unwrap();
+            let payload: Payload = serde_json::from_value(serde_json::json!({
+                "id": incoming_guid,
+                "hostname": "https://www.example.com",
+                "formSubmitURL": "https://www.example.com",
+                "username": "alice",
+                "password": "hunter2",
+                "usernameField": "username",
+                "passwordField": "password",
+                "timeCreated": 1_500_000_000_000i64,
+                "timePasswordChanged": now_ms,
+                "timesUsed": 1,
+            }))
+            .unwrap();
+            let mut inbound = IncomingChangeset::new("passwords", future);
+            inbound.changes.push((payload, future));
+            db.apply_incoming_with_policy(inbound, ConflictResolution::FieldLevel)
+                .unwrap();
+            check(&db, incoming_guid, what);
+        }
+    }
 
-        let metrics = MigrationMetrics {
-            fixup_phase: MigrationPhaseMetrics {
-                num_processed: import_start_total_logins,
-                num_succeeded: num_post_fixup,
-                num_failed: num_failed_fixup,
-                total_duration: fixup_phase_duration.as_millis(),
-                errors: fixup_errors,
-            },
-            insert_phase: MigrationPhaseMetrics {
-                num_processed: num_post_fixup,
-                num_succeeded: num_post_fixup - num_failed_insert,
-                num_failed: num_failed_insert,
-                total_duration: insert_phase_duration.as_millis(),
-                errors: insert_errors,
-            },
-            num_processed: import_start_total_logins,
-            num_succeeded: import_start_total_logins - num_failed,
-            num_failed,
-            total_duration: fixup_phase_duration
-                .checked_add(insert_phase_duration)
-                .unwrap_or_else(|| Duration::new(0, 0))
-                .as_millis(),
-            errors: all_errors,
+    #[test]
+    fn test_imported_login_losing_conflict_keeps_source() {
+        let db = LoginDb::open_in_memory(Some("testing".into())).unwrap();
+        db.import_multiple(&[Login {
+            guid: "dummy_000001".into(),
+            ..Login::with_defaults_for_testing("https://www.example.com", "alice", "local-pw")
+        }])
+        .unwrap();
+        // As for every other login which wasn't imported with a name.
+        let name_is_null: bool = db
+            .query_one("SELECT creation_source_name IS NULL FROM loginsL")
+            .unwrap();
+        assert!(name_is_null);
+        let imported = CreationSource::Imported {
+            source_name: String::new(),
         };
-        log::info!(
-            "Finished importing logins with the following metrics: {:#?}",
-            metrics
-        );
-        Ok(metrics)
-    }
+        assert_eq!(db.get_all_for_source(imported.clone()).unwrap().len(), 1);
 
-    pub fn update(&self, login: Login) -> Result<()> {
-        let login = self.fixup_and_check_for_dupes(login)?;
+        let now_ms = util::system_time_ms_i64(SystemTime::now());
+        let future = ServerTimestamp::from_millis(now_ms + 60 * 60 * 1000);
+        let payload: Payload = serde_json::from_value(serde_json::json!({
+            "id": "dummy_000001",
+            "hostname": "https://www.example.com",
+            "formSubmitURL": "https://www.example.com",
+            "username": "alice",
+            "password": "hunter2",
+            "timeCreated": 1_500_000_000_000i64,
+            "timePasswordChanged": now_ms,
+        }))
+        .unwrap();
+        let mut inbound = IncomingChangeset::new("passwords", future);
+        inbound.changes.push((payload, future));
+        db.apply_incoming_with_policy(inbound, ConflictResolution::ServerWins)
+            .unwrap();
+        let logins = db.get_all_for_source(imported).unwrap();
+        assert_eq!(logins.len(), 1);
+        assert_eq!(logins[0].password, "hunter2");
+        assert!(db
+            .get_all_for_source(CreationSource::Synced)
+            .unwrap()
+            .is_empty());
+    }
 
-        let tx = self.unchecked_transaction()?;
-        // Note: These fail with DuplicateGuid if the record doesn't exist.
-        self.ensure_local_overlay_exists(login.guid_str())?;
-        self.mark_mirror_overridden(login.guid_str())?;
+    #[test]
+    fn test_conflict_resolution() {
+        let now_ms = util::system_time_ms_i64(SystemTime::now());
+        let past = ServerTimestamp::from_millis(now_ms - 60 * 60 * 1000);
+        // `make_conflict` changes the password locally, and the username field
+        // remotely.
+        // (resolution, expected password, expected username field, uploads)
+        for (resolution, password, username_field, uploads) in &[
+            (
+                ConflictResolution::FieldLevel,
+                "local-pw",
+                "remote-user",
+                true,
+            ),
+            (
+                ConflictResolution::ServerWins,
+                "hunter2",
+                "remote-user",
+                false,
+            ),
+            (ConflictResolution::ClientWins, "local-pw", "user", true),
+        ] {
+            let (db, inbound) = make_conflict(past);
+            assert_eq!(db.conflict_resolution(), ConflictResolution::FieldLevel);
+            db.set_conflict_resolution(*resolution);
+            let mut telem = telemetry::Engine::new("passwords");
+            let outgoing = db
+                .do_apply_incoming(inbound, &mut telem, &db.begin_interrupt_scope())
+                .unwrap();
+            let login = db.get_by_id("dummy_000001").unwrap().unwrap();
+            assert_eq!(login.password, *password, "{:?}", resolution);
+            assert_eq!(login.username_field, *username_field, "{:?}", resolution);
+            assert_eq!(
+                outgoing.changes.iter().any(|p| p.id() == "dummy_000001"),
+                *uploads,
+                "{:?}",
+                resolution
+            );
+        }
+    }
 
+    #[test]
+    fn test_get_logins_for_autocomplete() {
+        let db = LoginDb::open_in_memory(Some("testing".into())).unwrap();
         let now_ms = util::system_time_ms_i64(SystemTime::now());
+        let hour_ms = 60 * 60 * 1000;
+        let year_ms = 365 * 24 * hour_ms;
+        for (hostname, username, form_submit_url, times_used, last_used_ago_ms) in &[
+            (
+                "https://www.example.com",
+                "same-form",
+                "https://www.example.com",
+                1,
+                year_ms,
+            ),
+            (
+                "https://www.example.com",
+                "recent",
+                "https://accounts.example.com",
+                20,
+                hour_ms,
+            ),
+            (
+                "https://www.example.com",
+                "exact",
+                "https://accounts.example.com",
+                1,
+                year_ms,
+            ),
+            (
+                "https://auth.example.com",
+                "subdomain-frequent",
+                "https://auth.example.com",
+                100,
+                hour_ms,
+            ),
+            (
+                "https://auth.example.com",
+                "subdomain",
+                "https://auth.example.com",
+                1,
+                year_ms,
+            ),
+            // These contain "example.com", but aren't under it.
+            (
+                "https://notexample.com",
+                "unrelated",
+                "https://notexample.com",
+                100,
+                hour_ms,
+            ),
+            (
+                "https://example.com.evil.net",
+                "evil",
+                "https://example.com.evil.net",
+                100,
+                hour_ms,
+            ),
+        ] {
+            db.add(Login {
+                hostname: (*hostname).into(),
+                username: (*username).into(),
+                password: "password".into(),
+                form_submit_url: Some((*form_submit_url).into()),
+                times_used: *times_used,
+                time_last_used: now_ms - last_used_ago_ms,
+                ..Login::default()
+            })
+            .unwrap();
+        }
 
-        let sql = format!(
-            "UPDATE loginsL
-             SET local_modified      = :now_millis,
-                 timeLastUsed        = :now_millis,
-                 -- Only update timePasswordChanged if, well, the password changed.
-                 timePasswordChanged = (CASE
-                     WHEN password = :password
-                     THEN timePasswordChanged
-                     ELSE :now_millis
-                 END),
-                 httpRealm           = :http_realm,
-                 formSubmitURL       = :form_submit_url,
-                 usernameField       = :username_field,
-                 passwordField       = :password_field,
-                 timesUsed           = timesUsed + 1,
-                 username            = :username,
-                 password            = :password,
-                 hostname            = :hostname,
-                 -- leave New records as they are, otherwise update them to `changed`
-                 sync_status         = max(sync_status, {changed})
-             WHERE guid = :guid",
-            changed = SyncStatus::Changed as u8
+        let scored = db
+            .get_logins_for_autocomplete("https://www.example.com/login")
+            .unwrap();
+        let usernames: Vec<&str> = scored.iter().map(|s| s.login.username.as_str()).collect();
+        assert_eq!(
+            usernames,
+            vec![
+                "same-form",
+                "recent",
+                "exact",
+                "subdomain-frequent",
+                "subdomain"
+            ]
         );
-
-        self.db.execute_named(
-            &sql,
-            named_params! {
-                ":hostname": login.hostname,
-                ":username": login.username,
-                ":password": login.password,
-                ":http_realm": login.http_realm,
-                ":form_submit_url": login.form_submit_url,
-                ":username_field": login.username_field,
-                ":password_field": login.password_field,
-                ":guid": login.guid,
-                ":now_millis": now_ms,
-            },
-        )?;
-        tx.commit()?;
-        Ok(())
+        assert!(scored[0].score >= 1.2, "{}", scored[0].score);
+        assert!(
+            scored[4].score > 0.8 && scored[4].score < 0.9,
+            "{}",
+            scored[4].score
+        );
+        assert!(db
+            .get_logins_for_autocomplete("about:blank")
+            .unwrap()
+            .is_empty());
     }
 
-    pub fn check_valid_with_no_dupes(&self, login: &Login) -> Result<()> {
-        login.check_valid()?;
-        self.check_for_dupes(login)
+    #[test]
+    fn test_merge_db() {
+        let dir = tempdir::TempDir::new("merge_db").unwrap();
+        let other_path = dir.path().join("other.sqlite");
+        let login = |username: &str, password: &str, changed_ms: i64| Login {
+            time_password_changed: changed_ms,
+            ..Login::with_defaults_for_testing("https://www.example.com", username, password)
+        };
+        {
+            let other = LoginDb::open(&other_path, Some("other-key".into())).unwrap();
+            other.add(login("newer-there", "new", 2000)).unwrap();
+            other.add(login("newer-here", "old", 1000)).unwrap();
+            other.add(login("identical", "password", 1000)).unwrap();
+            let synced = other.add(login("only-there", "password", 1000)).unwrap();
+            other
+                .mark_as_synchronized(
+                    &[synced.guid_str()],
+                    ServerTimestamp(1000),
+                    &other.begin_interrupt_scope(),
+                )
+                .unwrap();
+        }
+        let open_main = || {
+            let db = LoginDb::open_in_memory(Some("testing".into())).unwrap();
+            db.add(login("newer-there", "old", 1000)).unwrap();
+            db.add(login("newer-here", "new", 2000)).unwrap();
+            db.add(login("identical", "password", 1000)).unwrap();
+            db.add(login("only-here", "password", 1000)).unwrap();
+            db
+        };
+        let passwords = |db: &LoginDb| {
+            let mut passwords: Vec<_> = db
+                .get_all()
+                .unwrap()
+                .into_iter()
+                .map(|l| (l.username, l.password))
+                .collect();
+            passwords.sort();
+            passwords
+        };
+        let pair = |username: &str, password: &str| (username.to_string(), password.to_string());
+
+        let db = open_main();
+        let report = db
+            .merge_db(&other_path, Some("other-key"), MergeStrategy::KeepNewer)
+            .unwrap();
+        assert_eq!(
+            report,
+            MergeReport {
+                inserted: 1,
+                updated: 1,
+                skipped: 2,
+            }
+        );
+        assert_eq!(
+            passwords(&db),
+            vec![
+                pair("identical", "password"),
+                pair("newer-here", "new"),
+                pair("newer-there", "new"),
+                pair("only-here", "password"),
+                pair("only-there", "password"),
+            ]
+        );
+
+        let db = open_main();
+        let report = db
+            .merge_db(&other_path, Some("other-key"), MergeStrategy::KeepAll)
+            .unwrap();
+        assert_eq!(
+            report,
+            MergeReport {
+                inserted: 3,
+                updated: 0,
+                skipped: 1,
+            }
+        );
+        assert_eq!(
+            passwords(&db),
+            vec![
+                pair("identical", "password"),
+                pair("newer-here", "new"),
+                pair("newer-here", "old"),
+                pair("newer-there", "new"),
+                pair("newer-there", "old"),
+                pair("only-here", "password"),
+                pair("only-there", "password"),
+            ]
+        );
+
+        // The other database is detached again, even after a failure.
+        assert!(db
+            .merge_db(&other_path, Some("wrong-key"), MergeStrategy::KeepAll)
+            .is_err());
+        db.merge_db(&other_path, Some("other-key"), MergeStrategy::KeepNewer)
+            .unwrap();
     }
 
-    pub fn fixup_and_check_for_dupes(&self, login: Login) -> Result<Login> {
-        let login = login.fixup()?;
-        self.check_for_dupes(&login)?;
-        Ok(login)
+    #[test]
+    fn test_merge_db_fixups_and_tombstones() {
+        let dir = tempdir::TempDir::new("merge_db_fixups").unwrap();
+        let other_path = dir.path().join("other.sqlite");
+        let db = LoginDb::open_in_memory(Some("testing".into())).unwrap();
+        let deleted = db
+            .add(Login::with_defaults_for_testing(
+                "https://www.example.com",
+                "deleted-here",
+                "password",
+            ))
+            .unwrap();
+        db.delete(deleted.guid_str()).unwrap();
+        let existing = db
+            .add(Login::with_defaults_for_testing(
+                "https://www.example.com",
+                "updated",
+                "old",
+            ))
+            .unwrap();
+        {
+            let other = LoginDb::open(&other_path, Some("other-key".into())).unwrap();
+            other.add(deleted.clone()).unwrap();
+            other
+                .add(Login {
+                    time_password_changed: existing.time_password_changed + 1000,
+                    time_last_used: existing.time_last_used + 1000,
+                    times_used: 7,
+                    ..Login::with_defaults_for_testing("https://www.example.com", "updated", "new")
+                })
+                .unwrap();
+            let fixable = other
+                .add(Login::with_defaults_for_testing(
+                    "https://www.example.org",
+                    "fixable",
+                    "password",
+                ))
+                .unwrap();
+            let invalid = other
+                .add(Login::with_defaults_for_testing(
+                    "https://www.example.net",
+                    "invalid",
+                    "password",
+                ))
+                .unwrap();
+            for (guid, hostname) in &[
+                (fixable.guid_str(), "https://www.example.org/some/path"),
+                (invalid.guid_str(), "not a url"),
+            ] {
+                other
+                    .execute_named(
+                        "UPDATE loginsL SET hostname = :hostname WHERE guid = :guid",
+                        named_params! { ":hostname": hostname, ":guid": guid },
+                    )
+                    .unwrap();
+            }
+        }
+
+        let report = db
+            .merge_db(&other_path, Some("other-key"), MergeStrategy::KeepNewer)
+            .unwrap();
+        assert_eq!(
+            report,
+            MergeReport {
+                inserted: 1,
+                updated: 1,
+                skipped: 2,
+            }
+        );
+        // The login deleted here stays deleted.
+        assert!(db.is_deleted_locally(deleted.guid_str()).unwrap());
+        assert!(db.get_by_id(deleted.guid_str()).unwrap().is_none());
+        // The updated login keeps the other database's usage metadata.
+        let updated = db.get_by_id(existing.guid_str()).unwrap().unwrap();
+        assert_eq!(updated.password, "new");
+        assert_eq!(updated.times_used, 7);
+        assert_eq!(
+            updated.time_password_changed,
+            existing.time_password_changed + 1000
+        );
+        assert_eq!(updated.time_last_used, existing.time_last_used + 1000);
+        // The fixable login is fixed up, and the invalid one left out.
+        let mut hostnames: Vec<_> = db
+            .get_all()
+            .unwrap()
+            .into_iter()
+            .map(|l| l.hostname)
+            .collect();
+        hostnames.sort();
+        assert_eq!(
+            hostnames,
+            vec!["https://www.example.com", "https://www.example.org"]
+        );
     }
 
-    pub fn check_for_dupes(&self, login: &Login) -> Result<()> {
-        if self.dupe_exists(&login)? {
-            throw!(InvalidLogin::DuplicateLogin);
+    #[test]
+    fn test_wipe_local_changes() {
+        let db = LoginDb::open_in_memory(Some("testing".into())).unwrap();
+        let add = |username: &str| {
+            db.add(Login::with_defaults_for_testing(
+                "https://www.example.com",
+                username,
+                "password",
+            ))
+            .unwrap()
+        };
+        let changed = add("changed");
+        let deleted = add("deleted");
+        let unchanged = add("unchanged");
+        db.mark_as_synchronized(
+            &[changed.guid_str(), deleted.guid_str(), unchanged.guid_str()],
+            ServerTimestamp(1000),
+            &db.begin_interrupt_scope(),
+        )
+        .unwrap();
+        let mut mirror = db.get_all().unwrap();
+        mirror.sort_by(|a, b| a.guid.cmp(&b.guid));
+
+        db.update(Login {
+            password: "new-password".into(),
+            ..changed
+        })
+        .unwrap();
+        db.delete(deleted.guid_str()).unwrap();
+        add("added");
+        assert_eq!(db.get_outgoing_count().unwrap(), 3);
+
+        db.wipe_local_changes().unwrap();
+        let mut all = db.get_all().unwrap();
+        all.sort_by(|a, b| a.guid.cmp(&b.guid));
+        assert_eq!(all, mirror);
+        assert_eq!(
+            db.get_sync_status_counts().unwrap(),
+            SyncStatusCounts::default()
+        );
+        assert!(!db.has_pending_changes().unwrap());
+        for login in &mirror {
+            assert!(db.get_local_record(login.guid_str()).unwrap().is_none());
+            let record = db.get_mirror_record(login.guid_str()).unwrap().unwrap();
+            assert!(!record.is_overridden);
+            assert_eq!(record.login, *login);
         }
-        Ok(())
     }
 
-    pub fn dupe_exists(&self, login: &Login) -> Result<bool> {
-        // Note: the query below compares the guids of the given login with existing logins
-        //  to prevent a login from being considered a duplicate of itself (e.g. during updates).
-        Ok(self.db.query_row_named(
-            "SELECT EXISTS(
-                SELECT 1 FROM loginsL
-                WHERE is_deleted = 0
-                    AND guid <> :guid
-                    AND hostname = :hostname
-                    AND NULLIF(username, '') = :username
-                    AND (
-                        formSubmitURL = :form_submit
-                        OR
-                        httpRealm = :http_realm
-                    )
+    #[test]
+    fn test_get_outgoing_count() {
+        let db = LoginDb::open_in_memory(Some("testing".into())).unwrap();
+        assert_eq!(db.get_outgoing_count().unwrap(), 0);
+        assert!(!db.has_pending_changes().unwrap());
 
-                UNION ALL
+        let login = db
+            .add(Login::with_defaults_for_testing(
+                "https://www.example.com",
+                "user",
+                "password",
+            ))
+            .unwrap();
+        assert_eq!(db.get_outgoing_count().unwrap(), 1);
+        assert!(db.has_pending_changes().unwrap());
 
-                SELECT 1 FROM loginsM
-                WHERE is_overridden = 0
-                    AND guid <> :guid
-                    AND hostname = :hostname
-                    AND NULLIF(username, '') = :username
-                    AND (
-                        formSubmitURL = :form_submit
-                        OR
-                        httpRealm = :http_realm
-                    )
-             )",
-            named_params! {
-                ":guid": &login.guid,
-                ":hostname": &login.hostname,
-                ":username": &login.username,
-                ":http_realm": login.http_realm.as_ref(),
-                ":form_submit": login.form_submit_url.as_ref(),
-            },
-            |row| row.get(0),
-        )?)
-    }
+        db.mark_as_synchronized(
+            &[login.guid_str()],
+            ServerTimestamp(1000),
+            &db.begin_interrupt_scope(),
+        )
+        .unwrap();
+        assert_eq!(db.get_outgoing_count().unwrap(), 0);
+        assert!(!db.has_pending_changes().unwrap());
 
-    pub fn potential_dupes_ignoring_username(&self, login: &Login) -> Result<Vec<Login>> {
-        // Could be lazy_static-ed...
-        lazy_static::lazy_static! {
-            static ref DUPES_IGNORING_USERNAME_SQL: String = format!(
-                "SELECT {common_cols} FROM loginsL
-                WHERE is_deleted = 0
-                    AND hostname = :hostname
-                    AND (
-                        formSubmitURL = :form_submit
-                        OR
-                        httpRealm = :http_realm
-                    )
+        // Deletions need uploading too.
+        db.delete(login.guid_str()).unwrap();
+        assert_eq!(db.get_outgoing_count().unwrap(), 1);
+    }
 
-                UNION ALL
+    #[test]
+    fn test_dry_run_apply_incoming() {
+        let db = LoginDb::open_in_memory(Some("testing".into())).unwrap();
+        let add = |username: &str| {
+            db.add(Login::with_defaults_for_testing(
+                "https://www.example.com",
+                username,
+                "password",
+            ))
+            .unwrap()
+        };
+        let local_only = add("local-only");
+        let remote_changed = add("remote-changed");
+        let remote_deleted = add("remote-deleted");
+        let both_changed = add("both-changed");
+        db.mark_as_synchronized(
+            &[
+                remote_changed.guid_str(),
+                remote_deleted.guid_str(),
+                both_changed.guid_str(),
+            ],
+            ServerTimestamp(1000),
+            &db.begin_interrupt_scope(),
+        )
+        .unwrap();
+        db.update(Login {
+            password: "local-password".into(),
+            ..both_changed.clone()
+        })
+        .unwrap();
+        let local_both = db.get_by_id(both_changed.guid_str()).unwrap().unwrap();
+        let remote_changed = db.get_by_id(remote_changed.guid_str()).unwrap().unwrap();
 
-                SELECT {common_cols} FROM loginsM
-                WHERE is_overridden = 0
-                    AND hostname = :hostname
-                    AND (
-                        formSubmitURL = :form_submit
-                        OR
-                        httpRealm = :http_realm
-                    )
-                ",
-                common_cols = schema::COMMON_COLS
-            );
-        }
-        let mut stmt = self.db.prepare_cached(&DUPES_IGNORING_USERNAME_SQL)?;
-        let params = named_params! {
-            ":hostname": &login.hostname,
-            ":http_realm": login.http_realm.as_ref(),
-            ":form_submit": login.form_submit_url.as_ref(),
+        let server_time = ServerTimestamp(2000);
+        let incoming_changed = Login {
+            password: "remote-password".into(),
+            ..remote_changed.clone()
         };
-        // Needs to be two lines for borrow checker
-        let rows = stmt.query_and_then_named(params, Login::from_row)?;
-        rows.collect()
+        let incoming_both = Login {
+            username_field: "remote-username-field".into(),
+            ..both_changed.clone()
+        };
+        let incoming_new =
+            Login::with_defaults_for_testing("https://www.example.com", "remote-new", "password");
+        let mut inbound = IncomingChangeset::new("passwords", server_time);
+        inbound.changes = vec![
+            Payload::from_record(incoming_changed.clone()).unwrap(),
+            Payload::new_tombstone(remote_deleted.guid.clone()),
+            Payload::from_record(incoming_both).unwrap(),
+            Payload::from_record(incoming_new.clone()).unwrap(),
+        ]
+        .into_iter()
+        .map(|payload| (payload, server_time))
+        .collect();
+
+        let before = db.get_all().unwrap();
+        let preview = db.dry_run_apply_incoming(inbound).unwrap();
+        let expected_merge = Login {
+            username_field: "remote-username-field".into(),
+            ..local_both.clone()
+        };
+        assert_eq!(preview.to_insert, vec![incoming_new]);
+        assert_eq!(
+            preview.to_update,
+            vec![
+                (local_both, expected_merge.clone()),
+                (remote_changed, incoming_changed),
+            ]
+        );
+        assert_eq!(preview.to_delete, vec![remote_deleted.guid.to_string()]);
+        assert_eq!(preview.to_upload, vec![expected_merge, local_only]);
+
+        // Nothing changed.
+        assert_eq!(db.get_all().unwrap(), before);
+        assert_eq!(
+            db.get_sync_status_counts().unwrap(),
+            SyncStatusCounts {
+                new: 1,
+                changed: 1,
+                synced: 0,
+            }
+        );
     }
 
-    pub fn exists(&self, id: &str) -> Result<bool> {
-        Ok(self.db.query_row_named(
-            "SELECT EXISTS(
-                 SELECT 1 FROM loginsL
-                 WHERE guid = :guid AND is_deleted = 0
-                 UNION ALL
-                 SELECT 1 FROM loginsM
-                 WHERE guid = :guid AND is_overridden IS NOT 1
-             )",
-            named_params! { ":guid": id },
-            |row| row.get(0),
-        )?)
+    #[test]
+    fn test_apply_incoming_with_progress() {
+        let db = LoginDb::open_in_memory(Some("testing".into())).unwrap();
+        let server_time = ServerTimestamp::from_millis(1_600_000_000_000);
+        let mut inbound = IncomingChangeset::new("passwords", server_time);
+        for i in 0..250 {
+            let payload = serde_json::from_value(serde_json::json!({
+                "id": format!("dummy_{:06}", i),
+                "hostname": "https://www.example.com",
+                "formSubmitURL": "https://www.example.com/login",
+                "username": format!("user{}", i),
+                "password": "password",
+                "usernameField": "user",
+                "passwordField": "pass",
+            }))
+            .unwrap();
+            inbound.changes.push((payload, server_time));
+        }
+
+        let mut reports = Vec::new();
+        db.apply_incoming_with_progress(inbound, |progress| reports.push(progress))
+            .unwrap();
+        assert_eq!(db.count().unwrap(), 250);
+
+        let processed = |stage| {
+            reports
+                .iter()
+                .filter(|p| p.stage == stage)
+                .map(|p| {
+                    assert_eq!(p.total, 250);
+                    p.processed
+                })
+                .collect::<Vec<_>>()
+        };
+        // All 250 GUIDs fit in one query.
+        assert_eq!(processed(SyncProgressStage::Fetching), vec![250]);
+        assert_eq!(
+            processed(SyncProgressStage::Reconciling),
+            vec![100, 200, 250]
+        );
+        assert_eq!(reports[1].last_guid, "dummy_000099");
+        assert_eq!(reports.last().unwrap().last_guid, "dummy_000249");
     }
 
-    /// Delete the record with the provided id. Returns true if the record
-    /// existed already.
-    pub fn delete(&self, id: &str) -> Result<bool> {
-        let tx = self.unchecked_transaction_imm()?;
-        let exists = self.exists(id)?;
-        let now_ms = util::system_time_ms_i64(SystemTime::now());
+    #[test]
+    fn test_get_all_with_sync_metadata() {
+        let db = LoginDb::open_in_memory(Some("testing".into())).unwrap();
+        let login = db
+            .add(Login::with_defaults_for_testing(
+                "https://www.example.com",
+                "user",
+                "password",
+            ))
+            .unwrap();
+        let guid = login.guid_str().to_owned();
+        let metadata = |db: &LoginDb| {
+            let all = db.get_all_with_sync_metadata().unwrap();
+            assert_eq!(all.len(), 1);
+            assert_eq!(all[0].login.guid_str(), guid);
+            assert!(all[0].local_modified > std::time::UNIX_EPOCH);
+            all.into_iter().next().unwrap()
+        };
 
-        // For IDs that have, mark is_deleted and clear sensitive fields
-        self.execute_named(
-            &format!(
-                "UPDATE loginsL
-                 SET local_modified = :now_ms,
-                     sync_status = {status_changed},
-                     is_deleted = 1,
-                     password = '',
-                     hostname = '',
-                     username = ''
-                 WHERE guid = :guid",
-                status_changed = SyncStatus::Changed as u8
-            ),
-            named_params! { ":now_ms": now_ms, ":guid": id },
-        )?;
+        let added = metadata(&db);
+        assert_eq!(added.sync_status, SyncStatus::New);
+        assert!(!added.is_deleted);
+        assert_eq!(added.login.password, "password");
 
-        // Mark the mirror as overridden
-        self.execute_named(
-            "UPDATE loginsM SET is_overridden = 1 WHERE guid = :guid",
-            named_params! { ":guid": id },
-        )?;
+        db.mark_as_synchronized(&[&guid], ServerTimestamp(1000), &db.begin_interrupt_scope())
+            .unwrap();
+        // Synced records live in the mirror, and aren't included.
+        assert!(db.get_all_with_sync_metadata().unwrap().is_empty());
 
-        // If we don't have a local record for this ID, but do have it in the mirror
-        // insert a tombstone.
-        self.execute_named(&format!("
-            INSERT OR IGNORE INTO loginsL
-                    (guid, local_modified, is_deleted, sync_status, hostname, timeCreated, timePasswordChanged, password, username)
-            SELECT   guid, :now_ms,        1,          {changed},   '',       timeCreated, :now_ms,                   '',       ''
-            FROM loginsM
-            WHERE guid = :guid",
-            changed = SyncStatus::Changed as u8),
-            named_params! { ":now_ms": now_ms, ":guid": id })?;
-        tx.commit()?;
-        Ok(exists)
+        db.update(Login {
+            password: "new-password".into(),
+            ..login
+        })
+        .unwrap();
+        let updated = metadata(&db);
+        assert_eq!(updated.sync_status, SyncStatus::Changed);
+        assert!(!updated.is_deleted);
+        assert_eq!(updated.login.password, "new-password");
+
+        db.delete(&guid).unwrap();
+        let deleted = metadata(&db);
+        assert_eq!(deleted.sync_status, SyncStatus::Changed);
+        assert!(deleted.is_deleted);
+        assert_eq!(deleted.login.password, "");
     }
 
-    fn mark_mirror_overridden(&self, guid: &str) -> Result<()> {
-        self.execute_named_cached(
-            "UPDATE loginsM SET is_overridden = 1 WHERE guid = :guid",
-            named_params! { ":guid": guid },
-        )?;
-        Ok(())
-    }
+    #[test]
+    fn test_reset_guid() {
+        let db = LoginDb::open_in_memory(Some("testing".into())).unwrap();
+        let login = db
+            .add(Login::with_defaults_for_testing(
+                "https://www.example.com",
+                "user",
+                "password",
+            ))
+            .unwrap();
+        let old_guid = login.guid_str();
 
-    fn ensure_local_overlay_exists(&self, guid: &str) -> Result<()> {
-        let already_have_local: bool = self.db.query_row_named(
-            "SELECT EXISTS(SELECT 1 FROM loginsL WHERE guid = :guid)",
-            named_params! { ":guid": guid },
-            |row| row.get(0),
-        )?;
+        let new_guid = db.reset_guid(old_guid, Some("new-guid")).unwrap();
+        assert_eq!(new_guid, "new-guid");
+        assert!(db.get_by_id(old_guid).unwrap().is_none());
+        assert_eq!(
+            db.get_by_id("new-guid").unwrap().unwrap().password,
+            "password"
+        );
 
-        if already_have_local {
-            return Ok(());
+        let random_guid = db.reset_guid("new-guid", None).unwrap();
+        assert!(login::is_valid_guid(&random_guid));
+        assert!(!db.exists("new-guid").unwrap());
+        assert!(db.exists(&random_guid).unwrap());
+
+        match db.reset_guid("new-guid", None).unwrap_err().kind() {
+            ErrorKind::NoSuchRecord(guid) => assert_eq!(guid, "new-guid"),
+            e => panic!("Unexpected error {:?}", e),
         }
 
-        log::debug!("No overlay; cloning one for {:?}.", guid);
-        let changed = self.clone_mirror_to_overlay(guid)?;
-        if changed == 0 {
-            log::error!("Failed to create local overlay for GUID {:?}.", guid);
-            throw!(ErrorKind::NoSuchRecord(guid.to_owned()));
+        // Synced logins keep their GUIDs.
+        db.mark_as_synchronized(
+            &[&random_guid],
+            ServerTimestamp(1000),
+            &db.begin_interrupt_scope(),
+        )
+        .unwrap();
+        match db.reset_guid(&random_guid, None).unwrap_err().kind() {
+            ErrorKind::InvalidOperation(_) => {}
+            e => panic!("Unexpected error {:?}", e),
         }
-        Ok(())
+        assert!(db.exists(&random_guid).unwrap());
+
+        // Even once they've been changed locally again.
+        db.touch(&random_guid).unwrap();
+        assert_eq!(
+            db.get_local_record(&random_guid)
+                .unwrap()
+                .unwrap()
+                .sync_status,
+            SyncStatus::Synced
+        );
+        db.update(Login {
+            password: "changed".into(),
+            ..db.get_by_id(&random_guid).unwrap().unwrap()
+        })
+        .unwrap();
+        match db.reset_guid(&random_guid, None).unwrap_err().kind() {
+            ErrorKind::InvalidOperation(_) => {}
+            e => panic!("Unexpected error {:?}", e),
+        }
+        assert!(db.exists(&random_guid).unwrap());
+    }
+
+    #[test]
+    fn test_last_sync_age() {
+        let db = LoginDb::open_in_memory(Some("testing".into())).unwrap();
+        assert_eq!(db.get_last_sync_age_ms().unwrap(), None);
+        assert!(db.needs_sync(i64::MAX).unwrap());
+
+        let hour_ms = 60 * 60 * 1000;
+        let now_ms = util::system_time_ms_i64(SystemTime::now());
+        db.set_last_sync(ServerTimestamp::from_millis(now_ms - hour_ms))
+            .unwrap();
+        let age_ms = db.get_last_sync_age_ms().unwrap().unwrap();
+        assert!(
+            age_ms >= hour_ms && age_ms < hour_ms + 60 * 1000,
+            "{}",
+            age_ms
+        );
+        assert!(!db.needs_sync(2 * hour_ms).unwrap());
+        assert!(db.needs_sync(hour_ms / 2).unwrap());
     }
 
-    fn clone_mirror_to_overlay(&self, guid: &str) -> Result<usize> {
-        Ok(self
-            .execute_named_cached(&*CLONE_SINGLE_MIRROR_SQL, &[(":guid", &guid as &dyn ToSql)])?)
+    #[test]
+    fn test_store_conflict_resolution() {
+        let now_ms = util::system_time_ms_i64(SystemTime::now());
+        let past = ServerTimestamp::from_millis(now_ms - 60 * 60 * 1000);
+        for (strategy, password) in &[
+            (ConflictResolution::ServerWins, "remote-pw"),
+            (ConflictResolution::ClientWins, "local-pw"),
+        ] {
+            // Both sides change the password.
+            let (db, mut inbound) = make_conflict(past);
+            inbound.changes[0].0 = serde_json::from_value(serde_json::json!({
+                "id": "dummy_000001",
+                "hostname": "https://www.example.com",
+                "formSubmitURL": "https://www.example.com/login",
+                "username": "alice",
+                "password": "remote-pw",
+                "usernameField": "user",
+                "passwordField": "pass",
+                "timeCreated": 1_500_000_000_000i64,
+                "timePasswordChanged": 1_500_000_000_000i64,
+                "timesUsed": 1,
+            }))
+            .unwrap();
+            let mut store = LoginStore::new(&db);
+            store.set_conflict_resolution(*strategy);
+            let mut telem = telemetry::Engine::new("passwords");
+            store.apply_incoming(vec![inbound], &mut telem).unwrap();
+            let login = db.get_by_id("dummy_000001").unwrap().unwrap();
+            assert_eq!(login.password, *password, "{:?}", strategy);
+        }
     }
 
-    pub fn reset(&self, assoc: &StoreSyncAssociation) -> Result<()> {
-        log::info!("Executing reset on password store!");
-        let tx = self.db.unchecked_transaction()?;
-        self.execute_all(&[
-            &*CLONE_ENTIRE_MIRROR_SQL,
-            "DELETE FROM loginsM",
-            &format!("UPDATE loginsL SET sync_status = {}", SyncStatus::New as u8),
-        ])?;
-        self.set_last_sync(ServerTimestamp(0))?;
-        match assoc {
-            StoreSyncAssociation::Disconnected => {
-                self.delete_meta(schema::GLOBAL_SYNCID_META_KEY)?;
-                self.delete_meta(schema::COLLECTION_SYNCID_META_KEY)?;
-            }
-            StoreSyncAssociation::Connected(ids) => {
-                self.put_meta(schema::GLOBAL_SYNCID_META_KEY, &ids.global)?;
-                self.put_meta(schema::COLLECTION_SYNCID_META_KEY, &ids.coll)?;
-            }
+    #[test]
+    fn test_get_all_for_hostname_with_subdomains() {
+        let db = LoginDb::open_in_memory(Some("testing".into())).unwrap();
+        for hostname in &[
+            "https://auth.example.com",
+            "https://www.example.com",
+            "https://example.com",
+            "https://notexample.com",
+            "https://example.org",
+        ] {
+            db.add(Login {
+                hostname: (*hostname).into(),
+                http_realm: Some("realm".into()),
+                username: "user".into(),
+                password: "password".into(),
+                ..Login::default()
+            })
+            .unwrap();
+        }
+        let hostnames = |query: &str| -> Vec<String> {
+            db.get_all_for_hostname_with_subdomains(query)
+                .unwrap()
+                .into_iter()
+                .map(|l| l.hostname)
+                .collect()
         };
-        self.delete_meta(schema::GLOBAL_STATE_META_KEY)?;
-        tx.commit()?;
-        Ok(())
-    }
 
-    pub fn wipe(&self, scope: &SqlInterruptScope) -> Result<()> {
-        let tx = self.unchecked_transaction()?;
-        log::info!("Executing wipe on password store!");
-        let now_ms = util::system_time_ms_i64(SystemTime::now());
-        scope.err_if_interrupted()?;
-        self.execute_named(
-            &format!(
-                "
-                UPDATE loginsL
-                SET local_modified = :now_ms,
-                    sync_status = {changed},
-                    is_deleted = 1,
-                    password = '',
-                    hostname = '',
-                    username = ''
-                WHERE is_deleted = 0",
-                changed = SyncStatus::Changed as u8
-            ),
-            named_params! { ":now_ms": now_ms },
-        )?;
-        scope.err_if_interrupted()?;
+        let found = hostnames("www.example.com");
+        assert_eq!(found.len(), 3);
+        assert_eq!(found[0], "https://www.example.com");
+        assert!(found.contains(&"https://auth.example.com".to_string()));
+        assert!(found.contains(&"https://example.com".to_string()));
 
-        self.execute("UPDATE loginsM SET is_overridden = 1", NO_PARAMS)?;
-        scope.err_if_interrupted()?;
+        let found = hostnames("https://auth.example.com");
+        assert_eq!(found.len(), 3);
+        assert_eq!(found[0], "https://auth.example.com");
 
-        self.execute_named(
-            &format!("
-                INSERT OR IGNORE INTO loginsL
-                      (guid, local_modified, is_deleted, sync_status, hostname, timeCreated, timePasswordChanged, password, username)
-                SELECT guid, :now_ms,        1,          {changed},   '',       timeCreated, :now_ms,             '',       ''
-                FROM loginsM",
-                changed = SyncStatus::Changed as u8),
-            named_params! { ":now_ms": now_ms })?;
-        scope.err_if_interrupted()?;
-        tx.commit()?;
-        Ok(())
+        assert_eq!(hostnames("example.org"), vec!["https://example.org"]);
+        assert!(hostnames("www.example.net").is_empty());
+        assert!(hostnames("").is_empty());
     }
 
-    pub fn wipe_local(&self) -> Result<()> {
-        log::info!("Executing wipe_local on password store!");
-        let tx = self.unchecked_transaction()?;
-        self.execute_all(&[
-            "DELETE FROM loginsL",
-            "DELETE FROM loginsM",
-            "DELETE FROM loginsSyncMeta",
-        ])?;
-        tx.commit()?;
-        Ok(())
-    }
+    #[test]
+    fn test_apply_outgoing_confirmation() {
+        let db = LoginDb::open_in_memory(Some("testing".into())).unwrap();
+        db.apply_incoming_from_json(include_str!("../fixtures/incoming_new.json"), 1000.0)
+            .unwrap();
+        let added = db
+            .add(Login {
+                hostname: "https://www.example.net".into(),
+                http_realm: Some("realm".into()),
+                username: "carol".into(),
+                password: "password".into(),
+                ..Login::default()
+            })
+            .unwrap();
+        db.delete("dummy_000002").unwrap();
 
-    fn reconcile(
-        &self,
-        records: Vec<SyncLoginData>,
-        server_now: ServerTimestamp,
-        telem: &mut telemetry::EngineIncoming,
-        scope: &SqlInterruptScope,
-    ) -> Result<UpdatePlan> {
-        let mut plan = UpdatePlan::default();
+        let scope = db.begin_interrupt_scope();
+        let outgoing = db.fetch_outgoing(ServerTimestamp(0), &scope).unwrap();
+        assert_eq!(outgoing.changes.len(), 2);
+        let new_server_timestamp = ServerTimestamp::from_float_seconds(2000.0);
+        db.apply_outgoing_confirmation(OutgoingConfirmation {
+            guids: outgoing
+                .changes
+                .iter()
+                .map(|p| p.id().to_string())
+                .collect(),
+            new_server_timestamp,
+        })
+        .unwrap();
 
-        for mut record in records {
-            scope.err_if_interrupted()?;
-            log::debug!("Processing remote change {}", record.guid());
-            let upstream = if let Some(inbound) = record.inbound.0.take() {
-                inbound
-            } else {
-                log::debug!("Processing inbound deletion (always prefer)");
-                plan.plan_delete(record.guid.clone());
-                continue;
-            };
-            let upstream_time = record.inbound.1;
-            match (record.mirror.take(), record.local.take()) {
-                (Some(mirror), Some(local)) => {
-                    log::debug!("  Conflict between remote and local, Resolving with 3WM");
-                    plan.plan_three_way_merge(local, mirror, upstream, upstream_time, server_now);
-                    telem.reconciled(1);
-                }
-                (Some(_mirror), None) => {
-                    log::debug!("  Forwarding mirror to remote");
-                    plan.plan_mirror_update(upstream, upstream_time);
-                    telem.applied(1);
-                }
-                (None, Some(local)) => {
-                    log::debug!("  Conflicting record without shared parent, using newer");
-                    plan.plan_two_way_merge(&local.login, (upstream, upstream_time));
-                    telem.reconciled(1);
-                }
-                (None, None) => {
-                    if let Some(dupe) = self.find_dupe(&upstream)? {
-                        log::debug!(
-                            "  Incoming record {} was is a dupe of local record {}",
-                            upstream.guid,
-                            dupe.guid
-                        );
-                        plan.plan_two_way_merge(&dupe, (upstream, upstream_time));
-                    } else {
-                        log::debug!("  No dupe found, inserting into mirror");
-                        plan.plan_mirror_insert(upstream, upstream_time, false);
-                    }
-                    telem.applied(1);
-                }
-            }
-        }
-        Ok(plan)
+        let count_local: i64 = db.query_one("SELECT COUNT(*) FROM loginsL").unwrap();
+        assert_eq!(count_local, 0);
+        let mirror: Vec<(String, i64, bool)> = db
+            .prepare("SELECT guid, server_modified, is_overridden FROM loginsM ORDER BY guid")
+            .unwrap()
+            .query_map(NO_PARAMS, |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap();
+        let mut expected = vec![
+            (
+                added.guid.to_string(),
+                new_server_timestamp.as_millis(),
+                false,
+            ),
+            ("dummy_000001".to_string(), 1_000_000, false),
+        ];
+        expected.sort();
+        assert_eq!(mirror, expected);
+        assert_eq!(db.get_last_sync().unwrap(), Some(new_server_timestamp));
+        assert!(db
+            .fetch_outgoing(ServerTimestamp(0), &scope)
+            .unwrap()
+            .changes
+            .is_empty());
     }
 
-    fn execute_plan(&self, plan: UpdatePlan, scope: &SqlInterruptScope) -> Result<()> {
-        // Because rusqlite want a mutable reference to create a transaction
-        // (as a way to save us from ourselves), we side-step that by creating
-        // it manually.
-        let tx = self.db.unchecked_transaction()?;
-        plan.execute(&tx, scope)?;
-        tx.commit()?;
-        Ok(())
+    #[test]
+    fn test_get_all_for_domain_suffix() {
+        let db = LoginDb::open_in_memory(Some("testing".into())).unwrap();
+        for hostname in &[
+            "https://login.example.com",
+            "https://dev.login.example.com",
+            "https://example.com:8443",
+            "https://notexample.com",
+            "https://example.com.example.org",
+            "https://examplexcom",
+        ] {
+            db.add(Login {
+                hostname: (*hostname).into(),
+                http_realm: Some("realm".into()),
+                username: "user".into(),
+                password: "password".into(),
+                ..Login::default()
+            })
+            .unwrap();
+        }
+        let hostnames = |suffix: &str| -> Vec<String> {
+            db.get_all_for_domain_suffix(suffix)
+                .unwrap()
+                .into_iter()
+                .map(|l| l.hostname)
+                .collect()
+        };
+        assert_eq!(
+            hostnames("example.com"),
+            vec![
+                "https://dev.login.example.com",
+                "https://example.com:8443",
+                "https://login.example.com",
+            ]
+        );
+        assert_eq!(
+            hostnames("*.login.example.com"),
+            vec!["https://dev.login.example.com", "https://login.example.com"]
+        );
+        // `_` would be a wildcard if we didn't escape it.
+        assert!(hostnames("example_com").is_empty());
+        assert!(hostnames("").is_empty());
     }
 
-    pub fn fetch_outgoing(
-        &self,
-        st: ServerTimestamp,
-        scope: &SqlInterruptScope,
-    ) -> Result<OutgoingChangeset> {
-        // Taken from iOS. Arbitrarily large, so that clients that want to
-        // process deletions first can; for us it doesn't matter.
-        const TOMBSTONE_SORTINDEX: i32 = 5_000_000;
-        const DEFAULT_SORTINDEX: i32 = 1;
-        let mut outgoing = OutgoingChangeset::new("passwords", st);
-        let mut stmt = self.db.prepare_cached(&format!(
-            "SELECT * FROM loginsL WHERE sync_status IS NOT {synced}",
-            synced = SyncStatus::Synced as u8
-        ))?;
-        let rows = stmt.query_and_then(NO_PARAMS, |row| {
-            scope.err_if_interrupted()?;
-            Ok(if row.get::<_, bool>("is_deleted")? {
-                Payload::new_tombstone(row.get::<_, String>("guid")?)
-                    .with_sortindex(TOMBSTONE_SORTINDEX)
-            } else {
-                let login = Login::from_row(row)?;
-                Payload::from_record(login)?.with_sortindex(DEFAULT_SORTINDEX)
+    #[test]
+    fn test_get_all_for_autofill_any() {
+        let db = LoginDb::open_in_memory(Some("testing".into())).unwrap();
+        for (hostname, username, form_submit_url, http_realm) in &[
+            (
+                "https://www.example.com",
+                "form",
+                Some("https://www.example.com"),
+                None,
+            ),
+            ("https://www.example.com", "realm1", None, Some("realm1")),
+            ("https://www.example.com", "realm2", None, Some("realm2")),
+            (
+                "https://www.example.org",
+                "other",
+                Some("https://www.example.org"),
+                None,
+            ),
+        ] {
+            db.add(Login {
+                hostname: (*hostname).into(),
+                username: (*username).into(),
+                password: "password".into(),
+                form_submit_url: form_submit_url.map(Into::into),
+                http_realm: http_realm.map(Into::into),
+                ..Login::default()
             })
-        })?;
-        outgoing.changes = rows.collect::<Result<_>>()?;
-
-        Ok(outgoing)
+            .unwrap();
+        }
+        let usernames = |hostname: &str, realm: Option<&str>| -> Vec<String> {
+            db.get_all_for_autofill_any(hostname, realm)
+                .unwrap()
+                .into_iter()
+                .map(|l| l.username)
+                .collect()
+        };
+        assert_eq!(usernames("https://www.example.com", None), vec!["form"]);
+        assert_eq!(
+            usernames("https://www.example.com", Some("realm1")),
+            vec!["realm1"]
+        );
+        assert_eq!(
+            usernames("https://www.example.com", Some("realm2")),
+            vec!["realm2"]
+        );
+        assert!(usernames("https://www.example.com", Some("realm3")).is_empty());
+        assert!(usernames("https://www.example.org", Some("realm1")).is_empty());
+        assert!(usernames("https://www.example.net", None).is_empty());
     }
 
-    fn do_apply_incoming(
-        &self,
-        inbound: IncomingChangeset,
-        telem: &mut telemetry::Engine,
-        scope: &SqlInterruptScope,
-    ) -> Result<OutgoingChangeset> {
-        let mut incoming_telemetry = telemetry::EngineIncoming::new();
-        let data = self.fetch_login_data(&inbound.changes, &mut incoming_telemetry, scope)?;
-        let plan = {
-            let result = self.reconcile(data, inbound.timestamp, &mut incoming_telemetry, scope);
-            telem.incoming(incoming_telemetry);
-            result
-        }?;
-        self.execute_plan(plan, scope)?;
-        Ok(self.fetch_outgoing(inbound.timestamp, scope)?)
-    }
+    #[test]
+    fn test_wipe_mirror_and_local_table() {
+        let db = LoginDb::open_in_memory(Some("testing".into())).unwrap();
+        db.apply_incoming_from_json(include_str!("../fixtures/incoming_new.json"), 1000.0)
+            .unwrap();
+        db.add(Login {
+            hostname: "https://www.example.net".into(),
+            http_realm: Some("realm".into()),
+            username: "carol".into(),
+            password: "password".into(),
+            ..Login::default()
+        })
+        .unwrap();
+        let count = |table: &str| -> i64 {
+            db.query_one(&format!("SELECT COUNT(*) FROM {}", table))
+                .unwrap()
+        };
+        assert_eq!(count("loginsL"), 1);
+        assert_eq!(count("loginsM"), 2);
+        db.set_last_sync(ServerTimestamp(1_000_000)).unwrap();
+
+        db.wipe_mirror().unwrap();
+        assert_eq!(count("loginsL"), 1);
+        assert_eq!(count("loginsM"), 0);
+        // The sync metadata is left alone.
+        assert_eq!(
+            db.get_last_sync().unwrap(),
+            Some(ServerTimestamp(1_000_000))
+        );
 
-    fn put_meta(&self, key: &str, value: &dyn ToSql) -> Result<()> {
-        self.execute_named_cached(
-            "REPLACE INTO loginsSyncMeta (key, value) VALUES (:key, :value)",
-            named_params! { ":key": key, ":value": value },
-        )?;
-        Ok(())
+        db.wipe_local_table().unwrap();
+        assert_eq!(count("loginsL"), 0);
+        assert!(db.get_all().unwrap().is_empty());
     }
 
-    fn get_meta<T: FromSql>(&self, key: &str) -> Result<Option<T>> {
-        Ok(self.try_query_row(
-            "SELECT value FROM loginsSyncMeta WHERE key = :key",
-            named_params! { ":key": key },
-            |row| Ok::<_, Error>(row.get(0)?),
-            true,
-        )?)
+    #[test]
+    fn test_insert_mirror_record() {
+        let db = LoginDb::open_in_memory(Some("testing".into())).unwrap();
+        let login = Login {
+            guid: "dummy_000001".into(),
+            hostname: "https://www.example.com".into(),
+            http_realm: Some("realm".into()),
+            username: "alice".into(),
+            password: "hunter2".into(),
+            time_created: 1_500_000_000_000,
+            time_password_changed: 1_500_000_000_000,
+            ..Login::default()
+        };
+        db.insert_mirror_record(MirrorLogin {
+            login: login.clone(),
+            is_overridden: false,
+            server_modified: ServerTimestamp(1_000_000),
+        })
+        .unwrap();
+        assert_eq!(
+            db.get_by_id("dummy_000001").unwrap().unwrap().password,
+            "hunter2"
+        );
+
+        // An incoming change to a mirror-only record should just replace it.
+        let mut changed = login;
+        changed.password = "hunter3".into();
+        let mut inbound = IncomingChangeset::new("passwords", ServerTimestamp(2_000_000));
+        inbound.changes.push((
+            Payload::from_record(changed).unwrap(),
+            ServerTimestamp(2_000_000),
+        ));
+        let scope = db.begin_interrupt_scope();
+        let mut telem = telemetry::Engine::new("passwords");
+        let outgoing = db.do_apply_incoming(inbound, &mut telem, &scope).unwrap();
+        assert!(outgoing.changes.is_empty());
+        let mirror = db.get_mirror_record("dummy_000001").unwrap().unwrap();
+        assert!(!mirror.is_overridden);
+        assert_eq!(mirror.login.password, "hunter3");
+        assert_eq!(mirror.server_modified, ServerTimestamp(2_000_000));
+        assert!(db.get_local_record("dummy_000001").unwrap().is_none());
+
+        // An overridden mirror record is hidden by its local record.
+        db.wipe_mirror().unwrap();
+        db.insert_mirror_record(MirrorLogin {
+            login: Login {
+                guid: "dummy_000002".into(),
+                hostname: "https://www.example.org".into(),
+                http_realm: Some("realm".into()),
+                username: "bob".into(),
+                password: "password".into(),
+                ..Login::default()
+            },
+            is_overridden: true,
+            server_modified: ServerTimestamp(1_000_000),
+        })
+        .unwrap();
+        assert!(db.get_by_id("dummy_000002").unwrap().is_none());
     }
 
-    fn delete_meta(&self, key: &str) -> Result<()> {
-        self.execute_named_cached(
-            "DELETE FROM loginsSyncMeta WHERE key = :key",
-            named_params! { ":key": key },
-        )?;
-        Ok(())
+    #[test]
+    fn test_get_all_sorted_by_domain() {
+        let db = LoginDb::open_in_memory(Some("testing".into())).unwrap();
+        for (hostname, username) in &[
+            ("https://mail.google.com", "bob"),
+            ("https://www.example.com", "alice"),
+            ("https://drive.google.com", "alice"),
+            ("https://mail.google.com", "alice"),
+            ("https://zzz.com", "alice"),
+            ("https://accounts.google.com", "carol"),
+        ] {
+            db.add(Login::with_defaults_for_testing(
+                hostname, username, "password",
+            ))
+            .unwrap();
+        }
+        let sorted: Vec<(String, String)> = db
+            .get_all_sorted_by_domain()
+            .unwrap()
+            .into_iter()
+            .map(|l| (l.hostname, l.username))
+            .collect();
+        let expected: Vec<(String, String)> = [
+            ("https://www.example.com", "alice"),
+            ("https://accounts.google.com", "carol"),
+            ("https://drive.google.com", "alice"),
+            ("https://mail.google.com", "alice"),
+            ("https://mail.google.com", "bob"),
+            ("https://zzz.com", "alice"),
+        ]
+        .iter()
+        .map(|(h, u)| ((*h).to_string(), (*u).to_string()))
+        .collect();
+        assert_eq!(sorted, expected);
     }
 
-    fn set_last_sync(&self, last_sync: ServerTimestamp) -> Result<()> {
-        log::debug!("Updating last sync to {}", last_sync);
-        let last_sync_millis = last_sync.as_millis() as i64;
-        self.put_meta(schema::LAST_SYNC_META_KEY, &last_sync_millis)
-    }
+    #[test]
+    fn test_get_password_for_form_fill() {
+        let db = LoginDb::open_in_memory(Some("testing".into())).unwrap();
+        db.apply_incoming_from_json(include_str!("../fixtures/incoming_new.json"), 1000.0)
+            .unwrap();
+        let added = db
+            .add(Login::with_defaults_for_testing(
+                "https://www.example.net",
+                "carol",
+                "secret",
+            ))
+            .unwrap();
 
-    fn get_last_sync(&self) -> Result<Option<ServerTimestamp>> {
-        let millis = self.get_meta::<i64>(schema::LAST_SYNC_META_KEY)?.unwrap();
-        Ok(Some(ServerTimestamp(millis)))
+        assert_eq!(
+            db.get_password_for_form_fill("https://www.example.net", "carol")
+                .unwrap(),
+            Some(("carol".to_string(), "secret".to_string()))
+        );
+        let login = db.get_by_id(&added.guid).unwrap().unwrap();
+        assert_eq!(login.times_used, added.times_used + 1);
+        assert!(login.time_last_used > added.time_last_used);
+
+        // Mirror-only records get a local overlay, like with `touch`.
+        assert_eq!(
+            db.get_password_for_form_fill("https://www.example.com", "alice")
+                .unwrap(),
+            Some(("alice".to_string(), "hunter2".to_string()))
+        );
+        assert_eq!(db.get_by_id("dummy_000001").unwrap().unwrap().times_used, 2);
+
+        assert_eq!(
+            db.get_password_for_form_fill("https://www.example.net", "dave")
+                .unwrap(),
+            None
+        );
+        db.delete(&added.guid).unwrap();
+        assert_eq!(
+            db.get_password_for_form_fill("https://www.example.net", "carol")
+                .unwrap(),
+            None
+        );
     }
 
-    pub fn set_global_state(&self, state: &Option<String>) -> Result<()> {
-        let to_write = match state {
-            Some(ref s) => s,
-            None => "",
+    #[test]
+    fn test_delete_all_created_before() {
+        let db = LoginDb::open_in_memory(Some("testing".into())).unwrap();
+        // Both of these were created at 1_500_000_000_000.
+        db.apply_incoming_from_json(include_str!("../fixtures/incoming_new.json"), 1000.0)
+            .unwrap();
+        for (username, time_created) in
+            &[("old", 1_400_000_000_000i64), ("new", 1_600_000_000_000i64)]
+        {
+            let mut login =
+                Login::with_defaults_for_testing("https://www.example.net", username, "password");
+            login.time_created = *time_created;
+            db.add(login).unwrap();
+        }
+        let usernames = |logins: Vec<Login>| -> Vec<String> {
+            let mut usernames: Vec<String> = logins.into_iter().map(|l| l.username).collect();
+            usernames.sort();
+            usernames
         };
-        self.put_meta(schema::GLOBAL_STATE_META_KEY, &to_write)
+
+        assert_eq!(
+            usernames(db.get_all_with_creation_before(1_500_000_000_001).unwrap()),
+            vec!["alice", "bob", "old"]
+        );
+        assert_eq!(db.delete_all_created_before(1_500_000_000_001).unwrap(), 3);
+        assert_eq!(usernames(db.get_all().unwrap()), vec!["new"]);
+        // The mirror records will have their deletion synced.
+        let outgoing = db
+            .fetch_outgoing(ServerTimestamp(0), &db.begin_interrupt_scope())
+            .unwrap();
+        let tombstones: Vec<&str> = outgoing
+            .changes
+            .iter()
+            .filter(|p| p.is_tombstone())
+            .map(|p| p.id())
+            .collect();
+        assert!(tombstones.contains(&"dummy_000001"));
+        assert!(tombstones.contains(&"dummy_000002"));
+
+        assert_eq!(db.delete_all_created_before(1_500_000_000_001).unwrap(), 0);
     }
 
-    pub fn get_global_state(&self) -> Result<Option<String>> {
-        self.get_meta::<String>(schema::GLOBAL_STATE_META_KEY)
+    #[test]
+    fn test_get_by_hostname() {
+        let db = LoginDb::open_in_memory(Some("testing".into())).unwrap();
+        db.apply_incoming_from_json(include_str!("../fixtures/incoming_new.json"), 1000.0)
+            .unwrap();
+        let hostname = "https://www.example.com";
+        let usernames = || -> Vec<String> {
+            db.get_by_hostname(hostname)
+                .unwrap()
+                .into_iter()
+                .map(|l| l.username)
+                .collect()
+        };
+
+        // Mirror only.
+        assert_eq!(usernames(), vec!["alice"]);
+
+        // Local only, alongside the mirror-only record.
+        let mut login = Login::with_defaults_for_testing(hostname, "carol", "password");
+        login.time_last_used = 1_600_000_000_000;
+        let carol = db.add(login).unwrap();
+        assert_eq!(usernames(), vec!["carol", "alice"]);
+
+        // Both local and mirror: we get the local version, once.
+        let mut alice = db.get_by_id("dummy_000001").unwrap().unwrap();
+        alice.password = "hunter3".into();
+        db.update(alice).unwrap();
+        db.touch("dummy_000001").unwrap();
+        let logins = db.get_by_hostname(hostname).unwrap();
+        assert_eq!(logins.len(), 2);
+        assert_eq!(logins[0].username, "alice");
+        assert_eq!(logins[0].password, "hunter3");
+
+        // Deleted records are excluded, whether local or mirror.
+        db.delete(&carol.guid).unwrap();
+        db.delete("dummy_000001").unwrap();
+        assert!(usernames().is_empty());
+        assert!(db
+            .get_by_hostname("https://www.example.net")
+            .unwrap()
+            .is_empty());
     }
 
-    /// A utility we can kill by the end of 2019 ;)
-    pub fn migrate_global_state(&self) -> Result<()> {
-        let tx = self.unchecked_transaction_imm()?;
-        if let Some(old_state) = self.get_meta("global_state")? {
-            log::info!("there's old global state - migrating");
-            let (new_sync_ids, new_global_state) = extract_v1_state(old_state, "passwords");
-            if let Some(sync_ids) = new_sync_ids {
-                self.put_meta(schema::GLOBAL_SYNCID_META_KEY, &sync_ids.global)?;
-                self.put_meta(schema::COLLECTION_SYNCID_META_KEY, &sync_ids.coll)?;
-                log::info!("migrated the sync IDs");
-            }
-            if let Some(new_global_state) = new_global_state {
-                self.set_global_state(&Some(new_global_state))?;
-                log::info!("migrated the global state");
-            }
-            self.delete_meta("global_state")?;
+    #[test]
+    fn test_get_all_for_bitwarden_uri_match() {
+        let db = LoginDb::open_in_memory(Some("testing".into())).unwrap();
+        for hostname in &[
+            "https://example.com",
+            "https://login.example.com",
+            "https://example.com:8443",
+            "http://example.com",
+            "https://example.com.evil.org",
+        ] {
+            db.add(Login::with_defaults_for_testing(
+                hostname, "user", "password",
+            ))
+            .unwrap();
         }
-        tx.commit()?;
-        Ok(())
-    }
-}
+        let find = |url: &str, match_type: BitwardenUriMatchType| -> Vec<String> {
+            let mut hostnames: Vec<String> = db
+                .get_all_for_bitwarden_uri_match(url, match_type)
+                .unwrap()
+                .into_iter()
+                .map(|l| l.hostname)
+                .collect();
+            hostnames.sort();
+            hostnames
+        };
+        let url = "https://user@example.com/login?next=/";
 
-pub struct LoginStore<'a> {
-    pub db: &'a LoginDb,
-    pub scope: sql_support::SqlInterruptScope,
-}
+        let base_domain = vec![
+            "http://example.com",
+            "https://example.com",
+            "https://example.com:8443",
+            "https://login.example.com",
+        ];
+        assert_eq!(find(url, BitwardenUriMatchType::BaseDomain), base_domain);
+        assert_eq!(find(url, BitwardenUriMatchType::Default), base_domain);
 
-impl<'a> LoginStore<'a> {
-    pub fn new(db: &'a LoginDb) -> Self {
-        Self {
-            db,
-            scope: db.begin_interrupt_scope(),
+        // The scheme doesn't matter, but the port does.
+        assert_eq!(
+            find(url, BitwardenUriMatchType::Host),
+            vec!["http://example.com", "https://example.com"]
+        );
+        assert_eq!(
+            find("https://example.com:8443/", BitwardenUriMatchType::Host),
+            vec!["https://example.com:8443"]
+        );
+
+        // Like in Bitwarden, this is a plain string prefix match.
+        assert_eq!(
+            find(
+                "https://example.com.evil.org/login",
+                BitwardenUriMatchType::StartsWith
+            ),
+            vec!["https://example.com", "https://example.com.evil.org"]
+        );
+        assert!(find(url, BitwardenUriMatchType::StartsWith).is_empty());
+
+        let regex = regex::Regex::new(r"^https://([a-z]+\.)?example\.com$").unwrap();
+        assert_eq!(
+            find(url, BitwardenUriMatchType::Regex(regex)),
+            vec!["https://example.com", "https://login.example.com"]
+        );
+
+        assert!(find(url, BitwardenUriMatchType::Never).is_empty());
+        for match_type in &[
+            BitwardenUriMatchType::BaseDomain,
+            BitwardenUriMatchType::Host,
+            BitwardenUriMatchType::StartsWith,
+        ] {
+            assert!(find("not a url", match_type.clone()).is_empty());
         }
     }
-}
 
-impl<'a> Store for LoginStore<'a> {
-    fn collection_name(&self) -> std::borrow::Cow<'static, str> {
-        "passwords".into()
+    #[test]
+    fn test_get_logins_for_url() {
+        let db = LoginDb::open_in_memory(Some("testing".into())).unwrap();
+        for (hostname, username, form_submit_url, http_realm) in &[
+            ("https://www.example.com", "realm", None, Some("realm")),
+            (
+                "https://www.example.com",
+                "other-form",
+                Some("https://accounts.example.com"),
+                None,
+            ),
+            (
+                "https://www.example.com",
+                "same-form",
+                Some("https://www.example.com"),
+                None,
+            ),
+            (
+                "https://www.example.com:8443",
+                "other-port",
+                Some("https://www.example.com:8443"),
+                None,
+            ),
+            (
+                "http://www.example.com",
+                "insecure",
+                Some("http://www.example.com"),
+                None,
+            ),
+        ] {
+            db.add(Login {
+                hostname: (*hostname).into(),
+                username: (*username).into(),
+                password: "password".into(),
+                form_submit_url: form_submit_url.map(Into::into),
+                http_realm: http_realm.map(Into::into),
+                ..Login::default()
+            })
+            .unwrap();
+        }
+        let usernames = |url: &str| -> Vec<String> {
+            db.get_logins_for_url(url)
+                .unwrap()
+                .into_iter()
+                .map(|l| l.username)
+                .collect()
+        };
+        assert_eq!(
+            usernames("https://WWW.example.com:443/login?next=%2F#top"),
+            vec!["same-form", "other-form", "realm"]
+        );
+        assert_eq!(
+            usernames("https://www.example.com:8443/"),
+            vec!["other-port"]
+        );
+        assert_eq!(usernames("http://www.example.com/"), vec!["insecure"]);
+        for url in &[
+            "about:blank",
+            "data:text/html,<form></form>",
+            "file:///etc/passwd",
+            "javascript:void(0)",
+            "not a url",
+            "",
+        ] {
+            assert!(usernames(url).is_empty(), "{:?}", url);
+        }
     }
 
-    fn apply_incoming(
-        &self,
-        inbound: Vec<IncomingChangeset>,
-        telem: &mut telemetry::Engine,
-    ) -> result::Result<OutgoingChangeset, failure::Error> {
-        assert_eq!(inbound.len(), 1, "logins only requests one item");
-        let inbound = inbound.into_iter().next().unwrap();
-        Ok(self.db.do_apply_incoming(inbound, telem, &self.scope)?)
-    }
+    #[test]
+    fn test_associated_app() {
+        let db = LoginDb::open_in_memory(Some("testing".into())).unwrap();
+        db.apply_incoming_from_json(include_str!("../fixtures/incoming_new.json"), 1000.0)
+            .unwrap();
+        let mut login = Login::with_defaults_for_testing("https://mail.example.net", "alice", "pw");
+        login.associated_app = Some("com.example.mail".into());
+        let app_login = db.add(login).unwrap();
+        db.add(Login::with_defaults_for_testing(
+            "https://www.example.net",
+            "bob",
+            "pw",
+        ))
+        .unwrap();
 
-    fn sync_finished(
-        &self,
-        new_timestamp: ServerTimestamp,
-        records_synced: Vec<Guid>,
-    ) -> result::Result<(), failure::Error> {
-        self.db.mark_as_synchronized(
-            &records_synced.iter().map(Guid::as_str).collect::<Vec<_>>(),
-            new_timestamp,
-            &self.scope,
-        )?;
-        Ok(())
-    }
+        let usernames = |logins: Vec<Login>| -> Vec<String> {
+            let mut usernames: Vec<String> = logins.into_iter().map(|l| l.username).collect();
+            usernames.sort();
+            usernames
+        };
+        let found = db.get_all_for_app("com.example.mail").unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].guid, app_login.guid);
+        assert_eq!(
+            found[0].associated_app,
+            Some("com.example.mail".to_string())
+        );
+        assert!(db.get_all_for_app("com.example.other").unwrap().is_empty());
 
-    fn get_collection_requests(
-        &self,
-        server_timestamp: ServerTimestamp,
-    ) -> result::Result<Vec<CollectionRequest>, failure::Error> {
-        let since = self.db.get_last_sync()?.unwrap_or_default();
-        Ok(if since == server_timestamp {
-            vec![]
-        } else {
-            vec![CollectionRequest::new("passwords").full().newer_than(since)]
-        })
-    }
+        assert_eq!(
+            usernames(
+                db.get_all_for_app_or_hostname("com.example.mail", "https://www.example.net")
+                    .unwrap()
+            ),
+            vec!["alice", "bob"]
+        );
+        assert_eq!(
+            usernames(
+                db.get_all_for_app_or_hostname("com.example.mail", "https://www.example.com")
+                    .unwrap()
+            ),
+            vec!["alice", "alice"]
+        );
 
-    fn get_sync_assoc(&self) -> result::Result<StoreSyncAssociation, failure::Error> {
-        let global = self.db.get_meta(schema::GLOBAL_SYNCID_META_KEY)?;
-        let coll = self.db.get_meta(schema::COLLECTION_SYNCID_META_KEY)?;
-        Ok(if let (Some(global), Some(coll)) = (global, coll) {
-            StoreSyncAssociation::Connected(CollSyncIds { global, coll })
-        } else {
-            StoreSyncAssociation::Disconnected
+        // The association survives being synced, and can be updated.
+        let scope = db.begin_interrupt_scope();
+        db.apply_outgoing_confirmation(OutgoingConfirmation {
+            guids: vec![app_login.guid.to_string()],
+            new_server_timestamp: ServerTimestamp(2_000_000),
         })
+        .unwrap();
+        let outgoing = db.fetch_outgoing(ServerTimestamp(0), &scope).unwrap();
+        assert!(outgoing.changes.iter().all(|p| p.id() != app_login.guid));
+        assert_eq!(db.get_all_for_app("com.example.mail").unwrap().len(), 1);
+        let mut updated = db.get_by_id(&app_login.guid).unwrap().unwrap();
+        updated.associated_app = Some("com.example.mail2".into());
+        db.update(updated).unwrap();
+        assert!(db.get_all_for_app("com.example.mail").unwrap().is_empty());
+        assert_eq!(db.get_all_for_app("com.example.mail2").unwrap().len(), 1);
     }
 
-    fn reset(&self, assoc: &StoreSyncAssociation) -> result::Result<(), failure::Error> {
-        self.db.reset(assoc)?;
-        Ok(())
-    }
+    #[test]
+    fn test_add_many() {
+        let db = LoginDb::open_in_memory(Some("testing".into())).unwrap();
+        let mut with_guid =
+            Login::with_defaults_for_testing("https://www.example.com", "alice", "pw");
+        with_guid.guid = "dummy_000001".into();
+        let mut without_guid =
+            Login::with_defaults_for_testing("https://www.example.com", "bob", "pw");
+        without_guid.guid = Guid::empty();
+        let added = db.add_many(vec![with_guid, without_guid]).unwrap();
+        assert_eq!(added.len(), 2);
+        assert_eq!(added[0].guid, "dummy_000001");
+        assert!(!added[1].guid.is_empty());
+        for login in &added {
+            assert_eq!(db.get_by_id(&login.guid).unwrap().as_ref(), Some(login));
+        }
 
-    fn wipe(&self) -> result::Result<(), failure::Error> {
-        self.db.wipe(&self.scope)?;
-        Ok(())
+        // Logins are fixed up like `add` does.
+        let mut fixable = Login::with_defaults_for_testing("https://www.example.net/", "eve", "pw");
+        fixable.form_submit_url = Some("https://www.example.net/login".into());
+        let added = db.add_many(vec![fixable]).unwrap();
+        assert_eq!(added[0].hostname, "https://www.example.net");
+        assert_eq!(
+            added[0].form_submit_url.as_deref(),
+            Some("https://www.example.net")
+        );
+        assert_eq!(
+            db.get_by_id(&added[0].guid).unwrap().unwrap().hostname,
+            "https://www.example.net"
+        );
+        db.delete(&added[0].guid).unwrap();
+
+        let count = || db.get_all().unwrap().len();
+        let valid = Login::with_defaults_for_testing("https://www.example.org", "carol", "pw");
+
+        // Invalid logins reject the whole batch.
+        let invalid = Login::with_defaults_for_testing("https://www.example.org", "dave", "");
+        assert!(db.add_many(vec![valid.clone(), invalid]).is_err());
+        assert_eq!(count(), 2);
+
+        // As do duplicate GUIDs...
+        let mut same_guid =
+            Login::with_defaults_for_testing("https://www.example.org", "dave", "pw");
+        same_guid.guid = valid.guid.clone();
+        match db
+            .add_many(vec![valid.clone(), same_guid])
+            .unwrap_err()
+            .kind()
+        {
+            ErrorKind::DuplicateGuid(guid) => assert_eq!(guid, valid.guid.as_str()),
+            e => panic!("Unexpected error {:?}", e),
+        }
+        assert_eq!(count(), 2);
+
+        // ... and dupes of existing logins, or of other logins in the batch.
+        let dupe = Login::with_defaults_for_testing("https://www.example.com", "alice", "pw2");
+        assert!(db.add_many(vec![valid.clone(), dupe]).is_err());
+        let dupe = Login::with_defaults_for_testing("https://www.example.org", "carol", "pw2");
+        assert!(db.add_many(vec![valid, dupe]).is_err());
+        assert_eq!(count(), 2);
     }
-}
-
-lazy_static! {
-    static ref GET_ALL_SQL: String = format!(
-        "SELECT {common_cols} FROM loginsL WHERE is_deleted = 0
-         UNION ALL
-         SELECT {common_cols} FROM loginsM WHERE is_overridden = 0",
-        common_cols = schema::COMMON_COLS,
-    );
-    static ref GET_BY_GUID_SQL: String = format!(
-        "SELECT {common_cols}
-         FROM loginsL
-         WHERE is_deleted = 0
-           AND guid = :guid
-
-         UNION ALL
 
-         SELECT {common_cols}
-         FROM loginsM
-         WHERE is_overridden IS NOT 1
-           AND guid = :guid
-         ORDER BY hostname ASC
-
-         LIMIT 1",
-        common_cols = schema::COMMON_COLS,
-    );
-    static ref CLONE_ENTIRE_MIRROR_SQL: String = format!(
-        "INSERT OR IGNORE INTO loginsL ({common_cols}, local_modified, is_deleted, sync_status)
-         SELECT {common_cols}, NULL AS local_modified, 0 AS is_deleted, 0 AS sync_status
-         FROM loginsM",
-        common_cols = schema::COMMON_COLS,
-    );
-    static ref CLONE_SINGLE_MIRROR_SQL: String =
-        format!("{} WHERE guid = :guid", &*CLONE_ENTIRE_MIRROR_SQL,);
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
     #[test]
-    fn test_bad_record() {
-        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
-        let scope = db.begin_interrupt_scope();
-        let mut telem = sync15::telemetry::EngineIncoming::new();
-        let res = db
-            .fetch_login_data(
-                &[
-                    // tombstone
-                    (
-                        sync15::Payload::new_tombstone("dummy_000001"),
-                        sync15::ServerTimestamp(10000),
-                    ),
-                    // invalid
-                    (
-                        sync15::Payload::from_json(serde_json::json!({
-                            "id": "dummy_000002",
-                            "garbage": "data",
-                            "etc": "not a login"
-                        }))
-                        .unwrap(),
-                        sync15::ServerTimestamp(10000),
-                    ),
-                    // valid
-                    (
-                        sync15::Payload::from_json(serde_json::json!({
-                            "id": "dummy_000003",
-                            "formSubmitURL": "https://www.example.com/submit",
-                            "hostname": "https://www.example.com",
-                            "username": "test",
-                            "password": "test",
-                        }))
-                        .unwrap(),
-                        sync15::ServerTimestamp(10000),
-                    ),
-                ],
-                &mut telem,
-                &scope,
+    fn test_verify_integrity() {
+        use std::io::{Seek, SeekFrom, Write};
+
+        let dir = tempdir::TempDir::new("verify_integrity").unwrap();
+        let dbpath = dir.path().join("logins.sqlite");
+        let db = LoginDb::open(&dbpath, None).unwrap();
+        for i in 0..100 {
+            db.add(Login::with_defaults_for_testing(
+                &format!("https://www{}.example.com", i),
+                "user",
+                "password",
+            ))
+            .unwrap();
+        }
+        assert_eq!(
+            db.verify_integrity().unwrap(),
+            IntegrityReport {
+                ok: true,
+                messages: vec![],
+            }
+        );
+        let page_size: i64 = db.query_one("PRAGMA page_size").unwrap();
+        let root_page: i64 = db
+            .query_row(
+                "SELECT rootpage FROM sqlite_master
+                 WHERE name = 'idx_loginsL_is_deleted_hostname'",
+                NO_PARAMS,
+                |row| row.get(0),
             )
             .unwrap();
-        assert_eq!(telem.get_failed(), 1);
-        assert_eq!(res.len(), 2);
-        assert_eq!(res[0].guid, "dummy_000001");
-        assert_eq!(res[1].guid, "dummy_000003");
+        db.db.close().map_err(|(_conn, err)| err).unwrap();
+
+        // Overwrite the index's root page with garbage. The index isn't used
+        // when opening the database, so that still works.
+        let mut file = std::fs::OpenOptions::new()
+            .write(true)
+            .open(&dbpath)
+            .unwrap();
+        file.seek(SeekFrom::Start(((root_page - 1) * page_size) as u64))
+            .unwrap();
+        file.write_all(&vec![0xff; page_size as usize]).unwrap();
+        drop(file);
+
+        let db = LoginDb::open(&dbpath, None).unwrap();
+        let report = db.verify_integrity().unwrap();
+        assert!(!report.ok);
+        assert!(!report.messages.is_empty());
     }
 
     #[test]
-    fn test_check_valid_with_no_dupes() {
-        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
-        db.add(Login {
-            guid: "dummy_000001".into(),
-            form_submit_url: Some("https://www.example.com".into()),
-            hostname: "https://www.example.com".into(),
-            http_realm: None,
-            username: "test".into(),
-            password: "test".into(),
-            ..Login::default()
-        })
+    fn test_run_integrity_repair() {
+        let mut db = LoginDb::open_in_memory(Some("testing".into())).unwrap();
+        assert_eq!(db.run_integrity_repair().unwrap(), RepairReport::default());
+
+        // Mirror-only records are fine, but an overridden one with no local
+        // record is orphaned.
+        db.apply_incoming_from_json(include_str!("../fixtures/incoming_new.json"), 1000.0)
+            .unwrap();
+        db.execute_all(&["UPDATE loginsM SET is_overridden = 1 WHERE guid = 'dummy_000002'"])
+            .unwrap();
+        assert_eq!(
+            db.run_integrity_repair().unwrap(),
+            RepairReport {
+                orphaned_mirror_records_healed: 1,
+                ..RepairReport::default()
+            }
+        );
+        assert_eq!(db.run_integrity_repair().unwrap(), RepairReport::default());
+
+        // An invalid local record, overriding a (valid) mirror record.
+        let mut alice = db.get_by_id("dummy_000001").unwrap().unwrap();
+        alice.password = "local".into();
+        db.update(alice).unwrap();
+        db.execute_all(&["UPDATE loginsL SET hostname = 'not a url' WHERE guid = 'dummy_000001'"])
+            .unwrap();
+        // And a pair of local records with the same GUID. We need to drop
+        // the unique constraint to create them.
+        let create_sql: String = db
+            .query_one("SELECT sql FROM sqlite_master WHERE name = 'loginsL'")
+            .unwrap();
+        db.execute_all(&[
+            "ALTER TABLE loginsL RENAME TO loginsL_old",
+            &create_sql.replace("UNIQUE", ""),
+            "INSERT INTO loginsL SELECT * FROM loginsL_old",
+            "DROP TABLE loginsL_old",
+        ])
+        .unwrap();
+        let new = db
+            .add(Login::with_defaults_for_testing(
+                "https://www.example.net",
+                "carol",
+                "new",
+            ))
+            .unwrap();
+        db.execute_named(
+            "INSERT INTO loginsL (guid, hostname, httpRealm, timeCreated, timePasswordChanged,
+                                  username, password, local_modified, sync_status)
+             VALUES (:guid, 'https://www.example.net', 'realm', 1, 1, 'carol', 'old', 1, 2)",
+            named_params! { ":guid": new.guid },
+        )
         .unwrap();
 
-        let unique_login_guid = Guid::empty();
-        let unique_login = Login {
-            guid: unique_login_guid.clone(),
-            form_submit_url: None,
-            hostname: "https://www.example.com".into(),
-            http_realm: Some("https://www.example.com".into()),
-            username: "test".into(),
-            password: "test".into(),
-            ..Login::default()
-        };
+        assert_eq!(
+            db.run_integrity_repair().unwrap(),
+            RepairReport {
+                duplicate_local_guids_removed: 1,
+                invalid_records_quarantined: 1,
+                ..RepairReport::default()
+            }
+        );
+        assert_eq!(db.get_by_id(&new.guid).unwrap().unwrap().password, "new");
+        // The mirror version of the invalid record is back.
+        let alice = db.get_by_id("dummy_000001").unwrap().unwrap();
+        assert_eq!(alice.password, "hunter2");
+        let quarantined = db.get_quarantined_records().unwrap();
+        assert_eq!(quarantined.len(), 1);
+        assert_eq!(
+            quarantined[0].1,
+            r#"Invalid login: [InvalidHostname("Origin is malformed")]"#
+        );
+        let login: Login = serde_json::from_str(&quarantined[0].2).unwrap();
+        assert_eq!(login.guid, "dummy_000001");
+        assert_eq!(login.hostname, "not a url");
+        assert_eq!(login.password, "local");
+        assert_eq!(db.run_integrity_repair().unwrap(), RepairReport::default());
+    }
 
-        let duplicate_login = Login {
+    #[test]
+    fn test_field_length_limits() {
+        let mut db = LoginDb::open_in_memory(Some("testing".into())).unwrap();
+        let login = db
+            .add(Login::with_defaults_for_testing(
+                "https://www.example.com",
+                "user",
+                "password",
+            ))
+            .unwrap();
+        assert_eq!(db.validate_all_logins().unwrap(), vec![]);
+
+        // Nothing stops an oversized value being written directly.
+        db.execute(
+            "UPDATE loginsL SET password = ? WHERE guid = ?",
+            &["a".repeat(5000).as_str(), login.guid.as_str()],
+        )
+        .unwrap();
+        assert_eq!(
+            db.validate_all_logins().unwrap(),
+            vec![(
+                login.guid.to_string(),
+                vec![LoginValidationError::PasswordTooLong(5000)]
+            )]
+        );
+        // But it's still a login the user saved, so isn't quarantined.
+        assert_eq!(db.run_integrity_repair().unwrap(), RepairReport::default());
+        assert_eq!(
+            db.get_by_id(&login.guid).unwrap().unwrap().password.len(),
+            5000
+        );
+
+        let long_username = Login {
             guid: Guid::empty(),
-            form_submit_url: Some("https://www.example.com".into()),
-            hostname: "https://www.example.com".into(),
-            http_realm: None,
-            username: "test".into(),
-            password: "test2".into(),
-            ..Login::default()
+            username: "u".repeat(5000),
+            ..login.clone()
         };
+        match db.add(long_username.clone()).unwrap_err().kind() {
+            ErrorKind::InvalidLogin(errs) => {
+                assert_eq!(errs.0, [LoginValidationError::UsernameTooLong(5000)])
+            }
+            e => panic!("Unexpected error {:?}", e),
+        }
 
-        let updated_login = Login {
-            guid: unique_login_guid,
-            form_submit_url: None,
-            hostname: "https://www.example.com".into(),
-            http_realm: Some("https://www.example.com".into()),
-            username: "test".into(),
-            password: "test4".into(),
-            ..Login::default()
+        db.set_max_field_lengths(8192, 8192);
+        assert_eq!(db.validate_all_logins().unwrap(), vec![]);
+        db.add(long_username).unwrap();
+    }
+
+    #[test]
+    fn test_auto_fix_invalid_logins() {
+        let mut db = LoginDb::open_in_memory(Some("testing".into())).unwrap();
+        let add = |username: &str| {
+            db.add(Login::with_defaults_for_testing(
+                "https://www.example.com",
+                username,
+                "password",
+            ))
+            .unwrap()
+            .guid
+            .to_string()
         };
+        let padded = add("padded");
+        let both = add("both");
+        let unfixable = add("unfixable");
+        db.execute_all(&[
+            &format!(
+                "UPDATE loginsL SET hostname = ' https://www.example.com ' WHERE guid = '{}'",
+                padded
+            ),
+            &format!(
+                "UPDATE loginsL SET httpRealm = 'realm' WHERE guid = '{}'",
+                both
+            ),
+            &format!(
+                "UPDATE loginsL SET password = '' WHERE guid = '{}'",
+                unfixable
+            ),
+        ])
+        .unwrap();
+        db.insert_mirror_record(MirrorLogin {
+            login: Login {
+                guid: "dummy_000001".into(),
+                hostname: "https://www.example.org/".into(),
+                http_realm: Some("realm".into()),
+                username: "mirror".into(),
+                password: "password".into(),
+                ..Login::default()
+            },
+            is_overridden: false,
+            server_modified: ServerTimestamp(1_000_000),
+        })
+        .unwrap();
 
-        struct TestCase {
-            login: Login,
-            should_err: bool,
-            expected_err: &'static str,
-        }
+        let not_normalized =
+            || LoginValidationError::InvalidHostname("Origin is not normalized".into());
+        assert_eq!(
+            db.validate_all_logins().unwrap(),
+            vec![
+                (padded.clone(), vec![not_normalized()]),
+                (
+                    both.clone(),
+                    vec![LoginValidationError::BothHttpRealmAndFormSubmitUrl]
+                ),
+                (unfixable.clone(), vec![LoginValidationError::EmptyPassword]),
+                ("dummy_000001".to_string(), vec![not_normalized()]),
+            ]
+        );
 
-        let test_cases = [
-            TestCase {
-                // unique_login should not error because it does not share the same hostname,
-                // username, and formSubmitURL or httpRealm with the pre-existing login
-                // (login with guid "dummy_000001").
-                login: unique_login,
-                should_err: false,
-                expected_err: "",
-            },
-            TestCase {
-                // duplicate_login has the same hostname, username, and formSubmitURL as a pre-existing
-                // login (guid "dummy_000001") and duplicate_login has no guid value, i.e. its guid
-                // doesn't match with that of a pre-existing record so it can't be considered update,
-                // so it should error.
-                login: duplicate_login,
-                should_err: true,
-                expected_err: "Invalid login: Login already exists",
-            },
-            TestCase {
-                // updated_login is an update to unique_login (has the same guid) so it is not a dupe
-                // and should not error.
-                login: updated_login,
-                should_err: false,
-                expected_err: "",
-            },
+        assert_eq!(db.auto_fix_invalid_logins().unwrap(), 3);
+        assert_eq!(
+            db.validate_all_logins().unwrap(),
+            vec![(unfixable, vec![LoginValidationError::EmptyPassword])]
+        );
+        assert_eq!(
+            db.get_by_id(&padded).unwrap().unwrap().hostname,
+            "https://www.example.com"
+        );
+        let both = db.get_by_id(&both).unwrap().unwrap();
+        assert_eq!(both.http_realm, None);
+        assert_eq!(
+            both.form_submit_url,
+            Some("https://www.example.com".to_string())
+        );
+        // The fixed mirror record is now overridden by a local change.
+        let fixed = db.get_by_id("dummy_000001").unwrap().unwrap();
+        assert_eq!(fixed.hostname, "https://www.example.org");
+        assert_eq!(fixed.password, "password");
+        let sync_status: u8 = db
+            .query_one("SELECT sync_status FROM loginsL WHERE guid = 'dummy_000001'")
+            .unwrap();
+        assert_eq!(sync_status, SyncStatus::Changed as u8);
+    }
+
+    #[test]
+    fn test_add_and_update_normalize_urls() {
+        let db = LoginDb::open_in_memory(Some("testing".into())).unwrap();
+        let variants = [
+            "https://example.com/",
+            "https://example.com",
+            "HTTPS://Example.Com",
         ];
+        for (i, hostname) in variants.iter().enumerate() {
+            let login = db
+                .add(Login::with_defaults_for_testing(
+                    hostname,
+                    &format!("user{}", i),
+                    "password",
+                ))
+                .unwrap();
+            assert_eq!(login.hostname, "https://example.com");
+            assert_eq!(
+                login.form_submit_url.as_deref(),
+                Some("https://example.com")
+            );
+        }
+        let stored: Vec<String> = db
+            .get_all()
+            .unwrap()
+            .into_iter()
+            .map(|l| l.hostname)
+            .collect();
+        assert_eq!(stored, vec!["https://example.com"; 3]);
+
+        let mut login = db.get_all().unwrap().remove(0);
+        login.hostname = "HTTPS://Example.Org/".into();
+        login.form_submit_url = Some("https://example.org/login".into());
+        db.update(login.clone()).unwrap();
+        let updated = db.get_by_id(&login.guid).unwrap().unwrap();
+        assert_eq!(updated.hostname, "https://example.org");
+        assert_eq!(
+            updated.form_submit_url.as_deref(),
+            Some("https://example.org")
+        );
+    }
 
-        for tc in &test_cases {
-            let login_check = db.check_valid_with_no_dupes(&tc.login);
-            if tc.should_err {
-                assert!(&login_check.is_err());
-                assert_eq!(&login_check.unwrap_err().to_string(), tc.expected_err)
-            } else {
-                assert!(&login_check.is_ok())
+    #[test]
+    fn test_touch_many() {
+        let db = LoginDb::open_in_memory(Some("testing".into())).unwrap();
+        let mut ids: Vec<String> = (0..3)
+            .map(|i| {
+                db.add(Login::with_defaults_for_testing(
+                    "https://www.example.com",
+                    &format!("user{}", i),
+                    "password",
+                ))
+                .unwrap()
+                .guid
+                .to_string()
+            })
+            .collect();
+        db.insert_mirror_record(MirrorLogin {
+            login: Login::with_defaults_for_testing("https://www.example.org", "mirror", "pw"),
+            is_overridden: false,
+            server_modified: ServerTimestamp(1_000_000),
+        })
+        .unwrap();
+        let mirror_id = db
+            .get_all()
+            .unwrap()
+            .into_iter()
+            .find(|l| l.username == "mirror");
+        ids.push(mirror_id.unwrap().guid.to_string());
+
+        let id_refs: Vec<&str> = ids.iter().map(String::as_str).collect();
+        db.touch_many(&id_refs).unwrap();
+        for id in &ids {
+            assert_eq!(db.get_by_id(id).unwrap().unwrap().times_used, 2);
+        }
+        let overlays: i64 = db
+            .query_one("SELECT COUNT(*) FROM loginsL WHERE username = 'mirror'")
+            .unwrap();
+        assert_eq!(overlays, 1);
+
+        // A missing ID means nothing is touched.
+        let err = db.touch_many(&[id_refs[0], "missing"]).unwrap_err();
+        match err.kind() {
+            ErrorKind::NoSuchRecord(id) => assert_eq!(id, "missing"),
+            e => panic!("Unexpected error {:?}", e),
+        }
+        assert_eq!(db.get_by_id(&ids[0]).unwrap().unwrap().times_used, 2);
+    }
+
+    #[test]
+    fn test_restore_from_quarantine() {
+        let mut db = LoginDb::open_in_memory(Some("testing".into())).unwrap();
+        let login = db
+            .add(Login {
+                creation_source: CreationSource::Imported {
+                    source_name: "Chrome".into(),
+                },
+                associated_app: Some("com.example.app".into()),
+                ..Login::with_defaults_for_testing("https://www.example.com", "user", "password")
+            })
+            .unwrap();
+        // Not normalized, so it fails `check_valid`, but can be fixed up.
+        db.execute_all(&["UPDATE loginsL SET hostname = 'https://www.example.com/'"])
+            .unwrap();
+        assert_eq!(
+            db.run_integrity_repair()
+                .unwrap()
+                .invalid_records_quarantined,
+            1
+        );
+        assert_eq!(db.get_all().unwrap().len(), 0);
+
+        let quarantined = db.get_quarantined_records().unwrap();
+        assert_eq!(quarantined.len(), 1);
+        let (id, reason, _) = &quarantined[0];
+        assert_eq!(
+            reason,
+            r#"Invalid login: [InvalidHostname("Origin is not normalized")]"#
+        );
+        assert!(db.restore_from_quarantine(id + 1).is_err());
+        db.restore_from_quarantine(*id).unwrap();
+
+        assert_eq!(db.get_quarantined_records().unwrap(), vec![]);
+        let all = db.get_all().unwrap();
+        assert_eq!(all.len(), 1);
+        assert_eq!(all[0].guid, login.guid);
+        assert_eq!(all[0].hostname, "https://www.example.com");
+        assert_eq!(all[0].password, "password");
+        // Local-only fields survive the round trip.
+        assert_eq!(
+            all[0].creation_source,
+            CreationSource::Imported {
+                source_name: "Chrome".into()
+            }
+        );
+        assert_eq!(all[0].associated_app.as_deref(), Some("com.example.app"));
+        assert!(db.restore_from_quarantine(*id).is_err());
+    }
+
+    #[test]
+    fn test_add_or_update() {
+        let db = LoginDb::open_in_memory(Some("testing".into())).unwrap();
+
+        // Insert.
+        let mut to_add =
+            Login::with_defaults_for_testing("https://www.example.com", "user", "password");
+        to_add.guid = Guid::empty();
+        to_add.time_created = 0;
+        to_add.times_used = 0;
+        let added = db.add_or_update(to_add).unwrap();
+        assert!(!added.guid.is_empty());
+        assert_ne!(added.time_created, 0);
+        assert_eq!(added.times_used, 1);
+        assert_eq!(db.get_all().unwrap(), vec![added.clone()]);
+
+        // Update, by GUID.
+        let mut to_update = added.clone();
+        to_update.password = "password2".into();
+        to_update.time_created = 1;
+        to_update.times_used = 100;
+        let updated = db.add_or_update(to_update).unwrap();
+        assert_eq!(updated.guid, added.guid);
+        assert_eq!(updated.password, "password2");
+        assert_eq!(updated.time_created, added.time_created);
+        assert_eq!(updated.times_used, 2);
+        assert_eq!(db.get_all().unwrap(), vec![updated.clone()]);
+
+        // Update, by finding a dupe.
+        let mut dupe =
+            Login::with_defaults_for_testing("https://www.example.com", "user", "password3");
+        dupe.guid = Guid::empty();
+        let merged = db.add_or_update(dupe).unwrap();
+        assert_eq!(merged.guid, added.guid);
+        assert_eq!(merged.password, "password3");
+        assert_eq!(merged.times_used, 3);
+        assert_eq!(db.get_all().unwrap(), vec![merged]);
+
+        // A different user is a different login.
+        let mut other =
+            Login::with_defaults_for_testing("https://www.example.com", "other", "password");
+        other.guid = Guid::empty();
+        let other = db.add_or_update(other).unwrap();
+        assert_ne!(other.guid, added.guid);
+        assert_eq!(db.get_all().unwrap().len(), 2);
+
+        // Invalid logins are rejected.
+        let invalid = Login::with_defaults_for_testing("https://www.example.com", "user", "");
+        assert!(db.add_or_update(invalid).is_err());
+    }
+
+    #[test]
+    fn test_get_all_paginated() {
+        let db = LoginDb::open_in_memory(Some("testing".into())).unwrap();
+        assert_eq!(db.get_total_count().unwrap(), 0);
+        assert_eq!(db.get_all_paginated(None, 10).unwrap(), vec![]);
+
+        let logins = (0..500)
+            .map(|i| {
+                Login::with_defaults_for_testing(
+                    &format!("https://www.example{}.com", i % 50),
+                    &format!("user{}", i),
+                    "password",
+                )
+            })
+            .collect();
+        db.add_many(logins).unwrap();
+        assert_eq!(db.get_total_count().unwrap(), 500);
+
+        let mut seen = Vec::new();
+        let mut after_guid: Option<String> = None;
+        loop {
+            let page = db.get_all_paginated(after_guid.as_deref(), 37).unwrap();
+            assert!(page.len() <= 37);
+            match page.last() {
+                Some(last) => after_guid = Some(last.guid.to_string()),
+                None => break,
             }
+            seen.extend(page.into_iter().map(|l| (l.hostname, l.guid.into_string())));
         }
+        assert_eq!(seen.len(), 500);
+        let mut sorted = seen.clone();
+        sorted.sort();
+        sorted.dedup();
+        assert_eq!(seen, sorted);
+
+        // Deleting a login from an earlier page doesn't change the next one.
+        let page = db.get_all_paginated(None, 10).unwrap();
+        let next = db.get_all_paginated(Some(&page[9].guid), 10).unwrap();
+        db.delete(page[0].guid_str()).unwrap();
+        assert_eq!(db.get_all_paginated(Some(&page[9].guid), 10).unwrap(), next);
+        assert_eq!(db.get_total_count().unwrap(), 499);
+
+        db.delete(page[9].guid_str()).unwrap();
+        assert!(db.get_all_paginated(Some(&page[9].guid), 10).is_err());
     }
 
     #[test]
-    fn test_unicode_submit() {
-        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
-        db.add(Login {
-            guid: "dummy_000001".into(),
-            form_submit_url: Some("http://😍.com".into()),
-            hostname: "http://😍.com".into(),
-            http_realm: None,
-            username: "😍".into(),
-            username_field: "😍".into(),
-            password: "😍".into(),
-            password_field: "😍".into(),
-            ..Login::default()
-        })
+    fn test_get_changed_and_deleted_since() {
+        let db = LoginDb::open_in_memory(Some("testing".into())).unwrap();
+        let a = db
+            .add(Login::with_defaults_for_testing(
+                "https://www.example.com",
+                "a",
+                "password",
+            ))
+            .unwrap();
+        let b = db
+            .add(Login::with_defaults_for_testing(
+                "https://www.example.com",
+                "b",
+                "password",
+            ))
+            .unwrap();
+        let c = db
+            .add(Login::with_defaults_for_testing(
+                "https://www.example.com",
+                "c",
+                "password",
+            ))
+            .unwrap();
+        assert_eq!(db.get_changed_since(0).unwrap().len(), 3);
+
+        // Pretend everything was added (and synced) a long time ago.
+        db.execute_all(&["UPDATE loginsL SET local_modified = 1000, sync_status = 0"])
+            .unwrap();
+        assert_eq!(db.get_changed_since(0).unwrap(), vec![]);
+        assert_eq!(db.get_deleted_since(0).unwrap(), Vec::<String>::new());
+
+        let since_ms = util::system_time_ms_i64(SystemTime::now());
+        let mut updated = db.get_by_id(&a.guid).unwrap().unwrap();
+        updated.password = "new password".into();
+        db.update(updated).unwrap();
+        db.delete(&b.guid).unwrap();
+
+        let changed = db.get_changed_since(since_ms).unwrap();
+        assert_eq!(changed.len(), 1);
+        assert_eq!(changed[0].guid, a.guid);
+        assert_eq!(changed[0].password, "new password");
+        assert_eq!(
+            db.get_deleted_since(since_ms).unwrap(),
+            vec![b.guid.to_string()]
+        );
+
+        // Changes from before `since_ms` aren't included, even if unsynced.
+        db.execute_named(
+            "UPDATE loginsL SET sync_status = 1 WHERE guid = :guid",
+            named_params! { ":guid": c.guid },
+        )
         .unwrap();
-        let fetched = db
-            .get_by_id("dummy_000001")
-            .expect("should work")
-            .expect("should get a record");
-        assert_eq!(fetched.hostname, "http://xn--r28h.com");
-        assert_eq!(fetched.form_submit_url.unwrap(), "http://xn--r28h.com");
-        assert_eq!(fetched.username, "😍");
-        assert_eq!(fetched.username_field, "😍");
-        assert_eq!(fetched.password, "😍");
-        assert_eq!(fetched.password_field, "😍");
+        assert_eq!(db.get_changed_since(since_ms).unwrap().len(), 1);
+        assert_eq!(db.get_changed_since(0).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_delete_by_hostname() {
+        let db = LoginDb::open_in_memory(Some("testing".into())).unwrap();
+        let add_mirror = |guid: &str, hostname: &str| {
+            db.insert_mirror_record(MirrorLogin {
+                login: Login {
+                    guid: guid.into(),
+                    hostname: hostname.into(),
+                    http_realm: Some("realm".into()),
+                    username: guid.into(),
+                    password: "password".into(),
+                    time_created: 1_500_000_000_000,
+                    time_password_changed: 1_500_000_000_000,
+                    ..Login::default()
+                },
+                is_overridden: false,
+                server_modified: ServerTimestamp(1_000_000),
+            })
+            .unwrap();
+        };
+        let add_local = |hostname: &str, username: &str| {
+            db.add(Login::with_defaults_for_testing(
+                hostname, username, "password",
+            ))
+            .unwrap()
+        };
+        let local_state = |guid: &str| -> Option<(bool, SyncStatus)> {
+            db.try_query_row(
+                "SELECT is_deleted, sync_status FROM loginsL WHERE guid = :guid",
+                named_params! { ":guid": guid },
+                |row| -> Result<_> { Ok((row.get(0)?, SyncStatus::from_u8(row.get(1)?)?)) },
+                false,
+            )
+            .unwrap()
+        };
+
+        // New records are removed without leaving tombstones, but not those
+        // for another port.
+        let new1 = add_local("https://new.example.com", "a");
+        let new2 = add_local("https://new.example.com", "b");
+        let other_port = add_local("https://new.example.com:8080", "a");
+        assert_eq!(db.delete_by_hostname("https://new.example.com").unwrap(), 2);
+        assert_eq!(db.get_by_id(&new1.guid).unwrap(), None);
+        assert_eq!(db.get_by_id(&new2.guid).unwrap(), None);
+        assert_eq!(local_state(&new1.guid), None);
+        assert_eq!(local_state(&new2.guid), None);
+        assert!(db.get_by_id(&other_port.guid).unwrap().is_some());
+        let scope = db.begin_interrupt_scope();
+        let outgoing = db.fetch_outgoing(ServerTimestamp(0), &scope).unwrap();
+        assert!(outgoing.changes.iter().all(|p| !p.is_tombstone()));
+
+        // A changed record becomes a tombstone.
+        add_mirror("changed_001", "https://changed.example.com");
+        let mut changed = db.get_by_id("changed_001").unwrap().unwrap();
+        changed.password = "new password".into();
+        db.update(changed).unwrap();
+        assert_eq!(
+            db.delete_by_hostname("https://changed.example.com")
+                .unwrap(),
+            1
+        );
+        assert_eq!(
+            local_state("changed_001"),
+            Some((true, SyncStatus::Changed))
+        );
+
+        // So does a mirror-only record.
+        add_mirror("mirror_0001", "https://mirror.example.com");
+        assert_eq!(local_state("mirror_0001"), None);
+        assert_eq!(
+            db.delete_by_hostname("https://mirror.example.com").unwrap(),
+            1
+        );
+        assert_eq!(
+            local_state("mirror_0001"),
+            Some((true, SyncStatus::Changed))
+        );
+        assert_eq!(db.get_all_mirror_without_local_overlay().unwrap().len(), 0);
+
+        // And all of them at once.
+        add_mirror("mixed_00001", "https://mixed.example.com");
+        add_mirror("mixed_00002", "https://mixed.example.com");
+        let mut changed = db.get_by_id("mixed_00002").unwrap().unwrap();
+        changed.password = "new password".into();
+        db.update(changed).unwrap();
+        add_local("https://mixed.example.com", "c");
+        add_local("https://mixed.example.com:8080", "c");
+        assert_eq!(
+            db.delete_by_hostname("https://mixed.example.com").unwrap(),
+            3
+        );
+        assert_eq!(
+            db.get_by_hostname("https://mixed.example.com").unwrap(),
+            vec![]
+        );
+        assert_eq!(
+            local_state("mixed_00001"),
+            Some((true, SyncStatus::Changed))
+        );
+        assert_eq!(
+            local_state("mixed_00002"),
+            Some((true, SyncStatus::Changed))
+        );
+        assert_eq!(
+            db.get_by_hostname("https://mixed.example.com:8080")
+                .unwrap()
+                .len(),
+            1
+        );
+
+        assert_eq!(
+            db.delete_by_hostname("https://mixed.example.com").unwrap(),
+            0
+        );
     }
 
     #[test]
-    fn test_unicode_realm() {
-        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
-        db.add(Login {
-            guid: "dummy_000001".into(),
-            form_submit_url: None,
-            hostname: "http://😍.com".into(),
-            http_realm: Some("😍😍".into()),
-            username: "😍".into(),
-            password: "😍".into(),
-            ..Login::default()
+    fn test_get_sync_status_counts() {
+        let db = LoginDb::open_in_memory(Some("testing".into())).unwrap();
+        let counts = |new, changed, synced| SyncStatusCounts {
+            new,
+            changed,
+            synced,
+        };
+        assert_eq!(db.get_sync_status_counts().unwrap(), counts(0, 0, 0));
+
+        let a = db
+            .add(Login::with_defaults_for_testing(
+                "https://www.example.com",
+                "a",
+                "password",
+            ))
+            .unwrap();
+        let b = db
+            .add(Login::with_defaults_for_testing(
+                "https://www.example.com",
+                "b",
+                "password",
+            ))
+            .unwrap();
+        assert_eq!(db.get_sync_status_counts().unwrap(), counts(2, 0, 0));
+
+        // Once uploaded, `a` only exists in the mirror.
+        db.mark_as_synchronized(
+            &[a.guid_str()],
+            ServerTimestamp(1000),
+            &db.begin_interrupt_scope(),
+        )
+        .unwrap();
+        assert_eq!(db.get_sync_status_counts().unwrap(), counts(1, 0, 0));
+
+        // Using it gives it a synced local overlay, and changing it marks that
+        // as changed.
+        db.touch(&a.guid).unwrap();
+        assert_eq!(db.get_sync_status_counts().unwrap(), counts(1, 0, 1));
+        db.update(Login {
+            password: "password2".into(),
+            ..db.get_by_id(&a.guid).unwrap().unwrap()
         })
         .unwrap();
-        let fetched = db
-            .get_by_id("dummy_000001")
-            .expect("should work")
-            .expect("should get a record");
-        assert_eq!(fetched.hostname, "http://xn--r28h.com");
-        assert_eq!(fetched.http_realm.unwrap(), "😍😍");
-    }
+        assert_eq!(db.get_sync_status_counts().unwrap(), counts(1, 1, 0));
 
-    fn check_matches(db: &LoginDb, query: &str, expected: &[&str]) {
-        let mut results = db
-            .get_by_base_domain(query)
-            .unwrap()
-            .into_iter()
-            .map(|l| l.hostname)
-            .collect::<Vec<String>>();
-        results.sort();
-        let mut sorted = expected.to_owned();
-        sorted.sort();
-        assert_eq!(sorted, results);
+        // Changing a new record leaves it new, and deleted records aren't
+        // counted.
+        db.update(Login {
+            password: "password2".into(),
+            ..b.clone()
+        })
+        .unwrap();
+        assert_eq!(db.get_sync_status_counts().unwrap(), counts(1, 1, 0));
+        db.delete(&b.guid).unwrap();
+        assert_eq!(db.get_sync_status_counts().unwrap(), counts(0, 1, 0));
     }
 
-    fn check_good_bad(
-        good: Vec<&str>,
-        bad: Vec<&str>,
-        good_queries: Vec<&str>,
-        zero_queries: Vec<&str>,
-    ) {
-        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
-        for h in good.iter().chain(bad.iter()) {
-            db.add(Login {
-                hostname: (*h).into(),
-                http_realm: Some((*h).into()),
-                password: "test".into(),
-                ..Login::default()
+    #[test]
+    fn test_get_statistics() {
+        let db = LoginDb::open_in_memory(Some("testing".into())).unwrap();
+        assert_eq!(db.get_statistics().unwrap(), LoginStatistics::default());
+
+        let add_mirror = |guid: &str| {
+            db.insert_mirror_record(MirrorLogin {
+                login: Login {
+                    guid: guid.into(),
+                    hostname: "https://www.example.com".into(),
+                    http_realm: Some("realm".into()),
+                    username: guid.into(),
+                    password: "password".into(),
+                    time_created: 1_500_000_000_000,
+                    time_password_changed: 1_500_000_000_000,
+                    ..Login::default()
+                },
+                is_overridden: false,
+                server_modified: ServerTimestamp(1_000_000),
             })
             .unwrap();
-        }
-        for query in good_queries {
-            check_matches(&db, query, &good);
-        }
-        for query in zero_queries {
-            check_matches(&db, query, &[]);
-        }
+        };
+        // Synced, and never used.
+        add_mirror("mirror_only");
+        // Synced, and used recently.
+        add_mirror("synced_used");
+        db.touch("synced_used").unwrap();
+        // Changed, and used recently.
+        add_mirror("changed_001");
+        let mut changed = db.get_by_id("changed_001").unwrap().unwrap();
+        changed.password = "new password".into();
+        db.update(changed).unwrap();
+        // Deleted.
+        add_mirror("deleted_001");
+        db.delete("deleted_001").unwrap();
+        // Local only, and never used.
+        db.add(Login::with_defaults_for_testing(
+            "https://www.example.org",
+            "a",
+            "password",
+        ))
+        .unwrap();
+        // Local only, and used recently.
+        let mut used = Login::with_defaults_for_testing("https://www.example.org", "b", "password");
+        used.time_last_used = util::system_time_ms_i64(SystemTime::now());
+        db.add(used).unwrap();
+
+        assert_eq!(
+            db.get_statistics().unwrap(),
+            LoginStatistics {
+                total_count: 5,
+                synced_count: 2,
+                local_only_count: 2,
+                deleted_count: 1,
+                never_used_count: 2,
+                used_last_30_days_count: 3,
+            }
+        );
     }
 
     #[test]
-    fn test_get_by_base_domain_invalid() {
-        check_good_bad(
-            vec!["https://example.com"],
-            vec![],
-            vec![],
-            vec!["invalid query"],
-        );
+    fn test_find_all_dupes_and_merge() {
+        let db = LoginDb::open_in_memory(Some("testing".into())).unwrap();
+        assert_eq!(db.find_all_dupes().unwrap(), vec![]);
+
+        let mut form = Login::with_defaults_for_testing("https://www.example.com", "user", "a");
+        form.guid = "aaaaaaaaaaaa".into();
+        form.times_used = 3;
+        form.time_last_used = 1_600_000_000_000;
+        let mut realm = Login::with_defaults_for_testing("https://www.example.com", "user", "b");
+        realm.guid = "bbbbbbbbbbbb".into();
+        realm.form_submit_url = None;
+        realm.http_realm = Some("realm".into());
+        realm.username_field = String::new();
+        realm.password_field = String::new();
+        realm.times_used = 2;
+        realm.time_last_used = 1_700_000_000_000;
+        let mut other_user =
+            Login::with_defaults_for_testing("https://www.example.com", "other", "c");
+        other_user.guid = "cccccccccccc".into();
+        let mut other_host =
+            Login::with_defaults_for_testing("https://www.example.org", "user", "d");
+        other_host.guid = "dddddddddddd".into();
+        db.add_many(vec![form, realm, other_user, other_host])
+            .unwrap();
+
+        let dupes = db.find_all_dupes().unwrap();
+        assert_eq!(dupes.len(), 1);
+        assert_eq!(dupes[0].0.guid, "aaaaaaaaaaaa");
+        assert_eq!(dupes[0].1.guid, "bbbbbbbbbbbb");
+
+        assert!(db.merge_logins("aaaaaaaaaaaa", "aaaaaaaaaaaa").is_err());
+        assert!(db.merge_logins("aaaaaaaaaaaa", "missing").is_err());
+        assert!(db.merge_logins("missing", "bbbbbbbbbbbb").is_err());
+        assert!(db.get_by_id("bbbbbbbbbbbb").unwrap().is_some());
+
+        db.merge_logins("aaaaaaaaaaaa", "bbbbbbbbbbbb").unwrap();
+        assert_eq!(db.get_by_id("bbbbbbbbbbbb").unwrap(), None);
+        let kept = db.get_by_id("aaaaaaaaaaaa").unwrap().unwrap();
+        assert_eq!(kept.times_used, 5);
+        assert_eq!(kept.time_last_used, 1_700_000_000_000);
+        assert_eq!(kept.password, "a");
+        assert_eq!(db.find_all_dupes().unwrap(), vec![]);
     }
 
     #[test]
-    fn test_get_by_base_domain() {
-        check_good_bad(
-            vec![
-                "https://example.com",
-                "https://www.example.com",
-                "http://www.example.com",
-                "http://www.example.com:8080",
-                "http://sub.example.com:8080",
-                "https://sub.example.com:8080",
-                "https://sub.sub.example.com",
-                "ftp://sub.example.com",
-            ],
+    fn test_password_health_queries() {
+        let db = LoginDb::open_in_memory(Some("testing".into())).unwrap();
+        assert_eq!(db.get_never_used().unwrap(), vec![]);
+        assert_eq!(db.get_weak_passwords(8).unwrap(), vec![]);
+        assert_eq!(db.get_reused_passwords().unwrap(), Vec::<Vec<Login>>::new());
+
+        let make = |guid: &str, username: &str, password: &str, times_used: i64| {
+            let mut login =
+                Login::with_defaults_for_testing("https://www.example.com", username, password);
+            login.guid = guid.into();
+            login.times_used = times_used;
+            login.time_last_used = login.time_created + times_used;
+            login
+        };
+        db.add_many(vec![
+            make("aaaaaaaaaaaa", "a", "hunter2", 1),
+            make("bbbbbbbbbbbb", "b", "correct horse", 2),
+            make("cccccccccccc", "c", "hunter2", 3),
+            make("dddddddddddd", "d", "correct horse", 4),
+            make("eeeeeeeeeeee", "e", "hunter2", 5),
+            make("ffffffffffff", "f", "unique password", 6),
+            make("gggggggggggg", "g", "deleted", 7),
+        ])
+        .unwrap();
+        // Not used since it was created.
+        db.execute_all(&[
+            "UPDATE loginsL SET timeLastUsed = timeCreated WHERE guid = 'ffffffffffff'",
+        ])
+        .unwrap();
+        // A mirror-only login with a reused password.
+        db.insert_mirror_record(MirrorLogin {
+            login: Login {
+                guid: "mmmmmmmmmmmm".into(),
+                hostname: "https://www.example.org".into(),
+                http_realm: Some("realm".into()),
+                username: "m".into(),
+                password: "correct horse".into(),
+                time_created: 1_500_000_000_000,
+                time_password_changed: 1_500_000_000_000,
+                ..Login::default()
+            },
+            is_overridden: false,
+            server_modified: ServerTimestamp(1_000_000),
+        })
+        .unwrap();
+        // Deleted logins are ignored.
+        db.delete("gggggggggggg").unwrap();
+        db.add(make("hhhhhhhhhhhh", "h", "deleted", 8)).unwrap();
+
+        let guids = |logins: Vec<Login>| -> Vec<String> {
+            let mut guids: Vec<_> = logins.into_iter().map(|l| l.guid.into_string()).collect();
+            guids.sort();
+            guids
+        };
+        assert_eq!(
+            guids(db.get_never_used().unwrap()),
+            vec!["ffffffffffff", "mmmmmmmmmmmm"]
+        );
+        // Everything which reports on unused logins agrees.
+        assert_eq!(
+            guids(
+                db.get_all()
+                    .unwrap()
+                    .into_iter()
+                    .filter(Login::is_never_used)
+                    .collect()
+            ),
+            vec!["ffffffffffff", "mmmmmmmmmmmm"]
+        );
+        assert_eq!(db.get_statistics().unwrap().never_used_count, 2);
+        assert_eq!(
+            db.get_security_report(&LengthChecker, 30)
+                .unwrap()
+                .never_used_count,
+            2
+        );
+        assert_eq!(
+            guids(db.get_weak_passwords(8).unwrap()),
             vec![
-                "https://badexample.com",
-                "https://example.co",
-                "https://example.com.au",
-            ],
-            vec!["example.com"],
-            vec!["foo.com"],
+                "aaaaaaaaaaaa",
+                "cccccccccccc",
+                "eeeeeeeeeeee",
+                "hhhhhhhhhhhh"
+            ]
         );
-        // punycode! This is likely to need adjusting once we normalize
-        // on insert.
-        check_good_bad(
+        assert_eq!(db.get_weak_passwords(1).unwrap(), vec![]);
+
+        let groups: Vec<Vec<String>> = db
+            .get_reused_passwords()
+            .unwrap()
+            .into_iter()
+            .map(guids)
+            .collect();
+        assert_eq!(
+            groups,
             vec![
-                "http://xn--r28h.com", // punycoded version of "http://😍.com"
-            ],
-            vec!["http://💖.com"],
-            vec!["😍.com", "xn--r28h.com"],
-            vec![],
+                vec!["bbbbbbbbbbbb", "dddddddddddd", "mmmmmmmmmmmm"],
+                vec!["aaaaaaaaaaaa", "cccccccccccc", "eeeeeeeeeeee"],
+            ]
         );
     }
 
     #[test]
-    fn test_get_by_base_domain_ipv4() {
-        check_good_bad(
-            vec!["http://127.0.0.1", "https://127.0.0.1:8000"],
-            vec!["https://127.0.0.0", "https://example.com"],
-            vec!["127.0.0.1"],
-            vec!["127.0.0.2"],
+    fn test_get_hostnames() {
+        let db = LoginDb::open_in_memory(Some("testing".into())).unwrap();
+        assert_eq!(db.get_all_hostnames().unwrap(), Vec::<String>::new());
+
+        db.apply_incoming_from_json(include_str!("../fixtures/incoming_new.json"), 1000.0)
+            .unwrap();
+        for (hostname, username) in &[
+            ("https://www.example.com", "carol"),
+            ("https://mail.example.com", "alice"),
+            ("https://www.ex_ample.com", "alice"),
+            ("https://www.exxample.com", "alice"),
+            ("https://deleted.example.com", "alice"),
+        ] {
+            db.add(Login::with_defaults_for_testing(
+                hostname, username, "password",
+            ))
+            .unwrap();
+        }
+        let deleted = db.get_by_hostname("https://deleted.example.com").unwrap();
+        db.delete(&deleted[0].guid).unwrap();
+
+        assert_eq!(
+            db.get_all_hostnames().unwrap(),
+            vec![
+                "https://mail.example.com",
+                "https://www.ex_ample.com",
+                "https://www.example.com",
+                "https://www.example.org",
+                "https://www.exxample.com",
+            ]
+        );
+        assert_eq!(
+            db.get_hostnames_matching("https://www.example.").unwrap(),
+            vec!["https://www.example.com", "https://www.example.org"]
+        );
+        // `_` and `%` aren't wildcards.
+        assert_eq!(
+            db.get_hostnames_matching("https://www.ex_").unwrap(),
+            vec!["https://www.ex_ample.com"]
+        );
+        assert_eq!(
+            db.get_hostnames_matching("%").unwrap(),
+            Vec::<String>::new()
+        );
+        assert_eq!(
+            db.get_hostnames_matching("").unwrap(),
+            db.get_all_hostnames().unwrap()
         );
     }
 
     #[test]
-    fn test_get_by_base_domain_ipv6() {
-        check_good_bad(
-            vec!["http://[::1]", "https://[::1]:8000"],
-            vec!["https://[0:0:0:0:0:0:1:1]", "https://example.com"],
-            vec!["[::1]", "[0:0:0:0:0:0:0:1]"],
-            vec!["[0:0:0:0:0:0:1:2]"],
+    fn test_busy_timeout() {
+        use std::time::Instant;
+
+        let dir = tempdir::TempDir::new("busy_timeout").unwrap();
+        let dbpath = dir.path().join("logins.sqlite");
+        let db = LoginDb::open(&dbpath, Some("testing".into())).unwrap();
+        let other = LoginDb::with_connection_options(
+            Connection::open(&dbpath).unwrap(),
+            Some("testing".into()),
+            None,
+            &ConnectionOptions {
+                busy_timeout_ms: Some(200),
+                ..ConnectionOptions::default()
+            },
+        )
+        .unwrap();
+        let login = Login::with_defaults_for_testing("https://www.example.com", "user", "password");
+
+        db.execute_all(&["BEGIN IMMEDIATE"]).unwrap();
+        let start = Instant::now();
+        assert!(other.add(login.clone()).is_err());
+        assert!(start.elapsed() >= Duration::from_millis(200));
+
+        other.set_busy_timeout(0).unwrap();
+        assert!(other.add(login.clone()).is_err());
+
+        db.execute_all(&["COMMIT"]).unwrap();
+        other.add(login).unwrap();
+    }
+
+    #[test]
+    fn test_journal_mode_and_checkpoint() {
+        let dir = tempdir::TempDir::new("checkpoint").unwrap();
+        let dbpath = dir.path().join("logins.sqlite");
+        let mut walpath = dbpath.as_os_str().to_owned();
+        walpath.push("-wal");
+        let db = LoginDb::with_connection_options(
+            Connection::open(&dbpath).unwrap(),
+            Some("testing".into()),
+            None,
+            &ConnectionOptions {
+                journal_mode: Some(JournalMode::Wal),
+                cache_size_pages: Some(500),
+                busy_timeout_ms: Some(1000),
+                ..ConnectionOptions::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            db.query_one::<String>("PRAGMA journal_mode").unwrap(),
+            "wal"
+        );
+        assert_eq!(db.query_one::<i32>("PRAGMA cache_size").unwrap(), 500);
+
+        for i in 0..50 {
+            db.add(Login::with_defaults_for_testing(
+                "https://www.example.com",
+                &format!("user{}", i),
+                "password",
+            ))
+            .unwrap();
+        }
+        let wal_size = || std::fs::metadata(&walpath).unwrap().len();
+        assert!(wal_size() > 0);
+
+        let result = db.checkpoint().unwrap();
+        assert_eq!(result.checkpointed_frames, result.log_frames);
+        assert_eq!(wal_size(), 0);
+        assert_eq!(db.get_all().unwrap().len(), 50);
+
+        // Checkpointing doesn't do anything outside of WAL mode.
+        let db = LoginDb::open_in_memory(Some("testing".into())).unwrap();
+        assert_eq!(
+            db.checkpoint().unwrap(),
+            CheckpointResult {
+                log_frames: -1,
+                checkpointed_frames: -1,
+            }
         );
     }
 
     #[test]
     fn test_delete() {
-        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        let db = LoginDb::open_in_memory(Some("testing".into())).unwrap();
         let _login = db
             .add(Login {
                 hostname: "https://www.example.com".into(),
@@ -1660,13 +10763,284 @@ mod tests {
             )
             .unwrap();
 
-        assert!(tombstone_exists);
-        assert!(!db.exists(_login.guid_str()).unwrap());
+        assert!(tombstone_exists);
+        assert!(!db.exists(_login.guid_str()).unwrap());
+    }
+
+    #[test]
+    fn test_deleted_ready_to_purge() {
+        let db = LoginDb::open_in_memory(Some("testing".into())).unwrap();
+        let now_ms = util::system_time_ms_i64(SystemTime::now());
+        let day_ms = 24 * 60 * 60 * 1000;
+        let mut tombstones = Vec::new();
+        for (i, age_days) in [1, 10, 20, 40].iter().enumerate() {
+            let login = db
+                .add(Login {
+                    hostname: format!("https://www.example{}.com", i),
+                    http_realm: Some("realm".into()),
+                    username: "test_user".into(),
+                    password: "test_password".into(),
+                    ..Login::default()
+                })
+                .unwrap();
+            db.delete(login.guid_str()).unwrap();
+            // As if the deletion had been uploaded.
+            db.execute_named(
+                "UPDATE loginsL SET local_modified = :modified, sync_status = 0
+                 WHERE guid = :guid",
+                named_params! {
+                    ":modified": now_ms - age_days * day_ms,
+                    ":guid": login.guid_str(),
+                },
+            )
+            .unwrap();
+            tombstones.push(login.guid.into_string());
+        }
+        // A deletion which hasn't been uploaded is never ready, however old.
+        let pending = db
+            .add(Login {
+                hostname: "https://www.pending.com".into(),
+                http_realm: Some("realm".into()),
+                username: "test_user".into(),
+                password: "test_password".into(),
+                ..Login::default()
+            })
+            .unwrap();
+        db.delete(pending.guid_str()).unwrap();
+        db.execute_named(
+            "UPDATE loginsL SET local_modified = 0 WHERE guid = :guid",
+            named_params! { ":guid": pending.guid_str() },
+        )
+        .unwrap();
+        // A record which isn't deleted should never be purged, however old.
+        let live = db
+            .add(Login {
+                hostname: "https://www.live.com".into(),
+                http_realm: Some("realm".into()),
+                username: "test_user".into(),
+                password: "test_password".into(),
+                ..Login::default()
+            })
+            .unwrap();
+        db.execute_named(
+            "UPDATE loginsL SET local_modified = 0 WHERE guid = :guid",
+            named_params! { ":guid": live.guid_str() },
+        )
+        .unwrap();
+
+        assert_eq!(
+            db.count_deleted_ready_to_purge(15 * day_ms as u64).unwrap(),
+            2
+        );
+        let mut ready = db.get_deleted_ready_to_purge(15 * day_ms as u64).unwrap();
+        ready.sort();
+        let mut expected = tombstones[2..].to_vec();
+        expected.sort();
+        assert_eq!(ready, expected);
+
+        assert_eq!(db.count_deleted_ready_to_purge(0).unwrap(), 4);
+        assert_eq!(
+            db.count_deleted_ready_to_purge(100 * day_ms as u64)
+                .unwrap(),
+            0
+        );
+        assert!(db
+            .get_deleted_ready_to_purge(100 * day_ms as u64)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_purge_tombstones() {
+        let db = LoginDb::open_in_memory(Some("testing".into())).unwrap();
+        let now_ms = util::system_time_ms_i64(SystemTime::now());
+        let day_ms = 24 * 60 * 60 * 1000;
+        let age = |guid: &str, age_days: i64, sync_status: SyncStatus| {
+            db.execute_named(
+                "UPDATE loginsL SET local_modified = :modified, sync_status = :sync_status
+                 WHERE guid = :guid",
+                named_params! {
+                    ":modified": now_ms - age_days * day_ms,
+                    ":sync_status": sync_status as u8,
+                    ":guid": guid,
+                },
+            )
+            .unwrap();
+        };
+        let add_deleted = |hostname: &str| {
+            let login = db
+                .add(Login::with_defaults_for_testing(
+                    hostname,
+                    "test_user",
+                    "test_password",
+                ))
+                .unwrap();
+            db.delete(login.guid_str()).unwrap();
+            login.guid.to_string()
+        };
+        // Deletions which have been uploaded.
+        let mut tombstones = Vec::new();
+        for (i, age_days) in [1, 20, 40, 60].iter().enumerate() {
+            let guid = add_deleted(&format!("https://www.example{}.com", i));
+            age(&guid, *age_days, SyncStatus::Synced);
+            tombstones.push(guid);
+        }
+        // An old deletion which hasn't been uploaded yet.
+        let pending = add_deleted("https://www.pending.com");
+        age(&pending, 90, SyncStatus::Changed);
+        // An old tombstone hiding a mirror record.
+        let mirrored = Login::with_defaults_for_testing(
+            "https://www.mirrored.com",
+            "test_user",
+            "test_password",
+        );
+        db.insert_mirror_record(MirrorLogin {
+            login: mirrored.clone(),
+            is_overridden: false,
+            server_modified: ServerTimestamp(1000),
+        })
+        .unwrap();
+        db.delete(mirrored.guid_str()).unwrap();
+        age(mirrored.guid_str(), 90, SyncStatus::Synced);
+        assert_eq!(db.get_tombstone_count().unwrap(), 6);
+
+        // It purges exactly what `get_deleted_ready_to_purge` returns.
+        let mut ready = db
+            .get_deleted_ready_to_purge(DEFAULT_TOMBSTONE_MAX_AGE_MS)
+            .unwrap();
+        ready.sort();
+        let mut expected = vec![tombstones[2].clone(), tombstones[3].clone()];
+        expected.sort();
+        assert_eq!(ready, expected);
+        assert_eq!(db.purge_old_tombstones().unwrap(), 2);
+        assert_eq!(db.get_tombstone_count().unwrap(), 4);
+        assert_eq!(
+            db.count_deleted_ready_to_purge(DEFAULT_TOMBSTONE_MAX_AGE_MS)
+                .unwrap(),
+            0
+        );
+        // The mirror record is still hidden by its tombstone.
+        assert!(db.get_mirror_record(mirrored.guid_str()).unwrap().is_some());
+        assert_eq!(db.get_by_id(mirrored.guid_str()).unwrap(), None);
+
+        assert_eq!(db.purge_tombstones(now_ms).unwrap(), 2);
+        assert_eq!(db.get_tombstone_count().unwrap(), 2);
+        assert_eq!(db.purge_tombstones(now_ms).unwrap(), 0);
+
+        // The pending deletion survives, and is still uploaded.
+        let local = db.get_local_record(&pending).unwrap().unwrap();
+        assert!(local.is_deleted);
+        let outgoing = db
+            .fetch_outgoing(ServerTimestamp(0), &db.begin_interrupt_scope())
+            .unwrap();
+        let deleted: Vec<_> = outgoing
+            .changes
+            .iter()
+            .filter(|p| p.is_tombstone())
+            .map(|p| p.id.to_string())
+            .collect();
+        assert_eq!(deleted, vec![pending]);
+    }
+
+    #[test]
+    fn test_get_mirror_and_local_records() {
+        let db = LoginDb::open_in_memory(Some("testing".into())).unwrap();
+        let login = db
+            .add(Login::with_defaults_for_testing(
+                "https://www.example.com",
+                "user",
+                "password",
+            ))
+            .unwrap();
+        assert!(db.get_mirror_record(&login.guid).unwrap().is_none());
+        let local = db.get_local_record(&login.guid).unwrap().unwrap();
+        assert_eq!(local.login.guid, login.guid);
+        assert_eq!(local.sync_status, SyncStatus::New);
+        assert!(!local.is_deleted);
+
+        db.mark_as_synchronized(
+            &[login.guid_str()],
+            ServerTimestamp(2000),
+            &db.begin_interrupt_scope(),
+        )
+        .unwrap();
+        assert!(db.get_local_record(&login.guid).unwrap().is_none());
+        let mirror = db.get_mirror_record(&login.guid).unwrap().unwrap();
+        assert_eq!(mirror.login.password, "password");
+        assert!(!mirror.is_overridden);
+        assert_eq!(mirror.server_modified, ServerTimestamp(2000));
+
+        // Unlike `get_by_id`, these still return overridden mirror records
+        // and tombstones.
+        db.delete(&login.guid).unwrap();
+        assert!(db.get_by_id(&login.guid).unwrap().is_none());
+        assert!(
+            db.get_mirror_record(&login.guid)
+                .unwrap()
+                .unwrap()
+                .is_overridden
+        );
+        let local = db.get_local_record(&login.guid).unwrap().unwrap();
+        assert!(local.is_deleted);
+        assert_eq!(local.sync_status, SyncStatus::Changed);
+
+        assert!(db.get_mirror_record("missing").unwrap().is_none());
+        assert!(db.get_local_record("missing").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_clone_for_testing() {
+        fn dump(db: &LoginDb, table: &str) -> Vec<Vec<rusqlite::types::Value>> {
+            let mut stmt = db
+                .prepare(&format!("SELECT * FROM {} ORDER BY 1", table))
+                .unwrap();
+            let num_cols = stmt.column_count();
+            stmt.query_map(NO_PARAMS, |row| {
+                (0..num_cols).map(|i| row.get(i)).collect::<Result<_, _>>()
+            })
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap()
+        }
+        let db = LoginDb::open_in_memory(Some("testing".into())).unwrap();
+        db.apply_incoming_from_json(include_str!("../fixtures/incoming_new.json"), 1000.0)
+            .unwrap();
+        db.add(Login {
+            hostname: "https://www.example.net".into(),
+            http_realm: Some("realm".into()),
+            username: "carol".into(),
+            password: "password".into(),
+            ..Login::default()
+        })
+        .unwrap();
+        db.delete("dummy_000002").unwrap();
+        db.set_global_state(&Some("global state".into())).unwrap();
+
+        let snapshot = db.clone_for_testing().unwrap();
+        for table in &["loginsL", "loginsM", "loginsSyncMeta"] {
+            let rows = dump(&db, table);
+            assert!(!rows.is_empty(), "{} should have rows", table);
+            assert_eq!(rows, dump(&snapshot, table), "{} differs", table);
+        }
+        assert_eq!(
+            snapshot.get_global_state().unwrap(),
+            Some("global state".into())
+        );
+        assert_eq!(
+            snapshot.get_all_in_order().unwrap(),
+            db.get_all_in_order().unwrap()
+        );
+
+        // Changes to the original don't affect the snapshot.
+        db.wipe_local().unwrap();
+        assert!(db.get_all().unwrap().is_empty());
+        assert_eq!(snapshot.get_all().unwrap().len(), 2);
+        assert!(snapshot.get_by_id("dummy_000002").unwrap().is_none());
     }
 
     #[test]
     fn test_wipe() {
-        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        let db = LoginDb::open_in_memory(Some("testing".into())).unwrap();
         let login1 = db
             .add(Login {
                 hostname: "https://www.example.com".into(),
@@ -1711,18 +11085,335 @@ mod tests {
 
     fn delete_logins(db: &LoginDb, guids: &[String]) -> Result<()> {
         sql_support::each_chunk(guids, |chunk, _| -> Result<()> {
+            let (vars, params) = util::sql_placeholders_and_params(chunk);
             db.execute(
-                &format!(
-                    "DELETE FROM loginsL WHERE guid IN ({vars})",
-                    vars = sql_support::repeat_sql_vars(chunk.len())
-                ),
-                chunk,
+                &format!("DELETE FROM loginsL WHERE guid IN ({vars})", vars = vars),
+                &params,
             )?;
             Ok(())
         })?;
         Ok(())
     }
 
+    const CHROME_CSV: &str = "name,url,username,password
+www.example.com,https://www.example.com/login?next=1,alice,new password
+\"Example, Inc.\",https://www.example.org/,bob,\"pass,word\"
+";
+
+    const FIREFOX_CSV: &str = "\"url\",\"username\",\"password\",\"httpRealm\",\"formActionOrigin\",\"guid\",\"timeCreated\",\"timeLastUsed\",\"timePasswordChanged\"
+\"https://www.example.com\",\"alice\",\"hunter2\",,\"https://login.example.com\",\"dummy_000001\",\"1589617814635\",\"1589710449871\",\"1589617846802\"
+\"https://www.example.org\",\"bob\",\"secret\",\"My Realm\",,\"{5ec0d12f-1d8b-4a72-9a4d-6d8d0b6e0f1a}\",\"1589617814635\",\"1589617814635\",\"1589617814635\"
+";
+
+    fn import_options(
+        on_duplicate: DuplicateStrategy,
+        on_invalid: InvalidStrategy,
+    ) -> ImportOptions {
+        ImportOptions {
+            on_duplicate,
+            on_invalid,
+        }
+    }
+
+    #[test]
+    fn test_import_from_csv() {
+        let db = LoginDb::open_in_memory(Some("testing".into())).unwrap();
+        let options = import_options(DuplicateStrategy::Skip, InvalidStrategy::Abort);
+
+        let report = db.import_from_csv(CHROME_CSV.as_bytes(), options).unwrap();
+        assert_eq!(
+            report,
+            ImportReport {
+                inserted: 2,
+                ..ImportReport::default()
+            }
+        );
+        let alice = db
+            .get_by_base_domain("www.example.com")
+            .unwrap()
+            .pop()
+            .unwrap();
+        assert_eq!(alice.hostname, "https://www.example.com");
+        assert_eq!(
+            alice.form_submit_url,
+            Some("https://www.example.com".into())
+        );
+        assert_eq!(alice.username, "alice");
+        assert_eq!(alice.password, "new password");
+        assert_eq!(
+            alice.creation_source,
+            CreationSource::Imported {
+                source_name: "Chrome".into()
+            }
+        );
+        let bob = db
+            .get_by_base_domain("www.example.org")
+            .unwrap()
+            .pop()
+            .unwrap();
+        assert_eq!(bob.password, "pass,word");
+
+        let db = LoginDb::open_in_memory(Some("testing".into())).unwrap();
+        let report = db.import_from_csv(FIREFOX_CSV.as_bytes(), options).unwrap();
+        assert_eq!(report.inserted, 2);
+        let alice = db.get_by_id("dummy_000001").unwrap().unwrap();
+        assert_eq!(
+            alice.form_submit_url,
+            Some("https://login.example.com".into())
+        );
+        assert_eq!(alice.http_realm, None);
+        assert_eq!(alice.time_created, 1_589_617_814_635);
+        assert_eq!(alice.time_last_used, 1_589_710_449_871);
+        assert_eq!(alice.time_password_changed, 1_589_617_846_802);
+        assert_eq!(
+            alice.creation_source,
+            CreationSource::Imported {
+                source_name: "Firefox".into()
+            }
+        );
+        // Desktop's braced GUIDs are kept.
+        let bob = db
+            .get_by_id("{5ec0d12f-1d8b-4a72-9a4d-6d8d0b6e0f1a}")
+            .unwrap()
+            .unwrap();
+        assert_eq!(bob.http_realm, Some("My Realm".into()));
+        assert_eq!(bob.form_submit_url, None);
+
+        // Importing the same file again only finds duplicates.
+        let report = db.import_from_csv(FIREFOX_CSV.as_bytes(), options).unwrap();
+        assert_eq!(
+            report,
+            ImportReport {
+                skipped: 2,
+                ..ImportReport::default()
+            }
+        );
+
+        let err = db
+            .import_from_csv(
+                "url,username\nhttps://www.example.com,alice\n".as_bytes(),
+                options,
+            )
+            .unwrap_err();
+        match err.kind() {
+            ErrorKind::MissingCsvColumn(column) => assert_eq!(column, "password"),
+            e => panic!("Unexpected error {:?}", e),
+        }
+    }
+
+    #[test]
+    fn test_export() {
+        let db = LoginDb::open_in_memory(Some("testing".into())).unwrap();
+        db.import_from_csv(
+            FIREFOX_CSV.as_bytes(),
+            import_options(DuplicateStrategy::Skip, InvalidStrategy::Abort),
+        )
+        .unwrap();
+        db.add(Login::with_defaults_for_testing(
+            "https://www.example.net",
+            "carol",
+            "with \"quotes\", commas\nand newlines",
+        ))
+        .unwrap();
+        let logins = db.get_all().unwrap();
+
+        let mut json = vec![];
+        let count = db
+            .export_to_json(&mut json, ExportPermission::allow_plaintext_passwords())
+            .unwrap();
+        assert_eq!(count, 3);
+        let exported: Vec<Login> = serde_json::from_slice(&json).unwrap();
+        assert_eq!(
+            exported.iter().map(|l| &l.password).collect::<Vec<_>>(),
+            logins.iter().map(|l| &l.password).collect::<Vec<_>>()
+        );
+
+        // Everything the Firefox format has survives a round trip.
+        let mut csv = vec![];
+        let count = db
+            .export_to_csv(
+                &mut csv,
+                CsvExportFormat::Firefox,
+                ExportPermission::allow_plaintext_passwords(),
+            )
+            .unwrap();
+        assert_eq!(count, 3);
+        let other = LoginDb::open_in_memory(Some("testing".into())).unwrap();
+        let report = other
+            .import_from_csv(
+                csv.as_slice(),
+                import_options(DuplicateStrategy::Skip, InvalidStrategy::Abort),
+            )
+            .unwrap();
+        assert_eq!(report.inserted, 3);
+        let summarize = |login: &Login| {
+            (
+                login.guid.clone(),
+                login.hostname.clone(),
+                login.username.clone(),
+                login.password.clone(),
+                login.http_realm.clone(),
+                login.form_submit_url.clone(),
+                login.time_created,
+                login.time_last_used,
+                login.time_password_changed,
+            )
+        };
+        assert_eq!(
+            other
+                .get_all()
+                .unwrap()
+                .iter()
+                .map(summarize)
+                .collect::<Vec<_>>(),
+            logins.iter().map(summarize).collect::<Vec<_>>()
+        );
+
+        // The Chrome format only has the origin, username and password.
+        let mut csv = vec![];
+        db.export_to_csv(
+            &mut csv,
+            CsvExportFormat::Chrome,
+            ExportPermission::allow_plaintext_passwords(),
+        )
+        .unwrap();
+        assert!(String::from_utf8(csv.clone())
+            .unwrap()
+            .starts_with("name,url,username,password\nwww.example.com,https://www.example.com,"));
+        let other = LoginDb::open_in_memory(Some("testing".into())).unwrap();
+        other
+            .import_from_csv(
+                csv.as_slice(),
+                import_options(DuplicateStrategy::Skip, InvalidStrategy::Abort),
+            )
+            .unwrap();
+        let summarize = |login: &Login| {
+            (
+                login.hostname.clone(),
+                login.username.clone(),
+                login.password.clone(),
+            )
+        };
+        assert_eq!(
+            other
+                .get_all()
+                .unwrap()
+                .iter()
+                .map(summarize)
+                .collect::<Vec<_>>(),
+            logins.iter().map(summarize).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_import_from_csv_duplicates() {
+        let setup = || {
+            let db = LoginDb::open_in_memory(Some("testing".into())).unwrap();
+            let existing = db
+                .add(Login::with_defaults_for_testing(
+                    "https://www.example.com",
+                    "alice",
+                    "old password",
+                ))
+                .unwrap();
+            (db, existing)
+        };
+
+        let (db, existing) = setup();
+        let report = db
+            .import_from_csv(
+                CHROME_CSV.as_bytes(),
+                import_options(DuplicateStrategy::Skip, InvalidStrategy::Abort),
+            )
+            .unwrap();
+        assert_eq!(
+            (report.inserted, report.replaced, report.skipped),
+            (1, 0, 1)
+        );
+        let alice = db.get_by_id(&existing.guid).unwrap().unwrap();
+        assert_eq!(alice.password, "old password");
+
+        let (db, existing) = setup();
+        let report = db
+            .import_from_csv(
+                CHROME_CSV.as_bytes(),
+                import_options(DuplicateStrategy::Replace, InvalidStrategy::Abort),
+            )
+            .unwrap();
+        assert_eq!(
+            (report.inserted, report.replaced, report.skipped),
+            (1, 1, 0)
+        );
+        let alice = db.get_by_id(&existing.guid).unwrap().unwrap();
+        assert_eq!(alice.password, "new password");
+        assert_eq!(db.get_total_count().unwrap(), 2);
+
+        let (db, existing) = setup();
+        let report = db
+            .import_from_csv(
+                CHROME_CSV.as_bytes(),
+                import_options(DuplicateStrategy::KeepBoth, InvalidStrategy::Abort),
+            )
+            .unwrap();
+        assert_eq!(
+            (report.inserted, report.replaced, report.skipped),
+            (2, 0, 0)
+        );
+        let alice = db.get_by_id(&existing.guid).unwrap().unwrap();
+        assert_eq!(alice.password, "old password");
+        assert_eq!(db.get_total_count().unwrap(), 3);
+
+        // A GUID which is already taken is replaced when keeping both.
+        let db = LoginDb::open_in_memory(Some("testing".into())).unwrap();
+        let options = import_options(DuplicateStrategy::KeepBoth, InvalidStrategy::Abort);
+        db.import_from_csv(FIREFOX_CSV.as_bytes(), options).unwrap();
+        let report = db.import_from_csv(FIREFOX_CSV.as_bytes(), options).unwrap();
+        assert_eq!(report.inserted, 2);
+        assert_eq!(db.get_total_count().unwrap(), 4);
+    }
+
+    #[test]
+    fn test_import_from_csv_invalid() {
+        let csv = "name,url,username,password
+one,https://www.example.com,alice,hunter2
+two,https://www.example.org,bob,
+three,not a url,carol,password
+four,https://www.example.net,dave
+five,https://www.example.net,erin,password
+";
+        let db = LoginDb::open_in_memory(Some("testing".into())).unwrap();
+        let report = db
+            .import_from_csv(
+                csv.as_bytes(),
+                import_options(DuplicateStrategy::Skip, InvalidStrategy::Skip),
+            )
+            .unwrap();
+        assert_eq!(report.inserted, 2);
+        assert_eq!(report.skipped, 3);
+        assert_eq!(
+            report.errors.iter().map(|e| e.row).collect::<Vec<_>>(),
+            vec![2, 3, 4]
+        );
+        assert_eq!(report.errors[0].message, "Invalid login: [EmptyPassword]");
+        assert_eq!(db.get_total_count().unwrap(), 2);
+
+        // Aborting rolls back the logins imported before the bad row.
+        let db = LoginDb::open_in_memory(Some("testing".into())).unwrap();
+        let err = db
+            .import_from_csv(
+                csv.as_bytes(),
+                import_options(DuplicateStrategy::Skip, InvalidStrategy::Abort),
+            )
+            .unwrap_err();
+        match err.kind() {
+            ErrorKind::InvalidLogin(errs) => {
+                assert_eq!(errs.0, [LoginValidationError::EmptyPassword]);
+            }
+            e => panic!("Unexpected error {:?}", e),
+        }
+        assert_eq!(db.get_total_count().unwrap(), 0);
+    }
+
     #[test]
     fn test_import_multiple() {
         struct TestCase {
@@ -1731,7 +11422,7 @@ mod tests {
             expected_metrics: MigrationMetrics,
         }
 
-        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        let db = LoginDb::open_in_memory(Some("testing".into())).unwrap();
 
         // Adding login to trigger non-empty table error
         let login = db
@@ -1922,13 +11613,75 @@ mod tests {
         conn.query_one::<i64>("PRAGMA user_version").unwrap();
     }
 
+    #[test]
+    fn test_upgrade_from_v4() {
+        let dir = tempdir::TempDir::new("upgrade_from_v4").unwrap();
+        let dbpath = dir.path().join("logins.sqlite");
+        {
+            let conn = Connection::open(&dbpath).unwrap();
+            conn.execute_batch(
+                "CREATE TABLE loginsL (
+                    id INTEGER PRIMARY KEY AUTOINCREMENT,
+                    hostname TEXT NOT NULL,
+                    httpRealm TEXT,
+                    formSubmitURL TEXT,
+                    usernameField TEXT,
+                    passwordField TEXT,
+                    timesUsed INTEGER NOT NULL DEFAULT 0,
+                    timeCreated INTEGER NOT NULL,
+                    timeLastUsed INTEGER,
+                    timePasswordChanged INTEGER NOT NULL,
+                    username TEXT,
+                    password TEXT NOT NULL,
+                    guid TEXT NOT NULL UNIQUE,
+                    local_modified INTEGER,
+                    is_deleted TINYINT NOT NULL DEFAULT 0,
+                    sync_status TINYINT NOT NULL DEFAULT 0
+                );
+                CREATE TABLE loginsM (
+                    id INTEGER PRIMARY KEY AUTOINCREMENT,
+                    hostname TEXT NOT NULL,
+                    httpRealm TEXT,
+                    formSubmitURL TEXT,
+                    usernameField TEXT,
+                    passwordField TEXT,
+                    timesUsed INTEGER NOT NULL DEFAULT 0,
+                    timeCreated INTEGER NOT NULL,
+                    timeLastUsed INTEGER,
+                    timePasswordChanged INTEGER NOT NULL,
+                    username TEXT,
+                    password TEXT NOT NULL,
+                    guid TEXT NOT NULL UNIQUE,
+                    server_modified INTEGER NOT NULL,
+                    is_overridden TINYINT NOT NULL DEFAULT 0
+                );
+                CREATE TABLE loginsSyncMeta (key TEXT PRIMARY KEY, value NOT NULL);
+                INSERT INTO loginsL (hostname, httpRealm, timeCreated, timePasswordChanged,
+                                     username, password, guid, sync_status)
+                VALUES ('https://www.example.com', 'realm', 1000, 1000,
+                        'user', 'password', 'dummy_000001', 2);
+                PRAGMA user_version = 4;",
+            )
+            .unwrap();
+        }
+        let db = LoginDb::open(&dbpath, None).unwrap();
+        assert_eq!(
+            db.query_one::<i64>("PRAGMA user_version").unwrap(),
+            schema::VERSION
+        );
+        let login = db.get_by_id("dummy_000001").unwrap().unwrap();
+        assert_eq!(login.username, "user");
+        assert_eq!(login.creation_source, CreationSource::Unknown);
+        assert_eq!(login.associated_app, None);
+    }
+
     #[test]
     fn test_get_salt_for_key() {
         // First we create a database.
         let dir = tempdir::TempDir::new("salt_for_key_test").unwrap();
         let dbpath = dir.path().join("logins.sqlite");
         let dbpath = dbpath.to_str().unwrap();
-        let conn = LoginDb::open(dbpath, Some("testing")).unwrap();
+        let conn = LoginDb::open(dbpath, Some("testing".into())).unwrap();
         // Database created.
         let expected_salt = conn.query_one::<String>("PRAGMA cipher_salt").unwrap();
 
@@ -1947,7 +11700,7 @@ mod tests {
         let dir = tempdir::TempDir::new("plaintext_header_migration").unwrap();
         let dbpath = dir.path().join("logins.sqlite");
         let dbpath = dbpath.to_str().unwrap();
-        let conn = LoginDb::open(dbpath, Some("testing")).unwrap();
+        let conn = LoginDb::open(dbpath, Some("testing".into())).unwrap();
         drop(conn);
         // Database created.
 
@@ -1962,6 +11715,277 @@ mod tests {
         conn.query_one::<i64>("PRAGMA user_version").unwrap();
     }
 
+    #[test]
+    fn test_raw_encryption_key() {
+        let dir = tempdir::TempDir::new("raw_key").unwrap();
+        let dbpath = dir.path().join("logins.sqlite");
+        let key = EncryptionKey::RawHex("0123456789abcdef".repeat(4));
+        let db = LoginDb::open(&dbpath, Some(key.clone())).unwrap();
+        let login = db
+            .add(Login::with_defaults_for_testing(
+                "https://www.example.com",
+                "user",
+                "password",
+            ))
+            .unwrap();
+        drop(db);
+
+        assert!(
+            LoginDb::open(&dbpath, Some("0123456789abcdef".repeat(4).as_str().into())).is_err()
+        );
+        let db = LoginDb::open(&dbpath, Some(key)).unwrap();
+        assert_eq!(
+            db.get_by_id(&login.guid).unwrap().unwrap().password,
+            "password"
+        );
+
+        for bad in &[
+            "",
+            "abcd",
+            &"0".repeat(63),
+            &"0".repeat(65),
+            &"g".repeat(64),
+        ] {
+            let err = LoginDb::open_in_memory(Some(EncryptionKey::RawHex(bad.to_string())))
+                .err()
+                .expect("Opening should fail");
+            match err.kind() {
+                ErrorKind::InvalidEncryptionKey => {}
+                e => panic!("Unexpected error for {:?}: {:?}", bad, e),
+            }
+        }
+    }
+
+    #[test]
+    fn test_cipher_config() {
+        let dir = tempdir::TempDir::new("cipher_config").unwrap();
+        let dbpath = dir.path().join("logins.sqlite");
+        let options = ConnectionOptions {
+            cipher: CipherConfig {
+                kdf_iter: 10000,
+                page_size: 4096,
+                hmac_algorithm: HmacAlgorithm::Sha512,
+            },
+            ..ConnectionOptions::default()
+        };
+        let open = |options: &ConnectionOptions| {
+            LoginDb::with_connection_options(
+                Connection::open(&dbpath).unwrap(),
+                Some("testing".into()),
+                None,
+                options,
+            )
+        };
+        let db = open(&options).unwrap();
+        assert_eq!(db.get_cipher_kdf_iter().unwrap(), 10000);
+        let login = db
+            .add(Login::with_defaults_for_testing(
+                "https://www.example.com",
+                "user",
+                "password",
+            ))
+            .unwrap();
+        drop(db);
+
+        assert!(open(&ConnectionOptions::default()).is_err());
+        let db = open(&options).unwrap();
+        assert!(db.get_by_id(&login.guid).unwrap().is_some());
+
+        let db = LoginDb::open_in_memory(Some("testing".into())).unwrap();
+        assert_eq!(db.get_cipher_kdf_iter().unwrap(), 64000);
+        db.set_cipher_kdf_iter(256_000).unwrap();
+        assert_eq!(db.get_cipher_kdf_iter().unwrap(), 256_000);
+    }
+
+    #[test]
+    fn test_cipher_config_everywhere() {
+        let dir = tempdir::TempDir::new("cipher_config_everywhere").unwrap();
+        let dbpath = dir.path().join("logins.sqlite");
+        let other_path = dir.path().join("other.sqlite");
+        let backup_path = dir.path().join("backup.sqlite");
+        let cipher = CipherConfig {
+            kdf_iter: 10000,
+            page_size: 4096,
+            hmac_algorithm: HmacAlgorithm::Sha512,
+        };
+        let open = |path: &Path, key: Option<&str>| {
+            LoginDb::with_connection_options(
+                Connection::open(path).unwrap(),
+                key.map(Into::into),
+                None,
+                &ConnectionOptions {
+                    cipher,
+                    ..ConnectionOptions::default()
+                },
+            )
+        };
+        let db = open(&dbpath, Some("testing")).unwrap();
+        assert_eq!(db.cipher_config(), cipher);
+        db.add(Login::with_defaults_for_testing(
+            "https://www.example.com",
+            "user",
+            "password",
+        ))
+        .unwrap();
+        open(&other_path, Some("other"))
+            .unwrap()
+            .add(Login::with_defaults_for_testing(
+                "https://www.example.org",
+                "user",
+                "password",
+            ))
+            .unwrap();
+        let report = db
+            .merge_db(&other_path, Some("other"), MergeStrategy::KeepAll)
+            .unwrap();
+        assert_eq!(report.inserted, 1);
+        let expected = db.get_all().unwrap();
+
+        db.backup(
+            &backup_path,
+            Some("testing".into()),
+            &BackupOptions::default(),
+            None,
+        )
+        .unwrap();
+        drop(db);
+        std::fs::write(&dbpath, vec![0xff; 4096]).unwrap();
+        LoginDb::restore_from_backup_with_cipher(
+            &backup_path,
+            &dbpath,
+            Some("testing".into()),
+            &cipher,
+        )
+        .unwrap();
+        assert_eq!(
+            open(&dbpath, Some("testing")).unwrap().get_all().unwrap(),
+            expected
+        );
+
+        let salt = LoginDb::open_and_get_salt_with_cipher(&dbpath, "testing", &cipher).unwrap();
+        LoginDb::open_and_migrate_to_plaintext_header_with_cipher(
+            &dbpath, "testing", &salt, &cipher,
+        )
+        .unwrap();
+        // The salt is now only kept outside the database.
+        let db = LoginDb::with_connection_options(
+            Connection::open(&dbpath).unwrap(),
+            Some("testing".into()),
+            Some(&salt),
+            &ConnectionOptions {
+                cipher,
+                ..ConnectionOptions::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(db.get_all().unwrap(), expected);
+        drop(db);
+
+        LoginDb::decrypt_with_cipher(&other_path, "other", &cipher).unwrap();
+        assert_eq!(open(&other_path, None).unwrap().get_all().unwrap().len(), 1);
+        LoginDb::encrypt_with_cipher(&other_path, "other", &cipher).unwrap();
+        assert!(LoginDb::open(&other_path, Some("other".into())).is_err());
+        assert_eq!(
+            open(&other_path, Some("other"))
+                .unwrap()
+                .get_all()
+                .unwrap()
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_rekey_database() {
+        let dir = tempdir::TempDir::new("rekey").unwrap();
+        let dbpath = dir.path().join("logins.sqlite");
+        let db = LoginDb::open(&dbpath, Some("old key".into())).unwrap();
+        let login = db
+            .add(Login::with_defaults_for_testing(
+                "https://www.example.com",
+                "user",
+                "password",
+            ))
+            .unwrap();
+        db.rekey_database("new key").unwrap();
+        drop(db);
+
+        assert!(LoginDb::open(&dbpath, Some("old key".into())).is_err());
+        let db = LoginDb::open(&dbpath, Some("new key".into())).unwrap();
+        assert_eq!(
+            db.get_by_id(&login.guid).unwrap().unwrap().password,
+            "password"
+        );
+
+        db.rekey_raw(&[0xab; 32]).unwrap();
+        drop(db);
+
+        assert!(LoginDb::open(&dbpath, Some("new key".into())).is_err());
+        let raw_key = EncryptionKey::RawHex("ab".repeat(32));
+        let db = LoginDb::open(&dbpath, Some(raw_key)).unwrap();
+        assert_eq!(
+            db.get_by_id(&login.guid).unwrap().unwrap().password,
+            "password"
+        );
+    }
+
+    #[test]
+    fn test_encrypt_and_decrypt() {
+        let dir = tempdir::TempDir::new("encrypt_decrypt").unwrap();
+        let dbpath = dir.path().join("logins.sqlite");
+        let db = LoginDb::open(&dbpath, None).unwrap();
+        let login = db
+            .add(Login::with_defaults_for_testing(
+                "https://www.example.com",
+                "user",
+                "password",
+            ))
+            .unwrap();
+        db.set_last_sync(ServerTimestamp(1234)).unwrap();
+        drop(db);
+
+        let check_contents = |db: LoginDb| {
+            let all = db.get_all().unwrap();
+            assert_eq!(all.len(), 1);
+            assert_eq!(all[0].guid, login.guid);
+            assert_eq!(all[0].password, "password");
+            assert_eq!(db.get_last_sync().unwrap(), Some(ServerTimestamp(1234)));
+        };
+
+        LoginDb::encrypt(&dbpath, "key").unwrap();
+        assert!(LoginDb::open(&dbpath, None).is_err());
+        check_contents(LoginDb::open(&dbpath, Some("key".into())).unwrap());
+
+        assert!(LoginDb::decrypt(&dbpath, "wrong key").is_err());
+        LoginDb::decrypt(&dbpath, "key").unwrap();
+        assert!(LoginDb::open(&dbpath, Some("key".into())).is_err());
+        check_contents(LoginDb::open(&dbpath, None).unwrap());
+
+        LoginDb::encrypt(&dbpath, "other key").unwrap();
+        check_contents(LoginDb::open(&dbpath, Some("other key".into())).unwrap());
+
+        // Journal files left behind by the original are removed, since they
+        // don't belong to the copy.
+        let sidecars: Vec<PathBuf> = ["-journal", "-wal", "-shm"]
+            .iter()
+            .map(|suffix| {
+                let mut path = dbpath.as_os_str().to_owned();
+                path.push(suffix);
+                PathBuf::from(path)
+            })
+            .collect();
+        for path in &sidecars {
+            std::fs::write(path, b"").unwrap();
+        }
+        LoginDb::decrypt(&dbpath, "other key").unwrap();
+        assert!(sidecars.iter().all(|path| !path.exists()));
+        check_contents(LoginDb::open(&dbpath, None).unwrap());
+
+        // No copies should be left behind.
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+        assert!(LoginDb::encrypt(dir.path().join("missing.sqlite"), "key").is_err());
+    }
+
     #[test]
     fn test_open_db_with_wrong_salt() {
         // First we create a database.
@@ -1989,6 +12013,215 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_backup_and_restore() {
+        let dir = tempdir::TempDir::new("backup").unwrap();
+        let dbpath = dir.path().join("logins.sqlite");
+        let backup_path = dir.path().join("backup.sqlite");
+        let db = LoginDb::open(&dbpath, Some("testing".into())).unwrap();
+        for i in 0..100 {
+            db.add(Login::with_defaults_for_testing(
+                &format!("https://www{}.example.com", i),
+                "user",
+                &"password".repeat(20),
+            ))
+            .unwrap();
+        }
+        let expected = db.get_all().unwrap();
+
+        let mut steps = vec![];
+        db.backup(
+            &backup_path,
+            Some("testing".into()),
+            &BackupOptions {
+                pages_per_step: Some(5),
+                pause_between_steps: Some(Duration::from_millis(0)),
+                ..BackupOptions::default()
+            },
+            Some(&mut |p| steps.push(p)),
+        )
+        .unwrap();
+        assert!(steps.len() > 1);
+        let last = steps.last().unwrap();
+        assert_eq!(last.pages_remaining, 0);
+        assert_eq!(
+            i64::from(last.pages_total),
+            db.query_one::<i64>("PRAGMA page_count").unwrap()
+        );
+        drop(db);
+
+        // Corrupt the original.
+        std::fs::write(&dbpath, vec![0xff; 4096]).unwrap();
+        assert!(LoginDb::open(&dbpath, Some("testing".into())).is_err());
+
+        // The backup can't be restored using the wrong key, and the original
+        // is left alone.
+        assert!(LoginDb::restore_from_backup(&backup_path, &dbpath, Some("wrong".into())).is_err());
+        assert_eq!(std::fs::read(&dbpath).unwrap(), vec![0xff; 4096]);
+        LoginDb::restore_from_backup(&backup_path, &dbpath, Some("testing".into())).unwrap();
+        let db = LoginDb::open(&dbpath, Some("testing".into())).unwrap();
+        assert_eq!(db.get_all().unwrap(), expected);
+        assert!(db.verify_integrity().unwrap().ok);
+    }
+
+    #[test]
+    fn test_backup_gives_up_when_locked() {
+        let dir = tempdir::TempDir::new("backup_locked").unwrap();
+        let dbpath = dir.path().join("logins.sqlite");
+        let backup_path = dir.path().join("backup.sqlite");
+        let db = LoginDb::open(&dbpath, None).unwrap();
+        db.add(Login::with_defaults_for_testing(
+            "https://www.example.com",
+            "user",
+            "password",
+        ))
+        .unwrap();
+        db.set_busy_timeout(0).unwrap();
+
+        // Another connection holding an exclusive lock stops the backup from
+        // reading anything.
+        let other = Connection::open(&dbpath).unwrap();
+        other
+            .execute_batch("BEGIN EXCLUSIVE; DELETE FROM loginsL;")
+            .unwrap();
+        let options = BackupOptions {
+            pause_between_steps: Some(Duration::from_millis(0)),
+            max_busy_retries: Some(3),
+            ..BackupOptions::default()
+        };
+        match db
+            .backup(&backup_path, None, &options, None)
+            .unwrap_err()
+            .kind()
+        {
+            ErrorKind::BackupBusy(3) => {}
+            e => panic!("Unexpected error: {:?}", e),
+        }
+
+        other.execute_batch("ROLLBACK").unwrap();
+        db.backup(&backup_path, None, &options, None).unwrap();
+    }
+
+    #[test]
+    fn test_storage_info() {
+        let dir = tempdir::TempDir::new("storage_info").unwrap();
+        let dbpath = dir.path().join("logins.sqlite");
+        let db = LoginDb::open(&dbpath, Some("testing".into())).unwrap();
+        let empty_size = db.get_db_size_bytes().unwrap();
+        assert!(empty_size > 0);
+        assert_eq!(db.get_wal_size_bytes().unwrap(), None);
+
+        for i in 0..100 {
+            db.add(Login::with_defaults_for_testing(
+                &format!("https://www{}.example.com", i),
+                "user",
+                &"password".repeat(20),
+            ))
+            .unwrap();
+        }
+        let info = db.get_storage_info().unwrap();
+        assert!(info.db_size_bytes > empty_size);
+        assert_eq!(
+            info.db_size_bytes,
+            std::fs::metadata(&dbpath).unwrap().len()
+        );
+        assert_eq!(info.wal_size_bytes, None);
+        assert_eq!(info.schema_version, schema::VERSION);
+        assert_eq!(info.record_count, 100);
+        drop(db);
+
+        let db = LoginDb::with_connection_options(
+            Connection::open(&dbpath).unwrap(),
+            Some("testing".into()),
+            None,
+            &ConnectionOptions {
+                journal_mode: Some(JournalMode::Wal),
+                ..ConnectionOptions::default()
+            },
+        )
+        .unwrap();
+        db.add(Login::with_defaults_for_testing(
+            "https://www.example.com",
+            "user",
+            "password",
+        ))
+        .unwrap();
+        assert!(db.get_wal_size_bytes().unwrap().unwrap() > 0);
+    }
+
+    #[test]
+    fn test_vacuum() {
+        let dir = tempdir::TempDir::new("vacuum").unwrap();
+        let dbpath = dir.path().join("logins.sqlite");
+        let mut db = LoginDb::open(&dbpath, Some("testing".into())).unwrap();
+        let file_size = || std::fs::metadata(&dbpath).unwrap().len();
+
+        let tx = db.unchecked_transaction().unwrap();
+        for i in 0..1000 {
+            db.add(Login::with_defaults_for_testing(
+                &format!("https://www{}.example.com", i),
+                "user",
+                &"password".repeat(20),
+            ))
+            .unwrap();
+        }
+        tx.commit().unwrap();
+        db.wipe_local().unwrap();
+        let size_before = file_size();
+
+        db.vacuum().unwrap();
+        assert!(file_size() < size_before);
+        assert_eq!(db.get_all().unwrap().len(), 0);
+
+        db.set_auto_vacuum(AutoVacuumMode::Incremental).unwrap();
+        db.vacuum().unwrap();
+        assert_eq!(
+            db.query_one::<u8>("PRAGMA auto_vacuum").unwrap(),
+            AutoVacuumMode::Incremental as u8
+        );
+
+        db.execute_all(&["BEGIN"]).unwrap();
+        match db.vacuum().unwrap_err().kind() {
+            ErrorKind::TransactionAlreadyOpen => {}
+            e => panic!("Unexpected error {:?}", e),
+        }
+        db.execute_all(&["ROLLBACK"]).unwrap();
+        db.vacuum().unwrap();
+    }
+
+    #[test]
+    fn test_temp_store_mode() {
+        let db = LoginDb::open_in_memory(Some("testing".into())).unwrap();
+        assert_eq!(
+            db.query_one::<u8>("PRAGMA temp_store").unwrap(),
+            TempStoreMode::default() as u8
+        );
+
+        for &mode in &[
+            TempStoreMode::Default,
+            TempStoreMode::File,
+            TempStoreMode::Memory,
+        ] {
+            let db = LoginDb::with_connection_options(
+                Connection::open_in_memory().unwrap(),
+                Some("testing".into()),
+                None,
+                &ConnectionOptions {
+                    temp_store: mode,
+                    ..ConnectionOptions::default()
+                },
+            )
+            .unwrap();
+            assert_eq!(db.query_one::<u8>("PRAGMA temp_store").unwrap(), mode as u8);
+        }
+
+        db.set_temp_store_mode(TempStoreMode::Memory).unwrap();
+        assert_eq!(
+            db.query_one::<u8>("PRAGMA temp_store").unwrap(),
+            TempStoreMode::Memory as u8
+        );
+    }
+
     #[test]
     fn test_ensure_valid_salt() {
         assert!(ensure_valid_salt("bobo").is_err());
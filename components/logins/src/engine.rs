@@ -7,8 +7,8 @@ use crate::login::Login;
 use std::cell::Cell;
 use std::path::Path;
 use sync15::{
-    sync_multiple, telemetry, KeyBundle, MemoryCachedState, StoreSyncAssociation,
-    Sync15StorageClientInit,
+    sync_multiple, telemetry, ConflictResolution, KeyBundle, MemoryCachedState,
+    StoreSyncAssociation, Sync15StorageClientInit,
 };
 
 // This isn't really an engine in the firefox sync15 desktop sense -- it's
@@ -21,7 +21,7 @@ pub struct PasswordEngine {
 
 impl PasswordEngine {
     pub fn new(path: impl AsRef<Path>, encryption_key: Option<&str>) -> Result<Self> {
-        let db = LoginDb::open(path, encryption_key)?;
+        let db = LoginDb::open(path, encryption_key.map(Into::into))?;
         Ok(Self {
             db,
             mem_cached_state: Cell::default(),
@@ -37,7 +37,7 @@ impl PasswordEngine {
     }
 
     pub fn new_in_memory(encryption_key: Option<&str>) -> Result<Self> {
-        let db = LoginDb::open_in_memory(encryption_key)?;
+        let db = LoginDb::open_in_memory(encryption_key.map(Into::into))?;
         Ok(Self {
             db,
             mem_cached_state: Cell::default(),
@@ -97,6 +97,10 @@ impl PasswordEngine {
         self.db.import_multiple(logins)
     }
 
+    pub fn set_conflict_resolution(&self, strategy: ConflictResolution) {
+        self.db.set_conflict_resolution(strategy)
+    }
+
     pub fn disable_mem_security(&self) -> Result<()> {
         self.db.disable_mem_security()
     }
@@ -121,37 +125,8 @@ impl PasswordEngine {
         storage_init: &Sync15StorageClientInit,
         root_sync_key: &KeyBundle,
     ) -> Result<telemetry::SyncTelemetryPing> {
-        // migrate our V1 state - this needn't live for long.
-        self.db.migrate_global_state()?;
-
-        let mut disk_cached_state = self.db.get_global_state()?;
         let mut mem_cached_state = self.mem_cached_state.take();
-        let store = LoginStore::new(&self.db);
-
-        let mut result = sync_multiple(
-            &[&store],
-            &mut disk_cached_state,
-            &mut mem_cached_state,
-            storage_init,
-            root_sync_key,
-            &store.scope,
-            None,
-        );
-        // We always update the state - sync_multiple does the right thing
-        // if it needs to be dropped (ie, they will be None or contain Nones etc)
-        self.db.set_global_state(&disk_cached_state)?;
-
-        // for b/w compat reasons, we do some dances with the result.
-        // XXX - note that this means telemetry isn't going to be reported back
-        // to the app - we need to check with lockwise about whether they really
-        // need these failures to be reported or whether we can loosen this.
-        if let Err(e) = result.result {
-            return Err(e.into());
-        }
-        match result.engine_results.remove("passwords") {
-            None | Some(Ok(())) => Ok(result.telemetry),
-            Some(Err(e)) => Err(e.into()),
-        }
+        sync_login_db(&self.db, &mut mem_cached_state, storage_init, root_sync_key)
     }
 
     pub fn check_valid_with_no_dupes(&self, login: &Login) -> Result<()> {
@@ -159,6 +134,46 @@ impl PasswordEngine {
     }
 }
 
+/// Syncs `db` using `sync_multiple`, as `PasswordEngine::sync` does, for
+/// callers which manage the `LoginDb` and `MemoryCachedState` themselves.
+pub fn sync_login_db(
+    db: &LoginDb,
+    mem_cached_state: &mut MemoryCachedState,
+    storage_init: &Sync15StorageClientInit,
+    root_sync_key: &KeyBundle,
+) -> Result<telemetry::SyncTelemetryPing> {
+    // migrate our V1 state - this needn't live for long.
+    db.migrate_global_state()?;
+
+    let mut disk_cached_state = db.get_global_state()?;
+    let store = LoginStore::new(db);
+
+    let mut result = sync_multiple(
+        &[&store],
+        &mut disk_cached_state,
+        mem_cached_state,
+        storage_init,
+        root_sync_key,
+        &store.scope,
+        None,
+    );
+    // We always update the state - sync_multiple does the right thing
+    // if it needs to be dropped (ie, they will be None or contain Nones etc)
+    db.set_global_state(&disk_cached_state)?;
+
+    // for b/w compat reasons, we do some dances with the result.
+    // XXX - note that this means telemetry isn't going to be reported back
+    // to the app - we need to check with lockwise about whether they really
+    // need these failures to be reported or whether we can loosen this.
+    if let Err(e) = result.result {
+        return Err(e.into());
+    }
+    match result.engine_results.remove("passwords") {
+        None | Some(Ok(())) => Ok(result.telemetry),
+        Some(Err(e)) => Err(e.into()),
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -240,13 +255,11 @@ mod test {
         assert_ge!(b_from_db.time_last_used, start_us);
         assert_eq!(b_from_db.times_used, 1);
 
-        let mut list = engine.list().expect("Grabbing list to work");
-        assert_eq!(list.len(), 2);
+        assert_eq!(engine.list().expect("Grabbing list to work").len(), 2);
 
+        let list = engine.db.get_all_in_order().expect("Grabbing list to work");
         let mut expect = vec![a_from_db, b_from_db.clone()];
-
-        list.sort_by(|a, b| b.guid.cmp(&a.guid));
-        expect.sort_by(|a, b| b.guid.cmp(&a.guid));
+        expect.sort_by(|a, b| a.guid.cmp(&b.guid));
         assert_eq!(list, expect);
 
         engine.delete(&a_id).expect("Successful delete");
@@ -3,6 +3,7 @@
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
 use failure::Fail;
+use std::fmt;
 
 // TODO: this is (IMO) useful and was dropped from `failure`, consider moving it
 // into `error_support`.
@@ -14,8 +15,10 @@ macro_rules! throw {
 
 #[derive(Debug, Fail)]
 pub enum ErrorKind {
+    /// Every problem found with the login, so a UI can point them all out at
+    /// once.
     #[fail(display = "Invalid login: {}", _0)]
-    InvalidLogin(InvalidLogin),
+    InvalidLogin(LoginValidationErrors),
 
     #[fail(
         display = "The `sync_status` column in DB has an illegal value: {}",
@@ -39,6 +42,18 @@ pub enum ErrorKind {
     #[fail(display = "The provided salt is invalid")]
     InvalidSalt,
 
+    #[fail(display = "The provided raw encryption key is invalid")]
+    InvalidEncryptionKey,
+
+    #[fail(display = "Operation not allowed: {}", _0)]
+    InvalidOperation(String),
+
+    #[fail(display = "Unknown conflict resolution strategy: {}", _0)]
+    InvalidConflictResolution(i32),
+
+    #[fail(display = "The provided ORDER BY clause is not allowed: {:?}", _0)]
+    InvalidOrderBy(String),
+
     #[fail(display = "Error synchronizing: {}", _0)]
     SyncAdapterError(#[fail(cause)] sync15::Error),
 
@@ -56,6 +71,30 @@ pub enum ErrorKind {
 
     #[fail(display = "Protobuf decode error: {}", _0)]
     ProtobufDecodeError(#[fail(cause)] prost::DecodeError),
+
+    #[fail(display = "IO error: {}", _0)]
+    IoError(#[fail(cause)] std::io::Error),
+
+    #[fail(display = "The copy of table {:?} is incomplete", _0)]
+    CopyIncomplete(String),
+
+    #[fail(
+        display = "The backup gave up after {} attempts found the database locked",
+        _0
+    )]
+    BackupBusy(u32),
+
+    #[fail(display = "Migration {} can't be reverted", _0)]
+    IrreversibleMigration(u32),
+
+    #[fail(display = "A transaction is already open")]
+    TransactionAlreadyOpen,
+
+    #[fail(display = "Error reading CSV: {}", _0)]
+    CsvError(#[fail(cause)] csv::Error),
+
+    #[fail(display = "The CSV file has no {:?} column", _0)]
+    MissingCsvColumn(String),
 }
 
 error_support::define_error! {
@@ -64,27 +103,66 @@ error_support::define_error! {
         (JsonError, serde_json::Error),
         (UrlParseError, url::ParseError),
         (SqlError, rusqlite::Error),
-        (InvalidLogin, InvalidLogin),
+        (InvalidLogin, LoginValidationErrors),
         (Interrupted, interrupt_support::Interrupted),
         (ProtobufDecodeError, prost::DecodeError),
+        (IoError, std::io::Error),
+        (CsvError, csv::Error),
     }
 }
 
-#[derive(Debug, Fail)]
-pub enum InvalidLogin {
-    // EmptyOrigin error occurs when the login's hostname field is empty.
+#[derive(Debug, Fail, Clone, PartialEq, Eq)]
+pub enum LoginValidationError {
     #[fail(display = "Origin is empty")]
-    EmptyOrigin,
+    EmptyHostname,
     #[fail(display = "Password is empty")]
     EmptyPassword,
     #[fail(display = "Login already exists")]
     DuplicateLogin,
     #[fail(display = "Both `formSubmitUrl` and `httpRealm` are present")]
-    BothTargets,
+    BothHttpRealmAndFormSubmitUrl,
     #[fail(display = "Neither `formSubmitUrl` or `httpRealm` are present")]
-    NoTarget,
+    NoHttpRealmOrFormSubmitUrl,
+    #[fail(display = "Invalid origin: {}", _0)]
+    InvalidHostname(String),
+    #[fail(display = "Invalid `formSubmitUrl`: {}", _0)]
+    InvalidFormSubmitUrl(String),
+    /// The length of the password, in bytes.
+    #[fail(display = "Password is too long ({} bytes)", _0)]
+    PasswordTooLong(usize),
+    /// The length of the username, in bytes.
+    #[fail(display = "Username is too long ({} bytes)", _0)]
+    UsernameTooLong(usize),
     #[fail(display = "Login has illegal field: {}", _0)]
     IllegalFieldValue { field_info: String },
+    /// The named field isn't in the format it needs to be, for example a
+    /// GUID containing spaces.
+    #[fail(display = "`{}` has an invalid format", field)]
+    InvalidFormat { field: String },
+}
+
+/// Every problem found with a login, in the order they were found. Displayed
+/// as each problem's message, separated by "; ".
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LoginValidationErrors(pub Vec<LoginValidationError>);
+
+impl fmt::Display for LoginValidationErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, e) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str("; ")?;
+            }
+            write!(f, "{}", e)?;
+        }
+        Ok(())
+    }
+}
+
+// So that a single problem can be thrown.
+impl From<LoginValidationError> for Error {
+    fn from(e: LoginValidationError) -> Self {
+        ErrorKind::InvalidLogin(LoginValidationErrors(vec![e])).into()
+    }
 }
 
 impl Error {
@@ -97,20 +175,89 @@ impl Error {
             ErrorKind::NoSuchRecord(_) => "NoSuchRecord",
             ErrorKind::NonEmptyTable => "NonEmptyTable",
             ErrorKind::InvalidSalt => "InvalidSalt",
+            ErrorKind::InvalidEncryptionKey => "InvalidEncryptionKey",
+            ErrorKind::InvalidOperation(_) => "InvalidOperation",
+            ErrorKind::InvalidConflictResolution(_) => "InvalidConflictResolution",
+            ErrorKind::InvalidOrderBy(_) => "InvalidOrderBy",
             ErrorKind::SyncAdapterError(_) => "SyncAdapterError",
             ErrorKind::JsonError(_) => "JsonError",
             ErrorKind::UrlParseError(_) => "UrlParseError",
             ErrorKind::SqlError(_) => "SqlError",
             ErrorKind::Interrupted(_) => "Interrupted",
-            ErrorKind::InvalidLogin(desc) => match desc {
-                InvalidLogin::EmptyOrigin => "InvalidLogin::EmptyOrigin",
-                InvalidLogin::EmptyPassword => "InvalidLogin::EmptyPassword",
-                InvalidLogin::DuplicateLogin => "InvalidLogin::DuplicateLogin",
-                InvalidLogin::BothTargets => "InvalidLogin::BothTargets",
-                InvalidLogin::NoTarget => "InvalidLogin::NoTarget",
-                InvalidLogin::IllegalFieldValue { .. } => "InvalidLogin::IllegalFieldValue",
+            // Labelled by the first problem found.
+            ErrorKind::InvalidLogin(errors) => match errors.0.first() {
+                // The labels for these three predate the variants' names.
+                Some(LoginValidationError::EmptyHostname) => "InvalidLogin::EmptyOrigin",
+                Some(LoginValidationError::EmptyPassword) => "InvalidLogin::EmptyPassword",
+                Some(LoginValidationError::DuplicateLogin) => "InvalidLogin::DuplicateLogin",
+                Some(LoginValidationError::BothHttpRealmAndFormSubmitUrl) => {
+                    "InvalidLogin::BothTargets"
+                }
+                Some(LoginValidationError::NoHttpRealmOrFormSubmitUrl) => "InvalidLogin::NoTarget",
+                Some(LoginValidationError::InvalidHostname(_)) => "InvalidLogin::InvalidHostname",
+                Some(LoginValidationError::InvalidFormSubmitUrl(_)) => {
+                    "InvalidLogin::InvalidFormSubmitUrl"
+                }
+                Some(LoginValidationError::PasswordTooLong(_)) => "InvalidLogin::PasswordTooLong",
+                Some(LoginValidationError::UsernameTooLong(_)) => "InvalidLogin::UsernameTooLong",
+                Some(LoginValidationError::IllegalFieldValue { .. }) => {
+                    "InvalidLogin::IllegalFieldValue"
+                }
+                Some(LoginValidationError::InvalidFormat { .. }) => "InvalidLogin::InvalidFormat",
+                None => "InvalidLogin",
             },
             ErrorKind::ProtobufDecodeError(_) => "BufDecodeError",
+            ErrorKind::IoError(_) => "IoError",
+            ErrorKind::CopyIncomplete(_) => "CopyIncomplete",
+            ErrorKind::BackupBusy(_) => "BackupBusy",
+            ErrorKind::IrreversibleMigration(_) => "IrreversibleMigration",
+            ErrorKind::TransactionAlreadyOpen => "TransactionAlreadyOpen",
+            ErrorKind::CsvError(_) => "CsvError",
+            ErrorKind::MissingCsvColumn(_) => "MissingCsvColumn",
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_invalid_login_labels() {
+        // These are reported in telemetry, so shouldn't change.
+        for (error, label) in &[
+            (
+                LoginValidationError::EmptyHostname,
+                "InvalidLogin::EmptyOrigin",
+            ),
+            (
+                LoginValidationError::BothHttpRealmAndFormSubmitUrl,
+                "InvalidLogin::BothTargets",
+            ),
+            (
+                LoginValidationError::NoHttpRealmOrFormSubmitUrl,
+                "InvalidLogin::NoTarget",
+            ),
+        ] {
+            assert_eq!(Error::from(error.clone()).label(), *label);
+        }
+    }
+
+    #[test]
+    fn test_invalid_login_message() {
+        assert_eq!(
+            Error::from(LoginValidationError::EmptyHostname).to_string(),
+            "Invalid login: Origin is empty"
+        );
+        let error: Error = ErrorKind::InvalidLogin(LoginValidationErrors(vec![
+            LoginValidationError::EmptyHostname,
+            LoginValidationError::EmptyPassword,
+            LoginValidationError::PasswordTooLong(5000),
+        ]))
+        .into();
+        assert_eq!(
+            error.to_string(),
+            "Invalid login: Origin is empty; Password is empty; Password is too long (5000 bytes)"
+        );
+    }
+}
@@ -5,7 +5,7 @@
 // This module implement the traits that make the FFI code easier to manage.
 
 use crate::msg_types;
-use crate::{Error, ErrorKind, InvalidLogin};
+use crate::{Error, ErrorKind, LoginValidationError};
 use ffi_support::{implement_into_ffi_by_protobuf, ErrorCode, ExternError};
 use sync15::ErrorKind as Sync15ErrorKind;
 
@@ -72,17 +72,32 @@ fn get_code(err: &Error) -> ErrorCode {
             log::error!("No record exists with id {}", id);
             ErrorCode::new(error_codes::NO_SUCH_RECORD)
         }
-        ErrorKind::InvalidLogin(desc) => {
-            log::error!("Invalid login: {}", desc);
-            ErrorCode::new(match desc {
-                InvalidLogin::EmptyOrigin => error_codes::INVALID_LOGIN_EMPTY_ORIGIN,
-                InvalidLogin::EmptyPassword => error_codes::INVALID_LOGIN_EMPTY_PASSWORD,
-                InvalidLogin::DuplicateLogin => error_codes::INVALID_LOGIN_DUPLICATE_LOGIN,
-                InvalidLogin::BothTargets => error_codes::INVALID_LOGIN_BOTH_TARGETS,
-                InvalidLogin::NoTarget => error_codes::INVALID_LOGIN_NO_TARGET,
-                InvalidLogin::IllegalFieldValue { .. } => {
-                    error_codes::INVALID_LOGIN_ILLEGAL_FIELD_VALUE
+        ErrorKind::InvalidLogin(errors) => {
+            log::error!("Invalid login: {:?}", errors);
+            // The error code can only describe one problem, so use the first.
+            ErrorCode::new(match errors.0.first() {
+                Some(LoginValidationError::EmptyHostname) => {
+                    error_codes::INVALID_LOGIN_EMPTY_ORIGIN
                 }
+                Some(LoginValidationError::EmptyPassword) => {
+                    error_codes::INVALID_LOGIN_EMPTY_PASSWORD
+                }
+                Some(LoginValidationError::DuplicateLogin) => {
+                    error_codes::INVALID_LOGIN_DUPLICATE_LOGIN
+                }
+                Some(LoginValidationError::BothHttpRealmAndFormSubmitUrl) => {
+                    error_codes::INVALID_LOGIN_BOTH_TARGETS
+                }
+                Some(LoginValidationError::NoHttpRealmOrFormSubmitUrl) => {
+                    error_codes::INVALID_LOGIN_NO_TARGET
+                }
+                Some(LoginValidationError::InvalidHostname(_))
+                | Some(LoginValidationError::InvalidFormSubmitUrl(_))
+                | Some(LoginValidationError::PasswordTooLong(_))
+                | Some(LoginValidationError::UsernameTooLong(_))
+                | Some(LoginValidationError::IllegalFieldValue { .. })
+                | Some(LoginValidationError::InvalidFormat { .. })
+                | None => error_codes::INVALID_LOGIN_ILLEGAL_FIELD_VALUE,
             })
         }
         // We can't destructure `err` without bringing in the libsqlite3_sys crate
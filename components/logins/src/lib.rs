@@ -11,6 +11,7 @@ mod login;
 
 mod db;
 mod engine;
+mod migrations;
 pub mod schema;
 mod update_plan;
 mod util;
@@ -18,11 +19,23 @@ mod util;
 mod ffi;
 
 // Mostly exposed for the sync manager.
-pub use crate::db::LoginDb;
 pub use crate::db::LoginStore;
+pub use crate::db::{
+    AuditEntry, AuditOperation, AutoVacuumMode, BackupOptions, BackupProgress,
+    BitwardenUriMatchType, ChangeEvent, ChangeKind, CheckpointResult, CipherConfig,
+    ConnectionOptions, CsvExportFormat, DuplicateStrategy, EncryptionKey, ExportPermission,
+    HmacAlgorithm, ImportError, ImportOptions, ImportReport, IntegrityReport, InvalidStrategy,
+    JournalMode, LoginChangeEvent, LoginDb, LoginDbSavepoint, LoginGroup, LoginSortOrder,
+    LoginStatistics, MergeReport, MergeStrategy, MigrationMetrics, OutgoingConfirmation,
+    PasswordStrengthChecker, ReadTransaction, RepairReport, ScoredLogin, SecurityReport,
+    StorageInfo, SyncPreview, SyncProgress, SyncProgressStage, SyncStats, SyncStatusCounts,
+    TempStoreMode, WriteTransaction, DEFAULT_TOMBSTONE_MAX_AGE_MS,
+};
 pub use crate::engine::*;
 pub use crate::error::*;
 pub use crate::login::*;
+pub use crate::migrations::{Migration, MigrationManager};
+pub use sync15::ConflictResolution;
 
 pub mod msg_types {
     include!("mozilla.appservices.logins.protobuf.rs");
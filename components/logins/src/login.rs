@@ -55,13 +55,14 @@
 //! - `password`:  The saved password, as a string.
 //!
 //!   This field is required, and must not be set to the empty string. It must not contain
-//!   null bytes, but can otherwise be an arbitrary unicode string.
+//!   null bytes or be longer than 4096 bytes as UTF-8 (see [FieldLimits]), but can otherwise be
+//!   an arbitrary unicode string.
 //!
 //! - `username`:  The username associated with this login, if any, as a string.
 //!
 //!   This field is required, but may be set to the empty string if no username is associated
-//!   with the login. It must not contain null bytes, but can otherwise be an arbitrary unicode
-//!   string.
+//!   with the login. It must not contain null bytes or be longer than 4096 bytes as UTF-8 (see
+//!   [FieldLimits]), but can otherwise be an arbitrary unicode string.
 //!
 //! - `httpRealm`:  The challenge string for HTTP Basic authentication, if any.
 //!
@@ -216,6 +217,13 @@
 //!   - test that we don't set this for changes to other fields.
 //!   - test that we correctly merge dupes
 //!
+//! - `creationSource`: Where this login originally came from; see [CreationSource].
+//!
+//!   This is local-only metadata: it is stored in the database but never uploaded to the server.
+//!   Logins added via `add()` without an explicit source are recorded as `UserSaved`, logins
+//!   added via `import_multiple()` are recorded as `Imported`, and logins first seen via sync
+//!   are recorded as `Synced`. Records which predate this field are `Unknown`.
+//!
 //! In order to deal with data from legacy clients in a robust way, it is necessary to be able to build
 //! and manipulate `Login` structs that contain invalid data.  The following methods can be used by callers
 //! to ensure that they're only working with valid records:
@@ -236,7 +244,8 @@ use sync15::ServerTimestamp;
 use sync_guid::Guid;
 use url::Url;
 
-#[derive(Debug, Clone, Hash, PartialEq, Serialize, Deserialize, Default)]
+// Note that `Serialize` is implemented by hand below, to control field order.
+#[derive(Debug, Clone, Hash, PartialEq, Deserialize, Default)]
 #[serde(rename_all = "camelCase")]
 pub struct Login {
     #[serde(rename = "id")]
@@ -247,10 +256,8 @@ pub struct Login {
     // rename_all = "camelCase" by default will do formSubmitUrl, but we can just
     // override this one field.
     #[serde(rename = "formSubmitURL")]
-    #[serde(skip_serializing_if = "Option::is_none")]
     pub form_submit_url: Option<String>,
 
-    #[serde(skip_serializing_if = "Option::is_none")]
     pub http_realm: Option<String>,
 
     #[serde(default)]
@@ -278,6 +285,111 @@ pub struct Login {
 
     #[serde(default)]
     pub times_used: i64,
+
+    // Local-only, so never sent to (or read from) the server.
+    #[serde(skip)]
+    pub creation_source: CreationSource,
+
+    /// The ID of the app (e.g. `com.example.app`) this login is for, if any.
+    /// Like `creation_source`, this isn't part of the sync format, so it's
+    /// local-only.
+    #[serde(skip)]
+    pub associated_app: Option<String>,
+}
+
+/// Where a login originally came from. This is stored in the database as a
+/// discriminant integer (see [CreationSource::discriminant]), plus a nullable
+/// text column holding the `source_name` of `Imported` logins.
+#[derive(Debug, Clone, Hash, PartialEq, Eq)]
+pub enum CreationSource {
+    /// Saved by the user in this application.
+    UserSaved,
+    /// Imported from another browser or password manager.
+    Imported { source_name: String },
+    /// First seen when it was downloaded via sync.
+    Synced,
+    /// Created before we started recording this information.
+    Unknown,
+}
+
+impl Default for CreationSource {
+    #[inline]
+    fn default() -> Self {
+        CreationSource::Unknown
+    }
+}
+
+impl CreationSource {
+    /// The value stored in the `creation_source` column.
+    pub fn discriminant(&self) -> u8 {
+        match self {
+            CreationSource::Unknown => 0,
+            CreationSource::UserSaved => 1,
+            CreationSource::Imported { .. } => 2,
+            CreationSource::Synced => 3,
+        }
+    }
+
+    /// The value stored in the `creation_source_name` column, which is NULL
+    /// for anything but an import with a name.
+    pub fn source_name(&self) -> Option<&str> {
+        match self {
+            CreationSource::Imported { source_name } if !source_name.is_empty() => {
+                Some(source_name)
+            }
+            _ => None,
+        }
+    }
+
+    /// The inverse of `discriminant` and `source_name`. Values we don't
+    /// understand (say, written by a future version) are treated as `Unknown`.
+    pub fn from_parts(discriminant: u8, source_name: Option<String>) -> Self {
+        match discriminant {
+            1 => CreationSource::UserSaved,
+            2 => CreationSource::Imported {
+                source_name: source_name.unwrap_or_default(),
+            },
+            3 => CreationSource::Synced,
+            _ => CreationSource::Unknown,
+        }
+    }
+}
+
+/// Logins are serialized with their fields in a fixed order, so that the JSON
+/// for a record is stable and easy to diff: `id`, `hostname`, `username`,
+/// `password`, `httpRealm`, `formSubmitURL`, `usernameField`, `passwordField`,
+/// `timeCreated`, `timePasswordChanged`, `timeLastUsed` and `timesUsed`.
+/// `httpRealm` and `formSubmitURL` are omitted when they're `None`, and the
+/// local-only `creation_source` is never serialized.
+impl serde::Serialize for Login {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        use serde::ser::SerializeStruct;
+        let num_fields =
+            10 + self.http_realm.is_some() as usize + self.form_submit_url.is_some() as usize;
+        let mut state = serializer.serialize_struct("Login", num_fields)?;
+        state.serialize_field("id", &self.guid)?;
+        state.serialize_field("hostname", &self.hostname)?;
+        state.serialize_field("username", &self.username)?;
+        state.serialize_field("password", &self.password)?;
+        match &self.http_realm {
+            Some(realm) => state.serialize_field("httpRealm", realm)?,
+            None => state.skip_field("httpRealm")?,
+        }
+        match &self.form_submit_url {
+            Some(url) => state.serialize_field("formSubmitURL", url)?,
+            None => state.skip_field("formSubmitURL")?,
+        }
+        state.serialize_field("usernameField", &self.username_field)?;
+        state.serialize_field("passwordField", &self.password_field)?;
+        state.serialize_field("timeCreated", &self.time_created)?;
+        state.serialize_field("timePasswordChanged", &self.time_password_changed)?;
+        state.serialize_field("timeLastUsed", &self.time_last_used)?;
+        state.serialize_field("timesUsed", &self.times_used)?;
+        state.end()
+    }
 }
 
 fn deserialize_timestamp<'de, D>(deserializer: D) -> std::result::Result<i64, D::Error>
@@ -292,25 +404,273 @@ where
     Ok(i64::deserialize(deserializer).unwrap_or_default().max(0))
 }
 
+/// The longest GUID we accept for a login.
+const MAX_GUID_LEN: usize = 32;
+
+/// The default for both of the maximums in `FieldLimits`.
+pub const DEFAULT_MAX_FIELD_LEN: usize = 4096;
+
+/// The longest passwords and usernames, in bytes, that validation accepts.
+/// Nothing else stops SQLite storing megabytes of data in them, which is
+/// either an attack or a sign of corruption.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FieldLimits {
+    pub max_password: usize,
+    pub max_username: usize,
+}
+
+impl FieldLimits {
+    /// No limits at all, for checking logins which are already stored. The
+    /// limits only apply to logins as they're added, updated or imported.
+    pub fn unlimited() -> Self {
+        Self {
+            max_password: usize::MAX,
+            max_username: usize::MAX,
+        }
+    }
+}
+
+impl Default for FieldLimits {
+    fn default() -> Self {
+        Self {
+            max_password: DEFAULT_MAX_FIELD_LEN,
+            max_username: DEFAULT_MAX_FIELD_LEN,
+        }
+    }
+}
+
+/// Returns true if `guid` is usable as the id of a login: either non-empty,
+/// no longer than 32 bytes, and made up only of the Sync GUID character set
+/// (alphanumerics, `-` and `_`), or a UUID in braces, which is what desktop
+/// Firefox uses for the logins it creates.
+pub(crate) fn is_valid_guid(guid: &str) -> bool {
+    let is_sync_guid = !guid.is_empty()
+        && guid.len() <= MAX_GUID_LEN
+        && guid
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_');
+    is_sync_guid || is_braced_uuid(guid)
+}
+
+// `{xxxxxxxx-xxxx-xxxx-xxxx-xxxxxxxxxxxx}`, with hex digits in either case.
+fn is_braced_uuid(guid: &str) -> bool {
+    if !guid.starts_with('{') || !guid.ends_with('}') || guid.len() != 38 {
+        return false;
+    }
+    let groups: Vec<&str> = guid[1..guid.len() - 1].split('-').collect();
+    groups
+        .iter()
+        .map(|g| g.len())
+        .eq([8, 4, 4, 4, 12].iter().cloned())
+        && groups
+            .iter()
+            .all(|g| g.bytes().all(|b| b.is_ascii_hexdigit()))
+}
+
+/// Rewrites `login.hostname`, and `login.form_submit_url` if it's a URL, as
+/// the origins they belong to, so that (for example) `HTTPS://Example.Com/`
+/// and `https://example.com` are stored the same way. This is the same
+/// normalization `Login::fixup` does, without the other fixups. Fails,
+/// reporting every problem with them, if either isn't a valid URL. Empty
+/// values, and the special `.` form URL, are left for `Login::check_valid` to
+/// judge.
+pub fn normalize_login_urls(login: &mut Login) -> Result<()> {
+    let mut errors = vec![];
+    if !login.hostname.is_empty() {
+        if let Some(fixed) = check_hostname(&login.hostname, &mut errors) {
+            login.hostname = fixed;
+        }
+    }
+    if let Some(href) = &login.form_submit_url {
+        if !href.is_empty() && href != "." {
+            if let Some(fixed) = check_form_submit_url(href, &mut errors) {
+                login.form_submit_url = Some(fixed);
+            }
+        }
+    }
+    errors_to_result(errors)
+}
+
+/// Checks `hostname` exactly as `Login::check_valid` would, for callers which
+/// want to validate it before they have a full `Login`.
+pub fn validate_hostname(hostname: &str) -> Result<()> {
+    let mut errors = vec![];
+    if check_hostname(hostname, &mut errors).is_some() {
+        errors.push(LoginValidationError::InvalidHostname(
+            "Origin is not normalized".into(),
+        ));
+    }
+    errors_to_result(errors)
+}
+
+/// Checks `password` exactly as `Login::check_valid` would, for callers which
+/// want to validate it before they have a full `Login`.
+pub fn validate_password(password: &str) -> Result<()> {
+    let mut errors = vec![];
+    check_password(password, &FieldLimits::default(), &mut errors);
+    errors_to_result(errors)
+}
+
+fn errors_to_result(errors: Vec<LoginValidationError>) -> Result<()> {
+    if !errors.is_empty() {
+        throw!(ErrorKind::InvalidLogin(LoginValidationErrors(errors)));
+    }
+    Ok(())
+}
+
+// Adds any problems with `hostname` to `errors`, and returns the normalized
+// version of it if it's valid but not already normalized.
+fn check_hostname(hostname: &str, errors: &mut Vec<LoginValidationError>) -> Option<String> {
+    if hostname.is_empty() {
+        errors.push(LoginValidationError::EmptyHostname);
+        return None;
+    }
+    check_field_value("hostname", hostname, errors);
+    match Login::validate_and_fixup_origin(hostname) {
+        Ok(fixed) => fixed,
+        Err(_) => {
+            errors.push(LoginValidationError::InvalidHostname(
+                "Origin is malformed".into(),
+            ));
+            None
+        }
+    }
+}
+
+// Like `check_hostname`, for a `form_submit_url` other than ".", which
+// `Login::validate_and_fixup` handles itself.
+fn check_form_submit_url(href: &str, errors: &mut Vec<LoginValidationError>) -> Option<String> {
+    // "javascript:" is a special case documented at the top of this file.
+    if href == "javascript:" {
+        return None;
+    }
+    match Login::validate_and_fixup_origin(href) {
+        Ok(fixed) => fixed,
+        Err(_) => {
+            errors.push(LoginValidationError::InvalidFormSubmitUrl(
+                "formActionOrigin is malformed".into(),
+            ));
+            None
+        }
+    }
+}
+
+fn check_password(password: &str, limits: &FieldLimits, errors: &mut Vec<LoginValidationError>) {
+    if password.is_empty() {
+        errors.push(LoginValidationError::EmptyPassword);
+        return;
+    }
+    if password.len() > limits.max_password {
+        errors.push(LoginValidationError::PasswordTooLong(password.len()));
+    }
+    check_field_value("password", password, errors);
+}
+
+// The checks which apply to the value of every field.
+fn check_field_value(field_name: &str, field_value: &str, errors: &mut Vec<LoginValidationError>) {
+    // Nuls are invalid.
+    if field_value.contains('\0') {
+        errors.push(LoginValidationError::IllegalFieldValue {
+            field_info: format!("`{}` contains Nul", field_name),
+        });
+    }
+
+    // Newlines are invalid in Desktop with the exception of the username
+    // and password fields.
+    if field_name != "username"
+        && field_name != "password"
+        && (field_value.contains('\n') || field_value.contains('\r'))
+    {
+        errors.push(LoginValidationError::IllegalFieldValue {
+            field_info: format!("`{}` contains newline", field_name),
+        });
+    }
+}
+
 fn string_or_default(row: &Row<'_>, col: &str) -> Result<String> {
     Ok(row.get::<_, Option<String>>(col)?.unwrap_or_default())
 }
 
 impl Login {
+    /// A form login for `hostname` with an empty GUID, so that `add` will
+    /// generate one. `form_submit_url` is the empty string, which matches
+    /// any form; use `LoginBuilder` to set it or the other optional fields.
+    pub fn new(
+        hostname: impl Into<String>,
+        username: impl Into<String>,
+        password: impl Into<String>,
+    ) -> Login {
+        Login {
+            hostname: hostname.into(),
+            form_submit_url: Some(String::new()),
+            username: username.into(),
+            password: password.into(),
+            ..Login::default()
+        }
+    }
+
+    /// Like `Login::new`, but with the given GUID instead of an empty one.
+    pub fn with_guid(
+        guid: impl Into<String>,
+        hostname: impl Into<String>,
+        username: impl Into<String>,
+        password: impl Into<String>,
+    ) -> Login {
+        Login {
+            guid: Guid::from(guid.into()),
+            ..Login::new(hostname, username, password)
+        }
+    }
+
     #[inline]
     pub fn guid(&self) -> &Guid {
         &self.guid
     }
 
+    /// A valid form login with a random GUID and every other field filled in,
+    /// so tests only need to spell out what they care about.
+    #[cfg(test)]
+    pub fn with_defaults_for_testing(hostname: &str, username: &str, password: &str) -> Login {
+        const TEST_TIME_MS: i64 = 1_500_000_000_000;
+        Login {
+            guid: Guid::random(),
+            hostname: hostname.into(),
+            form_submit_url: Some(hostname.into()),
+            http_realm: None,
+            username: username.into(),
+            password: password.into(),
+            username_field: "username".into(),
+            password_field: "password".into(),
+            time_created: TEST_TIME_MS,
+            time_password_changed: TEST_TIME_MS,
+            time_last_used: TEST_TIME_MS,
+            times_used: 1,
+            creation_source: CreationSource::default(),
+            associated_app: None,
+        }
+    }
+
     #[inline]
     pub fn guid_str(&self) -> &str {
         self.guid.as_str()
     }
 
+    /// Whether the login hasn't been used since it was created. `add` sets
+    /// `time_last_used` to `time_created` for new logins, and each use moves
+    /// it on.
+    pub fn is_never_used(&self) -> bool {
+        self.time_last_used <= self.time_created
+    }
+
     /// Checks whether the Login is valid, without attempting to fix any fields.
     /// Returns an error if invalid data is found, even if it could have been fixed.
     pub fn check_valid(&self) -> Result<()> {
-        self.validate_and_fixup(false)?;
+        self.check_valid_with_limits(&FieldLimits::default())
+    }
+
+    /// Like `check_valid()`, but with `limits` instead of the default ones.
+    pub fn check_valid_with_limits(&self, limits: &FieldLimits) -> Result<()> {
+        self.validate_and_fixup(false, limits)?;
         Ok(())
     }
 
@@ -319,7 +679,12 @@ impl Login {
     /// replace a Login with an owned fixed-up version, preventing them from
     /// using one that is invalid.
     pub fn fixup(self) -> Result<Self> {
-        match self.maybe_fixup()? {
+        self.fixup_with_limits(&FieldLimits::default())
+    }
+
+    /// Like `fixup()`, but with `limits` instead of the default ones.
+    pub fn fixup_with_limits(self, limits: &FieldLimits) -> Result<Self> {
+        match self.maybe_fixup_with_limits(limits)? {
             None => Ok(self),
             Some(login) => Ok(login),
         }
@@ -329,12 +694,19 @@ impl Login {
     /// an Option for the fixed-up version, allowing the caller to make
     /// more choices about what to do next.
     pub fn maybe_fixup(&self) -> Result<Option<Self>> {
-        self.validate_and_fixup(true)
+        self.maybe_fixup_with_limits(&FieldLimits::default())
+    }
+
+    /// Like `maybe_fixup()`, but with `limits` instead of the default ones.
+    pub fn maybe_fixup_with_limits(&self, limits: &FieldLimits) -> Result<Option<Self>> {
+        self.validate_and_fixup(true, limits)
     }
 
     /// Internal helper for validation and fixups of an "origin" stored as
-    /// a string.
-    fn validate_and_fixup_origin(origin: &str) -> Result<Option<String>> {
+    /// a string. Fails if it isn't a URL at all.
+    fn validate_and_fixup_origin(
+        origin: &str,
+    ) -> std::result::Result<Option<String>, url::ParseError> {
         // Check we can parse the origin, then use the normalized version of it.
         match Url::parse(&origin) {
             Ok(mut u) => {
@@ -373,52 +745,70 @@ impl Login {
                 }
                 Ok(None)
             }
-            Err(_) => {
-                // We can't fixup completely invalid records, so always throw.
-                throw!(InvalidLogin::IllegalFieldValue {
-                    field_info: "Origin is Malformed".into()
-                });
-            }
+            // We can't fixup completely invalid records.
+            Err(e) => Err(e),
         }
     }
 
-    /// Internal helper for doing validation and fixups.
-    fn validate_and_fixup(&self, fixup: bool) -> Result<Option<Self>> {
+    /// Internal helper for doing validation and fixups. Every problem which
+    /// can't be (or, if `fixup` is false, isn't) fixed is reported, not just
+    /// the first.
+    fn validate_and_fixup(&self, fixup: bool, limits: &FieldLimits) -> Result<Option<Self>> {
         // XXX TODO: we've definitely got more validation and fixups to add here!
 
         let mut maybe_fixed = None;
+        let mut errors = vec![];
 
-        /// A little helper to magic a Some(self.clone()) into existence when needed.
-        macro_rules! get_fixed_or_throw {
+        /// A little helper to magic a Some(self.clone()) into existence when
+        /// needed, or, if we aren't fixing up, to report the problem.
+        macro_rules! get_fixed_or_report {
             ($err:expr) => {
                 // This is a block expression returning a local variable,
                 // entirely so we can give it an explicit type declaration.
                 {
-                    if !fixup {
-                        throw!($err)
-                    }
-                    log::warn!("Fixing login record {}: {:?}", self.guid, $err);
-                    let fixed: Result<&mut Login> =
-                        Ok(maybe_fixed.get_or_insert_with(|| self.clone()));
+                    let fixed: Option<&mut Login> = if fixup {
+                        log::warn!("Fixing login record {}: {:?}", self.guid, $err);
+                        Some(maybe_fixed.get_or_insert_with(|| self.clone()))
+                    } else {
+                        errors.push($err);
+                        None
+                    };
                     fixed
                 }
             };
         };
 
-        if self.hostname.is_empty() {
-            throw!(InvalidLogin::EmptyOrigin);
+        // An empty GUID is allowed, and means one should be generated on insert.
+        if !self.guid.is_empty() && !is_valid_guid(&self.guid) {
+            errors.push(LoginValidationError::InvalidFormat {
+                field: "guid".into(),
+            });
+        }
+
+        // Check we can parse the origin, then use the normalized version of it.
+        if let Some(fixed) = check_hostname(&self.hostname, &mut errors) {
+            if let Some(login) = get_fixed_or_report!(LoginValidationError::InvalidHostname(
+                "Origin is not normalized".into()
+            )) {
+                login.hostname = fixed;
+            }
         }
 
-        if self.password.is_empty() {
-            throw!(InvalidLogin::EmptyPassword);
+        check_password(&self.password, limits, &mut errors);
+        if self.username.len() > limits.max_username {
+            errors.push(LoginValidationError::UsernameTooLong(self.username.len()));
         }
 
         if self.form_submit_url.is_some() && self.http_realm.is_some() {
-            get_fixed_or_throw!(InvalidLogin::BothTargets)?.http_realm = None;
+            if let Some(login) =
+                get_fixed_or_report!(LoginValidationError::BothHttpRealmAndFormSubmitUrl)
+            {
+                login.http_realm = None;
+            }
         }
 
         if self.form_submit_url.is_none() && self.http_realm.is_none() {
-            throw!(InvalidLogin::NoTarget);
+            errors.push(LoginValidationError::NoHttpRealmOrFormSubmitUrl);
         }
 
         let form_submit_url = self.form_submit_url.clone().unwrap_or_default();
@@ -429,66 +819,47 @@ impl Login {
             .clone()
             .unwrap_or_default();
 
+        // `hostname` and `password` were checked above.
         let field_data = [
             ("formSubmitUrl", &form_submit_url),
             ("httpRealm", &http_realm),
-            ("hostname", &self.hostname),
             ("usernameField", &self.username_field),
             ("passwordField", &self.password_field),
             ("username", &self.username),
-            ("password", &self.password),
         ];
 
         for (field_name, field_value) in &field_data {
-            // Nuls are invalid.
-            if field_value.contains('\0') {
-                throw!(InvalidLogin::IllegalFieldValue {
-                    field_info: format!("`{}` contains Nul", field_name)
-                });
-            }
-
-            // Newlines are invalid in Desktop with the exception of the username
-            // and password fields.
-            if field_name != &"username"
-                && field_name != &"password"
-                && (field_value.contains('\n') || field_value.contains('\r'))
-            {
-                throw!(InvalidLogin::IllegalFieldValue {
-                    field_info: format!("`{}` contains newline", field_name)
-                });
-            }
+            check_field_value(field_name, field_value, &mut errors);
         }
 
         // Desktop doesn't like fields with the below patterns
         if self.username_field == "." {
-            throw!(InvalidLogin::IllegalFieldValue {
-                field_info: "`usernameField` is a period".into()
+            errors.push(LoginValidationError::IllegalFieldValue {
+                field_info: "`usernameField` is a period".into(),
             });
         }
 
-        // Check we can parse the origin, then use the normalized version of it.
-        if let Some(fixed) = Login::validate_and_fixup_origin(&self.hostname)? {
-            get_fixed_or_throw!(InvalidLogin::IllegalFieldValue {
-                field_info: "Origin is not normalized".into()
-            })?
-            .hostname = fixed;
-        }
-
-        match &maybe_fixed.as_ref().unwrap_or(self).form_submit_url {
+        match maybe_fixed.as_ref().unwrap_or(self).form_submit_url.clone() {
             None => {
                 if !self.username_field.is_empty() {
-                    get_fixed_or_throw!(InvalidLogin::IllegalFieldValue {
-                        field_info: "usernameField must be empty when formSubmitURL is null".into()
-                    })?
-                    .username_field
-                    .clear();
+                    if let Some(login) =
+                        get_fixed_or_report!(LoginValidationError::IllegalFieldValue {
+                            field_info: "usernameField must be empty when formSubmitURL is null"
+                                .into()
+                        })
+                    {
+                        login.username_field.clear();
+                    }
                 }
                 if !self.password_field.is_empty() {
-                    get_fixed_or_throw!(InvalidLogin::IllegalFieldValue {
-                        field_info: "passwordField must be empty when formSubmitURL is null".into()
-                    })?
-                    .password_field
-                    .clear();
+                    if let Some(login) =
+                        get_fixed_or_report!(LoginValidationError::IllegalFieldValue {
+                            field_info: "passwordField must be empty when formSubmitURL is null"
+                                .into()
+                        })
+                    {
+                        login.password_field.clear();
+                    }
                 }
             }
             Some(href) => {
@@ -501,22 +872,36 @@ impl Login {
                             .get_or_insert_with(|| self.clone())
                             .form_submit_url = Some("".into());
                     }
-                } else if href != "javascript:" {
-                    if let Some(fixed) = Login::validate_and_fixup_origin(&href)? {
-                        get_fixed_or_throw!(InvalidLogin::IllegalFieldValue {
-                            field_info: "formActionOrigin is not normalized".into()
-                        })?
-                        .form_submit_url = Some(fixed);
+                } else if let Some(fixed) = check_form_submit_url(&href, &mut errors) {
+                    if let Some(login) =
+                        get_fixed_or_report!(LoginValidationError::InvalidFormSubmitUrl(
+                            "formActionOrigin is not normalized".into()
+                        ))
+                    {
+                        login.form_submit_url = Some(fixed);
                     }
                 }
             }
         }
 
+        errors_to_result(errors)?;
         Ok(maybe_fixed)
     }
 
     pub(crate) fn from_row(row: &Row<'_>) -> Result<Login> {
-        let login = Login {
+        let login = Login::from_row_unfixed(row)?;
+        // For now, we want to apply fixups but still return the record if
+        // there is unfixably invalid data in the db.
+        Ok(login
+            .maybe_fixup_with_limits(&FieldLimits::unlimited())
+            .unwrap_or(None)
+            .unwrap_or(login))
+    }
+
+    /// Like `from_row`, but returns the record exactly as it's stored, for
+    /// callers which need to know whether it's valid.
+    pub(crate) fn from_row_unfixed(row: &Row<'_>) -> Result<Login> {
+        Ok(Login {
             guid: row.get("guid")?,
             password: row.get("password")?,
             username: string_or_default(row, "username")?,
@@ -537,10 +922,13 @@ impl Login {
 
             time_password_changed: row.get("timePasswordChanged")?,
             times_used: row.get("timesUsed")?,
-        };
-        // For now, we want to apply fixups but still return the record if
-        // there is unfixably invalid data in the db.
-        Ok(login.maybe_fixup().unwrap_or(None).unwrap_or(login))
+
+            creation_source: CreationSource::from_parts(
+                row.get("creation_source")?,
+                row.get("creation_source_name")?,
+            ),
+            associated_app: row.get("associated_app")?,
+        })
     }
 }
 
@@ -578,12 +966,61 @@ impl From<PasswordInfo> for Login {
             time_created: info.time_created,
             time_last_used: info.time_last_used,
             time_password_changed: info.time_password_changed,
+            creation_source: CreationSource::default(),
+            associated_app: None,
         }
     }
 }
 
+/// The parts of a `Login` needed to display it in a list, without the
+/// password, so callers which don't need it never have it in memory.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LockedLogin {
+    pub id: String,
+    pub hostname: String,
+    pub username: String,
+    pub username_field: String,
+    pub password_field: String,
+    pub http_realm: Option<String>,
+    pub form_submit_url: Option<String>,
+    pub time_last_used: i64,
+    pub times_used: u32,
+}
+
+impl LockedLogin {
+    /// The columns `from_row` expects. Notably, this doesn't include `password`.
+    pub(crate) const COLUMNS: &'static str = "
+        guid,
+        hostname,
+        username,
+        usernameField,
+        passwordField,
+        httpRealm,
+        formSubmitURL,
+        timeLastUsed,
+        timesUsed
+    ";
+
+    pub(crate) fn from_row(row: &Row<'_>) -> Result<LockedLogin> {
+        let times_used = row.get::<_, i64>("timesUsed")?;
+        Ok(LockedLogin {
+            id: row.get("guid")?,
+            hostname: row.get("hostname")?,
+            username: string_or_default(row, "username")?,
+            username_field: string_or_default(row, "usernameField")?,
+            password_field: string_or_default(row, "passwordField")?,
+            http_realm: row.get("httpRealm")?,
+            form_submit_url: row.get("formSubmitURL")?,
+            time_last_used: row
+                .get::<_, Option<i64>>("timeLastUsed")?
+                .unwrap_or_default(),
+            times_used: times_used.max(0).min(i64::from(u32::MAX)) as u32,
+        })
+    }
+}
+
 #[derive(Clone, Debug)]
-pub(crate) struct MirrorLogin {
+pub struct MirrorLogin {
     pub login: Login,
     pub is_overridden: bool,
     pub server_modified: ServerTimestamp,
@@ -607,7 +1044,7 @@ impl MirrorLogin {
 // This doesn't really belong here.
 #[derive(Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
 #[repr(u8)]
-pub(crate) enum SyncStatus {
+pub enum SyncStatus {
     Synced = 0,
     Changed = 1,
     New = 2,
@@ -626,7 +1063,7 @@ impl SyncStatus {
 }
 
 #[derive(Clone, Debug)]
-pub(crate) struct LocalLogin {
+pub struct LocalLogin {
     pub login: Login,
     pub sync_status: SyncStatus,
     pub is_deleted: bool,
@@ -722,7 +1159,8 @@ impl SyncLoginData {
         let login: Option<Login> = if payload.is_tombstone() {
             None
         } else {
-            let record: Login = payload.into_record()?;
+            let mut record: Login = payload.into_record()?;
+            record.creation_source = CreationSource::Synced;
             // If we can fixup incoming records from sync, do so.
             // But if we can't then keep the invalid data.
             record.maybe_fixup().unwrap_or(None).or(Some(record))
@@ -771,155 +1209,259 @@ macro_rules! impl_login_setter {
 impl_login_setter!(set_local, local, LocalLogin);
 impl_login_setter!(set_mirror, mirror, MirrorLogin);
 
-#[derive(Debug, Default, Clone)]
-pub(crate) struct LoginDelta {
-    // "non-commutative" fields
-    pub hostname: Option<String>,
-    pub password: Option<String>,
-    pub username: Option<String>,
-    pub http_realm: Option<String>,
-    pub form_submit_url: Option<String>,
-
-    pub time_created: Option<i64>,
-    pub time_last_used: Option<i64>,
-    pub time_password_changed: Option<i64>,
-
-    // "non-conflicting" fields (which are the same)
-    pub password_field: Option<String>,
-    pub username_field: Option<String>,
+/// The result of `three_way_merge`.
+#[derive(Debug, Clone, PartialEq)]
+#[allow(clippy::large_enum_variant)] // These are short-lived, so boxing isn't worth it.
+pub enum MergeResult {
+    /// Every field merged cleanly.
+    Merged(Login),
+    /// Both sides changed at least one text field to different values. Each
+    /// login is the full merge, taking the local or remote value
+    /// (respectively) for those fields.
+    Conflict { local: Login, remote: Login },
+}
 
-    // Commutative field
-    pub times_used: i64,
+impl MergeResult {
+    /// Returns the merged login, preferring the remote values for any
+    /// conflicting fields.
+    pub fn into_login(self) -> Login {
+        match self {
+            MergeResult::Merged(login) => login,
+            MergeResult::Conflict { remote, .. } => remote,
+        }
+    }
 }
 
-macro_rules! merge_field {
-    ($merged:ident, $b:ident, $prefer_b:expr, $field:ident) => {
-        if let Some($field) = $b.$field.take() {
-            if $merged.$field.is_some() {
+macro_rules! merge_text_fields {
+    ($local:ident, $base:ident, $remote:ident => $prefer_local:ident, $prefer_remote:ident, $conflict:ident; $($field:ident),*) => {
+        $(
+            let local_changed = $local.$field != $base.$field;
+            let remote_changed = $remote.$field != $base.$field;
+            if local_changed && remote_changed && $local.$field != $remote.$field {
                 log::warn!("Collision merging login field {}", stringify!($field));
-                if $prefer_b {
-                    $merged.$field = Some($field);
-                }
-            } else {
-                $merged.$field = Some($field);
+                $conflict = true;
+                $prefer_local.$field = $local.$field.clone();
+                $prefer_remote.$field = $remote.$field.clone();
+            } else if local_changed {
+                $prefer_local.$field = $local.$field.clone();
+                $prefer_remote.$field = $local.$field.clone();
+            } else if remote_changed {
+                $prefer_local.$field = $remote.$field.clone();
+                $prefer_remote.$field = $remote.$field.clone();
             }
-        }
+        )*
     };
 }
 
-impl LoginDelta {
-    #[allow(clippy::cognitive_complexity)] // Looks like clippy considers this after macro-expansion...
-    pub fn merge(self, mut b: LoginDelta, b_is_newer: bool) -> LoginDelta {
-        let mut merged = self;
-        merge_field!(merged, b, b_is_newer, hostname);
-        merge_field!(merged, b, b_is_newer, password);
-        merge_field!(merged, b, b_is_newer, username);
-        merge_field!(merged, b, b_is_newer, http_realm);
-        merge_field!(merged, b, b_is_newer, form_submit_url);
-
-        merge_field!(merged, b, b_is_newer, time_created);
-        merge_field!(merged, b, b_is_newer, time_last_used);
-        merge_field!(merged, b, b_is_newer, time_password_changed);
-
-        merge_field!(merged, b, b_is_newer, password_field);
-        merge_field!(merged, b, b_is_newer, username_field);
-
-        // commutative fields
-        merged.times_used += b.times_used;
-
-        merged
+// We discard zero (and negative numbers) for timestamps so that a record that
+// doesn't contain this information (these are `#[serde(default)]`) doesn't
+// skew our records.
+//
+// Arguably, we should also also ignore values later than our `time_created`,
+// or earlier than our `time_last_used` or `time_password_changed`. Doing this
+// properly would probably require a scheme analogous to Desktop's
+// weak-reupload system, so I'm punting on it for now.
+fn merge_timestamp(local: i64, base: i64, remote: i64) -> i64 {
+    let local_changed = local > 0 && local != base;
+    let remote_changed = remote > 0 && remote != base;
+    match (local_changed, remote_changed) {
+        (true, true) => local.max(remote),
+        (true, false) => local,
+        (false, true) => remote,
+        (false, false) => base,
     }
 }
 
-macro_rules! apply_field {
-    ($login:ident, $delta:ident, $field:ident) => {
-        if let Some($field) = $delta.$field.take() {
-            $login.$field = $field.into();
+/// Merges the changes `local` and `remote` each made to `base`, field by
+/// field. A field changed on only one side takes that side's value. If both
+/// changed a timestamp, the later one wins, and `times_used` is commutative,
+/// so both sides' uses are added. If both changed a text field to different
+/// values, the result is a `MergeResult::Conflict`, which `into_login`
+/// resolves in favor of `remote`.
+///
+/// Fields which aren't synced, like `associated_app`, come from `base`.
+pub fn three_way_merge(local: &Login, base: &Login, remote: &Login) -> MergeResult {
+    let mut prefer_local = base.clone();
+    let mut prefer_remote = base.clone();
+    let mut conflict = false;
+    merge_text_fields!(
+        local, base, remote => prefer_local, prefer_remote, conflict;
+        hostname, username, password, http_realm, form_submit_url, username_field, password_field
+    );
+
+    let time_created = merge_timestamp(local.time_created, base.time_created, remote.time_created);
+    let time_last_used = merge_timestamp(
+        local.time_last_used,
+        base.time_last_used,
+        remote.time_last_used,
+    );
+    let time_password_changed = merge_timestamp(
+        local.time_password_changed,
+        base.time_password_changed,
+        remote.time_password_changed,
+    );
+    let uses = |login: &Login| {
+        if login.times_used > 0 {
+            login.times_used - base.times_used
+        } else {
+            0
         }
     };
+    let times_used = base.times_used + uses(local) + uses(remote);
+    for merged in &mut [&mut prefer_local, &mut prefer_remote] {
+        merged.time_created = time_created;
+        merged.time_last_used = time_last_used;
+        merged.time_password_changed = time_password_changed;
+        merged.times_used = times_used;
+    }
+
+    if conflict {
+        MergeResult::Conflict {
+            local: prefer_local,
+            remote: prefer_remote,
+        }
+    } else {
+        MergeResult::Merged(prefer_remote)
+    }
 }
 
-impl Login {
-    pub(crate) fn apply_delta(&mut self, mut delta: LoginDelta) {
-        apply_field!(self, delta, hostname);
+/// Builds a `Login` one field at a time, starting from `Login::new`, and
+/// checks it with `check_valid` at the end.
+#[derive(Debug, Clone)]
+pub struct LoginBuilder {
+    login: Login,
+}
 
-        apply_field!(self, delta, password);
-        apply_field!(self, delta, username);
+impl LoginBuilder {
+    pub fn new(
+        hostname: impl Into<String>,
+        username: impl Into<String>,
+        password: impl Into<String>,
+    ) -> Self {
+        Self {
+            login: Login::new(hostname, username, password),
+        }
+    }
 
-        apply_field!(self, delta, time_created);
-        apply_field!(self, delta, time_last_used);
-        apply_field!(self, delta, time_password_changed);
+    pub fn guid(mut self, guid: impl Into<String>) -> Self {
+        self.login.guid = Guid::from(guid.into());
+        self
+    }
 
-        apply_field!(self, delta, password_field);
-        apply_field!(self, delta, username_field);
+    /// Makes this a form login. Clears `http_realm`.
+    pub fn form_submit_url(mut self, form_submit_url: impl Into<String>) -> Self {
+        self.login.form_submit_url = Some(form_submit_url.into());
+        self.login.http_realm = None;
+        self
+    }
 
-        // Use Some("") to indicate that it should be changed to be None (hacky...)
-        if let Some(realm) = delta.http_realm.take() {
-            self.http_realm = if realm.is_empty() { None } else { Some(realm) };
-        }
+    /// Makes this an HTTP auth login. Clears `form_submit_url`.
+    pub fn http_realm(mut self, http_realm: impl Into<String>) -> Self {
+        self.login.http_realm = Some(http_realm.into());
+        self.login.form_submit_url = None;
+        self
+    }
 
-        if let Some(url) = delta.form_submit_url.take() {
-            self.form_submit_url = if url.is_empty() { None } else { Some(url) };
-        }
+    pub fn username_field(mut self, username_field: impl Into<String>) -> Self {
+        self.login.username_field = username_field.into();
+        self
+    }
 
-        self.times_used += delta.times_used;
+    pub fn password_field(mut self, password_field: impl Into<String>) -> Self {
+        self.login.password_field = password_field.into();
+        self
     }
 
-    pub(crate) fn delta(&self, older: &Login) -> LoginDelta {
-        let mut delta = LoginDelta::default();
+    pub fn time_created(mut self, time_created: i64) -> Self {
+        self.login.time_created = time_created;
+        self
+    }
 
-        if self.form_submit_url != older.form_submit_url {
-            delta.form_submit_url = Some(self.form_submit_url.clone().unwrap_or_default());
-        }
+    pub fn time_password_changed(mut self, time_password_changed: i64) -> Self {
+        self.login.time_password_changed = time_password_changed;
+        self
+    }
 
-        if self.http_realm != older.http_realm {
-            delta.http_realm = Some(self.http_realm.clone().unwrap_or_default());
-        }
+    pub fn time_last_used(mut self, time_last_used: i64) -> Self {
+        self.login.time_last_used = time_last_used;
+        self
+    }
 
-        if self.hostname != older.hostname {
-            delta.hostname = Some(self.hostname.clone());
-        }
-        if self.username != older.username {
-            delta.username = Some(self.username.clone());
-        }
-        if self.password != older.password {
-            delta.password = Some(self.password.clone());
-        }
-        if self.password_field != older.password_field {
-            delta.password_field = Some(self.password_field.clone());
-        }
-        if self.username_field != older.username_field {
-            delta.username_field = Some(self.username_field.clone());
-        }
+    pub fn times_used(mut self, times_used: i64) -> Self {
+        self.login.times_used = times_used;
+        self
+    }
+
+    pub fn creation_source(mut self, creation_source: CreationSource) -> Self {
+        self.login.creation_source = creation_source;
+        self
+    }
+
+    pub fn associated_app(mut self, associated_app: impl Into<String>) -> Self {
+        self.login.associated_app = Some(associated_app.into());
+        self
+    }
 
-        // We discard zero (and negative numbers) for timestamps so that a
-        // record that doesn't contain this information (these are
-        // `#[serde(default)]`) doesn't skew our records.
-        //
-        // Arguably, we should also also ignore values later than our
-        // `time_created`, or earlier than our `time_last_used` or
-        // `time_password_changed`. Doing this properly would probably require
-        // a scheme analogous to Desktop's weak-reupload system, so I'm punting
-        // on it for now.
-        if self.time_created > 0 && self.time_created != older.time_created {
-            delta.time_created = Some(self.time_created);
+    /// Returns the login, or an error if it isn't valid.
+    pub fn build(self) -> Result<Login> {
+        self.login.check_valid()?;
+        Ok(self.login)
+    }
+}
+
+impl Login {
+    /// Returns which of the user-visible fields differ between `a` and `b`,
+    /// with both values. The timestamps and usage counts are ignored.
+    pub fn diff(a: &Login, b: &Login) -> LoginDiff {
+        fn field(a: &str, b: &str) -> Option<(String, String)> {
+            if a == b {
+                None
+            } else {
+                Some((a.to_owned(), b.to_owned()))
+            }
         }
-        if self.time_last_used > 0 && self.time_last_used != older.time_last_used {
-            delta.time_last_used = Some(self.time_last_used);
+        fn optional_field(a: &Option<String>, b: &Option<String>) -> Option<(String, String)> {
+            if a == b {
+                None
+            } else {
+                Some((a.clone().unwrap_or_default(), b.clone().unwrap_or_default()))
+            }
         }
-        if self.time_password_changed > 0
-            && self.time_password_changed != older.time_password_changed
-        {
-            delta.time_password_changed = Some(self.time_password_changed);
+        LoginDiff {
+            hostname: field(&a.hostname, &b.hostname),
+            username: field(&a.username, &b.username),
+            password: field(&a.password, &b.password),
+            http_realm: optional_field(&a.http_realm, &b.http_realm),
+            form_submit_url: optional_field(&a.form_submit_url, &b.form_submit_url),
+            username_field: field(&a.username_field, &b.username_field),
+            password_field: field(&a.password_field, &b.password_field),
         }
+    }
+}
 
-        if self.times_used > 0 && self.times_used != older.times_used {
-            delta.times_used = self.times_used - older.times_used;
-        }
+/// The user-visible fields which differ between two logins, as returned by
+/// `Login::diff`. Each field is `None` if it's the same in both, or the old
+/// and new values if it changed. `http_realm` and `form_submit_url` use an
+/// empty string for `None`, so a change from `Some("")` to `None` isn't
+/// distinguishable from the values alone.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct LoginDiff {
+    pub hostname: Option<(String, String)>,
+    pub username: Option<(String, String)>,
+    pub password: Option<(String, String)>,
+    pub http_realm: Option<(String, String)>,
+    pub form_submit_url: Option<(String, String)>,
+    pub username_field: Option<(String, String)>,
+    pub password_field: Option<(String, String)>,
+}
 
-        delta
+impl LoginDiff {
+    /// Returns true if none of the fields changed.
+    pub fn is_empty(&self) -> bool {
+        *self == LoginDiff::default()
     }
 }
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -1023,12 +1565,97 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_serialize_field_order() {
+        let login = Login {
+            guid: "dummy_000001".into(),
+            hostname: "https://www.example.com".into(),
+            form_submit_url: Some("https://www.example.com/login".into()),
+            username: "test".into(),
+            password: "hunter2".into(),
+            username_field: "user".into(),
+            password_field: "pass".into(),
+            time_created: 1000,
+            time_password_changed: 2000,
+            time_last_used: 3000,
+            times_used: 4,
+            creation_source: CreationSource::UserSaved,
+            ..Login::default()
+        };
+        let json = serde_json::to_string(&login).unwrap();
+        assert_eq!(json, serde_json::to_string(&login.clone()).unwrap());
+
+        let expected_order = [
+            "id",
+            "hostname",
+            "username",
+            "password",
+            "formSubmitURL",
+            "usernameField",
+            "passwordField",
+            "timeCreated",
+            "timePasswordChanged",
+            "timeLastUsed",
+            "timesUsed",
+        ];
+        // `serde_json::Value` sorts its keys, so this only checks which keys
+        // are present...
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        let mut keys = value.as_object().unwrap().keys().collect::<Vec<_>>();
+        let mut expected_keys = expected_order.to_vec();
+        keys.sort();
+        expected_keys.sort();
+        assert_eq!(keys, expected_keys);
+        // ... and we check the order using the raw string.
+        let positions = expected_order
+            .iter()
+            .map(|key| json.find(&format!("\"{}\":", key)).unwrap())
+            .collect::<Vec<_>>();
+        let mut sorted_positions = positions.clone();
+        sorted_positions.sort();
+        assert_eq!(positions, sorted_positions);
+
+        // It still round-trips, apart from the local-only creation source.
+        let round_tripped: Login = serde_json::from_str(&json).unwrap();
+        assert_eq!(
+            round_tripped,
+            Login {
+                creation_source: CreationSource::Unknown,
+                ..login
+            }
+        );
+    }
+
+    #[test]
+    fn test_creation_source_round_trip() {
+        for source in &[
+            CreationSource::UserSaved,
+            CreationSource::Imported {
+                source_name: "Fennec".into(),
+            },
+            CreationSource::Imported {
+                source_name: String::new(),
+            },
+            CreationSource::Synced,
+            CreationSource::Unknown,
+        ] {
+            let name = source.source_name().map(str::to_owned);
+            assert_eq!(
+                &CreationSource::from_parts(source.discriminant(), name),
+                source
+            );
+        }
+        assert_eq!(
+            CreationSource::from_parts(42, None),
+            CreationSource::Unknown
+        );
+    }
+
     #[test]
     fn test_check_valid() {
         struct TestCase {
             login: Login,
-            should_err: bool,
-            expected_err: &'static str,
+            expected_errs: Vec<LoginValidationError>,
         }
 
         let valid_login = Login {
@@ -1191,123 +1818,455 @@ mod tests {
             ..Login::default()
         };
 
+        let login_with_space_in_guid = Login {
+            guid: "aaaa aaaaaaa".into(),
+            ..valid_login.clone()
+        };
+
+        let login_with_long_guid = Login {
+            guid: "a".repeat(33).into(),
+            ..valid_login.clone()
+        };
+
+        let login_with_base64url_guid = Login {
+            guid: "Az09-_Az09-_".into(),
+            ..valid_login.clone()
+        };
+
+        let login_with_desktop_guid = Login {
+            guid: "{5d7f3a02-0b5e-4a8f-9c1d-2E4F6A8B0C1D}".into(),
+            ..valid_login.clone()
+        };
+
+        let login_with_bad_braced_guid = Login {
+            guid: "{5d7f3a02-0b5e-4a8f-9c1d-2e4f6a8b0c1}".into(),
+            ..valid_login.clone()
+        };
+
+        let login_with_long_password = Login {
+            password: "a".repeat(DEFAULT_MAX_FIELD_LEN + 1),
+            ..valid_login.clone()
+        };
+
+        let login_with_long_username = Login {
+            username: "a".repeat(DEFAULT_MAX_FIELD_LEN + 1),
+            ..valid_login.clone()
+        };
+
+        let login_with_malformed_form_submit_url = Login {
+            form_submit_url: Some("not a url".into()),
+            hostname: "https://www.example.com".into(),
+            username: "test".into(),
+            password: "test".into(),
+            ..Login::default()
+        };
+
+        let login_with_unnormalized_form_submit_url = Login {
+            form_submit_url: Some("https://www.example.com/login".into()),
+            hostname: "https://www.example.com".into(),
+            username: "test".into(),
+            password: "test".into(),
+            ..Login::default()
+        };
+
+        let login_with_many_problems = Login {
+            hostname: "https://www.example.com/".into(),
+            password: "".into(),
+            username_field: ".".into(),
+            ..Login::default()
+        };
+
         let test_cases = [
             TestCase {
                 login: valid_login,
-                should_err: false,
-                expected_err: "",
+                expected_errs: vec![],
+            },
+            TestCase {
+                login: login_with_space_in_guid,
+                expected_errs: vec![LoginValidationError::InvalidFormat {
+                    field: "guid".into(),
+                }],
+            },
+            TestCase {
+                login: login_with_long_guid,
+                expected_errs: vec![LoginValidationError::InvalidFormat {
+                    field: "guid".into(),
+                }],
+            },
+            TestCase {
+                login: login_with_base64url_guid,
+                expected_errs: vec![],
+            },
+            TestCase {
+                login: login_with_desktop_guid,
+                expected_errs: vec![],
+            },
+            TestCase {
+                login: login_with_bad_braced_guid,
+                expected_errs: vec![LoginValidationError::InvalidFormat {
+                    field: "guid".into(),
+                }],
             },
             TestCase {
                 login: login_with_empty_hostname,
-                should_err: true,
-                expected_err: "Invalid login: Origin is empty",
+                expected_errs: vec![LoginValidationError::EmptyHostname],
             },
             TestCase {
                 login: login_with_empty_password,
-                should_err: true,
-                expected_err: "Invalid login: Password is empty",
+                expected_errs: vec![LoginValidationError::EmptyPassword],
             },
             TestCase {
                 login: login_with_form_submit_and_http_realm,
-                should_err: true,
-                expected_err: "Invalid login: Both `formSubmitUrl` and `httpRealm` are present",
+                expected_errs: vec![LoginValidationError::BothHttpRealmAndFormSubmitUrl],
             },
             TestCase {
                 login: login_without_form_submit_or_http_realm,
-                should_err: true,
-                expected_err: "Invalid login: Neither `formSubmitUrl` or `httpRealm` are present",
+                expected_errs: vec![LoginValidationError::NoHttpRealmOrFormSubmitUrl],
             },
             TestCase {
                 login: login_with_null_http_realm,
-                should_err: true,
-                expected_err: "Invalid login: Login has illegal field: `httpRealm` contains Nul",
+                expected_errs: vec![LoginValidationError::IllegalFieldValue {
+                    field_info: "`httpRealm` contains Nul".into(),
+                }],
             },
             TestCase {
                 login: login_with_null_username,
-                should_err: true,
-                expected_err: "Invalid login: Login has illegal field: `username` contains Nul",
+                expected_errs: vec![LoginValidationError::IllegalFieldValue {
+                    field_info: "`username` contains Nul".into(),
+                }],
             },
             TestCase {
                 login: login_with_null_password,
-                should_err: true,
-                expected_err: "Invalid login: Login has illegal field: `password` contains Nul",
+                expected_errs: vec![LoginValidationError::IllegalFieldValue {
+                    field_info: "`password` contains Nul".into(),
+                }],
             },
             TestCase {
                 login: login_with_newline_hostname,
-                should_err: true,
-                expected_err: "Invalid login: Login has illegal field: `hostname` contains newline",
+                expected_errs: vec![
+                    LoginValidationError::IllegalFieldValue {
+                        field_info: "`hostname` contains newline".into(),
+                    },
+                    LoginValidationError::InvalidHostname("Origin is not normalized".into()),
+                ],
             },
             TestCase {
                 login: login_with_newline_realm,
-                should_err: true,
-                expected_err:
-                    "Invalid login: Login has illegal field: `httpRealm` contains newline",
+                expected_errs: vec![LoginValidationError::IllegalFieldValue {
+                    field_info: "`httpRealm` contains newline".into(),
+                }],
             },
             TestCase {
                 login: login_with_newline_username_field,
-                should_err: true,
-                expected_err:
-                    "Invalid login: Login has illegal field: `usernameField` contains newline",
+                expected_errs: vec![LoginValidationError::IllegalFieldValue {
+                    field_info: "`usernameField` contains newline".into(),
+                }],
             },
             TestCase {
                 login: login_with_newline_password,
-                should_err: false,
-                expected_err: "",
+                expected_errs: vec![],
             },
             TestCase {
                 login: login_with_period_username_field,
-                should_err: true,
-                expected_err: "Invalid login: Login has illegal field: `usernameField` is a period",
+                expected_errs: vec![LoginValidationError::IllegalFieldValue {
+                    field_info: "`usernameField` is a period".into(),
+                }],
             },
             TestCase {
                 login: login_with_period_form_submit_url,
-                should_err: false,
-                expected_err: "",
+                expected_errs: vec![],
             },
             TestCase {
                 login: login_with_javascript_form_submit_url,
-                should_err: false,
-                expected_err: "",
+                expected_errs: vec![],
             },
             TestCase {
                 login: login_with_malformed_origin_parens,
-                should_err: true,
-                expected_err: "Invalid login: Login has illegal field: Origin is Malformed",
+                expected_errs: vec![LoginValidationError::InvalidHostname(
+                    "Origin is malformed".into(),
+                )],
             },
             TestCase {
                 login: login_with_host_unicode,
-                should_err: true,
-                expected_err: "Invalid login: Login has illegal field: Origin is not normalized",
+                expected_errs: vec![LoginValidationError::InvalidHostname(
+                    "Origin is not normalized".into(),
+                )],
             },
             TestCase {
                 login: login_with_hostname_trailing_slash,
-                should_err: true,
-                expected_err: "Invalid login: Login has illegal field: Origin is not normalized",
+                expected_errs: vec![LoginValidationError::InvalidHostname(
+                    "Origin is not normalized".into(),
+                )],
             },
             TestCase {
                 login: login_with_hostname_expanded_ipv6,
-                should_err: true,
-                expected_err: "Invalid login: Login has illegal field: Origin is not normalized",
+                expected_errs: vec![LoginValidationError::InvalidHostname(
+                    "Origin is not normalized".into(),
+                )],
             },
             TestCase {
                 login: login_with_unknown_protocol,
-                should_err: false,
-                expected_err: "",
+                expected_errs: vec![],
+            },
+            TestCase {
+                login: login_with_long_password,
+                expected_errs: vec![LoginValidationError::PasswordTooLong(
+                    DEFAULT_MAX_FIELD_LEN + 1,
+                )],
+            },
+            TestCase {
+                login: login_with_long_username,
+                expected_errs: vec![LoginValidationError::UsernameTooLong(
+                    DEFAULT_MAX_FIELD_LEN + 1,
+                )],
+            },
+            TestCase {
+                login: login_with_malformed_form_submit_url,
+                expected_errs: vec![LoginValidationError::InvalidFormSubmitUrl(
+                    "formActionOrigin is malformed".into(),
+                )],
+            },
+            TestCase {
+                login: login_with_unnormalized_form_submit_url,
+                expected_errs: vec![LoginValidationError::InvalidFormSubmitUrl(
+                    "formActionOrigin is not normalized".into(),
+                )],
+            },
+            // Every problem is reported, not just the first.
+            TestCase {
+                login: login_with_many_problems,
+                expected_errs: vec![
+                    LoginValidationError::InvalidHostname("Origin is not normalized".into()),
+                    LoginValidationError::EmptyPassword,
+                    LoginValidationError::NoHttpRealmOrFormSubmitUrl,
+                    LoginValidationError::IllegalFieldValue {
+                        field_info: "`usernameField` is a period".into(),
+                    },
+                    LoginValidationError::IllegalFieldValue {
+                        field_info: "usernameField must be empty when formSubmitURL is null".into(),
+                    },
+                ],
             },
         ];
 
         for tc in &test_cases {
             let actual = tc.login.check_valid();
 
-            if tc.should_err {
-                assert!(actual.is_err());
-                assert_eq!(tc.expected_err, actual.unwrap_err().to_string());
+            if tc.expected_errs.is_empty() {
+                assert!(actual.is_ok(), "{:?}", actual);
             } else {
-                assert!(actual.is_ok());
+                match actual.unwrap_err().kind() {
+                    ErrorKind::InvalidLogin(errs) => assert_eq!(errs.0, tc.expected_errs),
+                    e => panic!("Unexpected error {:?}", e),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_with_defaults_for_testing() {
+        let login = Login::with_defaults_for_testing("https://www.example.com", "user", "pass");
+        login.check_valid().unwrap();
+        assert!(is_valid_guid(login.guid_str()));
+        assert_eq!(login.hostname, "https://www.example.com");
+        assert_eq!(login.username, "user");
+        assert_eq!(login.password, "pass");
+        assert_eq!(login.times_used, 1);
+        assert_ne!(
+            login.guid,
+            Login::with_defaults_for_testing("https://www.example.com", "user", "pass").guid
+        );
+    }
+
+    #[test]
+    fn test_new_and_builder() {
+        let login = Login::new("https://www.example.com", "user", "pass");
+        login.check_valid().unwrap();
+        assert!(login.guid.is_empty());
+        assert_eq!(login.form_submit_url, Some(String::new()));
+        assert_eq!(login.http_realm, None);
+
+        let login = Login::with_guid("dummy_000001", "https://www.example.com", "user", "pass");
+        assert_eq!(login.guid, "dummy_000001");
+        assert_eq!(login.hostname, "https://www.example.com");
+
+        let login = LoginBuilder::new("https://www.example.com", "user", "pass")
+            .http_realm("Some Realm")
+            .times_used(3)
+            .associated_app("com.example.app")
+            .build()
+            .unwrap();
+        assert_eq!(login.form_submit_url, None);
+        assert_eq!(login.http_realm, Some("Some Realm".into()));
+        assert_eq!(login.times_used, 3);
+        assert_eq!(login.associated_app, Some("com.example.app".into()));
+
+        let login = LoginBuilder::new("https://www.example.com", "user", "pass")
+            .http_realm("Some Realm")
+            .form_submit_url("https://www.example.com")
+            .username_field("user_input")
+            .password_field("pass_input")
+            .build()
+            .unwrap();
+        assert_eq!(
+            login.form_submit_url,
+            Some("https://www.example.com".into())
+        );
+        assert_eq!(login.http_realm, None);
+        assert_eq!(login.username_field, "user_input");
+
+        match LoginBuilder::new("https://www.example.com", "user", "")
+            .build()
+            .unwrap_err()
+            .kind()
+        {
+            ErrorKind::InvalidLogin(errs) => {
+                assert_eq!(errs.0, [LoginValidationError::EmptyPassword])
+            }
+            e => panic!("Unexpected error {:?}", e),
+        }
+        assert!(LoginBuilder::new("https://www.example.com", "user", "pass")
+            .guid("has space")
+            .build()
+            .is_err());
+    }
+
+    #[test]
+    fn test_validate_hostname() {
+        let cases = [
+            ("https://www.example.com", None),
+            ("", Some(LoginValidationError::EmptyHostname)),
+            (
+                "https://www.example.com\0",
+                Some(LoginValidationError::IllegalFieldValue {
+                    field_info: "`hostname` contains Nul".into(),
+                }),
+            ),
+            (
+                "https://www.example.com\n",
+                Some(LoginValidationError::IllegalFieldValue {
+                    field_info: "`hostname` contains newline".into(),
+                }),
+            ),
+            (
+                "(\"https://www.example.com\")",
+                Some(LoginValidationError::InvalidHostname(
+                    "Origin is malformed".into(),
+                )),
+            ),
+            (
+                "https://www.example.com/",
+                Some(LoginValidationError::InvalidHostname(
+                    "Origin is not normalized".into(),
+                )),
+            ),
+            (
+                "https://www.example.com/path",
+                Some(LoginValidationError::InvalidHostname(
+                    "Origin is not normalized".into(),
+                )),
+            ),
+        ];
+        for (hostname, expected_err) in &cases {
+            let result = validate_hostname(hostname);
+            match expected_err {
+                None => assert!(result.is_ok(), "{:?}", hostname),
+                Some(err) => match result.unwrap_err().kind() {
+                    ErrorKind::InvalidLogin(errs) => assert_eq!(errs.0.first(), Some(err)),
+                    e => panic!("Unexpected error {:?}", e),
+                },
             }
         }
     }
 
+    #[test]
+    fn test_validate_password() {
+        let cases = [
+            ("hunter2", None),
+            // Unlike most fields, newlines are fine in passwords.
+            ("hunter\n2", None),
+            ("", Some(LoginValidationError::EmptyPassword)),
+            (
+                "hunter\0",
+                Some(LoginValidationError::IllegalFieldValue {
+                    field_info: "`password` contains Nul".into(),
+                }),
+            ),
+        ];
+        for (password, expected_err) in &cases {
+            let result = validate_password(password);
+            match expected_err {
+                None => assert!(result.is_ok(), "{:?}", password),
+                Some(err) => match result.unwrap_err().kind() {
+                    ErrorKind::InvalidLogin(errs) => assert_eq!(errs.0.first(), Some(err)),
+                    e => panic!("Unexpected error {:?}", e),
+                },
+            }
+        }
+    }
+
+    #[test]
+    fn test_normalize_login_urls() {
+        for hostname in &[
+            "https://example.com/",
+            "https://example.com",
+            "HTTPS://Example.Com",
+            "https://example.com/path?query#fragment",
+        ] {
+            let mut login = Login {
+                hostname: (*hostname).into(),
+                form_submit_url: Some(format!("{}/login", hostname)),
+                ..Login::default()
+            };
+            normalize_login_urls(&mut login).unwrap();
+            assert_eq!(login.hostname, "https://example.com");
+            assert_eq!(
+                login.form_submit_url.as_deref(),
+                Some("https://example.com")
+            );
+        }
+
+        let mut login = Login {
+            hostname: "http://example.com:8080/".into(),
+            form_submit_url: Some("javascript:".into()),
+            ..Login::default()
+        };
+        normalize_login_urls(&mut login).unwrap();
+        assert_eq!(login.hostname, "http://example.com:8080");
+        assert_eq!(login.form_submit_url.as_deref(), Some("javascript:"));
+
+        // Every problem is reported, not just the first.
+        let mut login = Login {
+            hostname: "not a url".into(),
+            form_submit_url: Some("also not a url".into()),
+            ..Login::default()
+        };
+        match normalize_login_urls(&mut login).unwrap_err().kind() {
+            ErrorKind::InvalidLogin(errs) => assert_eq!(
+                errs.0,
+                [
+                    LoginValidationError::InvalidHostname("Origin is malformed".into()),
+                    LoginValidationError::InvalidFormSubmitUrl(
+                        "formActionOrigin is malformed".into()
+                    ),
+                ]
+            ),
+            e => panic!("Unexpected error {:?}", e),
+        }
+
+        // It does the same normalization as `fixup`.
+        for hostname in &["HTTPS://Example.Com/", "http://example.com:8080/path"] {
+            let login = Login {
+                hostname: (*hostname).into(),
+                form_submit_url: Some(format!("{}/login", hostname)),
+                ..Login::with_defaults_for_testing("", "user", "password")
+            };
+            let mut normalized = login.clone();
+            normalize_login_urls(&mut normalized).unwrap();
+            assert_eq!(normalized, login.fixup().unwrap());
+        }
+    }
+
     #[test]
     fn test_fixup() {
         #[derive(Default)]
@@ -1429,4 +2388,212 @@ mod tests {
         assert!(login.check_valid().is_err());
         assert_eq!(login.fixup().unwrap().password_field, "");
     }
+
+    #[test]
+    fn test_diff() {
+        let a = Login {
+            guid: "aaaaaaaaaaaa".into(),
+            hostname: "https://www.example.com".into(),
+            form_submit_url: Some("https://www.example.com".into()),
+            username: "user".into(),
+            password: "password".into(),
+            username_field: "username".into(),
+            password_field: "password".into(),
+            time_created: 1000,
+            times_used: 1,
+            ..Login::default()
+        };
+        // The GUID, timestamps and usage count don't count.
+        let same = Login {
+            guid: "bbbbbbbbbbbb".into(),
+            time_created: 2000,
+            times_used: 5,
+            ..a.clone()
+        };
+        let diff = Login::diff(&a, &same);
+        assert!(diff.is_empty());
+        assert_eq!(diff, LoginDiff::default());
+
+        let diff = Login::diff(
+            &a,
+            &Login {
+                password: "password2".into(),
+                ..a.clone()
+            },
+        );
+        assert!(!diff.is_empty());
+        assert_eq!(
+            diff,
+            LoginDiff {
+                password: Some(("password".into(), "password2".into())),
+                ..LoginDiff::default()
+            }
+        );
+
+        let b = Login {
+            hostname: "https://www.example.org".into(),
+            form_submit_url: None,
+            http_realm: Some("realm".into()),
+            username: "user2".into(),
+            password: "password2".into(),
+            username_field: "username2".into(),
+            password_field: "password2".into(),
+            ..a.clone()
+        };
+        assert_eq!(
+            Login::diff(&a, &b),
+            LoginDiff {
+                hostname: Some((
+                    "https://www.example.com".into(),
+                    "https://www.example.org".into()
+                )),
+                username: Some(("user".into(), "user2".into())),
+                password: Some(("password".into(), "password2".into())),
+                http_realm: Some(("".into(), "realm".into())),
+                form_submit_url: Some(("https://www.example.com".into(), "".into())),
+                username_field: Some(("username".into(), "username2".into())),
+                password_field: Some(("password".into(), "password2".into())),
+            }
+        );
+    }
+
+    #[test]
+    fn test_three_way_merge() {
+        let base = Login {
+            guid: "aaaaaaaaaaaa".into(),
+            hostname: "https://www.example.com".into(),
+            http_realm: Some("realm".into()),
+            username: "user".into(),
+            password: "password".into(),
+            time_created: 1000,
+            time_last_used: 2000,
+            time_password_changed: 1000,
+            times_used: 5,
+            ..Login::default()
+        };
+
+        // Nothing changed.
+        assert_eq!(
+            three_way_merge(&base, &base, &base),
+            MergeResult::Merged(base.clone())
+        );
+
+        // Only local changed.
+        let local = Login {
+            username: "local_user".into(),
+            ..base.clone()
+        };
+        assert_eq!(
+            three_way_merge(&local, &base, &base),
+            MergeResult::Merged(local.clone())
+        );
+
+        // Only remote changed.
+        let remote = Login {
+            password: "remote_password".into(),
+            http_realm: None,
+            ..base.clone()
+        };
+        assert_eq!(
+            three_way_merge(&base, &base, &remote),
+            MergeResult::Merged(remote.clone())
+        );
+
+        // Each changed a different field.
+        assert_eq!(
+            three_way_merge(&local, &base, &remote),
+            MergeResult::Merged(Login {
+                username: "local_user".into(),
+                password: "remote_password".into(),
+                http_realm: None,
+                ..base.clone()
+            })
+        );
+
+        // Both changed the same field to the same value.
+        assert_eq!(
+            three_way_merge(&remote, &base, &remote),
+            MergeResult::Merged(remote.clone())
+        );
+
+        // Both changed the same field to different values.
+        let local = Login {
+            username: "local_user".into(),
+            password: "local_password".into(),
+            ..base.clone()
+        };
+        let result = three_way_merge(&local, &base, &remote);
+        assert_eq!(
+            result,
+            MergeResult::Conflict {
+                local: Login {
+                    username: "local_user".into(),
+                    password: "local_password".into(),
+                    http_realm: None,
+                    ..base.clone()
+                },
+                remote: Login {
+                    username: "local_user".into(),
+                    password: "remote_password".into(),
+                    http_realm: None,
+                    ..base.clone()
+                },
+            }
+        );
+        assert_eq!(result.into_login().password, "remote_password");
+    }
+
+    #[test]
+    fn test_three_way_merge_numeric_fields() {
+        let base = Login {
+            guid: "aaaaaaaaaaaa".into(),
+            hostname: "https://www.example.com".into(),
+            http_realm: Some("realm".into()),
+            username: "user".into(),
+            password: "password".into(),
+            time_created: 1000,
+            time_last_used: 2000,
+            time_password_changed: 1000,
+            times_used: 5,
+            ..Login::default()
+        };
+        let local = Login {
+            time_last_used: 5000,
+            time_password_changed: 3000,
+            times_used: 7,
+            ..base.clone()
+        };
+        let remote = Login {
+            time_last_used: 4000,
+            time_password_changed: 1000,
+            times_used: 6,
+            ..base.clone()
+        };
+        let merged = three_way_merge(&local, &base, &remote).into_login();
+        // Both changed, so the later one wins.
+        assert_eq!(merged.time_last_used, 5000);
+        // Only local changed.
+        assert_eq!(merged.time_password_changed, 3000);
+        assert_eq!(merged.time_created, 1000);
+        // Both sides' uses count.
+        assert_eq!(merged.times_used, 8);
+
+        let merged = three_way_merge(&remote, &base, &local).into_login();
+        assert_eq!(merged.time_last_used, 5000);
+        assert_eq!(merged.time_password_changed, 3000);
+        assert_eq!(merged.times_used, 8);
+
+        // Missing values (zero) don't count as changes.
+        let missing = Login {
+            time_created: 0,
+            time_last_used: 0,
+            time_password_changed: 0,
+            times_used: 0,
+            ..base.clone()
+        };
+        assert_eq!(
+            three_way_merge(&missing, &base, &missing),
+            MergeResult::Merged(base)
+        );
+    }
 }
@@ -0,0 +1,277 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Versioned migrations, which are how the schema is upgraded past version 8.
+//!
+//! Migrations are part of the `PRAGMA user_version` upgrade path: when
+//! `schema::init` finds an older database, `schema::upgrade` applies its own
+//! steps for versions up to 8, then every migration newer than the database's
+//! `user_version`, oldest first, then sets `user_version` to
+//! `schema::VERSION`. Each migration also sets `user_version` to its own
+//! version once it's applied.
+//!
+//! To change the schema, bump `schema::VERSION`, change `schema::create` so
+//! that new databases get the new schema directly (migrations don't run on
+//! them), and add a migration with the new version to [MIGRATIONS].
+
+use crate::error::*;
+use rusqlite::Connection;
+use sql_support::ConnExt;
+
+/// A single migration. `up` applies it, and `down`, if present, undoes it.
+#[derive(Clone, Copy)]
+pub struct Migration {
+    pub version: u32,
+    pub up: fn(&Connection) -> Result<()>,
+    pub down: Option<fn(&Connection) -> Result<()>>,
+}
+
+/// The migrations for the logins database. Versions must be unique, greater
+/// than 8 and no greater than `schema::VERSION`, but needn't be in order.
+pub(crate) static MIGRATIONS: &[Migration] = &[];
+
+/// Applies (or reverts) a set of migrations, each in its own savepoint, so
+/// that a failed migration is rolled back whether or not the caller is in a
+/// transaction.
+pub struct MigrationManager<'a> {
+    // Sorted by version.
+    migrations: Vec<&'a Migration>,
+}
+
+impl<'a> MigrationManager<'a> {
+    pub fn new(migrations: &'a [Migration]) -> Self {
+        let mut migrations: Vec<_> = migrations.iter().collect();
+        migrations.sort_by_key(|m| m.version);
+        for pair in migrations.windows(2) {
+            assert_ne!(
+                pair[0].version, pair[1].version,
+                "Duplicate migration version"
+            );
+        }
+        Self { migrations }
+    }
+
+    /// Returns the schema version of `db`, from `PRAGMA user_version`.
+    pub fn current_version(db: &Connection) -> Result<u32> {
+        Ok(db.query_one::<i64>("PRAGMA user_version")? as u32)
+    }
+
+    /// Applies every migration newer than the current version, oldest first,
+    /// and returns the new version. If a migration fails, it's rolled back,
+    /// and the migrations before it stay applied (unless the caller's own
+    /// transaction is rolled back too).
+    pub fn run(&self, db: &Connection) -> Result<u32> {
+        let current = Self::current_version(db)?;
+        let mut version = current;
+        for migration in self.migrations.iter().filter(|m| m.version > current) {
+            log::info!("Applying logins migration {}", migration.version);
+            in_savepoint(db, migration.version, migration.up)?;
+            version = migration.version;
+        }
+        Ok(version)
+    }
+
+    /// Reverts every applied migration newer than `target_version`, newest
+    /// first, and returns the new version. Reverting the first migration
+    /// leaves the database at the version just before it. Fails with
+    /// `IrreversibleMigration`, leaving the migrations from that one down
+    /// applied, if any of them has no `down` function.
+    pub fn revert_to(&self, db: &Connection, target_version: u32) -> Result<u32> {
+        let mut version = Self::current_version(db)?;
+        for (i, migration) in self.migrations.iter().enumerate().rev() {
+            if migration.version > version || migration.version <= target_version {
+                continue;
+            }
+            let down = match migration.down {
+                Some(down) => down,
+                None => throw!(ErrorKind::IrreversibleMigration(migration.version)),
+            };
+            let previous = if i == 0 {
+                migration.version - 1
+            } else {
+                self.migrations[i - 1].version
+            };
+            log::info!("Reverting logins migration {}", migration.version);
+            in_savepoint(db, previous, down)?;
+            version = previous;
+        }
+        Ok(version)
+    }
+}
+
+// Runs `f`, then sets `user_version` to `version`, rolling both back if
+// either fails.
+fn in_savepoint(db: &Connection, version: u32, f: fn(&Connection) -> Result<()>) -> Result<()> {
+    db.execute_batch("SAVEPOINT logins_migration")?;
+    let result = f(db).and_then(|()| {
+        db.execute_batch(&format!("PRAGMA user_version = {}", version))?;
+        Ok(())
+    });
+    if result.is_err() {
+        db.execute_batch("ROLLBACK TO logins_migration")?;
+    }
+    db.execute_batch("RELEASE logins_migration")?;
+    result
+}
+
+/// Applies any pending migrations to `db`, returning the new version. Called
+/// by `schema::upgrade`.
+pub(crate) fn run_migrations(db: &Connection) -> Result<u32> {
+    MigrationManager::new(MIGRATIONS).run(db)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::LoginDb;
+    use crate::schema;
+    use rusqlite::NO_PARAMS;
+
+    fn create_table(db: &Connection) -> Result<()> {
+        db.execute_all(&["CREATE TABLE migration_test (value TEXT NOT NULL)"])?;
+        Ok(())
+    }
+
+    fn drop_table(db: &Connection) -> Result<()> {
+        db.execute_all(&["DROP TABLE migration_test"])?;
+        Ok(())
+    }
+
+    fn insert_row(db: &Connection) -> Result<()> {
+        db.execute_all(&["INSERT INTO migration_test (value) VALUES ('v2')"])?;
+        Ok(())
+    }
+
+    fn delete_row(db: &Connection) -> Result<()> {
+        db.execute_all(&["DELETE FROM migration_test WHERE value = 'v2'"])?;
+        Ok(())
+    }
+
+    fn insert_and_fail(db: &Connection) -> Result<()> {
+        db.execute_all(&["INSERT INTO migration_test (value) VALUES ('v3')"])?;
+        throw!(ErrorKind::NoSuchRecord("test".into()))
+    }
+
+    fn count_rows(db: &Connection) -> i64 {
+        db.query_one("SELECT COUNT(*) FROM migration_test").unwrap()
+    }
+
+    fn table_exists(db: &Connection) -> bool {
+        db.query_row(
+            "SELECT EXISTS(SELECT 1 FROM sqlite_master WHERE name = 'migration_test')",
+            NO_PARAMS,
+            |row| row.get(0),
+        )
+        .unwrap()
+    }
+
+    // Test migrations start just past the current schema version, which is
+    // what `schema::create` leaves a new database at.
+    const BASE: u32 = schema::VERSION as u32;
+
+    // Deliberately out of order.
+    static TEST_MIGRATIONS: &[Migration] = &[
+        Migration {
+            version: BASE + 2,
+            up: insert_row,
+            down: Some(delete_row),
+        },
+        Migration {
+            version: BASE + 1,
+            up: create_table,
+            down: Some(drop_table),
+        },
+    ];
+
+    fn new_db() -> Connection {
+        let db = Connection::open_in_memory().unwrap();
+        schema::init(&db).unwrap();
+        db
+    }
+
+    #[test]
+    fn test_migration_versions() {
+        let manager = MigrationManager::new(MIGRATIONS);
+        for migration in &manager.migrations {
+            // Versions up to 8 are handled by `schema::upgrade` itself.
+            assert!(migration.version > 8);
+            assert!(i64::from(migration.version) <= schema::VERSION);
+        }
+        // A new database already has the latest schema, so has nothing to
+        // apply.
+        assert_eq!(run_migrations(&new_db()).unwrap(), BASE);
+    }
+
+    #[test]
+    fn test_run_migrations() {
+        let db = new_db();
+        assert_eq!(MigrationManager::current_version(&db).unwrap(), BASE);
+
+        let manager = MigrationManager::new(TEST_MIGRATIONS);
+        assert_eq!(manager.run(&db).unwrap(), BASE + 2);
+        assert_eq!(MigrationManager::current_version(&db).unwrap(), BASE + 2);
+        assert_eq!(count_rows(&db), 1);
+
+        // Running them again doesn't do anything.
+        assert_eq!(manager.run(&db).unwrap(), BASE + 2);
+        assert_eq!(count_rows(&db), 1);
+    }
+
+    #[test]
+    fn test_migrations_survive_wipe() {
+        let db = LoginDb::open_in_memory(Some("testing".into())).unwrap();
+        MigrationManager::new(TEST_MIGRATIONS).run(&db).unwrap();
+        db.wipe_local().unwrap();
+        assert_eq!(MigrationManager::current_version(&db).unwrap(), BASE + 2);
+    }
+
+    #[test]
+    fn test_failed_migration() {
+        let db = new_db();
+        let migrations = [
+            TEST_MIGRATIONS[0],
+            TEST_MIGRATIONS[1],
+            Migration {
+                version: BASE + 3,
+                up: insert_and_fail,
+                down: None,
+            },
+        ];
+        let manager = MigrationManager::new(&migrations);
+        assert!(manager.run(&db).is_err());
+        assert_eq!(MigrationManager::current_version(&db).unwrap(), BASE + 2);
+        assert_eq!(count_rows(&db), 1);
+    }
+
+    #[test]
+    fn test_revert_migrations() {
+        let db = new_db();
+        let manager = MigrationManager::new(TEST_MIGRATIONS);
+        manager.run(&db).unwrap();
+
+        assert_eq!(manager.revert_to(&db, BASE + 1).unwrap(), BASE + 1);
+        assert_eq!(count_rows(&db), 0);
+        assert_eq!(manager.revert_to(&db, BASE).unwrap(), BASE);
+        assert!(!table_exists(&db));
+        assert_eq!(MigrationManager::current_version(&db).unwrap(), BASE);
+
+        // Migrations without a `down` can't be reverted.
+        let migrations = [
+            TEST_MIGRATIONS[1],
+            Migration {
+                version: BASE + 2,
+                up: insert_row,
+                down: None,
+            },
+        ];
+        let manager = MigrationManager::new(&migrations);
+        manager.run(&db).unwrap();
+        match manager.revert_to(&db, BASE).unwrap_err().kind() {
+            ErrorKind::IrreversibleMigration(v) => assert_eq!(*v, BASE + 2),
+            e => panic!("Unexpected error: {:?}", e),
+        }
+        assert_eq!(MigrationManager::current_version(&db).unwrap(), BASE + 2);
+        assert!(table_exists(&db));
+    }
+}
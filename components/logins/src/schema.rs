@@ -2,15 +2,18 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
-//! Logins Schema v4
+//! Logins Schema v8
 //! ================
 //!
 //! The schema we use is a evolution of the firefox-ios logins database format.
-//! There are three tables:
+//! There are five tables:
 //!
 //! - `loginsL`: The local table.
 //! - `loginsM`: The mirror table.
 //! - `loginsSyncMeta`: The table used to to store various sync metadata.
+//! - `loginsQuarantine`: Invalid local records set aside by
+//!   `LoginDb::run_integrity_repair`.
+//! - `loginsAuditLog`: Who changed which logins, and when.
 //!
 //! ## `loginsL`
 //!
@@ -87,15 +90,48 @@
 //!    [GLOBAL_STATE_META_KEY]. This is a `sync15::GlobalState` stored as
 //!    JSON.
 //!
+//! ## `loginsQuarantine`
+//!
+//! Local records which failed validation, moved out of `loginsL` by
+//! `LoginDb::run_integrity_repair` so they no longer interfere with queries or
+//! syncing, but aren't lost either. This table was added in version 7.
+//!
+//! - `reason`: Why the record failed validation.
+//!
+//! - `raw_json`: The record, as a JSON `Login`, plus its local-only fields as
+//!   `creationSource`, `creationSourceName` and `associatedApp`.
+//!
+//! - `quarantined_at`: A millisecond local timestamp.
+//!
+//! ## `loginsAuditLog`
+//!
+//! A row for each add, update, delete, touch or wipe of a login, written
+//! only while `LoginDb::set_audit_actor` has set an actor. This table was
+//! added in version 8, and like `loginsQuarantine`, has no counterpart on
+//! the server. It survives `LoginDb::wipe_local`.
+//!
+//! - `guid`: The login's GUID.
+//!
+//! - `operation`: One of `add`, `update`, `delete`, `touch` or `wipe`.
+//!
+//! - `timestamp_ms`: A millisecond local timestamp.
+//!
+//! - `actor`: The actor set by `LoginDb::set_audit_actor`.
+//!
 
 use crate::error::*;
+use crate::migrations;
 use lazy_static::lazy_static;
 use rusqlite::Connection;
 use sql_support::ConnExt;
 
-/// Note that firefox-ios is currently on version 3. Version 4 is this version,
-/// which adds a metadata table and changes timestamps to be in milliseconds
-pub const VERSION: i64 = 4;
+/// Note that firefox-ios is currently on version 3. Version 4 added a metadata
+/// table and changed timestamps to be in milliseconds, version 5 added the
+/// `creation_source` and `creation_source_name` columns, version 6 added the
+/// `associated_app` column, version 7 added the `loginsQuarantine` table, and
+/// version 8 added the `loginsAuditLog` table. Later versions are added by
+/// migrations (see the `migrations` module).
+pub const VERSION: i64 = 8;
 
 /// Every column shared by both tables except for `id`
 ///
@@ -114,6 +150,13 @@ pub const VERSION: i64 = 4;
 /// (of `loginsM`) are stored as milliseconds as well both on firefox-ios and
 /// here (and so they do not need to be updated with the `timeLastUsed`/
 /// `timePasswordChanged`/`timeCreated` timestamps.
+///
+/// `creation_source` and `creation_source_name` were added in v5 (by this
+/// rust crate), and store a `CreationSource` as its discriminant and (for
+/// imported logins) the name of the source it was imported from.
+///
+/// `associated_app` was added in v6, and holds the ID of the (mobile) app the
+/// login is for, if any.
 pub const COMMON_COLS: &str = "
     guid,
     username,
@@ -126,7 +169,10 @@ pub const COMMON_COLS: &str = "
     timeCreated,
     timeLastUsed,
     timePasswordChanged,
-    timesUsed
+    timesUsed,
+    creation_source,
+    creation_source_name,
+    associated_app
 ";
 
 const COMMON_SQL: &str = "
@@ -143,7 +189,12 @@ const COMMON_SQL: &str = "
     timePasswordChanged INTEGER NOT NULL,
     username            TEXT,
     password            TEXT NOT NULL,
-    guid                TEXT NOT NULL UNIQUE
+    guid                TEXT NOT NULL UNIQUE,
+    -- A `CreationSource` discriminant, 0 (Unknown) for records from before v5.
+    creation_source      TINYINT NOT NULL DEFAULT 0,
+    creation_source_name TEXT,
+    -- The ID of the app (e.g. `com.example.app`) this login is for, if any.
+    associated_app       TEXT
 ";
 
 lazy_static! {
@@ -179,6 +230,30 @@ const CREATE_META_TABLE_SQL: &str = "
     )
 ";
 
+const CREATE_QUARANTINE_TABLE_SQL: &str = "
+    CREATE TABLE IF NOT EXISTS loginsQuarantine (
+        id             INTEGER PRIMARY KEY,
+        reason         TEXT,
+        raw_json       TEXT,
+        quarantined_at INTEGER
+    )
+";
+
+const CREATE_AUDIT_LOG_TABLE_SQL: &str = "
+    CREATE TABLE IF NOT EXISTS loginsAuditLog (
+        id           INTEGER PRIMARY KEY,
+        guid         TEXT,
+        operation    TEXT,
+        timestamp_ms INTEGER,
+        actor        TEXT
+    )
+";
+
+const CREATE_AUDIT_LOG_GUID_INDEX_SQL: &str = "
+    CREATE INDEX IF NOT EXISTS idx_loginsAuditLog_guid
+    ON loginsAuditLog (guid)
+";
+
 const CREATE_OVERRIDE_HOSTNAME_INDEX_SQL: &str = "
     CREATE INDEX IF NOT EXISTS idx_loginsM_is_overridden_hostname
     ON loginsM (is_overridden, hostname)
@@ -205,6 +280,20 @@ const UPDATE_MIRROR_TIMESTAMPS_TO_MILLIS_SQL: &str = "
         timePasswordChanged = timePasswordChanged / 1000
 ";
 
+// Used when updating from schema v4, to add the `CreationSource` columns.
+const ADD_LOCAL_CREATION_SOURCE_SQL: &str =
+    "ALTER TABLE loginsL ADD COLUMN creation_source TINYINT NOT NULL DEFAULT 0";
+const ADD_LOCAL_CREATION_SOURCE_NAME_SQL: &str =
+    "ALTER TABLE loginsL ADD COLUMN creation_source_name TEXT";
+const ADD_MIRROR_CREATION_SOURCE_SQL: &str =
+    "ALTER TABLE loginsM ADD COLUMN creation_source TINYINT NOT NULL DEFAULT 0";
+const ADD_MIRROR_CREATION_SOURCE_NAME_SQL: &str =
+    "ALTER TABLE loginsM ADD COLUMN creation_source_name TEXT";
+
+// Used when updating from schema v5, to add the `associated_app` column.
+const ADD_LOCAL_ASSOCIATED_APP_SQL: &str = "ALTER TABLE loginsL ADD COLUMN associated_app TEXT";
+const ADD_MIRROR_ASSOCIATED_APP_SQL: &str = "ALTER TABLE loginsM ADD COLUMN associated_app TEXT";
+
 pub(crate) static LAST_SYNC_META_KEY: &str = "last_sync_time";
 pub(crate) static GLOBAL_STATE_META_KEY: &str = "global_state_v2";
 pub(crate) static GLOBAL_SYNCID_META_KEY: &str = "global_sync_id";
@@ -277,9 +366,32 @@ fn upgrade(db: &Connection, from: i64) -> Result<()> {
             CREATE_META_TABLE_SQL,
             UPDATE_LOCAL_TIMESTAMPS_TO_MILLIS_SQL,
             UPDATE_MIRROR_TIMESTAMPS_TO_MILLIS_SQL,
-            &*SET_VERSION_SQL,
         ])?;
     }
+    if from < 5 {
+        // v5 added the `creation_source` and `creation_source_name` columns.
+        db.execute_all(&[
+            ADD_LOCAL_CREATION_SOURCE_SQL,
+            ADD_LOCAL_CREATION_SOURCE_NAME_SQL,
+            ADD_MIRROR_CREATION_SOURCE_SQL,
+            ADD_MIRROR_CREATION_SOURCE_NAME_SQL,
+        ])?;
+    }
+    if from < 6 {
+        // v6 added the `associated_app` column.
+        db.execute_all(&[ADD_LOCAL_ASSOCIATED_APP_SQL, ADD_MIRROR_ASSOCIATED_APP_SQL])?;
+    }
+    if from < 7 {
+        // v7 added the `loginsQuarantine` table.
+        db.execute_all(&[CREATE_QUARANTINE_TABLE_SQL])?;
+    }
+    if from < 8 {
+        // v8 added the `loginsAuditLog` table.
+        db.execute_all(&[CREATE_AUDIT_LOG_TABLE_SQL, CREATE_AUDIT_LOG_GUID_INDEX_SQL])?;
+    }
+    // Anything newer is a migration.
+    migrations::run_migrations(db)?;
+    db.execute_batch(&SET_VERSION_SQL)?;
     Ok(())
 }
 
@@ -291,6 +403,9 @@ pub(crate) fn create(db: &Connection) -> Result<()> {
         CREATE_OVERRIDE_HOSTNAME_INDEX_SQL,
         CREATE_DELETED_HOSTNAME_INDEX_SQL,
         CREATE_META_TABLE_SQL,
+        CREATE_QUARANTINE_TABLE_SQL,
+        CREATE_AUDIT_LOG_TABLE_SQL,
+        CREATE_AUDIT_LOG_GUID_INDEX_SQL,
         &*SET_VERSION_SQL,
     ])?;
     Ok(())
@@ -302,6 +417,8 @@ pub(crate) fn drop(db: &Connection) -> Result<()> {
         "DROP TABLE IF EXISTS loginsM",
         "DROP TABLE IF EXISTS loginsL",
         "DROP TABLE IF EXISTS loginsSyncMeta",
+        "DROP TABLE IF EXISTS loginsQuarantine",
+        "DROP TABLE IF EXISTS loginsAuditLog",
         "PRAGMA user_version = 0",
     ])?;
     Ok(())
@@ -3,7 +3,7 @@
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
 use crate::error::*;
-use crate::login::{LocalLogin, Login, MirrorLogin, SyncStatus};
+use crate::login::{self, LocalLogin, Login, MergeResult, MirrorLogin, SyncStatus};
 use crate::util;
 use rusqlite::{named_params, Connection};
 use sql_support::SqlInterruptScope;
@@ -24,14 +24,51 @@ pub(crate) struct UpdatePlan {
 impl UpdatePlan {
     pub fn plan_two_way_merge(&mut self, local: &Login, upstream: (Login, ServerTimestamp)) {
         let is_override = local.time_password_changed > upstream.0.time_password_changed;
-        self.mirror_inserts
-            .push((upstream.0, upstream.1.as_millis() as i64, is_override));
-        if !is_override {
+        self.plan_pick_winner(local, false, upstream, is_override);
+    }
+
+    /// Resolves a conflict by taking either the local or the upstream record
+    /// as-is, instead of merging them. `has_mirror` says whether we already
+    /// have a mirror record for this login.
+    pub fn plan_pick_winner(
+        &mut self,
+        local: &Login,
+        has_mirror: bool,
+        upstream: (Login, ServerTimestamp),
+        local_wins: bool,
+    ) {
+        // Fields which aren't synced are always missing upstream, so keep
+        // the local values, whichever side wins.
+        let upstream = (
+            Login {
+                creation_source: local.creation_source.clone(),
+                associated_app: local.associated_app.clone(),
+                ..upstream.0
+            },
+            upstream.1,
+        );
+        if local_wins {
+            if has_mirror {
+                self.plan_mirror_update(upstream.0, upstream.1);
+            } else {
+                self.plan_mirror_insert(upstream.0, upstream.1, true);
+            }
+        } else {
+            // Deletes happen before inserts, so replacing an existing mirror
+            // record (and clearing its `is_overridden` flag) is fine.
+            if has_mirror {
+                self.delete_mirror.push(local.guid.clone());
+            }
             self.delete_local.push(local.guid.clone());
+            self.plan_mirror_insert(upstream.0, upstream.1, false);
         }
     }
 
-    pub fn plan_three_way_merge(
+    /// Merges the local and upstream changes to `shared` with
+    /// `login::three_way_merge`, so changes to different fields are all kept.
+    /// Fields changed on both sides take the value from whichever side
+    /// changed most recently.
+    pub fn plan_field_level_merge(
         &mut self,
         local: LocalLogin,
         shared: MirrorLogin,
@@ -44,18 +81,26 @@ impl UpdatePlan {
             .unwrap_or_default();
         let remote_age = server_now.duration_since(upstream_time).unwrap_or_default();
 
-        let local_delta = local.login.delta(&shared.login);
-        let upstream_delta = upstream.delta(&shared.login);
-
-        let merged_delta = local_delta.merge(upstream_delta, remote_age < local_age);
+        let merged = match login::three_way_merge(&local.login, &shared.login, &upstream) {
+            MergeResult::Merged(login) => login,
+            // Conflicting fields take the value from the newer side.
+            MergeResult::Conflict { local, remote } => {
+                if remote_age < local_age {
+                    remote
+                } else {
+                    local
+                }
+            }
+        };
 
         // Update mirror to upstream
         self.mirror_updates
             .push((upstream, upstream_time.as_millis() as i64));
-        let mut new = shared;
-
-        new.login.apply_delta(merged_delta);
-        new.server_modified = upstream_time;
+        let new = MirrorLogin {
+            login: merged,
+            server_modified: upstream_time,
+            ..shared
+        };
         self.local_updates.push(new);
     }
 
@@ -75,24 +120,20 @@ impl UpdatePlan {
 
     fn perform_deletes(&self, conn: &Connection, scope: &SqlInterruptScope) -> Result<()> {
         sql_support::each_chunk(&self.delete_local, |chunk, _| -> Result<()> {
+            let (vars, params) = util::sql_placeholders_and_params(chunk);
             conn.execute(
-                &format!(
-                    "DELETE FROM loginsL WHERE guid IN ({vars})",
-                    vars = sql_support::repeat_sql_vars(chunk.len())
-                ),
-                chunk,
+                &format!("DELETE FROM loginsL WHERE guid IN ({vars})", vars = vars),
+                &params,
             )?;
             scope.err_if_interrupted()?;
             Ok(())
         })?;
 
         sql_support::each_chunk(&self.delete_mirror, |chunk, _| {
+            let (vars, params) = util::sql_placeholders_and_params(chunk);
             conn.execute(
-                &format!(
-                    "DELETE FROM loginsM WHERE guid IN ({vars})",
-                    vars = sql_support::repeat_sql_vars(chunk.len())
-                ),
-                chunk,
+                &format!("DELETE FROM loginsM WHERE guid IN ({vars})", vars = vars),
+                &params,
             )?;
             Ok(())
         })
@@ -159,6 +200,10 @@ impl UpdatePlan {
                 timePasswordChanged,
                 timeCreated,
 
+                creation_source,
+                creation_source_name,
+                associated_app,
+
                 guid
             ) VALUES (
                 :is_overridden,
@@ -177,6 +222,10 @@ impl UpdatePlan {
                 :time_password_changed,
                 :time_created,
 
+                :creation_source,
+                :creation_source_name,
+                :associated_app,
+
                 :guid
             )";
         let mut stmt = conn.prepare_cached(&sql)?;
@@ -197,6 +246,9 @@ impl UpdatePlan {
                 ":time_last_used": login.time_last_used,
                 ":time_password_changed": login.time_password_changed,
                 ":time_created": login.time_created,
+                ":creation_source": login.creation_source.discriminant(),
+                ":creation_source_name": login.creation_source.source_name(),
+                ":associated_app": login.associated_app,
                 ":guid": login.guid_str(),
             })?;
             scope.err_if_interrupted()?;
@@ -3,12 +3,30 @@
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
 use crate::error::*;
-use rusqlite::Row;
+use rusqlite::{types::ToSql, Row};
+use std::net::Ipv6Addr;
 use std::time;
-use url::Url;
+use url::{Host, Url};
 
+/// Returns the host and (non-default) port of `url_str`, like
+/// `www.example.com:8443`. If `url_str` has no scheme, or is a bare IPv6
+/// address, it's parsed as a bare `host[:port]` instead. URLs without a host,
+/// like `javascript:` or `mailto:a@b`, return `None`. Either way, the host is
+/// normalized as a URL's would be: internationalized domain names are
+/// Punycode-encoded, and IPv6 addresses are bracketed, like `[::1]`.
 pub fn url_host_port(url_str: &str) -> Option<String> {
-    let url = Url::parse(url_str).ok()?;
+    if let Ok(addr) = url_str.parse::<Ipv6Addr>() {
+        return Some(format!("[{}]", addr));
+    }
+    let parse_bare = || Url::parse(&format!("http://{}", url_str)).ok();
+    let url = match Url::parse(url_str) {
+        Ok(url) if url.host_str().is_some() => url,
+        // A bare `host:port` parses as a URL whose scheme is the host and
+        // whose path starts with the port.
+        Ok(url) if is_port(url.path().split('/').next().unwrap_or_default()) => parse_bare()?,
+        Err(url::ParseError::RelativeUrlWithoutBase) => parse_bare()?,
+        _ => return None,
+    };
     let host = url.host_str()?;
     Some(if let Some(p) = url.port() {
         format!("{}:{}", host, p)
@@ -17,6 +35,10 @@ pub fn url_host_port(url_str: &str) -> Option<String> {
     })
 }
 
+fn is_port(s: &str) -> bool {
+    !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit())
+}
+
 pub fn system_time_millis_from_row(row: &Row<'_>, col_name: &str) -> Result<time::SystemTime> {
     let time_ms = row.get::<_, Option<i64>>(col_name)?.unwrap_or_default() as u64;
     Ok(time::UNIX_EPOCH + time::Duration::from_millis(time_ms))
@@ -30,6 +52,49 @@ pub fn system_time_ms_i64(t: time::SystemTime) -> i64 {
     duration_ms_i64(t.duration_since(time::UNIX_EPOCH).unwrap_or_default())
 }
 
+/// Approximates the registrable domain (the "eTLD+1") of `host` as its last
+/// two labels. We don't have the public suffix list, so this is wrong for
+/// suffixes like `co.uk`. IP addresses (and anything unparseable) are
+/// returned unchanged.
+pub fn approximate_base_domain(host: &str) -> String {
+    match Host::parse(host) {
+        Ok(Host::Domain(domain)) => {
+            let labels: Vec<&str> = domain.rsplitn(3, '.').collect();
+            if labels.len() < 3 {
+                domain
+            } else {
+                format!("{}.{}", labels[1], labels[0])
+            }
+        }
+        Ok(other) => other.to_string(),
+        Err(_) => host.to_string(),
+    }
+}
+
+/// Escapes the `LIKE` wildcards (`%` and `_`) in `s`, along with the escape
+/// character itself, so it can be used with `LIKE ... ESCAPE '\'`.
+pub fn escape_like(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        if c == '%' || c == '_' || c == '\\' {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Returns numbered placeholders for each of `values` (like `?1,?2,?3`),
+/// along with the values themselves as parameters to bind to them. Since
+/// the placeholders are numbered, they can be used more than once in the
+/// same statement.
+pub fn sql_placeholders_and_params<T: ToSql>(values: &[T]) -> (String, Vec<&dyn ToSql>) {
+    let placeholders =
+        sql_support::repeat_display(values.len(), ",", |i, f| write!(f, "?{}", i + 1)).to_string();
+    let params = values.iter().map(|v| v as &dyn ToSql).collect();
+    (placeholders, params)
+}
+
 // Unfortunately, there's not a better way to turn on logging in tests AFAICT
 #[cfg(test)]
 pub(crate) fn init_test_logging() {
@@ -39,3 +104,54 @@ pub(crate) fn init_test_logging() {
         env_logger::init_from_env(env_logger::Env::default().filter_or("RUST_LOG", "trace"));
     });
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_url_host_port() {
+        for (input, expected) in &[
+            (
+                "https://www.example.com:8443/login",
+                Some("www.example.com:8443"),
+            ),
+            ("https://www.example.com:443/", Some("www.example.com")),
+            ("www.example.com", Some("www.example.com")),
+            ("www.example.com:8080", Some("www.example.com:8080")),
+            // IPv6, bare and bracketed.
+            ("::1", Some("[::1]")),
+            ("0:0::1", Some("[::1]")),
+            ("fe80::1", Some("[fe80::1]")),
+            ("[::1]", Some("[::1]")),
+            ("[::1]:8080", Some("[::1]:8080")),
+            ("http://[0:0::1]:8080/", Some("[::1]:8080")),
+            // Internationalized domain names.
+            ("https://m\u{fc}nchen.de/", Some("xn--mnchen-3ya.de")),
+            ("m\u{fc}nchen.de", Some("xn--mnchen-3ya.de")),
+            ("xn--mnchen-3ya.de", Some("xn--mnchen-3ya.de")),
+            ("localhost:8080/login", Some("localhost:8080")),
+            // URLs with a scheme, but no host.
+            ("about:blank", None),
+            ("javascript:", None),
+            ("mailto:a@b", None),
+            ("data:text/html,hi", None),
+            ("", None),
+        ] {
+            assert_eq!(url_host_port(input).as_deref(), *expected, "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn test_sql_placeholders_and_params() {
+        for len in 0..=1000 {
+            let values: Vec<i64> = (0..len).collect();
+            let (placeholders, params) = sql_placeholders_and_params(&values);
+            assert_eq!(params.len(), values.len());
+            let vars: Vec<&str> = placeholders.split(',').filter(|v| !v.is_empty()).collect();
+            assert_eq!(vars.len(), values.len());
+        }
+        let (placeholders, _) = sql_placeholders_and_params(&["a", "b", "c"]);
+        assert_eq!(placeholders, "?1,?2,?3");
+    }
+}
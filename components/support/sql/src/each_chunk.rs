@@ -2,8 +2,10 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
+use crate::repeat_sql_vars;
 use lazy_static::lazy_static;
 use rusqlite::{self, limits::Limit, types::ToSql};
+use std::fmt;
 use std::iter::Map;
 use std::slice::Iter;
 
@@ -42,6 +44,24 @@ pub fn default_max_variable_number() -> usize {
 ///
 /// See `each_chunk_mapped` for the case where `T` doesn't implement `ToSql`, but can be
 /// converted to something that does.
+///
+/// # Example
+///
+/// ```rust
+/// # use sql_support::{each_chunk, repeat_sql_vars};
+/// # use rusqlite::Connection;
+/// let conn = Connection::open_in_memory()?;
+/// conn.execute_batch("CREATE TABLE t (id INTEGER); INSERT INTO t VALUES (1), (2), (3);")?;
+/// let mut deleted = 0;
+/// each_chunk(&[1, 3, 5], |chunk, _offset| -> rusqlite::Result<()> {
+///     let sql = format!("DELETE FROM t WHERE id IN ({})", repeat_sql_vars(chunk.len()));
+///     deleted += conn.execute(&sql, chunk)?;
+///     Ok(())
+/// })?;
+/// assert_eq!(deleted, 2);
+/// # Ok::<(), rusqlite::Error>(())
+/// ```
+#[inline]
 pub fn each_chunk<'a, T, E, F>(items: &'a [T], do_chunk: F) -> Result<(), E>
 where
     T: 'a,
@@ -52,6 +72,28 @@ where
 
 /// A version of `each_chunk` for the case when the conversion to `to_sql` requires an custom
 /// intermediate step. For example, you might want to grab a property off of an arrray of records
+///
+/// # Example
+///
+/// ```rust
+/// # use sql_support::{each_chunk_mapped, repeat_sql_vars};
+/// # use rusqlite::Connection;
+/// struct Record {
+///     id: i64,
+/// }
+/// let conn = Connection::open_in_memory()?;
+/// conn.execute_batch("CREATE TABLE t (id INTEGER); INSERT INTO t VALUES (1), (2), (3);")?;
+/// let records = [Record { id: 2 }, Record { id: 3 }];
+/// let mut deleted = 0;
+/// each_chunk_mapped(&records, |r| r.id, |chunk, _offset| -> rusqlite::Result<()> {
+///     let sql = format!("DELETE FROM t WHERE id IN ({})", repeat_sql_vars(chunk.len()));
+///     deleted += conn.execute(&sql, chunk)?;
+///     Ok(())
+/// })?;
+/// assert_eq!(deleted, 2);
+/// # Ok::<(), rusqlite::Error>(())
+/// ```
+#[inline]
 pub fn each_chunk_mapped<'a, T, U, E, Mapper, DoChunk>(
     items: &'a [T],
     to_sql: Mapper,
@@ -66,6 +108,108 @@ where
     each_sized_chunk_mapped(items, default_max_variable_number(), to_sql, do_chunk)
 }
 
+/// An error returned by the `do_chunk` callback of `each_chunk_with_context`,
+/// along with which chunk it was returned for.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChunkError<E> {
+    /// The index of the chunk, counting from 0.
+    pub chunk_index: usize,
+    /// The offset of the chunk's first item from the start of the items.
+    pub offset: usize,
+    pub error: E,
+}
+
+impl<E: fmt::Display> fmt::Display for ChunkError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Error in chunk {} (starting at item {}): {}",
+            self.chunk_index, self.offset, self.error
+        )
+    }
+}
+
+impl<E: std::error::Error + 'static> std::error::Error for ChunkError<E> {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+/// Like `each_chunk`, but if `do_chunk` fails, the error is wrapped in a
+/// `ChunkError` saying which chunk failed.
+///
+/// # Example
+///
+/// ```rust
+/// # use sql_support::each_chunk_with_context;
+/// let items: Vec<i64> = (0..2000).collect();
+/// let err = each_chunk_with_context(&items, |chunk, _offset| {
+///     if chunk.contains(&1500) {
+///         Err("bad item")
+///     } else {
+///         Ok(())
+///     }
+/// })
+/// .unwrap_err();
+/// assert!(err.chunk_index > 0);
+/// assert_eq!(err.error, "bad item");
+/// ```
+#[inline]
+pub fn each_chunk_with_context<'a, T, E, F>(
+    items: &'a [T],
+    mut do_chunk: F,
+) -> Result<(), ChunkError<E>>
+where
+    T: 'a,
+    F: FnMut(&'a [T], usize) -> Result<(), E>,
+{
+    let chunk_size = default_max_variable_number();
+    each_sized_chunk(items, chunk_size, |chunk, offset| {
+        do_chunk(chunk, offset).map_err(|error| ChunkError {
+            chunk_index: offset / chunk_size,
+            offset,
+            error,
+        })
+    })
+}
+
+/// Returns a `SELECT * FROM table WHERE col IN (?,?,...)` statement for each
+/// chunk of at most `max_var_count` of `values`, in order. Bind each one to
+/// the matching chunk of `values.chunks(max_var_count)`.
+///
+/// Panics if `max_var_count` is zero.
+///
+/// # Example
+///
+/// ```rust
+/// # use sql_support::chunked_sql_in;
+/// let sql: Vec<String> = chunked_sql_in("logins", "guid", &["a", "b", "c"], 2).collect();
+/// assert_eq!(
+///     sql,
+///     vec![
+///         "SELECT * FROM logins WHERE guid IN (?,?)",
+///         "SELECT * FROM logins WHERE guid IN (?)",
+///     ]
+/// );
+/// ```
+#[inline]
+pub fn chunked_sql_in<'a, T: ToSql>(
+    table: &'a str,
+    col: &'a str,
+    values: &'a [T],
+    max_var_count: usize,
+) -> impl Iterator<Item = String> + 'a {
+    assert!(max_var_count > 0, "max_var_count must be positive");
+    values.chunks(max_var_count).map(move |chunk| {
+        format!(
+            "SELECT * FROM {} WHERE {} IN ({})",
+            table,
+            col,
+            repeat_sql_vars(chunk.len())
+        )
+    })
+}
+
 // Split out for testing. Separate so that we can pass an actual slice
 // to the callback if they don't need mapping. We could probably unify
 // this with each_sized_chunk_mapped with a lot of type system trickery,
@@ -237,6 +381,58 @@ mod test_mapped {
     }
 }
 
+#[cfg(test)]
+mod test_context {
+    use super::*;
+
+    #[test]
+    fn test_chunk_error() {
+        let items: Vec<i64> = (0..(default_max_variable_number() as i64 * 2 + 1)).collect();
+        let last = *items.last().unwrap();
+        let mut calls = 0;
+        let e = each_chunk_with_context(&items, |chunk, _| {
+            calls += 1;
+            if chunk.contains(&last) {
+                Err("testing".to_string())
+            } else {
+                Ok(())
+            }
+        })
+        .expect_err("Should be an error");
+        assert_eq!(calls, 3);
+        assert_eq!(
+            e,
+            ChunkError {
+                chunk_index: 2,
+                offset: default_max_variable_number() * 2,
+                error: "testing".to_string(),
+            }
+        );
+        assert_eq!(
+            e.to_string(),
+            format!(
+                "Error in chunk 2 (starting at item {}): testing",
+                default_max_variable_number() * 2
+            )
+        );
+    }
+
+    #[test]
+    fn test_chunked_sql_in() {
+        let values: Vec<i64> = (0..5).collect();
+        let sql: Vec<String> = chunked_sql_in("t", "id", &values, 2).collect();
+        assert_eq!(
+            sql,
+            vec![
+                "SELECT * FROM t WHERE id IN (?,?)",
+                "SELECT * FROM t WHERE id IN (?,?)",
+                "SELECT * FROM t WHERE id IN (?)",
+            ]
+        );
+        assert_eq!(chunked_sql_in("t", "id", &[] as &[i64], 2).count(), 0);
+    }
+}
+
 #[cfg(test)]
 mod test_unmapped {
     use super::*;
@@ -31,3 +31,60 @@ impl<T> RecordChangeset<T> {
         }
     }
 }
+
+impl IncomingChangeset {
+    /// Drops records which share a GUID with another record in the changeset,
+    /// keeping only the one with the newest server timestamp (or, for ties,
+    /// the one which appears last). Returns the number of records removed.
+    pub fn dedup_by_guid(&mut self) -> usize {
+        let mut newest: std::collections::HashMap<&str, usize> = Default::default();
+        for (i, (payload, ts)) in self.changes.iter().enumerate() {
+            let entry = newest.entry(payload.id()).or_insert(i);
+            if *ts >= self.changes[*entry].1 {
+                *entry = i;
+            }
+        }
+        if newest.len() == self.changes.len() {
+            return 0;
+        }
+        let keep: std::collections::HashSet<usize> = newest.values().cloned().collect();
+        let before = self.changes.len();
+        let mut i = 0;
+        self.changes.retain(|_| {
+            let kept = keep.contains(&i);
+            i += 1;
+            kept
+        });
+        before - self.changes.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dedup_by_guid() {
+        let mut changeset = IncomingChangeset::new("test", ServerTimestamp(0));
+        changeset.changes = vec![
+            (Payload::new_tombstone("aaaa"), ServerTimestamp(1000)),
+            (Payload::new_tombstone("bbbb"), ServerTimestamp(1000)),
+            (Payload::new_tombstone("aaaa"), ServerTimestamp(3000)),
+            (Payload::new_tombstone("aaaa"), ServerTimestamp(2000)),
+        ];
+        assert_eq!(changeset.dedup_by_guid(), 2);
+        let remaining: Vec<_> = changeset
+            .changes
+            .iter()
+            .map(|(p, ts)| (p.id(), *ts))
+            .collect();
+        assert_eq!(
+            remaining,
+            vec![
+                ("bbbb", ServerTimestamp(1000)),
+                ("aaaa", ServerTimestamp(3000))
+            ]
+        );
+        assert_eq!(changeset.dedup_by_guid(), 0);
+    }
+}
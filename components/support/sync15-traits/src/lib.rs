@@ -17,7 +17,7 @@ pub use changeset::{IncomingChangeset, OutgoingChangeset, RecordChangeset};
 pub use payload::Payload;
 pub use request::{CollectionRequest, RequestOrder};
 pub use server_timestamp::ServerTimestamp;
-pub use store::{CollSyncIds, Store, StoreSyncAssociation};
+pub use store::{CollSyncIds, ConflictResolution, Store, StoreSyncAssociation};
 pub use sync_guid::Guid;
 
 // For skip_serializing_if
@@ -23,6 +23,23 @@ pub enum StoreSyncAssociation {
     Connected(CollSyncIds),
 }
 
+/// How a store should resolve conflicts between incoming records and local
+/// changes to the same record, as set by `Store::set_conflict_resolution`.
+/// Stores which don't support choosing can ignore this.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConflictResolution {
+    /// Keep both sides' changes to different fields. If both changed the
+    /// same field, the newer change wins.
+    FieldLevel,
+    /// Take the incoming record, discarding the local changes.
+    ServerWins,
+    /// Keep the local record, and upload it.
+    ClientWins,
+    /// Keep whichever of the incoming record and the local record was
+    /// changed most recently.
+    NewerWins,
+}
+
 /// Low-level store functionality. Stores that need custom reconciliation logic
 /// should use this.
 ///
@@ -31,6 +48,10 @@ pub enum StoreSyncAssociation {
 pub trait Store {
     fn collection_name(&self) -> std::borrow::Cow<'static, str>;
 
+    /// Sets how `apply_incoming` should resolve conflicts. Stores which only
+    /// support one strategy don't need to implement this.
+    fn set_conflict_resolution(&mut self, _strategy: ConflictResolution) {}
+
     /// Prepares the store for syncing. The tabs store currently uses this to
     /// store the current list of clients, which it uses to look up device names
     /// and types.
@@ -12,7 +12,7 @@ use crate::state::GlobalState;
 use crate::telemetry;
 use interrupt_support::Interruptee;
 
-pub use sync15_traits::{IncomingChangeset, Store};
+pub use sync15_traits::{ConflictResolution, IncomingChangeset, Store};
 
 pub fn synchronize(
     client: &Sync15StorageClient,